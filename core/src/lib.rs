@@ -317,6 +317,99 @@ impl From<f64> for TypedValue {
     }
 }
 
+/// A single value in the results of a query. Most queries produce scalar `TypedValue`s; pull
+/// expressions produce nested maps and vectors, much like EDN.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum Binding {
+    Scalar(TypedValue),
+    Vec(Rc<Vec<Binding>>),
+    Map(Rc<StructuredMap>),
+}
+
+/// A map from attribute keyword to value, as produced by a pull expression.
+/// Cardinality-many attributes map to a `Binding::Vec`; refs map to a nested `Binding::Map`.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct StructuredMap(pub BTreeMap<NamespacedKeyword, Binding>);
+
+impl StructuredMap {
+    pub fn insert<B: Into<Binding>>(&mut self, key: NamespacedKeyword, value: B) {
+        self.0.insert(key, value.into());
+    }
+
+    pub fn get(&self, key: &NamespacedKeyword) -> Option<&Binding> {
+        self.0.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<TypedValue> for Binding {
+    fn from(value: TypedValue) -> Binding {
+        Binding::Scalar(value)
+    }
+}
+
+impl From<StructuredMap> for Binding {
+    fn from(value: StructuredMap) -> Binding {
+        Binding::Map(Rc::new(value))
+    }
+}
+
+impl From<Vec<Binding>> for Binding {
+    fn from(value: Vec<Binding>) -> Binding {
+        Binding::Vec(Rc::new(value))
+    }
+}
+
+impl Binding {
+    /// Returns the `ValueType` of this binding, or `None` if it isn't a scalar.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            &Binding::Scalar(ref v) => Some(v.value_type()),
+            &Binding::Vec(_) | &Binding::Map(_) => None,
+        }
+    }
+
+    #[inline]
+    pub fn matches_type(&self, t: ValueType) -> bool {
+        self.value_type() == Some(t)
+    }
+
+    pub fn as_scalar(&self) -> Option<&TypedValue> {
+        match self {
+            &Binding::Scalar(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn into_scalar(self) -> Option<TypedValue> {
+        match self {
+            Binding::Scalar(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_vec(&self) -> Option<&Vec<Binding>> {
+        match self {
+            &Binding::Vec(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&StructuredMap> {
+        match self {
+            &Binding::Map(ref m) => Some(m),
+            _ => None,
+        }
+    }
+}
+
 /// Type safe representation of the possible return values from SQLite's `typeof`
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
 pub enum SQLTypeAffinity {
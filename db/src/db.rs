@@ -11,7 +11,11 @@
 #![allow(dead_code)]
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    HashMap,
+};
 use std::fmt::Display;
 use std::iter::{once, repeat};
 use std::ops::Range;
@@ -20,6 +24,7 @@ use std::rc::Rc;
 
 use itertools;
 use itertools::Itertools;
+use regex::Regex;
 use rusqlite;
 use rusqlite::TransactionBehavior;
 use rusqlite::limits::Limit;
@@ -49,7 +54,13 @@ use mentat_core::{
     ToMicros,
     ValueType,
 };
-use errors::{ErrorKind, Result, ResultExt};
+use errors::{
+    CardinalityConflict,
+    ErrorKind,
+    Result,
+    ResultExt,
+    UniqueValueConflict,
+};
 use metadata;
 use schema::{
     SchemaBuilding,
@@ -84,9 +95,34 @@ pub fn new_connection<T>(uri: T) -> rusqlite::Result<rusqlite::Connection> where
         PRAGMA temp_store=2;
     ")?;
 
+    register_functions(&conn)?;
+
     Ok(conn)
 }
 
+/// Register the SQL functions that queries use but SQLite doesn't provide. Functions belong to a
+/// connection, so every connection a store is opened on needs them: see `ensure_current_version`.
+fn register_functions(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // `regexp(pattern, text)` is true if `text` contains a match for `pattern`. This also gives
+    // meaning to SQLite's `text REGEXP pattern` operator. A query usually uses the same pattern for
+    // every row, so we keep the last one we compiled.
+    let mut last: Option<(String, Regex)> = None;
+    conn.create_scalar_function("regexp", 2, true, move |ctx| {
+        let pattern: String = ctx.get(0)?;
+        let text: String = ctx.get(1)?;
+
+        let compiled = match last {
+            Some((ref p, _)) => *p == pattern,
+            None => false,
+        };
+        if !compiled {
+            let regex = Regex::new(pattern.as_str()).map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+            last = Some((pattern, regex));
+        }
+        Ok(last.as_ref().map_or(false, |&(_, ref regex)| regex.is_match(text.as_str())))
+    })
+}
+
 /// Version history:
 ///
 /// 1: initial Rust Mentat schema.
@@ -341,6 +377,9 @@ pub fn ensure_current_version(conn: &mut rusqlite::Connection) -> Result<DB> {
         panic!("Mentat requires at least sqlite {}", MIN_SQLITE_VERSION);
     }
 
+    // The connection needn't have come from `new_connection`.
+    register_functions(conn)?;
+
     let user_version = get_user_version(&conn)?;
     match user_version {
         0               => create_current_version(conn),
@@ -496,6 +535,33 @@ pub fn read_db(conn: &rusqlite::Connection) -> Result<DB> {
     Ok(DB::new(partition_map, schema))
 }
 
+/// Read the [e a v added] datoms of the transaction `tx` from the given SQL store, ordered by
+/// (e, a, v, added).  Fulltext values are read back as strings.
+///
+/// `schema` must describe every attribute in the transaction, including any it installs.
+pub fn read_transaction(conn: &rusqlite::Connection, schema: &Schema, tx: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>> {
+    let mut stmt = conn.prepare_cached("SELECT e, a, v, value_type_tag, added FROM transactions WHERE tx = ? ORDER BY e, a, value_type_tag, v, added")?;
+    let mut fulltext_stmt = conn.prepare_cached("SELECT text FROM fulltext_values WHERE rowid = ?")?;
+    let params = [&tx as &ToSql];
+    let m: Result<Vec<_>> = stmt.query_and_then(&params[..], |row| -> Result<(Entid, Entid, TypedValue, bool)> {
+        let a: Entid = row.get_checked(1)?;
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+
+        // A fulltext value is stored as a rowid into fulltext_values.
+        let v: rusqlite::types::Value = if schema.require_attribute_for_entid(a)?.fulltext {
+            fulltext_stmt.query_row(&[&v as &ToSql], |row| row.get_checked(0))??
+        } else {
+            v
+        };
+
+        Ok((row.get_checked(0)?,
+            a,
+            TypedValue::from_sql_value_pair(v, row.get_checked(3)?)?,
+            row.get_checked(4)?))
+    })?.collect();
+    m
+}
+
 /// Internal representation of an [e a v added] datom, ready to be transacted against the store.
 pub type ReducedEntity<'a> = (Entid, Entid, &'a Attribute, TypedValue, bool);
 
@@ -540,6 +606,12 @@ pub trait MentatStoring {
 
     /// Extract metadata-related [e a typed_value added] datoms committed in the given transaction.
     fn committed_metadata_assertions(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>>;
+
+    /// Extract the [a typed_value] pairs of every datom asserted about the entity `e`.
+    fn entity_avs(&self, e: Entid) -> Result<Vec<(Entid, TypedValue)>>;
+
+    /// Extract the [e a] pairs of every datom whose value is a reference to the entity `v`.
+    fn referencing_eas(&self, v: Entid) -> Result<Vec<(Entid, Entid)>>;
 }
 
 /// Take search rows and complete `temp.search_results`.
@@ -643,13 +715,79 @@ fn update_datoms(conn: &rusqlite::Connection, tx: Entid) -> Result<()> {
       AttributeBitFlags::UniqueValue as u8);
 
     let mut stmt = conn.prepare_cached(&s)?;
-    stmt.execute(&[&tx])
+    let inserted = stmt.execute(&[&tx]);
+    if inserted.is_err() {
+        // The likeliest failure is a :db/unique value held by more than one entity.  SQLite
+        // doesn't say which, so go and look.
+        let conflicts = unique_value_conflicts(conn)?;
+        if !conflicts.is_empty() {
+            bail!(ErrorKind::UniqueValueConflicts(conflicts));
+        }
+    }
+    inserted
         .map(|_c| ())
         .chain_err(|| "Could not update datoms: failed to add datoms not already present")?;
 
     Ok(())
 }
 
+/// Find the `:db/unique` [a v] pairs that the datoms being added by `update_datoms` would give to
+/// more than one entity, either within the transaction or alongside datoms already in the store.
+fn unique_value_conflicts(conn: &rusqlite::Connection) -> Result<Vec<UniqueValueConflict>> {
+    // Fulltext values are stored by rowid, so they can't be compared here.
+    let s = format!(r#"
+      SELECT e0, a0, v0, value_type_tag0
+      FROM temp.search_results
+      WHERE added0 IS 1 AND ((rid IS NULL) OR ((rid IS NOT NULL) AND (v0 IS NOT v))) AND
+            flags0 & {unique} IS NOT 0 AND flags0 & {fulltext} IS 0
+      UNION
+      SELECT d.e, d.a, d.v, d.value_type_tag
+      FROM datoms AS d, temp.search_results AS s
+      WHERE s.added0 IS 1 AND s.flags0 & {unique} IS NOT 0 AND s.flags0 & {fulltext} IS 0 AND
+            d.a IS s.a0 AND d.value_type_tag IS s.value_type_tag0 AND d.v IS s.v0 AND
+            d.unique_value IS NOT 0"#,
+      unique = AttributeBitFlags::UniqueValue as u8,
+      fulltext = AttributeBitFlags::IndexFulltext as u8);
+
+    let mut stmt = conn.prepare(&s)?;
+    let m: Result<Vec<(Entid, Entid, TypedValue)>> = stmt.query_and_then(&[], |row| {
+        let e: Entid = row.get_checked(0)?;
+        let a: Entid = row.get_checked(1)?;
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+        let typed_value = TypedValue::from_sql_value_pair(v, value_type_tag)?;
+        Ok((e, a, typed_value))
+    })?.collect();
+
+    let mut holders: BTreeMap<(Entid, Value), BTreeSet<Entid>> = BTreeMap::default();
+    for (e, a, v) in m? {
+        holders.entry((a, v.to_edn_value_pair().0)).or_insert_with(BTreeSet::default).insert(e);
+    }
+
+    Ok(holders.into_iter()
+              .filter(|&(_, ref es)| es.len() > 1)
+              .map(|((a, v), es)| UniqueValueConflict { a: a, v: v, es: es })
+              .collect())
+}
+
+/// Find the [e a] pairs that are asserted with more than one value in a set of
+/// `:db.cardinality/one` searches.
+///
+/// Asserting the same value twice isn't a conflict, and retractions are ignored.
+fn cardinality_conflicts<'a>(entities: &'a [ReducedEntity<'a>]) -> Vec<CardinalityConflict> {
+    let mut values: BTreeMap<(Entid, Entid), BTreeSet<Value>> = BTreeMap::default();
+    for &(e, a, _, ref typed_value, added) in entities {
+        if added {
+            values.entry((e, a)).or_insert_with(BTreeSet::default).insert(typed_value.to_edn_value_pair().0);
+        }
+    }
+
+    values.into_iter()
+          .filter(|&(_, ref vs)| vs.len() > 1)
+          .map(|((e, a), vs)| CardinalityConflict { e: e, a: a, vs: vs })
+          .collect()
+}
+
 impl MentatStoring for rusqlite::Connection {
     fn resolve_avs<'a>(&self, avs: &'a [&'a AVPair]) -> Result<AVMap<'a>> {
         // Start search_id's at some identifiable number.
@@ -783,6 +921,13 @@ impl MentatStoring for rusqlite::Connection {
     /// Eventually, the details of this approach will be captured in
     /// https://github.com/mozilla/mentat/wiki/Transacting:-entity-to-SQL-translation.
     fn insert_non_fts_searches<'a>(&self, entities: &'a [ReducedEntity<'a>], search_type: SearchType) -> Result<()> {
+        if search_type == SearchType::Inexact {
+            let conflicts = cardinality_conflicts(entities);
+            if !conflicts.is_empty() {
+                bail!(ErrorKind::CardinalityConflicts(conflicts));
+            }
+        }
+
         let bindings_per_statement = 6;
 
         let max_vars = self.limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER) as usize;
@@ -847,6 +992,13 @@ impl MentatStoring for rusqlite::Connection {
     /// Eventually, the details of this approach will be captured in
     /// https://github.com/mozilla/mentat/wiki/Transacting:-entity-to-SQL-translation.
     fn insert_fts_searches<'a>(&self, entities: &'a [ReducedEntity<'a>], search_type: SearchType) -> Result<()> {
+        if search_type == SearchType::Inexact {
+            let conflicts = cardinality_conflicts(entities);
+            if !conflicts.is_empty() {
+                bail!(ErrorKind::CardinalityConflicts(conflicts));
+            }
+        }
+
         let max_vars = self.limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER) as usize;
         let bindings_per_statement = 6;
 
@@ -959,6 +1111,26 @@ impl MentatStoring for rusqlite::Connection {
         })?.collect();
         m
     }
+
+    fn entity_avs(&self, e: Entid) -> Result<Vec<(Entid, TypedValue)>> {
+        let mut stmt = self.prepare_cached("SELECT a, v, value_type_tag FROM all_datoms WHERE e = ? ORDER BY a, value_type_tag, v")?;
+        let params = [&e as &ToSql];
+        let m: Result<Vec<_>> = stmt.query_and_then(&params[..], |row| -> Result<(Entid, TypedValue)> {
+            Ok((row.get_checked(0)?,
+                TypedValue::from_sql_value_pair(row.get_checked(1)?, row.get_checked(2)?)?))
+        })?.collect();
+        m
+    }
+
+    fn referencing_eas(&self, v: Entid) -> Result<Vec<(Entid, Entid)>> {
+        // Only :db.type/ref values are tagged 0, and they're never fulltext indexed.
+        let mut stmt = self.prepare_cached("SELECT e, a FROM datoms WHERE v = ? AND value_type_tag = 0 ORDER BY e, a")?;
+        let params = [&v as &ToSql];
+        let m: Result<Vec<_>> = stmt.query_and_then(&params[..], |row| -> Result<(Entid, Entid)> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?))
+        })?.collect();
+        m
+    }
 }
 
 /// Update the current partition map materialized view.
@@ -1259,6 +1431,21 @@ mod tests {
         edn::Value::Map(map)
     }
 
+    #[test]
+    fn test_regexp_function() {
+        let conn = new_connection("").expect("Couldn't open in-memory db");
+        let matches = |sql: &str| -> bool {
+            conn.query_row(sql, &[], |row| row.get(0)).expect("to run regexp")
+        };
+
+        assert!(matches("SELECT regexp('[0-9]+', 'abc123')"));
+        assert!(!matches("SELECT regexp('^[0-9]+$', 'abc123')"));
+        assert!(matches("SELECT 'abc123' REGEXP 'c1'"));
+
+        // An invalid pattern is an error, not a failed match.
+        assert!(conn.query_row("SELECT regexp('(', 'abc')", &[], |row| row.get::<_, bool>(0)).is_err());
+    }
+
     #[test]
     fn test_add() {
         let mut conn = TestConn::default();
@@ -1421,6 +1608,120 @@ mod tests {
                           [200 :db.schema/attribute 101]]");
     }
 
+    #[test]
+    fn test_retract_entity_and_attribute() {
+        let mut conn = TestConn::default();
+
+        // Start by installing a few attributes.
+        assert_transact!(conn, "[[:db/add 111 :db/ident :test/many]
+                                 [:db/add 111 :db/valueType :db.type/long]
+                                 [:db/add 111 :db/cardinality :db.cardinality/many]
+                                 [:db/add 222 :db/ident :test/component]
+                                 [:db/add 222 :db/isComponent true]
+                                 [:db/add 222 :db/valueType :db.type/ref]
+                                 [:db/add 222 :db/cardinality :db.cardinality/many]
+                                 [:db/add 333 :db/ident :test/unique]
+                                 [:db/add 333 :db/unique :db.unique/identity]
+                                 [:db/add 333 :db/index true]
+                                 [:db/add 333 :db/valueType :db.type/long]
+                                 [:db/add 444 :db/ident :test/ref]
+                                 [:db/add 444 :db/valueType :db.type/ref]
+                                 [:db/add 444 :db/cardinality :db.cardinality/many]]");
+
+        // 500 has components 501 and (transitively) 502, refers to 503, and is referred to by 504.
+        assert_transact!(conn, "[[:db/add 500 :test/unique 1]
+                                 [:db/add 500 :test/many 10]
+                                 [:db/add 500 :test/many 11]
+                                 [:db/add 500 :test/component 501]
+                                 [:db/add 501 :test/many 12]
+                                 [:db/add 501 :test/component 502]
+                                 [:db/add 502 :test/many 13]
+                                 [:db/add 500 :test/ref 503]
+                                 [:db/add 503 :test/many 14]
+                                 [:db/add 504 :test/ref 500]
+                                 [:db/add 504 :test/many 15]]");
+
+        // Tempids name no existing entity, so there's nothing to retract.
+        assert_transact!(conn, "[[:db/retractEntity \"t\"]]",
+                         Err("not yet implemented: Cannot retract entity or attribute of tempid t"));
+
+        // Retracting an entity retracts its datoms, the datoms that refer to it, and its
+        // components, but not the entities it merely refers to.
+        assert_transact!(conn, "[[:db/retractEntity (lookup-ref :test/unique 1)]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[500 :test/many 10 ?tx false]
+                          [500 :test/many 11 ?tx false]
+                          [500 :test/component 501 ?tx false]
+                          [500 :test/unique 1 ?tx false]
+                          [500 :test/ref 503 ?tx false]
+                          [501 :test/many 12 ?tx false]
+                          [501 :test/component 502 ?tx false]
+                          [502 :test/many 13 ?tx false]
+                          [504 :test/ref 500 ?tx false]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // Retracting an attribute retracts every value of that attribute.
+        assert_transact!(conn, "[[:db/add 503 :test/many 16]]");
+        assert_transact!(conn, "[[:db/retractAttribute 503 :test/many]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[503 :test/many 14 ?tx false]
+                          [503 :test/many 16 ?tx false]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // Retracting an entity or attribute with no datoms doesn't change the store.
+        assert_transact!(conn, "[[:db/retractEntity 500]
+                                 [:db/retractAttribute 504 :test/ref]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[?tx :db/txInstant ?ms ?tx true]]");
+    }
+
+    #[test]
+    fn test_cas() {
+        let mut conn = TestConn::default();
+
+        // Start by installing a few attributes.
+        assert_transact!(conn, "[[:db/add 111 :db/ident :test/one]
+                                 [:db/add 111 :db/valueType :db.type/long]
+                                 [:db/add 111 :db/cardinality :db.cardinality/one]
+                                 [:db/add 222 :db/ident :test/many]
+                                 [:db/add 222 :db/valueType :db.type/long]
+                                 [:db/add 222 :db/cardinality :db.cardinality/many]]");
+
+        assert_transact!(conn, "[[:db/add 500 :test/one 1]]");
+
+        // Swapping from the current value replaces it.
+        assert_transact!(conn, "[[:db/cas 500 :test/one 1 2]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[500 :test/one 1 ?tx false]
+                          [500 :test/one 2 ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // Swapping from a stale value fails the whole transaction.
+        assert_transact!(conn, "[[:db/cas 500 :test/one 1 3]
+                                 [:db/add 501 :test/one 4]]",
+                         Err(":db/cas failed for [500 111]: expected 1 but found 2"));
+        assert_matches!(conn.last_transaction(),
+                        "[[500 :test/one 1 ?tx false]
+                          [500 :test/one 2 ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // nil expects no current value.
+        assert_transact!(conn, "[[:db/cas 501 :test/one nil 5]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[501 :test/one 5 ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+        assert_transact!(conn, "[[:db/cas 501 :test/one nil 6]]",
+                         Err(":db/cas failed for [501 111]: expected nil but found 5"));
+        assert_transact!(conn, "[[:db/cas 502 :test/one 1 6]]",
+                         Err(":db/cas failed for [502 111]: expected 1 but found nil"));
+
+        // Only a single value can be compared and swapped.
+        assert_transact!(conn, "[[:db/cas 500 :test/many 1 2]]",
+                         Err("not yet implemented: Cannot :db/cas attribute 222 that is not :db.cardinality :db.cardinality/one"));
+        assert_transact!(conn, "[[:db/cas \"t\" :test/one 1 2]]",
+                         Err("not yet implemented: Cannot :db/cas tempid t"));
+    }
+
     // Unique is required!
     #[test]
     fn test_upsert_issue_538() {
@@ -1501,7 +1802,7 @@ mod tests {
         // Conflicting upserts fail.
         assert_transact!(conn, "[[:db/add \"t1\" :db/ident :name/Ivan]
                                 [:db/add \"t1\" :db/ident :name/Petr]]",
-                         Err("not yet implemented: Conflicting upsert: tempid \'t1\' resolves to more than one entid: 100, 101"));
+                         Err("conflicting upserts in tx: tempid t1 upserts to #{ 100 101 } via [1 :name/Ivan] -> 100, [1 :name/Petr] -> 101"));
 
         // tempids in :db/retract that don't upsert fail.
         assert_transact!(conn, "[[:db/retract \"t1\" :db/ident :name/Anonymous]]",
@@ -2359,13 +2660,98 @@ mod tests {
             [:db/add "bar" :test/unique "x"]
             [:db/add "bar" :test/one 124]
         ]"#,
-        Err("Could not insert non-fts one statements into temporary search table!"));
+        Err("cardinality one conflicts in tx: [65536 111] asserted with values #{ 123 124 }"));
 
         // It also fails for map notation.
         assert_transact!(conn, r#"[
             {:test/unique "x", :test/one 123}
             {:test/unique "x", :test/one 124}
         ]"#,
-        Err("Could not insert non-fts one statements into temporary search table!"));
+        Err("cardinality one conflicts in tx: [65536 111] asserted with values #{ 123 124 }"));
+    }
+
+    #[test]
+    fn test_conflicting_upserts() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, r#"[
+            [:db/add 111 :db/ident :test/unique]
+            [:db/add 111 :db/valueType :db.type/string]
+            [:db/add 111 :db/unique :db.unique/identity]
+            [:db/add 111 :db/index true]
+            [:db/add 222 :db/ident :test/other]
+            [:db/add 222 :db/valueType :db.type/long]
+            [:db/add 222 :db/unique :db.unique/identity]
+            [:db/add 222 :db/index true]
+        ]"#);
+
+        assert_transact!(conn, r#"[
+            [:db/add 501 :test/unique "x"]
+            [:db/add 502 :test/unique "y"]
+            [:db/add 503 :test/other 1]
+        ]"#);
+
+        // Every conflicting tempid is reported, with each [a v] pair and the entid it upserted
+        // to.  "c" upserts without conflict, so it isn't mentioned.
+        let err = conn.transact(r#"[
+            [:db/add "a" :test/unique "x"]
+            [:db/add "a" :test/unique "y"]
+            [:db/add "b" :test/unique "x"]
+            [:db/add "b" :test/other 1]
+            [:db/add "c" :test/unique "x"]
+        ]"#).unwrap_err();
+        match err.kind() {
+            &ErrorKind::ConflictingUpserts(ref conflicts) => {
+                let tempids: Vec<String> = conflicts.iter().map(|c| c.tempid.to_string()).collect();
+                assert_eq!(tempids, vec!["a".to_string(), "b".to_string()]);
+                assert_eq!(conflicts[0].entids().into_iter().collect::<Vec<_>>(), vec![501, 502]);
+                assert_eq!(conflicts[1].entids().into_iter().collect::<Vec<_>>(), vec![501, 503]);
+            },
+            x => panic!("expected conflicting upserts, got {:?}", x),
+        }
+        assert_eq!(err.to_string(),
+                   "conflicting upserts in tx: \
+                    tempid a upserts to #{ 501 502 } via [111 \"x\"] -> 501, [111 \"y\"] -> 502; \
+                    tempid b upserts to #{ 501 503 } via [111 \"x\"] -> 501, [222 1] -> 503");
+    }
+
+    #[test]
+    fn test_unique_value_violation() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, r#"[
+            [:db/add 111 :db/ident :test/unique_value]
+            [:db/add 111 :db/valueType :db.type/string]
+            [:db/add 111 :db/unique :db.unique/value]
+            [:db/add 111 :db/index true]
+        ]"#);
+
+        assert_transact!(conn, r#"[
+            [:db/add 501 :test/unique_value "x"]
+        ]"#);
+
+        // A value already held by another entity.
+        assert_transact!(conn, r#"[
+            [:db/add 502 :test/unique_value "x"]
+        ]"#,
+        Err("unique value conflicts in tx: [111 \"x\"] held by entids #{ 501 502 }"));
+
+        // A value given to more than one entity in the same transaction.
+        assert_transact!(conn, r#"[
+            [:db/add 503 :test/unique_value "y"]
+            [:db/add 504 :test/unique_value "y"]
+            [:db/add 505 :test/unique_value "z"]
+        ]"#,
+        Err("unique value conflicts in tx: [111 \"y\"] held by entids #{ 503 504 }"));
+
+        // Moving a value from one entity to another is fine.
+        assert_transact!(conn, r#"[
+            [:db/retract 501 :test/unique_value "x"]
+            [:db/add 502 :test/unique_value "x"]
+        ]"#);
+        assert_matches!(conn.last_transaction(),
+                        r#"[[501 :test/unique_value "x" ?tx false]
+                            [502 :test/unique_value "x" ?tx true]
+                            [?tx :db/txInstant ?ms ?tx true]]"#);
     }
 }
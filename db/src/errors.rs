@@ -10,12 +10,83 @@
 
 #![allow(dead_code)]
 
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::fmt;
+
 use edn;
 use rusqlite;
 
+use mentat_tx::entities::TempId;
 use mentat_tx_parser;
 use types::{Entid, ValueType};
 
+// Values are reported as EDN rather than `TypedValue`, which isn't `Send`.
+
+/// A tempid that upserted to more than one entid: each `[a v]` pair it was asserted with, mapped to
+/// the entid that pair names in the store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpsertConflict {
+    pub tempid: TempId,
+    pub upserts: BTreeMap<(Entid, edn::Value), Entid>,
+}
+
+impl UpsertConflict {
+    /// The distinct entids the tempid upserted to.
+    pub fn entids(&self) -> BTreeSet<Entid> {
+        self.upserts.values().cloned().collect()
+    }
+}
+
+impl fmt::Display for UpsertConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tempid {} upserts to {} via", self.tempid, entid_set(&self.entids()))?;
+        for (i, (&(a, ref v), e)) in self.upserts.iter().enumerate() {
+            write!(f, "{} [{} {}] -> {}", if i == 0 { "" } else { "," }, a, v, e)?;
+        }
+        Ok(())
+    }
+}
+
+/// An `[e a]` pair asserted with more than one value in a single transaction, where `a` is
+/// `:db.cardinality/one`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CardinalityConflict {
+    pub e: Entid,
+    pub a: Entid,
+    pub vs: BTreeSet<edn::Value>,
+}
+
+impl fmt::Display for CardinalityConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{} {}] asserted with values {}", self.e, self.a, edn::Value::Set(self.vs.clone()))
+    }
+}
+
+/// An `[a v]` pair that would belong to more than one entity, where `a` is `:db/unique`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UniqueValueConflict {
+    pub a: Entid,
+    pub v: edn::Value,
+    pub es: BTreeSet<Entid>,
+}
+
+impl fmt::Display for UniqueValueConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{} {}] held by entids {}", self.a, self.v, entid_set(&self.es))
+    }
+}
+
+fn entid_set(entids: &BTreeSet<Entid>) -> edn::Value {
+    edn::Value::Set(entids.iter().map(|&e| edn::Value::Integer(e)).collect())
+}
+
+fn join<T: fmt::Display>(conflicts: &[T]) -> String {
+    conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("; ")
+}
+
 error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
@@ -83,9 +154,52 @@ error_chain! {
             display("unrecognized or no ident found for entid: {}", entid)
         }
 
+        /// Tempids that upserted to more than one entid.
+        ConflictingUpserts(conflicts: Vec<UpsertConflict>) {
+            description("conflicting upserts in tx")
+            display("conflicting upserts in tx: {}", join(conflicts))
+        }
+
+        /// `:db.cardinality/one` attributes asserted with more than one value for an entity.
+        CardinalityConflicts(conflicts: Vec<CardinalityConflict>) {
+            description("cardinality one conflicts in tx")
+            display("cardinality one conflicts in tx: {}", join(conflicts))
+        }
+
+        /// `:db/unique` values that would belong to more than one entity.
+        UniqueValueConflicts(conflicts: Vec<UniqueValueConflict>) {
+            description("unique value conflicts in tx")
+            display("unique value conflicts in tx: {}", join(conflicts))
+        }
+
         ConflictingDatoms {
             description("conflicting datoms in tx")
             display("conflicting datoms in tx")
         }
+
+        /// A `:db/cas` found a value other than the one it expected.  `nil` means no value.
+        CasFailed(e: Entid, a: Entid, expected: edn::Value, actual: edn::Value) {
+            description(":db/cas failed")
+            display(":db/cas failed for [{} {}]: expected {} but found {}", e, a, expected, actual)
+        }
+
+        /// A transaction invoked a function that isn't registered.
+        UnknownTxFunction(name: String) {
+            description("unknown transaction function")
+            display("unknown transaction function: {}", name)
+        }
+
+        /// Transaction function invocations nested more deeply than the limit, most likely
+        /// because a function invokes itself.
+        TxFunctionDepthExceeded(name: String, limit: usize) {
+            description("transaction functions nested too deeply")
+            display("transaction function {} nested more than {} invocations deep", name, limit)
+        }
+
+        /// A transaction function failed.  The function's own error is chained.
+        TxFunctionFailed(name: String) {
+            description("transaction function failed")
+            display("transaction function {} failed", name)
+        }
     }
 }
@@ -56,6 +56,24 @@ pub enum LookupRefOrTempId {
     TempId(TempIdHandle)
 }
 
+/// A `:db/retractEntity` or `:db/retractAttribute` on its way to being expanded into the
+/// `:db/retract` terms it stands for.  Expansion reads the store, so it waits until lookup refs
+/// have been resolved.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum Retraction<E> {
+    Entity(E),
+    Attribute(E, Entid),
+}
+
+/// A `:db/cas` expectation on its way to being checked against the store: the current value of
+/// `[e a]` must be `v`, or there must be no current value if `v` is `None`.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct CasCheck<E> {
+    pub e: E,
+    pub a: Entid,
+    pub v: Option<TypedValue>,
+}
+
 pub type TermWithTempIdsAndLookupRefs = Term<KnownEntidOr<LookupRefOrTempId>, TypedValueOr<LookupRefOrTempId>>;
 pub type TermWithTempIds = Term<KnownEntidOr<TempIdHandle>, TypedValueOr<TempIdHandle>>;
 pub type TermWithoutTempIds = Term<KnownEntid, TypedValue>;
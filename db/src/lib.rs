@@ -17,6 +17,7 @@ extern crate itertools;
 
 #[macro_use]
 extern crate lazy_static;
+extern crate regex;
 extern crate rusqlite;
 extern crate tabwriter;
 extern crate time;
@@ -31,7 +32,15 @@ use std::iter::repeat;
 
 use itertools::Itertools;
 
-pub use errors::{Error, ErrorKind, ResultExt, Result};
+pub use errors::{
+    CardinalityConflict,
+    Error,
+    ErrorKind,
+    ResultExt,
+    Result,
+    UniqueValueConflict,
+    UpsertConflict,
+};
 
 mod add_retract_alter_set;
 pub mod cache;
@@ -74,8 +83,12 @@ pub use db::{
 };
 
 pub use tx::{
+    MAX_TX_FUNCTION_DEPTH,
+    NoTxFunctions,
+    TxFunctions,
     transact,
     transact_terms,
+    transact_with_functions,
 };
 
 pub use types::{
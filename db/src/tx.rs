@@ -57,16 +57,20 @@ use db;
 use db::{
     MentatStoring,
     PartitionMapping,
+    TypedSQLValue,
 };
+use edn;
 use edn::{
     NamespacedKeyword,
 };
 use entids;
-use errors::{ErrorKind, Result};
+use errors::{ErrorKind, Result, UpsertConflict};
 use internal_types::{
     KnownEntidOr,
     LookupRef,
+    CasCheck,
     LookupRefOrTempId,
+    Retraction,
     TempIdHandle,
     TempIdMap,
     Term,
@@ -115,6 +119,28 @@ use types::{
 };
 use upsert_resolution::Generation;
 
+/// How deeply transaction function invocations may nest: an invocation that expands to an
+/// invocation that expands to ..., more than this many times over, fails the transaction.
+pub const MAX_TX_FUNCTION_DEPTH: usize = 32;
+
+/// Expands transaction function invocations, like `[:app/increment e a delta]`, into the entities
+/// they stand for.  The transactor asks for the expansion of each invocation it sees, and then
+/// transacts the resulting entities in its place -- which may themselves invoke functions.
+pub trait TxFunctions {
+    /// Expand an invocation of the function named `f` with the given `args`.  `sqlite` and `schema`
+    /// read the store as it stands before the current transaction is applied.
+    fn expand(&self, sqlite: &rusqlite::Connection, schema: &Schema, f: &NamespacedKeyword, args: &[edn::ValueAndSpan]) -> Result<Vec<Entity>>;
+}
+
+/// No transaction functions at all: every invocation fails.
+pub struct NoTxFunctions;
+
+impl TxFunctions for NoTxFunctions {
+    fn expand(&self, _sqlite: &rusqlite::Connection, _schema: &Schema, f: &NamespacedKeyword, _args: &[edn::ValueAndSpan]) -> Result<Vec<Entity>> {
+        bail!(ErrorKind::UnknownTxFunction(f.to_string()))
+    }
+}
+
 /// A transaction on its way to being applied.
 #[derive(Debug)]
 pub struct Tx<'conn, 'a> {
@@ -179,20 +205,36 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         // Lookup in the store.
         let av_map: AVMap = self.store.resolve_avs(&av_pairs[..])?;
 
-        // Map id->entid.
-        let mut temp_id_map: TempIdMap = TempIdMap::default();
+        // Map id->[a v]->entid, so that conflicting upserts can be reported in full.
+        let mut upserts: BTreeMap<TempIdHandle, BTreeMap<(Entid, edn::Value), Entid>> = BTreeMap::default();
         for &(ref temp_id, ref av_pair) in temp_id_avs {
             if let Some(n) = av_map.get(&av_pair) {
-                if let Some(&KnownEntid(previous_n)) = temp_id_map.get(&*temp_id) {
-                    if *n != previous_n {
-                        // Conflicting upsert!  TODO: collect conflicts and give more details on what failed this transaction.
-                        bail!(ErrorKind::NotYetImplemented(format!("Conflicting upsert: tempid '{}' resolves to more than one entid: {:?}, {:?}", temp_id, previous_n, n))) // XXX
-                    }
-                }
-                temp_id_map.insert(temp_id.clone(), KnownEntid(*n));
+                upserts.entry(temp_id.clone())
+                       .or_insert_with(BTreeMap::default)
+                       .insert((av_pair.0, av_pair.1.to_edn_value_pair().0), *n);
             }
         }
 
+        // Map id->entid, collecting every tempid that resolves to more than one entid.
+        let mut temp_id_map: TempIdMap = TempIdMap::default();
+        let mut conflicts: Vec<UpsertConflict> = vec![];
+        for (temp_id, upserts) in upserts {
+            let conflict = UpsertConflict {
+                tempid: (*temp_id).clone(),
+                upserts: upserts,
+            };
+            let entids = conflict.entids();
+            if entids.len() > 1 {
+                conflicts.push(conflict);
+            } else if let Some(&n) = entids.iter().next() {
+                temp_id_map.insert(temp_id, KnownEntid(n));
+            }
+        }
+
+        if !conflicts.is_empty() {
+            bail!(ErrorKind::ConflictingUpserts(conflicts));
+        }
+
         Ok(temp_id_map)
     }
 
@@ -201,7 +243,11 @@ impl<'conn, 'a> Tx<'conn, 'a> {
     ///
     /// The `Term` instances produce share interned TempId and LookupRef handles, and we return the
     /// interned handle sets so that consumers can ensure all handles are used appropriately.
-    fn entities_into_terms_with_temp_ids_and_lookup_refs<I>(&self, entities: I) -> Result<(Vec<TermWithTempIdsAndLookupRefs>, InternSet<TempId>, InternSet<AVPair>)> where I: IntoIterator<Item=Entity> {
+    ///
+    /// `:db/retractEntity` and `:db/retractAttribute` can't be turned into terms without reading
+    /// the store, so they are returned separately as `Retraction` instances.  Likewise each
+    /// `:db/cas` becomes a `:db/add` term and a `CasCheck` to make against the store.
+    fn entities_into_terms_with_temp_ids_and_lookup_refs<I>(&self, tx_functions: &TxFunctions, entities: I) -> Result<(Vec<TermWithTempIdsAndLookupRefs>, Vec<Retraction<KnownEntidOr<LookupRefOrTempId>>>, Vec<CasCheck<KnownEntidOr<LookupRefOrTempId>>>, InternSet<TempId>, InternSet<AVPair>)> where I: IntoIterator<Item=Entity> {
         struct InProcess<'a> {
             partition_map: &'a PartitionMap,
             schema: &'a Schema,
@@ -294,6 +340,15 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                 Ok(a)
             }
 
+            fn entity_e_into_retraction_e(&mut self, x: entmod::EntidOrLookupRefOrTempId) -> Result<KnownEntidOr<LookupRefOrTempId>> {
+                match self.entity_e_into_term_e(x)? {
+                    Either::Right(LookupRefOrTempId::TempId(ref temp_id)) => {
+                        bail!(ErrorKind::NotYetImplemented(format!("Cannot retract entity or attribute of tempid {}", temp_id)))
+                    },
+                    e => Ok(e),
+                }
+            }
+
             fn entity_e_into_term_v(&mut self, x: entmod::EntidOrLookupRefOrTempId) -> Result<TypedValueOr<LookupRefOrTempId>> {
                 self.entity_e_into_term_e(x).map(|r| r.map_left(|ke| TypedValue::Ref(ke.0)))
             }
@@ -346,12 +401,15 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         // We want to handle entities in the order they're given to us, while also "exploding" some
         // entities into many.  We therefore push the initial entities onto the back of the deque,
         // take from the front of the deque, and explode onto the front as well.
-        let mut deque: VecDeque<Entity> = VecDeque::default();
-        deque.extend(entities);
+        // Each entity carries the depth of transaction function invocations that produced it.
+        let mut deque: VecDeque<(Entity, usize)> = VecDeque::default();
+        deque.extend(entities.into_iter().map(|entity| (entity, 0)));
 
         let mut terms: Vec<TermWithTempIdsAndLookupRefs> = Vec::with_capacity(deque.len());
+        let mut retractions: Vec<Retraction<KnownEntidOr<LookupRefOrTempId>>> = vec![];
+        let mut cas_checks: Vec<CasCheck<KnownEntidOr<LookupRefOrTempId>>> = vec![];
 
-        while let Some(entity) = deque.pop_front() {
+        while let Some((entity, depth)) = deque.pop_front() {
             match entity {
                 Entity::MapNotation(mut map_notation) => {
                     // :db/id is optional; if it's not given, we generate a special internal tempid
@@ -361,12 +419,12 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                     // We're not nested, so :db/isComponent is not relevant.  We just explode the
                     // map notation.
                     for (a, v) in map_notation {
-                        deque.push_front(Entity::AddOrRetract {
+                        deque.push_front((Entity::AddOrRetract {
                             op: OpType::Add,
                             e: db_id.clone(),
                             a: a,
                             v: v,
-                        });
+                        }, depth));
                     }
                 },
 
@@ -407,12 +465,12 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                                 }
 
                                 for vv in vs {
-                                    deque.push_front(Entity::AddOrRetract {
+                                    deque.push_front((Entity::AddOrRetract {
                                         op: op.clone(),
                                         e: e.clone(),
                                         a: entmod::Entid::Entid(a),
                                         v: vv,
-                                    });
+                                    }, depth));
                                 }
                                 continue
                             },
@@ -470,12 +528,12 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                                             dangling = false;
                                         }
 
-                                        deque.push_front(Entity::AddOrRetract {
+                                        deque.push_front((Entity::AddOrRetract {
                                             op: OpType::Add,
                                             e: db_id.clone(),
                                             a: entmod::Entid::Entid(inner_a),
                                             v: inner_v,
-                                        });
+                                        }, depth));
                                     }
                                 }
 
@@ -491,9 +549,56 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                         terms.push(Term::AddOrRetract(op, e, a, v));
                     }
                 },
+
+                Entity::RetractEntity { e } => {
+                    let e = in_process.entity_e_into_retraction_e(e)?;
+                    retractions.push(Retraction::Entity(e));
+                },
+
+                Entity::RetractAttribute { e, a } => {
+                    let e = in_process.entity_e_into_retraction_e(e)?;
+                    let a = in_process.entity_a_into_term_a(a)?;
+                    self.schema.require_attribute_for_entid(a)?;
+                    retractions.push(Retraction::Attribute(e, a));
+                },
+
+                Entity::Call { f, args } => {
+                    // A function that invokes itself, directly or not, would never finish expanding.
+                    if depth >= MAX_TX_FUNCTION_DEPTH {
+                        bail!(ErrorKind::TxFunctionDepthExceeded(f.to_string(), MAX_TX_FUNCTION_DEPTH));
+                    }
+
+                    // Explode onto the front of the deque, keeping the expansion's order.
+                    let expansion = tx_functions.expand(self.store, self.schema, &f, &args[..])?;
+                    for entity in expansion.into_iter().rev() {
+                        deque.push_front((entity, depth + 1));
+                    }
+                },
+
+                Entity::Cas { e, a, v_old, v_new } => {
+                    let e = in_process.entity_e_into_term_e(e)?;
+                    if let Either::Right(LookupRefOrTempId::TempId(ref temp_id)) = e {
+                        bail!(ErrorKind::NotYetImplemented(format!("Cannot :db/cas tempid {}", temp_id)))
+                    }
+
+                    let a = in_process.entity_a_into_term_a(a)?;
+                    let attribute = self.schema.require_attribute_for_entid(a)?;
+                    if attribute.multival {
+                        bail!(ErrorKind::NotYetImplemented(format!("Cannot :db/cas attribute {} that is not :db.cardinality :db.cardinality/one", a)))
+                    }
+
+                    let v_old = match v_old {
+                        Some(v) => Some(self.schema.to_typed_value(&v.without_spans(), attribute.value_type)?),
+                        None => None,
+                    };
+                    let v_new = self.schema.to_typed_value(&v_new.without_spans(), attribute.value_type)?;
+
+                    cas_checks.push(CasCheck { e: e.clone(), a: a, v: v_old });
+                    terms.push(Term::AddOrRetract(OpType::Add, e, a, Either::Left(v_new)));
+                },
             }
         };
-        Ok((terms, in_process.temp_ids, in_process.lookup_refs))
+        Ok((terms, retractions, cas_checks, in_process.temp_ids, in_process.lookup_refs))
     }
 
     /// Pipeline stage 2: rewrite `Term` instances with lookup refs into `Term` instances without
@@ -512,19 +617,96 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         }).collect::<Result<Vec<_>>>()
     }
 
+    /// Check each `:db/cas` against the store as it stood before this transaction, failing the
+    /// transaction if the current value of `[e a]` isn't the expected one.
+    fn check_cas<I>(&self, lookup_ref_map: &AVMap, cas_checks: I) -> Result<()> where I: IntoIterator<Item=CasCheck<KnownEntidOr<LookupRefOrTempId>>> {
+        for CasCheck { e, a, v: expected } in cas_checks {
+            let e = resolve_known_e(lookup_ref_map, e)?;
+            let actual = self.store.entity_avs(e)?
+                                   .into_iter()
+                                   .find(|&(datom_a, _)| datom_a == a)
+                                   .map(|(_, v)| v);
+            if actual != expected {
+                let to_edn = |v: Option<TypedValue>| v.map_or(edn::Value::Nil, |v| v.to_edn_value_pair().0);
+                bail!(ErrorKind::CasFailed(e, a, to_edn(expected), to_edn(actual)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Expand `:db/retractEntity` and `:db/retractAttribute` into a `:db/retract` term for each
+    /// datom they name in the store as it stood before this transaction.
+    ///
+    /// Retracting an entity retracts all of its datoms and all datoms that refer to it, and
+    /// recursively retracts every entity it refers to via a `:db/isComponent` attribute.
+    /// Retracting an attribute does not cascade.
+    fn expand_retractions<I>(&self, lookup_ref_map: &AVMap, retractions: I) -> Result<Vec<TermWithTempIds>> where I: IntoIterator<Item=Retraction<KnownEntidOr<LookupRefOrTempId>>> {
+        // The same datom can be named more than once -- a component is referenced by its parent,
+        // for example -- and transacting the same [e a v] twice is an error, so we collect into a
+        // set.  BTreeSet rather than HashSet so this is deterministic.
+        let mut datoms: BTreeSet<(Entid, Entid, TypedValue)> = BTreeSet::default();
+        let mut entities: VecDeque<Entid> = VecDeque::default();
+
+        for retraction in retractions {
+            match retraction {
+                Retraction::Entity(e) => entities.push_back(resolve_known_e(lookup_ref_map, e)?),
+                Retraction::Attribute(e, a) => {
+                    let e = resolve_known_e(lookup_ref_map, e)?;
+                    for (datom_a, v) in self.store.entity_avs(e)? {
+                        if datom_a == a {
+                            datoms.insert((e, a, v));
+                        }
+                    }
+                },
+            }
+        }
+
+        let mut seen: BTreeSet<Entid> = BTreeSet::default();
+        while let Some(e) = entities.pop_front() {
+            if !seen.insert(e) {
+                continue;
+            }
+
+            for (a, v) in self.store.entity_avs(e)? {
+                if let TypedValue::Ref(component) = v {
+                    if self.schema.require_attribute_for_entid(a)?.component {
+                        entities.push_back(component);
+                    }
+                }
+                datoms.insert((e, a, v));
+            }
+
+            for (referrer, a) in self.store.referencing_eas(e)? {
+                datoms.insert((referrer, a, TypedValue::Ref(e)));
+            }
+        }
+
+        Ok(datoms.into_iter()
+                 .map(|(e, a, v)| Term::AddOrRetract(OpType::Retract, Either::Left(KnownEntid(e)), a, Either::Left(v)))
+                 .collect())
+    }
+
     /// Transact the given `entities` against the store.
     ///
     /// This approach is explained in https://github.com/mozilla/mentat/wiki/Transacting.
     // TODO: move this to the transactor layer.
-    pub fn transact_entities<I>(&mut self, entities: I) -> Result<TxReport> where I: IntoIterator<Item=Entity> {
+    ///
+    /// Invocations of transaction functions are expanded using `tx_functions`.
+    pub fn transact_entities<I>(&mut self, tx_functions: &TxFunctions, entities: I) -> Result<TxReport> where I: IntoIterator<Item=Entity> {
         // Pipeline stage 1: entities -> terms with tempids and lookup refs.
-        let (terms_with_temp_ids_and_lookup_refs, tempid_set, lookup_ref_set) = self.entities_into_terms_with_temp_ids_and_lookup_refs(entities)?;
+        let (terms_with_temp_ids_and_lookup_refs, retractions, cas_checks, tempid_set, lookup_ref_set) = self.entities_into_terms_with_temp_ids_and_lookup_refs(tx_functions, entities)?;
 
         // Pipeline stage 2: resolve lookup refs -> terms with tempids.
         let lookup_ref_avs: Vec<&(i64, TypedValue)> = lookup_ref_set.inner.iter().map(|rc| &**rc).collect();
         let lookup_ref_map: AVMap = self.store.resolve_avs(&lookup_ref_avs[..])?;
 
-        let terms_with_temp_ids = self.resolve_lookup_refs(&lookup_ref_map, terms_with_temp_ids_and_lookup_refs)?;
+        let mut terms_with_temp_ids = self.resolve_lookup_refs(&lookup_ref_map, terms_with_temp_ids_and_lookup_refs)?;
+
+        // Now that lookup refs are resolved, check :db/cas and expand :db/retractEntity and
+        // :db/retractAttribute.
+        self.check_cas(&lookup_ref_map, cas_checks)?;
+        let retraction_terms = self.expand_retractions(&lookup_ref_map, retractions)?;
+        terms_with_temp_ids.extend(retraction_terms);
 
         self.transact_simple_terms(terms_with_temp_ids, tempid_set)
     }
@@ -722,6 +904,15 @@ impl<'conn, 'a> Tx<'conn, 'a> {
     }
 }
 
+/// Replace any lookup ref in an entity position that can't hold a tempid.
+fn resolve_known_e(lookup_ref_map: &AVMap, e: KnownEntidOr<LookupRefOrTempId>) -> Result<Entid> {
+    match replace_lookup_ref(lookup_ref_map, e, |x| KnownEntid(x))? {
+        Either::Left(KnownEntid(e)) => Ok(e),
+        // Tempids are rejected in pipeline stage 1.
+        Either::Right(_) => unreachable!(),
+    }
+}
+
 /// Initialize a new Tx object with a new tx id and a tx instant. Kick off the SQLite conn, too.
 fn start_tx<'conn, 'a>(conn: &'conn rusqlite::Connection,
                        mut partition_map: PartitionMap,
@@ -756,8 +947,20 @@ pub fn transact<'conn, 'a, I>(conn: &'conn rusqlite::Connection,
                               entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)>
     where I: IntoIterator<Item=Entity> {
 
+    transact_with_functions(conn, partition_map, schema_for_mutation, schema, &NoTxFunctions, entities)
+}
+
+/// Just like `transact`, but expands invocations of transaction functions using `tx_functions`.
+pub fn transact_with_functions<'conn, 'a, I>(conn: &'conn rusqlite::Connection,
+                                             partition_map: PartitionMap,
+                                             schema_for_mutation: &'a Schema,
+                                             schema: &'a Schema,
+                                             tx_functions: &TxFunctions,
+                                             entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)>
+    where I: IntoIterator<Item=Entity> {
+
     let mut tx = start_tx(conn, partition_map, schema_for_mutation, schema)?;
-    let report = tx.transact_entities(entities)?;
+    let report = tx.transact_entities(tx_functions, entities)?;
     conclude_tx(tx, report)
 }
 
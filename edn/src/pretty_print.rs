@@ -15,6 +15,7 @@ use std::io;
 use std::borrow::Cow;
 
 use types::Value;
+use utils::escape_text;
 
 impl Value {
     /// Return a pretty string representation of this `Value`.
@@ -68,7 +69,7 @@ impl Value {
             Value::PlainSymbol(ref v) => pp.text(v.0.as_ref()),
             Value::NamespacedKeyword(ref v) => pp.text(":").append(v.namespace.as_ref()).append("/").append(v.name.as_ref()),
             Value::Keyword(ref v) => pp.text(":").append(v.0.as_ref()),
-            Value::Text(ref v) => pp.text("\"").append(v.as_ref()).append("\""),
+            Value::Text(ref v) => pp.text(format!("\"{}\"", escape_text(v))),
             Value::Uuid(ref u) => pp.text("#uuid \"").append(u.hyphenated().to_string()).append("\""),
             _ => pp.text(self.to_string())
         }
@@ -451,7 +451,7 @@ macro_rules! def_common_value_display {
             $t::Nil => write!($f, "nil"),
             $t::Boolean(v) => write!($f, "{}", v),
             $t::Integer(v) => write!($f, "{}", v),
-            $t::Instant(v) => write!($f, "{}", v),
+            $t::Instant(v) => write!($f, "#inst \"{}\"", v.format("%Y-%m-%dT%H:%M:%S%.fZ")),
             $t::BigInteger(ref v) => write!($f, "{}N", v),
             // TODO: make sure float syntax is correct.
             $t::Float(ref v) => {
@@ -462,11 +462,16 @@ macro_rules! def_common_value_display {
                 } else if *v == OrderedFloat(f64::NAN) {
                     write!($f, "#f NaN")
                 } else {
-                    write!($f, "{}", v)
+                    // Keep a decimal point, so that `1.0` doesn't read back as an integer.
+                    let s = v.to_string();
+                    if s.contains('.') || s.contains('e') {
+                        write!($f, "{}", s)
+                    } else {
+                        write!($f, "{}.0", s)
+                    }
                 }
             }
-            // TODO: EDN escaping.
-            $t::Text(ref v) => write!($f, "\"{}\"", v),
+            $t::Text(ref v) => write!($f, "\"{}\"", ::utils::escape_text(v)),
             $t::Uuid(ref u) => write!($f, "#uuid \"{}\"", u.hyphenated().to_string()),
             $t::PlainSymbol(ref v) => v.fmt($f),
             $t::NamespacedSymbol(ref v) => v.fmt($f),
@@ -649,6 +654,17 @@ mod test {
         assert_eq!(string, parse::value(&data.to_string()).unwrap().without_spans().to_string());
     }
 
+    #[test]
+    fn test_print_edn_reads_back() {
+        let instant = DateTime::parse_from_rfc3339("2017-06-16T00:56:41.257Z").unwrap().with_timezone(&Utc);
+        let data = Value::Vector(vec![
+            Value::from_float(42.0),
+            Value::Instant(instant),
+        ]);
+        assert_eq!("[ 42.0 #inst \"2017-06-16T00:56:41.257Z\" ]", data.to_string());
+        assert_eq!(data, parse::value(&data.to_string()).unwrap().without_spans());
+    }
+
     #[test]
     fn test_ord() {
         // TODO: Check we follow the equality rules at the bottom of https://github.com/edn-format/edn
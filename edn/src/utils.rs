@@ -29,3 +29,20 @@ pub fn merge(left: &Value, right: &Value) -> Option<Value> {
         _ => None
     }
 }
+
+/// Escape `s` so that it can be written between double quotes as an EDN string literal and read
+/// back unchanged.
+pub fn escape_text(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -326,8 +326,29 @@ fn test_text() {
     assert_eq!(text("\"hello world\"").unwrap(), Text("hello world".to_string()));
     assert_eq!(text("\"\"").unwrap(), Text("".to_string()));
 
+    assert_eq!(text(r#""say \"hi\"""#).unwrap(), Text("say \"hi\"".to_string()));
+    assert_eq!(text(r#""a\\b""#).unwrap(), Text("a\\b".to_string()));
+    assert_eq!(text(r#""\n\t\r""#).unwrap(), Text("\n\t\r".to_string()));
+
+    // Other escapes aren't escapes at all: the backslash is kept.
+    assert_eq!(text(r#""\d+""#).unwrap(), Text("\\d+".to_string()));
+    assert_eq!(text(r#""\u0041""#).unwrap(), Text("\\u0041".to_string()));
+    assert_eq!(text(r#""C:\Users\d""#).unwrap(), Text("C:\\Users\\d".to_string()));
+    assert_eq!(text(r#""a\\\d""#).unwrap(), Text("a\\\\d".to_string()));
+
     assert!(text("\"").is_err());
     assert!(text("nil").is_err());
+    assert!(text(r#""\""#).is_err());
+}
+
+#[test]
+fn test_print_text_round_trips() {
+    for s in &["", "plain", "say \"hi\"", "back\\slash", "line\nbreak\ttab\rreturn", "\\\"", "\\d+"] {
+        let value = Value::Text(s.to_string());
+        assert_eq!(self::value(value.to_string().as_str()).unwrap(), value);
+        assert_eq!(self::value(value.to_pretty(20).unwrap().as_str()).unwrap(), value);
+    }
+    assert_eq!(Value::Text("a\"b\\c\n".to_string()).to_string(), r#""a\"b\\c\n""#);
 }
 
 #[test]
@@ -344,6 +365,13 @@ fn test_symbol() {
     assert_eq!(symbol(".").unwrap(), s_plain("."));
     assert_eq!(symbol("...").unwrap(), s_plain("..."));
 
+    assert_eq!(symbol("+").unwrap(), s_plain("+"));
+    assert_eq!(symbol("-").unwrap(), s_plain("-"));
+    assert_eq!(symbol("/").unwrap(), s_plain("/"));
+    assert!(symbol("-foo").is_err());
+    assert!(symbol("+1").is_err());
+    assert!(symbol("//").is_err());
+
     assert_eq!(symbol("hello/world").unwrap(), s_ns("hello", "world"));
     assert_eq!(symbol("foo-bar/baz-boz").unwrap(), s_ns("foo-bar", "baz-boz"));
 
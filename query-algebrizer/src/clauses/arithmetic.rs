@@ -0,0 +1,342 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    NonIntegerConstant,
+    PlainSymbol,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    ColumnConstraint,
+    Computation,
+    QueryValue,
+};
+
+/// Application of arithmetic functions.
+impl ConjoiningClauses {
+    /// Apply `[(+ ?a ?b …) ?c]`, `[(- ?a ?b …) ?c]`, `[(* ?a ?b …) ?c]`, or `[(/ ?a ?b …) ?c]`,
+    /// binding `?c` to the result as computed by SQLite.
+    ///
+    /// Over numbers, the result is a long if every argument is a long, and a double otherwise.
+    /// When we can't tell whether an argument is a long or a double, the result is cast to a
+    /// double, so that we always know its type. Dividing longs truncates, as in SQLite. Rows for
+    /// which a divisor is zero produce no result.
+    ///
+    /// Instants are stored as microseconds since the epoch, and can be offset by a long number of
+    /// microseconds: `[(+ ?instant 1000000) ?later]`, `[(- ?instant 1000000) ?earlier]`.
+    /// Subtracting one instant from another produces a long number of microseconds. An argument is
+    /// only treated as an instant if it's known to be one.
+    pub fn apply_arithmetic<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
+        let var = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::UnexpectedBinding)),
+        };
+
+        let operator = where_fn.operator;
+        let op = match operator.0.as_str() {
+            "+" => "+",
+            "-" => "-",
+            "*" => "*",
+            "/" => "/",
+            _ => bail!(ErrorKind::UnknownFunction(operator.clone())),
+        };
+
+        if where_fn.args.len() < 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(operator.clone(), where_fn.args.len(), 2));
+        }
+
+        let instants: Vec<bool> = where_fn.args.iter().map(|arg| self.is_instant_argument(arg)).collect();
+        let (computation, value_type) = if instants.contains(&true) {
+            self.instant_arithmetic(&operator, op, where_fn.args, instants)?
+        } else {
+            self.numeric_arithmetic(&operator, op, where_fn.args)?
+        };
+
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        self.bind_computation_to_var(schema, computation, value_type, var);
+        Ok(())
+    }
+
+    fn is_instant_argument(&self, arg: &FnArg) -> bool {
+        match arg {
+            &FnArg::Variable(ref var) => self.known_type(var) == Some(ValueType::Instant),
+            &FnArg::Constant(NonIntegerConstant::Instant(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn numeric_arithmetic(&mut self, function: &PlainSymbol, op: &'static str, args: Vec<FnArg>) -> Result<(Computation, ValueType)> {
+        let numeric = ValueTypeSet::of_numeric_types();
+        let mut values = Vec::with_capacity(args.len());
+        let mut types = Vec::with_capacity(args.len());
+        for (position, arg) in args.into_iter().enumerate() {
+            let var = arg.as_variable().cloned();
+            let value = self.resolve_argument_of_types(function, position, arg, numeric, "numeric")?;
+            types.push(match (var, &value) {
+                (Some(ref var), _) => self.known_type_set(var),
+                (None, &QueryValue::TypedValue(ref v)) => ValueTypeSet::of_one(v.value_type()),
+                (None, _) => numeric,
+            });
+            values.push(value);
+        }
+
+        if op == "/" {
+            // SQLite divides by zero to produce NULL. We'd rather produce nothing.
+            for divisor in values.iter().skip(1) {
+                let nonzero = match divisor {
+                    &QueryValue::TypedValue(TypedValue::Long(v)) => v != 0,
+                    &QueryValue::TypedValue(TypedValue::Double(v)) => v.0 != 0.0,
+                    _ => false,
+                };
+                if !nonzero {
+                    let zero = QueryValue::TypedValue(TypedValue::Long(0));
+                    self.wheres.add_intersection(ColumnConstraint::Computed(Computation::Infix("<>", divisor.clone(), zero)));
+                }
+            }
+        }
+
+        let mut values = values.into_iter();
+        let first = values.next().unwrap();
+        let second = values.next().unwrap();
+        let computation = values.fold(Computation::Infix(op, first, second),
+                                      |acc, value| Computation::Infix(op, QueryValue::Computed(Box::new(acc)), value));
+
+        let long = ValueTypeSet::of_one(ValueType::Long);
+        let double = ValueTypeSet::of_one(ValueType::Double);
+        if types.iter().all(|t| *t == long) {
+            Ok((computation, ValueType::Long))
+        } else if types.iter().any(|t| *t == double) {
+            Ok((computation, ValueType::Double))
+        } else {
+            Ok((Computation::CastToReal(QueryValue::Computed(Box::new(computation))), ValueType::Double))
+        }
+    }
+
+    fn instant_arithmetic(&mut self, function: &PlainSymbol, op: &'static str, args: Vec<FnArg>, instants: Vec<bool>) -> Result<(Computation, ValueType)> {
+        if args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(function.clone(), args.len(), 2));
+        }
+
+        let instant = ValueTypeSet::of_one(ValueType::Instant);
+        let long = ValueTypeSet::of_one(ValueType::Long);
+        let mut args = args.into_iter();
+        let left = args.next().unwrap();
+        let right = args.next().unwrap();
+
+        match (op, instants[0], instants[1]) {
+            ("-", true, true) => {
+                let left = self.resolve_argument_of_types(function, 0, left, instant, "instant")?;
+                let right = self.resolve_argument_of_types(function, 1, right, instant, "instant")?;
+                Ok((Computation::Infix(op, left, right), ValueType::Long))
+            },
+            ("+", true, false) |
+            ("-", true, false) => {
+                let left = self.resolve_argument_of_types(function, 0, left, instant, "instant")?;
+                let right = self.resolve_argument_of_types(function, 1, right, long, "integer")?;
+                Ok((Computation::Infix(op, left, right), ValueType::Instant))
+            },
+            ("+", false, true) => {
+                let left = self.resolve_argument_of_types(function, 0, left, long, "integer")?;
+                let right = self.resolve_argument_of_types(function, 1, right, instant, "instant")?;
+                Ok((Computation::Infix(op, left, right), ValueType::Instant))
+            },
+            ("+", true, true) => bail!(ErrorKind::InvalidArgument(function.clone(), "integer", 1)),
+            ("-", false, true) => bail!(ErrorKind::InvalidArgument(function.clone(), "instant", 0)),
+            _ => {
+                let position = if instants[0] { 0 } else { 1 };
+                bail!(ErrorKind::InvalidArgument(function.clone(), "numeric", position))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use mentat_core::{
+        Attribute,
+    };
+
+    use mentat_query::{
+        NamespacedKeyword,
+        Pattern,
+        PatternNonValuePlace,
+        PatternValuePlace,
+        Variable,
+    };
+
+    use clauses::{
+        add_attribute,
+        associate_ident,
+        bind_value_of,
+    };
+
+    use types::{
+        Column,
+        DatomsColumn,
+        QualifiedAlias,
+    };
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "age"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "height"), 100);
+        add_attribute(&mut schema, 100, Attribute {
+            value_type: ValueType::Double,
+            ..Default::default()
+        });
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "born"), 101);
+        add_attribute(&mut schema, 101, Attribute {
+            value_type: ValueType::Instant,
+            ..Default::default()
+        });
+        schema
+    }
+
+    fn apply(cc: &mut ConjoiningClauses, schema: &Schema, op: &str, args: Vec<FnArg>, var: &Variable) -> Result<()> {
+        cc.apply_arithmetic(schema, WhereFn {
+            operator: PlainSymbol::new(op),
+            args: args,
+            binding: Binding::BindScalar(var.clone()),
+        })
+    }
+
+    #[test]
+    fn test_result_types() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let age = Variable::from_valid_name("?age");
+        let height = Variable::from_valid_name("?height");
+        bind_value_of(&mut cc, &schema, "age", &age);
+        bind_value_of(&mut cc, &schema, "height", &height);
+
+        let older = Variable::from_valid_name("?older");
+        apply(&mut cc, &schema, "+", vec![FnArg::Variable(age.clone()), FnArg::EntidOrInteger(1)], &older).expect("to apply +");
+        assert_eq!(cc.known_type(&older), Some(ValueType::Long));
+
+        let d0v = QueryValue::Column(QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value));
+        let sum = Computation::Infix("+", d0v, QueryValue::TypedValue(TypedValue::Long(1)));
+        assert_eq!(cc.column_bindings.get(&older),
+                   Some(&vec![QualifiedAlias::new("datoms00".to_string(), Column::Computed(Box::new(sum)))]));
+
+        let product = Variable::from_valid_name("?product");
+        apply(&mut cc, &schema, "*", vec![FnArg::Variable(age.clone()), FnArg::Variable(height.clone())], &product).expect("to apply *");
+        assert_eq!(cc.known_type(&product), Some(ValueType::Double));
+
+        // The result can be used like any other value.
+        let twice = Variable::from_valid_name("?twice");
+        apply(&mut cc, &schema, "*", vec![FnArg::Variable(older.clone()), FnArg::EntidOrInteger(2)], &twice).expect("to apply *");
+        assert_eq!(cc.known_type(&twice), Some(ValueType::Long));
+        assert!(!cc.is_known_empty());
+    }
+
+    #[test]
+    fn test_unknown_numeric_type_is_cast() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let v = Variable::from_valid_name("?v");
+        let w = Variable::from_valid_name("?w");
+        cc.apply_pattern(&schema, Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+            attribute: PatternNonValuePlace::Placeholder,
+            value: PatternValuePlace::Variable(v.clone()),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        });
+
+        apply(&mut cc, &schema, "-", vec![FnArg::Variable(v.clone()), FnArg::EntidOrInteger(1)], &w).expect("to apply -");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.known_type_set(&v), ValueTypeSet::of_numeric_types());
+        assert_eq!(cc.known_type(&w), Some(ValueType::Double));
+        match cc.column_bindings.get(&w).map(|cols| &cols[0].1) {
+            Some(&Column::Computed(ref c)) => {
+                match **c {
+                    Computation::CastToReal(_) => {},
+                    _ => panic!("Expected a cast."),
+                }
+            },
+            _ => panic!("Expected a computed column."),
+        }
+    }
+
+    #[test]
+    fn test_division_excludes_zero() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let age = Variable::from_valid_name("?age");
+        let height = Variable::from_valid_name("?height");
+        let ratio = Variable::from_valid_name("?ratio");
+        bind_value_of(&mut cc, &schema, "age", &age);
+        bind_value_of(&mut cc, &schema, "height", &height);
+
+        apply(&mut cc, &schema, "/", vec![FnArg::Variable(height.clone()), FnArg::Variable(age.clone()), FnArg::EntidOrInteger(2)], &ratio).expect("to apply /");
+        assert_eq!(cc.known_type(&ratio), Some(ValueType::Double));
+
+        // Only the divisor that might be zero is checked.
+        let d0v = QueryValue::Column(QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value));
+        let zero = QueryValue::TypedValue(TypedValue::Long(0));
+        assert_eq!(cc.wheres.0.last(), Some(&ColumnConstraint::Computed(Computation::Infix("<>", d0v, zero)).into()));
+    }
+
+    #[test]
+    fn test_instant_arithmetic() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let born = Variable::from_valid_name("?born");
+        let age = Variable::from_valid_name("?age");
+        bind_value_of(&mut cc, &schema, "born", &born);
+        bind_value_of(&mut cc, &schema, "age", &age);
+
+        let later = Variable::from_valid_name("?later");
+        apply(&mut cc, &schema, "+", vec![FnArg::Variable(born.clone()), FnArg::Variable(age.clone())], &later).expect("to apply +");
+        assert_eq!(cc.known_type(&later), Some(ValueType::Instant));
+
+        let elapsed = Variable::from_valid_name("?elapsed");
+        apply(&mut cc, &schema, "-", vec![FnArg::Variable(later.clone()), FnArg::Variable(born.clone())], &elapsed).expect("to apply -");
+        assert_eq!(cc.known_type(&elapsed), Some(ValueType::Long));
+        assert!(!cc.is_known_empty());
+
+        // Instants can't be multiplied, or added to one another.
+        let bad = Variable::from_valid_name("?bad");
+        assert!(apply(&mut cc, &schema, "*", vec![FnArg::Variable(born.clone()), FnArg::EntidOrInteger(2)], &bad).is_err());
+        assert!(apply(&mut cc, &schema, "+", vec![FnArg::Variable(born.clone()), FnArg::Variable(later.clone())], &bad).is_err());
+    }
+}
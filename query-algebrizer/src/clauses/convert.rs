@@ -87,6 +87,7 @@ impl ValueTypes for FnArg {
 
                 // These don't make sense here. TODO: split FnArg into scalar and non-scalar…
                 &FnArg::Vector(_) |
+                &FnArg::Keyword(_) |
                 &FnArg::SrcVar(_) => bail!(ErrorKind::UnsupportedArgument),
 
                 // These are all straightforward.
@@ -210,6 +211,7 @@ impl ConjoiningClauses {
 
             // These don't make sense here.
             FnArg::Vector(_) |
+            FnArg::Keyword(_) |
             FnArg::SrcVar(_) => bail!(ErrorKind::InvalidGroundConstant),
 
             // These are all straightforward.
@@ -226,7 +228,8 @@ impl ConjoiningClauses {
                 coerce_to_typed_value!(var, x, known_types, ValueType::Double, TypedValue::Double)
             },
             FnArg::Constant(NonIntegerConstant::Text(x)) => {
-                coerce_to_typed_value!(var, x, known_types, ValueType::String, TypedValue::String)
+                let x = x.as_str();
+                coerce_to_typed_value!(var, x, known_types, ValueType::String, TypedValue::typed_string)
             },
         }
     }
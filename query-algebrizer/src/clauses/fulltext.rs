@@ -158,7 +158,7 @@ impl ConjoiningClauses {
         // - It's not bound. The query cannot be algebrized.
         let search: Either<TypedValue, QualifiedAlias> = match args.next().unwrap() {
             FnArg::Constant(NonIntegerConstant::Text(s)) => {
-                Either::Left(TypedValue::String(s))
+                Either::Left(TypedValue::typed_string(s.as_str()))
             },
             FnArg::Variable(in_var) => {
                 match self.bound_value(&in_var) {
@@ -255,6 +255,7 @@ mod testing {
     use super::*;
 
     use std::rc::Rc;
+    use std::sync::Arc;
 
     use mentat_core::{
         Attribute,
@@ -300,7 +301,7 @@ mod testing {
             args: vec![
                 FnArg::SrcVar(SrcVar::DefaultSrc),
                 FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "fts")),
-                FnArg::Constant(NonIntegerConstant::Text(Rc::new("needle".into()))),
+                FnArg::Constant(NonIntegerConstant::Text(Arc::new("needle".into()))),
             ],
             binding: Binding::BindRel(vec![VariableOrPlaceholder::Variable(Variable::from_valid_name("?entity")),
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?value")),
@@ -358,7 +359,7 @@ mod testing {
             args: vec![
                 FnArg::SrcVar(SrcVar::DefaultSrc),
                 FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")),
-                FnArg::Constant(NonIntegerConstant::Text(Rc::new("needle".into()))),
+                FnArg::Constant(NonIntegerConstant::Text(Arc::new("needle".into()))),
             ],
             binding: Binding::BindRel(vec![VariableOrPlaceholder::Variable(Variable::from_valid_name("?entity")),
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?value")),
@@ -0,0 +1,254 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    HasSchema,
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    SrcVar,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use clauses::convert::ValueConversion;
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    Column,
+    ColumnConstraint,
+    ColumnIntersection,
+    DatomsColumn,
+    DatomsTable,
+    EmptyBecause,
+    LeftJoin,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+};
+
+impl ConjoiningClauses {
+    /// Apply `[(get-else $ ?e :attr default) ?v]`, which binds `?v` to the value of `:attr` on
+    /// `?e`, or to `default` if `?e` has no such value.
+    ///
+    /// This becomes a `LEFT JOIN` against the datoms table, and `?v` is bound to
+    /// `COALESCE(datoms01.v, default)`. `?e` must already be bound, and `:attr` must be a
+    /// cardinality-one attribute whose type agrees with `default`.
+    pub fn apply_get_else<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 4 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 4));
+        }
+
+        let var = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::UnexpectedBinding)),
+        };
+
+        let mut args = where_fn.args.into_iter();
+
+        // TODO: process source variables.
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "source variable".into(), 0)),
+        }
+
+        let e = args.next().unwrap();
+
+        let a = match args.next().unwrap() {
+            FnArg::IdentOrKeyword(i) => schema.get_entid(&i).map(|k| k.into()),
+            FnArg::EntidOrInteger(e) => Some(e),
+            _ => None,
+        };
+
+        // As with `fulltext`, an attribute we can't use is most likely a coding error, so we bail
+        // rather than marking the clause as known-empty.
+        let a = a.ok_or(ErrorKind::InvalidArgument(where_fn.operator.clone(), "attribute".into(), 2))?;
+        let attribute = schema.attribute_for_entid(a).cloned().ok_or(ErrorKind::InvalidArgument(where_fn.operator.clone(), "attribute".into(), 2))?;
+        if attribute.multival {
+            // Which of the values would we pick?
+            bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "cardinality-one attribute".into(), 2));
+        }
+
+        // The default must be a value of the attribute's type.
+        let value_type = attribute.value_type;
+        let default = match self.typed_value_from_arg(schema, &var, args.next().unwrap(), ValueTypeSet::of_one(value_type))? {
+            ValueConversion::Val(ref value) if value.value_type() == value_type => value.clone(),
+            _ => bail!(ErrorKind::InvalidDefault(where_fn.operator.clone(), value_type)),
+        };
+
+        // Find the entity to join against.
+        let entity = match e {
+            FnArg::Variable(e) => {
+                let binding = self.column_bindings
+                                  .get(&e)
+                                  .and_then(|bindings| bindings.get(0).cloned());
+                if let Some(binding) = binding {
+                    self.constrain_var_to_type(e, ValueType::Ref);
+                    if self.is_known_empty() {
+                        return Ok(());
+                    }
+                    QueryValue::Column(binding)
+                } else {
+                    match self.bound_value(&e) {
+                        Some(TypedValue::Ref(entid)) => QueryValue::Entid(entid),
+                        Some(TypedValue::Keyword(ref kw)) => {
+                            match self.entid_for_ident(schema, kw) {
+                                Some(entid) => QueryValue::Entid(entid.into()),
+                                None => {
+                                    self.mark_known_empty(EmptyBecause::UnresolvedIdent((**kw).clone()));
+                                    return Ok(());
+                                },
+                            }
+                        },
+                        Some(tv) => {
+                            bail!(ErrorKind::InputTypeDisagreement(e.name().clone(), ValueType::Ref, tv.value_type()));
+                        },
+                        None => {
+                            bail!(ErrorKind::UnboundVariable((*e.0).clone()));
+                        },
+                    }
+                }
+            },
+            FnArg::EntidOrInteger(entid) => QueryValue::Entid(entid),
+            FnArg::IdentOrKeyword(ident) => {
+                match schema.get_entid(&ident) {
+                    Some(entid) => QueryValue::Entid(entid.into()),
+                    None => {
+                        self.mark_known_empty(EmptyBecause::UnresolvedIdent(ident));
+                        return Ok(());
+                    },
+                }
+            },
+            _ => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "entity".into(), 1)),
+        };
+
+        // `?v` can only ever be of the attribute's type.
+        self.constrain_var_to_type(var.clone(), value_type);
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        // Fulltext values are stored out of line; the view brings them back in.
+        let table = if attribute.fulltext {
+            DatomsTable::FulltextDatoms
+        } else {
+            DatomsTable::Datoms
+        };
+        let alias = self.next_alias_for_table(table);
+
+        // These go in the join's `ON` clause, not in `WHERE`: an entity without a matching datom
+        // must still produce a row.
+        let mut constraints = ColumnIntersection::default();
+        constraints.add_intersection(ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Entity), entity));
+        constraints.add_intersection(ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Attribute), QueryValue::Entid(a)));
+
+        self.left_joins.push(LeftJoin {
+            table: SourceAlias(table, alias.clone()),
+            constraints: constraints,
+        });
+
+        self.bind_column_to_var(schema, alias, Column::WithDefault(DatomsColumn::Value, default), var);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use mentat_core::{
+        Attribute,
+        ValueType,
+    };
+
+    use mentat_query::{
+        NamespacedKeyword,
+        NonIntegerConstant,
+        PlainSymbol,
+        Variable,
+    };
+
+    use clauses::{
+        add_attribute,
+        associate_ident,
+    };
+
+    #[test]
+    fn test_apply_get_else() {
+        let mut cc = ConjoiningClauses::default();
+        let mut schema = Schema::default();
+
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "name"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let x = Variable::from_valid_name("?x");
+        let name = Variable::from_valid_name("?name");
+
+        // Bind `?x` the way a pattern would.
+        let datoms00 = cc.next_alias_for_table(DatomsTable::Datoms);
+        cc.from.push(SourceAlias(DatomsTable::Datoms, datoms00.clone()));
+        cc.bind_column_to_var(&schema, datoms00.clone(), DatomsColumn::Entity, x.clone());
+
+        let op = PlainSymbol::new("get-else");
+        cc.apply_get_else(&schema, WhereFn {
+            operator: op,
+            args: vec![
+                FnArg::SrcVar(SrcVar::DefaultSrc),
+                FnArg::Variable(x.clone()),
+                FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "name")),
+                FnArg::Constant(NonIntegerConstant::Text(Arc::new("Anonymous".to_string()))),
+            ],
+            binding: Binding::BindScalar(name.clone()),
+        }).expect("to be able to apply_get_else");
+
+        assert!(!cc.is_known_empty());
+
+        // The datom is left-joined, not added to the inner join.
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Datoms, datoms00.clone())]);
+        assert!(cc.wheres.is_empty());
+
+        let datoms01 = "datoms01".to_string();
+        let mut constraints = ColumnIntersection::default();
+        constraints.add_intersection(ColumnConstraint::Equals(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Entity),
+                                                              QueryValue::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity))));
+        constraints.add_intersection(ColumnConstraint::Equals(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Attribute),
+                                                              QueryValue::Entid(99)));
+        assert_eq!(cc.left_joins, vec![LeftJoin {
+            table: SourceAlias(DatomsTable::Datoms, datoms01.clone()),
+            constraints: constraints,
+        }]);
+
+        let default = TypedValue::typed_string("Anonymous");
+        assert_eq!(cc.column_bindings.get(&name),
+                   Some(&vec![QualifiedAlias::new(datoms01.clone(), Column::WithDefault(DatomsColumn::Value, default))]));
+        assert_eq!(cc.known_type(&name), Some(ValueType::String));
+    }
+}
@@ -11,11 +11,14 @@
 use std::collections::BTreeMap;
 
 use mentat_core::{
+    Entid,
     TypedValue,
     ValueType,
 };
 
 use mentat_query::{
+    LookupRef,
+    Rule,
     Variable,
 };
 
@@ -30,10 +33,20 @@ use errors::{
 /// the bindings that will be used at execution time.
 /// When built correctly, `types` is guaranteed to contain the types of `values` -- use
 /// `QueryInputs::new` or `QueryInputs::with_values` to construct an instance.
+///
+/// Inputs can also carry a rule set, which is used by queries that name `%` in `:in`.
+///
+/// Variables can be bound to lookup refs rather than values; see `with_lookup_ref`. The algebrizer
+/// can't read the store, so the entities named by lookup refs -- both those bound here and those
+/// that appear in the query's patterns -- are supplied in `lookup_refs`, keyed by the attribute
+/// and value returned by `lookup_ref_av`. A lookup ref that isn't present names no entity.
 pub struct QueryInputs {
     // These should be crate-private.
     pub types: BTreeMap<Variable, ValueType>,
     pub values: BTreeMap<Variable, TypedValue>,
+    pub rules: Vec<Rule>,
+    pub lookup_ref_values: BTreeMap<Variable, LookupRef>,
+    pub lookup_refs: BTreeMap<(Entid, TypedValue), Entid>,
 }
 
 impl Default for QueryInputs {
@@ -41,6 +54,9 @@ impl Default for QueryInputs {
         QueryInputs {
             types: BTreeMap::default(),
             values: BTreeMap::default(),
+            rules: vec![],
+            lookup_ref_values: BTreeMap::default(),
+            lookup_refs: BTreeMap::default(),
         }
     }
 }
@@ -52,9 +68,13 @@ impl QueryInputs {
     }
 
     pub fn with_type_sequence(types: Vec<(Variable, ValueType)>) -> QueryInputs {
+        QueryInputs::with_types(types.into_iter().collect())
+    }
+
+    pub fn with_types(types: BTreeMap<Variable, ValueType>) -> QueryInputs {
         QueryInputs {
-            types: types.into_iter().collect(),
-            values: BTreeMap::default(),
+            types: types,
+            ..Default::default()
         }
     }
 
@@ -62,6 +82,14 @@ impl QueryInputs {
         QueryInputs {
             types: values.iter().map(|(var, val)| (var.clone(), val.value_type())).collect(),
             values: values,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rules(rules: Vec<Rule>) -> QueryInputs {
+        QueryInputs {
+            rules: rules,
+            ..Default::default()
         }
     }
 
@@ -77,6 +105,13 @@ impl QueryInputs {
                 }
             }
         }
-        Ok(QueryInputs { types: types, values: values })
+        Ok(QueryInputs { types: types, values: values, ..Default::default() })
+    }
+
+    /// Bind `var` to the entity named by `lookup_ref`. The variable is typed as a ref.
+    pub fn with_lookup_ref(mut self, var: Variable, lookup_ref: LookupRef) -> QueryInputs {
+        self.types.insert(var.clone(), ValueType::Ref);
+        self.lookup_ref_values.insert(var, lookup_ref);
+        self
     }
 }
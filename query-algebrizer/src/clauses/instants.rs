@@ -0,0 +1,309 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    computed,
+    long,
+};
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    Computation,
+    QueryValue,
+};
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+
+fn string(value: &str) -> QueryValue {
+    QueryValue::TypedValue(TypedValue::typed_string(value))
+}
+
+/// `value` modulo `divisor`, rounded towards negative infinity. SQLite's `%` takes the sign of
+/// its dividend, and instants before the epoch are negative.
+fn floor_mod(value: QueryValue, divisor: i64) -> QueryValue {
+    let remainder = computed(Computation::Infix("%", value, long(divisor)));
+    let positive = computed(Computation::Infix("+", remainder, long(divisor)));
+    computed(Computation::Infix("%", positive, long(divisor)))
+}
+
+/// Whole seconds since the epoch, which is what SQLite's date functions expect.
+fn unix_seconds(instant: QueryValue) -> QueryValue {
+    let whole = computed(Computation::Infix("-", instant.clone(), floor_mod(instant, MICROS_PER_SECOND)));
+    computed(Computation::Infix("/", whole, long(MICROS_PER_SECOND)))
+}
+
+/// `strftime(format, seconds, 'unixepoch', modifiers…)`, which produces text.
+fn strftime(format: &str, instant: QueryValue, modifiers: &[&str]) -> QueryValue {
+    let mut args = vec![string(format), unix_seconds(instant), string("unixepoch")];
+    args.extend(modifiers.iter().map(|modifier| string(modifier)));
+    computed(Computation::Function("strftime", args))
+}
+
+/// Truncate to a multiple of `length` microseconds, counting from `offset` microseconds before
+/// the epoch.
+fn truncate_to_multiple(instant: QueryValue, length: i64, offset: i64) -> Computation {
+    let shifted = if offset == 0 {
+        instant.clone()
+    } else {
+        computed(Computation::Infix("+", instant.clone(), long(offset)))
+    };
+    Computation::Infix("-", instant, floor_mod(shifted, length))
+}
+
+/// Truncate using one of SQLite's date modifiers, like 'start of month'. Months and years vary in
+/// length, so we can't do this arithmetically.
+fn truncate_with_modifier(instant: QueryValue, modifier: &str) -> Computation {
+    let seconds = computed(Computation::CastToInteger(strftime("%s", instant, &[modifier])));
+    Computation::Infix("*", seconds, long(MICROS_PER_SECOND))
+}
+
+/// Truncate `instant` to the start of the named unit, in UTC. Weeks start on Monday.
+fn truncate(instant: QueryValue, unit: &str) -> Option<Computation> {
+    match unit {
+        "second" => Some(truncate_to_multiple(instant, MICROS_PER_SECOND, 0)),
+        "minute" => Some(truncate_to_multiple(instant, 60 * MICROS_PER_SECOND, 0)),
+        "hour" => Some(truncate_to_multiple(instant, 3_600 * MICROS_PER_SECOND, 0)),
+        "day" => Some(truncate_to_multiple(instant, MICROS_PER_DAY, 0)),
+        // The epoch was a Thursday: the preceding Monday was three days earlier.
+        "week" => Some(truncate_to_multiple(instant, 7 * MICROS_PER_DAY, 3 * MICROS_PER_DAY)),
+        "month" => Some(truncate_with_modifier(instant, "start of month")),
+        "year" => Some(truncate_with_modifier(instant, "start of year")),
+        _ => None,
+    }
+}
+
+/// Application of functions over instants.
+///
+/// Instants are stored as microseconds since the epoch. These functions are evaluated by SQLite,
+/// in UTC; they produce values that can be grouped, ordered, and limited like any other.
+impl ConjoiningClauses {
+    /// Apply one of:
+    /// - `[(truncate-instant ?t :day) ?day]`, which binds the start of the second, minute, hour,
+    ///   day, week, month, or year that contains `?t`. Weeks start on Monday.
+    /// - `[(instant-year ?t) ?year]`, and similarly `instant-month`, `instant-day` (of the month),
+    ///   `instant-hour`, and `instant-minute`, which bind longs.
+    /// - `[(instant->long ?t) ?micros]` and `[(long->instant ?micros) ?t]`, which convert between
+    ///   instants and microseconds since the epoch.
+    pub fn apply_instant_function<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
+        let var = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::UnexpectedBinding)),
+        };
+
+        let operator = where_fn.operator;
+        let expected = if operator.0 == "truncate-instant" { 2 } else { 1 };
+        if where_fn.args.len() != expected {
+            bail!(ErrorKind::InvalidNumberOfArguments(operator.clone(), where_fn.args.len(), expected));
+        }
+
+        let instants = ValueTypeSet::of_one(ValueType::Instant);
+        let mut args = where_fn.args.into_iter();
+
+        let (computation, value_type) = match operator.0.as_str() {
+            "truncate-instant" => {
+                let instant = self.resolve_argument_of_types(&operator, 0, args.next().unwrap(), instants, "instant")?;
+                let truncated = match args.next().unwrap() {
+                    FnArg::Keyword(ref unit) => truncate(instant, unit.0.as_str()),
+                    _ => None,
+                };
+                match truncated {
+                    Some(computation) => (computation, ValueType::Instant),
+                    None => bail!(ErrorKind::InvalidArgument(operator.clone(), "time unit", 1)),
+                }
+            },
+            "instant->long" => {
+                let instant = self.resolve_argument_of_types(&operator, 0, args.next().unwrap(), instants, "instant")?;
+                (Computation::CastToInteger(instant), ValueType::Long)
+            },
+            "long->instant" => {
+                let micros = self.resolve_argument_of_types(&operator, 0, args.next().unwrap(), ValueTypeSet::of_one(ValueType::Long), "integer")?;
+                (Computation::CastToInteger(micros), ValueType::Instant)
+            },
+            name => {
+                let format = match name {
+                    "instant-year" => "%Y",
+                    "instant-month" => "%m",
+                    "instant-day" => "%d",
+                    "instant-hour" => "%H",
+                    "instant-minute" => "%M",
+                    _ => bail!(ErrorKind::UnknownFunction(operator.clone())),
+                };
+                let instant = self.resolve_argument_of_types(&operator, 0, args.next().unwrap(), instants, "instant")?;
+                (Computation::CastToInteger(strftime(format, instant, &[])), ValueType::Long)
+            },
+        };
+
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        self.bind_computation_to_var(schema, computation, value_type, var);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use mentat_core::{
+        Attribute,
+    };
+
+    use mentat_query::{
+        Keyword,
+        NamespacedKeyword,
+        PlainSymbol,
+        Variable,
+    };
+
+    use clauses::{
+        add_attribute,
+        associate_ident,
+        bind_value_of,
+    };
+
+    use types::{
+        Column,
+        DatomsColumn,
+        EmptyBecause,
+        QualifiedAlias,
+    };
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "when"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::Instant,
+            ..Default::default()
+        });
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "count"), 100);
+        add_attribute(&mut schema, 100, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        schema
+    }
+
+    fn apply(cc: &mut ConjoiningClauses, schema: &Schema, function: &str, args: Vec<FnArg>, var: &Variable) -> Result<()> {
+        cc.apply_instant_function(schema, WhereFn {
+            operator: PlainSymbol::new(function),
+            args: args,
+            binding: Binding::BindScalar(var.clone()),
+        })
+    }
+
+    fn d0v() -> QueryValue {
+        QueryValue::Column(QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value))
+    }
+
+    #[test]
+    fn test_truncate_instant() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let when = Variable::from_valid_name("?when");
+        let day = Variable::from_valid_name("?day");
+        let month = Variable::from_valid_name("?month");
+        bind_value_of(&mut cc, &schema, "when", &when);
+
+        apply(&mut cc, &schema, "truncate-instant", vec![FnArg::Variable(when.clone()), FnArg::Keyword(Keyword::new("day"))], &day).expect("to apply");
+        assert_eq!(cc.known_type(&day), Some(ValueType::Instant));
+        let truncated = truncate_to_multiple(d0v(), MICROS_PER_DAY, 0);
+        assert_eq!(cc.column_bindings.get(&day),
+                   Some(&vec![QualifiedAlias::new("datoms00".to_string(), Column::Computed(Box::new(truncated)))]));
+
+        apply(&mut cc, &schema, "truncate-instant", vec![FnArg::Variable(when.clone()), FnArg::Keyword(Keyword::new("month"))], &month).expect("to apply");
+        assert_eq!(cc.known_type(&month), Some(ValueType::Instant));
+        assert!(!cc.is_known_empty());
+    }
+
+    #[test]
+    fn test_truncate_instant_requires_a_unit() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let when = Variable::from_valid_name("?when");
+        let out = Variable::from_valid_name("?out");
+        bind_value_of(&mut cc, &schema, "when", &when);
+
+        match apply(&mut cc, &schema, "truncate-instant", vec![FnArg::Variable(when.clone()), FnArg::Keyword(Keyword::new("fortnight"))], &out) {
+            Err(::errors::Error(ErrorKind::InvalidArgument(_, "time unit", 1), _)) => {},
+            x => panic!("Expected an invalid argument, got {:?}.", x),
+        }
+        match apply(&mut cc, &schema, "truncate-instant", vec![FnArg::Variable(when.clone()), FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "day"))], &out) {
+            Err(::errors::Error(ErrorKind::InvalidArgument(_, "time unit", 1), _)) => {},
+            x => panic!("Expected an invalid argument, got {:?}.", x),
+        }
+    }
+
+    #[test]
+    fn test_instant_components_and_conversions() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let when = Variable::from_valid_name("?when");
+        let year = Variable::from_valid_name("?year");
+        let micros = Variable::from_valid_name("?micros");
+        let back = Variable::from_valid_name("?back");
+        bind_value_of(&mut cc, &schema, "when", &when);
+
+        apply(&mut cc, &schema, "instant-year", vec![FnArg::Variable(when.clone())], &year).expect("to apply");
+        assert_eq!(cc.known_type(&year), Some(ValueType::Long));
+
+        apply(&mut cc, &schema, "instant->long", vec![FnArg::Variable(when.clone())], &micros).expect("to apply");
+        assert_eq!(cc.known_type(&micros), Some(ValueType::Long));
+
+        apply(&mut cc, &schema, "long->instant", vec![FnArg::Variable(micros.clone())], &back).expect("to apply");
+        assert_eq!(cc.known_type(&back), Some(ValueType::Instant));
+        assert!(!cc.is_known_empty());
+    }
+
+    #[test]
+    fn test_instant_functions_require_instants() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let count = Variable::from_valid_name("?count");
+        let year = Variable::from_valid_name("?year");
+        bind_value_of(&mut cc, &schema, "count", &count);
+
+        match apply(&mut cc, &schema, "instant-year", vec![FnArg::Variable(count.clone())], &year) {
+            Ok(()) => {},
+            x => panic!("Expected the CC to be empty, got {:?}.", x),
+        }
+        assert!(cc.is_known_empty());
+
+        let mut cc = ConjoiningClauses::default();
+        match apply(&mut cc, &schema, "instant-year", vec![FnArg::EntidOrInteger(2018)], &year) {
+            Err(::errors::Error(ErrorKind::InvalidArgument(_, "instant", 0), _)) => {},
+            x => panic!("Expected an invalid argument, got {:?}.", x),
+        }
+        assert_eq!(cc.empty_because, Some(EmptyBecause::NonInstantArgument));
+    }
+}
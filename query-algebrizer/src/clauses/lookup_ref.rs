@@ -0,0 +1,100 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::BTreeMap;
+
+use mentat_core::{
+    Entid,
+    HasSchema,
+    Schema,
+    TypedValue,
+    ValueType,
+};
+
+use mentat_query::{
+    IdentOrEntid,
+    LookupRef,
+    Variable,
+};
+
+use clauses::ConjoiningClauses;
+
+use types::EmptyBecause;
+
+/// Turn a lookup ref into the attribute-value pair that a store can resolve to an entity.
+///
+/// The attribute must exist and be unique. The value is coerced to the attribute's type: an
+/// integer or a keyword can name an entity when the attribute is a ref.
+pub fn lookup_ref_av(schema: &Schema, lookup_ref: &LookupRef) -> ::std::result::Result<(Entid, TypedValue), EmptyBecause> {
+    let a: Entid = match lookup_ref.attribute {
+        IdentOrEntid::Entid(entid) => entid,
+        IdentOrEntid::Ident(ref kw) =>
+            schema.get_entid(kw)
+                  .ok_or_else(|| EmptyBecause::InvalidAttributeIdent(kw.as_ref().clone()))?
+                  .into(),
+    };
+
+    let attribute = schema.attribute_for_entid(a)
+                          .ok_or_else(|| EmptyBecause::InvalidAttributeEntid(a))?;
+    if attribute.unique.is_none() {
+        return Err(EmptyBecause::NonUniqueLookupRefAttribute(a));
+    }
+
+    let v = match (attribute.value_type, &lookup_ref.value) {
+        (ValueType::Ref, &TypedValue::Long(x)) => TypedValue::Ref(x),
+        (ValueType::Ref, &TypedValue::Keyword(ref kw)) =>
+            schema.get_entid(kw)
+                  .map(|entid| TypedValue::Ref(entid.into()))
+                  .ok_or_else(|| EmptyBecause::UnresolvedIdent(kw.as_ref().clone()))?,
+        (value_type, value) => {
+            if !value.is_congruent_with(value_type) {
+                return Err(EmptyBecause::ValueTypeMismatch(value_type, value.clone()));
+            }
+            value.clone()
+        },
+    };
+
+    Ok((a, v))
+}
+
+/// Lookup refs.
+impl ConjoiningClauses {
+    /// Find the entity named by a lookup ref. The algebrizer can't read the store, so this uses
+    /// the resolutions supplied with the query's inputs; anything missing from those names no
+    /// entity.
+    pub fn entid_for_lookup_ref(&self, schema: &Schema, lookup_ref: &LookupRef) -> ::std::result::Result<Entid, EmptyBecause> {
+        let av = lookup_ref_av(schema, lookup_ref)?;
+        self.lookup_refs
+            .get(&av)
+            .cloned()
+            .ok_or_else(|| EmptyBecause::UnresolvedLookupRef(lookup_ref.clone()))
+    }
+
+    /// Bind `:in` variables that were supplied as lookup refs. Each binds to the entity it names,
+    /// and a lookup ref that names no entity means the query can't return results.
+    pub fn bind_lookup_ref_inputs(&mut self, schema: &Schema, bindings: BTreeMap<Variable, LookupRef>) {
+        for (var, lookup_ref) in bindings.into_iter() {
+            if !self.input_variables.contains(&var) {
+                continue;
+            }
+            match self.entid_for_lookup_ref(schema, &lookup_ref) {
+                Ok(entid) => {
+                    let value = TypedValue::Ref(entid);
+                    self.constrain_var_to_type(var.clone(), ValueType::Ref);
+                    self.value_bindings.insert(var, value);
+                },
+                Err(why) => {
+                    self.mark_known_empty(why);
+                    return;
+                },
+            }
+        }
+    }
+}
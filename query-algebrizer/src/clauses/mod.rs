@@ -8,6 +8,8 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::cell::RefCell;
+
 use std::cmp;
 
 use std::collections::{
@@ -22,6 +24,8 @@ use std::fmt::{
     Formatter,
 };
 
+use std::rc::Rc;
+
 use mentat_core::{
     Attribute,
     Entid,
@@ -41,6 +45,8 @@ use mentat_query::{
     Pattern,
     PatternNonValuePlace,
     PatternValuePlace,
+    PlainSymbol,
+    Rule,
     Variable,
     WhereClause,
 };
@@ -56,26 +62,36 @@ use types::{
     ColumnIntersection,
     ComputedTable,
     Column,
+    Computation,
     DatomsColumn,
     DatomsTable,
     EmptyBecause,
     FulltextColumn,
+    LeftJoin,
     QualifiedAlias,
     QueryValue,
     SourceAlias,
     TableAlias,
+    TransactionsColumn,
 };
 
+mod arithmetic;
 mod convert;              // Converting args to values.
 mod inputs;
+mod lookup_ref;
 mod or;
 mod not;
 mod pattern;
 mod predicate;
 mod resolve;
+mod rules;
 
 mod ground;
 mod fulltext;
+mod get_else;
+mod instants;
+mod strings;
+mod tx_log;
 mod where_fn;
 
 use validate::{
@@ -84,6 +100,7 @@ use validate::{
 };
 
 pub use self::inputs::QueryInputs;
+pub use self::lookup_ref::lookup_ref_av;
 
 // We do this a lot for errors.
 trait RcCloned<T> {
@@ -184,6 +201,10 @@ pub struct ConjoiningClauses {
     /// an identifier in a `DatomsTable::Computed(c)` table reference.
     pub computed_tables: Vec<ComputedTable>,
 
+    /// Tables that are `LEFT JOIN`ed onto the tables in `from`, each with its own `ON`
+    /// constraints. Their columns are `NULL` when there's no matching row -- see `get-else`.
+    pub left_joins: Vec<LeftJoin>,
+
     /// A list of fragments that can be joined by `AND`.
     pub wheres: ColumnIntersection,
 
@@ -219,6 +240,20 @@ pub struct ConjoiningClauses {
 
     /// Map of variables to the set of type requirements we have for them.
     required_types: BTreeMap<Variable, ValueTypeSet>,
+
+    /// The definitions of the rules that the query can invoke, by name. These come from the
+    /// query's inputs, and are shared with any nested CCs.
+    rules: Rc<BTreeMap<PlainSymbol, Vec<Rule>>>,
+
+    /// The entities named by lookup refs, keyed by attribute and value. These are resolved
+    /// against the store by the caller and supplied with the query's inputs, and are shared with
+    /// any nested CCs.
+    lookup_refs: Rc<BTreeMap<(Entid, TypedValue), Entid>>,
+
+    /// Input variables that the generated SQL refers to as parameters, whether or not they're
+    /// bound to a column -- function arguments, for example. Their values must be bound when the
+    /// query is run. Shared with any nested CCs.
+    late_bound_inputs: Rc<RefCell<BTreeSet<Variable>>>,
 }
 
 impl PartialEq for ConjoiningClauses {
@@ -226,6 +261,7 @@ impl PartialEq for ConjoiningClauses {
         self.empty_because.eq(&other.empty_because) &&
         self.from.eq(&other.from) &&
         self.computed_tables.eq(&other.computed_tables) &&
+        self.left_joins.eq(&other.left_joins) &&
         self.wheres.eq(&other.wheres) &&
         self.column_bindings.eq(&other.column_bindings) &&
         self.input_variables.eq(&other.input_variables) &&
@@ -244,6 +280,7 @@ impl Debug for ConjoiningClauses {
             .field("empty_because", &self.empty_because)
             .field("from", &self.from)
             .field("computed_tables", &self.computed_tables)
+            .field("left_joins", &self.left_joins)
             .field("wheres", &self.wheres)
             .field("column_bindings", &self.column_bindings)
             .field("input_variables", &self.input_variables)
@@ -263,6 +300,7 @@ impl Default for ConjoiningClauses {
             alias_counter: RcCounter::new(),
             from: vec![],
             computed_tables: vec![],
+            left_joins: vec![],
             wheres: ColumnIntersection::default(),
             required_types: BTreeMap::new(),
             input_variables: BTreeSet::new(),
@@ -270,6 +308,9 @@ impl Default for ConjoiningClauses {
             value_bindings: BTreeMap::new(),
             known_types: BTreeMap::new(),
             extracted_types: BTreeMap::new(),
+            rules: Rc::new(BTreeMap::new()),
+            lookup_refs: Rc::new(BTreeMap::new()),
+            late_bound_inputs: Rc::new(RefCell::new(BTreeSet::new())),
         }
     }
 }
@@ -296,15 +337,23 @@ impl ConjoiningClauses {
     where T: Into<Option<QueryInputs>> {
         match inputs.into() {
             None => ConjoiningClauses::with_alias_counter(alias_counter),
-            Some(QueryInputs { mut types, mut values }) => {
+            Some(QueryInputs { mut types, mut values, rules, lookup_refs, .. }) => {
                 // Discard any bindings not mentioned in our :in clause.
                 types.keep_intersected_keys(&in_variables);
                 values.keep_intersected_keys(&in_variables);
 
+                // Several definitions with the same name are alternatives.
+                let mut rules_by_name: BTreeMap<PlainSymbol, Vec<Rule>> = BTreeMap::new();
+                for rule in rules {
+                    rules_by_name.entry(rule.name.clone()).or_insert_with(Vec::new).push(rule);
+                }
+
                 let mut cc = ConjoiningClauses {
                     alias_counter: alias_counter,
                     input_variables: in_variables,
                     value_bindings: values,
+                    rules: Rc::new(rules_by_name),
+                    lookup_refs: Rc::new(lookup_refs),
                     ..Default::default()
                 };
 
@@ -329,6 +378,9 @@ impl ConjoiningClauses {
             known_types: self.known_types.clone(),
             extracted_types: self.extracted_types.clone(),
             required_types: self.required_types.clone(),
+            rules: self.rules.clone(),
+            lookup_refs: self.lookup_refs.clone(),
+            late_bound_inputs: self.late_bound_inputs.clone(),
             ..Default::default()
         }
     }
@@ -344,6 +396,9 @@ impl ConjoiningClauses {
             known_types: self.known_types.with_intersected_keys(&vars),
             extracted_types: self.extracted_types.with_intersected_keys(&vars),
             required_types: self.required_types.with_intersected_keys(&vars),
+            rules: self.rules.clone(),
+            lookup_refs: self.lookup_refs.clone(),
+            late_bound_inputs: self.late_bound_inputs.clone(),
             ..Default::default()
         }
     }
@@ -427,7 +482,8 @@ impl ConjoiningClauses {
                     unimplemented!()
                 },
 
-                Column::Fixed(DatomsColumn::ValueTypeTag) => {
+                Column::Fixed(DatomsColumn::ValueTypeTag) |
+                Column::Transactions(TransactionsColumn::ValueTypeTag) => {
                     // I'm pretty sure this is meaningless right now, because we will never bind
                     // a type tag to a variable -- there's no syntax for doing so.
                     // In the future we might expose a way to do so, perhaps something like:
@@ -440,7 +496,11 @@ impl ConjoiningClauses {
                 },
 
                 // TODO: recognize when the valueType might be a ref and also translate entids there.
-                Column::Fixed(DatomsColumn::Value) => {
+                Column::Fixed(DatomsColumn::Value) |
+                Column::WithDefault(_, _) |
+                Column::Computed(_) |
+                Column::Transactions(TransactionsColumn::Value) |
+                Column::Transactions(TransactionsColumn::Added) => {
                     self.constrain_column_to_constant(table, column, bound_val);
                 },
 
@@ -448,7 +508,10 @@ impl ConjoiningClauses {
                 // get an entity out of the bound value, the pattern cannot produce results.
                 Column::Fixed(DatomsColumn::Attribute) |
                 Column::Fixed(DatomsColumn::Entity) |
-                Column::Fixed(DatomsColumn::Tx) => {
+                Column::Fixed(DatomsColumn::Tx) |
+                Column::Transactions(TransactionsColumn::Attribute) |
+                Column::Transactions(TransactionsColumn::Entity) |
+                Column::Transactions(TransactionsColumn::Tx) => {
                     match bound_val {
                         TypedValue::Keyword(ref kw) => {
                             if let Some(entid) = self.entid_for_ident(schema, kw) {
@@ -485,7 +548,8 @@ impl ConjoiningClauses {
             !late_binding &&                                // Never need to extract for bound vars.
             // Never need to extract types for refs, and var columns are handled elsewhere:
             // a subquery will be projecting a type tag.
-            column == Column::Fixed(DatomsColumn::Value) &&
+            (column == Column::Fixed(DatomsColumn::Value) ||
+             column == Column::Transactions(TransactionsColumn::Value)) &&
             self.known_type(&var).is_none() &&              // Don't need to extract if we know a single type.
             !self.extracted_types.contains_key(&var);       // We're already extracting the type.
 
@@ -499,6 +563,21 @@ impl ConjoiningClauses {
         self.column_bindings.entry(var).or_insert(vec![]).push(alias);
     }
 
+    /// Bind `var` to the result of `computation`, which SQLite will evaluate to a value of type
+    /// `value_type`.
+    pub fn bind_computation_to_var(&mut self, schema: &Schema, computation: Computation, value_type: ValueType, var: Variable) {
+        self.constrain_var_to_type(var.clone(), value_type);
+        if self.is_known_empty() {
+            return;
+        }
+
+        // The alias of a computed column is never rendered, but keep it meaningful.
+        let table = computation.first_column()
+                               .map(|qa| qa.0.clone())
+                               .unwrap_or_else(TableAlias::new);
+        self.bind_column_to_var(schema, table, Column::Computed(Box::new(computation)), var);
+    }
+
     pub fn constrain_column_to_constant<C: Into<Column>>(&mut self, table: TableAlias, column: C, constant: TypedValue) {
         match constant {
             // Be a little more explicit.
@@ -688,6 +767,11 @@ impl ConjoiningClauses {
         }
     }
 
+    /// The input variables whose values must be bound as SQL parameters when the query is run.
+    pub fn late_bound_inputs(&self) -> BTreeSet<Variable> {
+        self.late_bound_inputs.borrow().clone()
+    }
+
     #[inline]
     pub fn is_known_empty(&self) -> bool {
         self.empty_because.is_some()
@@ -773,6 +857,9 @@ impl ConjoiningClauses {
                 schema.attribute_for_entid(id)
                       .ok_or_else(|| EmptyBecause::InvalidAttributeEntid(id))
                       .and_then(|attribute| self.table_for_attribute_and_value(attribute, value)),
+            &PatternNonValuePlace::LookupRef(ref lookup_ref) =>
+                self.entid_for_lookup_ref(schema, lookup_ref)
+                    .and_then(|id| self.table_for_places(schema, &PatternNonValuePlace::Entid(id), value)),
             // TODO: In a prepared context, defer this decision until a second algebrizing phase.
             // #278.
             &PatternNonValuePlace::Placeholder =>
@@ -802,6 +889,32 @@ impl ConjoiningClauses {
         }
     }
 
+    /// Decide which table to use for the provided pattern.
+    /// Patterns against `$history` always use the transaction log, but we still check the
+    /// attribute and value places as we would for `datoms`. Where those would need the text of
+    /// fulltext values, we use `all_transactions`, which resolves the log's fulltext rowids just
+    /// as `all_datoms` does for `datoms`.
+    ///
+    /// A lookup ref in the entity or tx place that names no entity means the pattern can't match,
+    /// so we report that here too.
+    fn table_for_pattern<'s, 'a>(&self, schema: &'s Schema, pattern: &'a Pattern) -> ::std::result::Result<DatomsTable, EmptyBecause> {
+        let table = self.table_for_places(schema, &pattern.attribute, &pattern.value)?;
+        for place in &[&pattern.entity, &pattern.tx] {
+            if let &&PatternNonValuePlace::LookupRef(ref lookup_ref) = place {
+                self.entid_for_lookup_ref(schema, lookup_ref)?;
+            }
+        }
+        match pattern.source {
+            Some(ref src) if src.is_history() => {
+                match table {
+                    DatomsTable::Datoms => Ok(DatomsTable::Transactions),
+                    _ => Ok(DatomsTable::AllTransactions),
+                }
+            },
+            _ => Ok(table),
+        }
+    }
+
     pub fn next_alias_for_table(&mut self, table: DatomsTable) -> TableAlias {
         match table {
             DatomsTable::Computed(u) =>
@@ -816,7 +929,7 @@ impl ConjoiningClauses {
     /// Note that if this function decides that a pattern cannot match, it will flip
     /// `empty_because`.
     fn alias_table<'s, 'a>(&mut self, schema: &'s Schema, pattern: &'a Pattern) -> Option<SourceAlias> {
-        self.table_for_places(schema, &pattern.attribute, &pattern.value)
+        self.table_for_pattern(schema, pattern)
             .map_err(|reason| {
                 self.mark_known_empty(reason);
             })
@@ -840,6 +953,10 @@ impl ConjoiningClauses {
                 schema.attribute_for_entid(id),
             PatternNonValuePlace::Ident(ref kw) =>
                 schema.attribute_for_ident(kw).map(|(a, _id)| a),
+            PatternNonValuePlace::LookupRef(ref lookup_ref) =>
+                self.entid_for_lookup_ref(schema, lookup_ref)
+                    .ok()
+                    .and_then(|id| schema.attribute_for_entid(id)),
             PatternNonValuePlace::Variable(ref var) =>
                 // If the pattern has a variable, we've already determined that the binding -- if
                 // any -- is acceptable and yields a table. Here, simply look to see if it names
@@ -875,7 +992,7 @@ impl ConjoiningClauses {
     ///    datoms12.e = datoms14.e
     /// ```
     pub fn expand_column_bindings(&mut self) {
-        for cols in self.column_bindings.values() {
+        for (var, cols) in self.column_bindings.iter() {
             if cols.len() > 1 {
                 let ref primary = cols[0];
                 let secondaries = cols.iter().skip(1);
@@ -886,6 +1003,26 @@ impl ConjoiningClauses {
                     self.wheres.add_intersection(ColumnConstraint::Equals(primary.clone(), QueryValue::Column(secondary.clone())));
                 }
             }
+
+            // An input variable without a value yet will be bound when the query is run. Its type
+            // was given up front, so we can constrain the type tag now.
+            if self.input_variables.contains(var) && !self.value_bindings.contains_key(var) {
+                if let Some(primary) = cols.first() {
+                    self.late_bound_inputs.borrow_mut().insert(var.clone());
+                    self.wheres.add_intersection(ColumnConstraint::Equals(primary.clone(), QueryValue::InputVariable(var.clone())));
+                    let is_value = match primary.1 {
+                        Column::Fixed(DatomsColumn::Value) |
+                        Column::Transactions(TransactionsColumn::Value) => true,
+                        _ => false,
+                    };
+                    let unit_type = self.known_types
+                                        .get(var)
+                                        .and_then(|types| if types.is_unit() { types.exemplar() } else { None });
+                    if let (true, Some(value_type)) = (is_value, unit_type) {
+                        self.wheres.add_intersection(ColumnConstraint::has_unit_type(primary.0.clone(), value_type));
+                    }
+                }
+            }
         }
     }
 
@@ -1025,7 +1162,9 @@ impl ConjoiningClauses {
             WhereClause::TypeAnnotation(anno) => {
                 self.apply_type_anno(&anno)
             },
-            _ => unimplemented!(),
+            WhereClause::RuleExpr(r) => {
+                self.apply_rule_expr(schema, r)
+            },
         }
     }
 }
@@ -1042,6 +1181,15 @@ impl PushComputed for Vec<ComputedTable> {
     }
 }
 
+// Shorthand for the values that functions compute, shared by `strings` and `instants`.
+fn computed(computation: Computation) -> QueryValue {
+    QueryValue::Computed(Box::new(computation))
+}
+
+fn long(value: i64) -> QueryValue {
+    QueryValue::TypedValue(TypedValue::Long(value))
+}
+
 // These are helpers that tests use to build Schema instances.
 #[cfg(test)]
 fn associate_ident(schema: &mut Schema, i: NamespacedKeyword, e: Entid) {
@@ -1059,6 +1207,20 @@ pub fn ident(ns: &str, name: &str) -> PatternNonValuePlace {
     PatternNonValuePlace::Ident(::std::rc::Rc::new(NamespacedKeyword::new(ns, name)))
 }
 
+/// Bind `var` to the value of `:foo/attr` on some entity `?x`.
+#[cfg(test)]
+fn bind_value_of(cc: &mut ConjoiningClauses, schema: &Schema, attr: &str, var: &Variable) {
+    cc.apply_pattern(schema, Pattern {
+        source: None,
+        entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+        attribute: ident("foo", attr),
+        value: PatternValuePlace::Variable(var.clone()),
+        tx: PatternNonValuePlace::Placeholder,
+        added: PatternValuePlace::Placeholder,
+    });
+    assert!(!cc.is_known_empty());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
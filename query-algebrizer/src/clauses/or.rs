@@ -60,6 +60,11 @@ fn _simply_matches_place(left: &PatternNonValuePlace, right: &PatternNonValuePla
         (&PatternNonValuePlace::Entid(_), &PatternNonValuePlace::Ident(_))       => true,
         (&PatternNonValuePlace::Ident(_), &PatternNonValuePlace::Ident(_))       => true,
         (&PatternNonValuePlace::Ident(_), &PatternNonValuePlace::Entid(_))       => true,
+        (&PatternNonValuePlace::LookupRef(_), &PatternNonValuePlace::LookupRef(_)) |
+        (&PatternNonValuePlace::LookupRef(_), &PatternNonValuePlace::Entid(_))     |
+        (&PatternNonValuePlace::LookupRef(_), &PatternNonValuePlace::Ident(_))     |
+        (&PatternNonValuePlace::Entid(_), &PatternNonValuePlace::LookupRef(_))     |
+        (&PatternNonValuePlace::Ident(_), &PatternNonValuePlace::LookupRef(_))     => true,
         _ => false,
     }
 }
@@ -216,7 +221,7 @@ impl ConjoiningClauses {
                 // the pattern cannot succeed; we drop it.
                 // Inside an `or` it's not a failure for a pattern to be unable to match, which
                 // manifests as a table being unable to be found.
-                let table = self.table_for_places(schema, &p.attribute, &p.value);
+                let table = self.table_for_pattern(schema, &p);
                 match table {
                     Err(e) => {
                         empty_because = Some(e);
@@ -232,7 +237,8 @@ impl ConjoiningClauses {
                                 _simply_matches_place(&template.entity, &p.entity) &&
                                 _simply_matches_place(&template.attribute, &p.attribute) &&
                                 _simply_matches_value_place(&template.value, &p.value) &&
-                                _simply_matches_place(&template.tx, &p.tx)
+                                _simply_matches_place(&template.tx, &p.tx) &&
+                                _simply_matches_value_place(&template.added, &p.added)
                             } else {
                                 // No previous pattern.
                                 true
@@ -682,8 +688,8 @@ impl ConjoiningClauses {
 }
 
 /// Helper to fold together a set of type maps.
-fn union_types(into: &mut BTreeMap<Variable, ValueTypeSet>,
-               additional_types: &BTreeMap<Variable, ValueTypeSet>) {
+pub fn union_types(into: &mut BTreeMap<Variable, ValueTypeSet>,
+                   additional_types: &BTreeMap<Variable, ValueTypeSet>) {
     // We want the exclusive disjunction -- any variable not mentioned in both sets -- to default
     // to ValueTypeSet::Any.
     // This is necessary because we lazily populate known_types, so sometimes the type set will
@@ -16,6 +16,7 @@ use mentat_core::{
 };
 
 use mentat_query::{
+    NonIntegerConstant,
     Pattern,
     PatternValuePlace,
     PatternNonValuePlace,
@@ -29,8 +30,10 @@ use clauses::ConjoiningClauses;
 use types::{
     ColumnConstraint,
     DatomsColumn,
+    DatomsTable,
     EmptyBecause,
     SourceAlias,
+    TransactionsColumn,
 };
 
 /// Application of patterns.
@@ -106,7 +109,16 @@ impl ConjoiningClauses {
                     self.mark_known_empty(EmptyBecause::UnresolvedIdent(ident.cloned()));
                     return;
                 }
-            }
+            },
+            PatternNonValuePlace::LookupRef(ref lookup_ref) => {
+                match self.entid_for_lookup_ref(schema, lookup_ref) {
+                    Ok(entid) => self.constrain_column_to_entity(col.clone(), DatomsColumn::Entity, entid),
+                    Err(why) => {
+                        self.mark_known_empty(why);
+                        return;
+                    },
+                }
+            },
         }
 
         match pattern.attribute {
@@ -136,7 +148,22 @@ impl ConjoiningClauses {
                     self.mark_known_empty(EmptyBecause::UnresolvedIdent(ident.cloned()));
                     return;
                 }
-            }
+            },
+            PatternNonValuePlace::LookupRef(ref lookup_ref) => {
+                match self.entid_for_lookup_ref(schema, lookup_ref) {
+                    Ok(entid) => {
+                        if !schema.is_attribute(entid) {
+                            self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(entid));
+                            return;
+                        }
+                        self.constrain_attribute(col.clone(), entid)
+                    },
+                    Err(why) => {
+                        self.mark_known_empty(why);
+                        return;
+                    },
+                }
+            },
         }
 
         // Determine if the pattern's value type is known.
@@ -259,14 +286,58 @@ impl ConjoiningClauses {
                     self.mark_known_empty(EmptyBecause::UnresolvedIdent(ident.cloned()));
                     return;
                 }
-            }
+            },
+            PatternNonValuePlace::LookupRef(ref lookup_ref) => {
+                match self.entid_for_lookup_ref(schema, lookup_ref) {
+                    Ok(entid) => self.constrain_column_to_entity(col.clone(), DatomsColumn::Tx, entid),
+                    Err(why) => {
+                        self.mark_known_empty(why);
+                        return;
+                    },
+                }
+            },
+        }
+
+        // Only the transaction log records whether a datom was added or retracted. The log
+        // shares its `e`, `a`, `v`, and `tx` column names with `datoms`, so everything above
+        // applies unchanged.
+        match alias.0 {
+            DatomsTable::Transactions |
+            DatomsTable::AllTransactions => (),
+            _ => return,
+        }
+
+        match pattern.added {
+            PatternValuePlace::Placeholder =>
+                (),
+            PatternValuePlace::Variable(ref v) => {
+                self.constrain_var_to_type(v.clone(), ValueType::Boolean);
+                if self.is_known_empty() {
+                    return;
+                }
+                self.bind_column_to_var(schema, col.clone(), TransactionsColumn::Added, v.clone());
+            },
+            PatternValuePlace::Constant(NonIntegerConstant::Boolean(added)) => {
+                self.constrain_column_to_constant(col.clone(), TransactionsColumn::Added, TypedValue::Boolean(added));
+            },
+            ref other => {
+                // The parser only accepts booleans here, but a pattern can be constructed by hand.
+                let why = match other {
+                    &PatternValuePlace::EntidOrInteger(i) => TypedValue::Long(i),
+                    &PatternValuePlace::IdentOrKeyword(ref kw) => TypedValue::Keyword(kw.clone()),
+                    &PatternValuePlace::Constant(ref c) => c.clone().into_typed_value(),
+                    _ => unreachable!(),
+                };
+                self.mark_known_empty(EmptyBecause::ValueTypeMismatch(ValueType::Boolean, why));
+            },
         }
     }
 
     pub fn apply_pattern<'s, 'p>(&mut self, schema: &'s Schema, pattern: Pattern) {
-        // For now we only support the default source.
+        // For now we only support the default source and the transaction log.
         match pattern.source {
             Some(SrcVar::DefaultSrc) | None => (),
+            Some(ref src) if src.is_history() => (),
             _ => unimplemented!(),
         };
 
@@ -292,6 +363,7 @@ mod testing {
     use std::collections::BTreeMap;
     use std::collections::BTreeSet;
     use std::rc::Rc;
+    use std::sync::Arc;
 
     use mentat_core::attribute::Unique;
     use mentat_core::{
@@ -343,6 +415,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         assert!(cc.is_known_empty());
@@ -361,6 +434,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         assert!(cc.is_known_empty());
@@ -384,6 +458,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // println!("{:#?}", cc);
@@ -424,6 +499,7 @@ mod testing {
             attribute: PatternNonValuePlace::Placeholder,
             value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // println!("{:#?}", cc);
@@ -473,6 +549,7 @@ mod testing {
             attribute: PatternNonValuePlace::Variable(a.clone()),
             value: PatternValuePlace::Variable(v.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // println!("{:#?}", cc);
@@ -516,6 +593,7 @@ mod testing {
             attribute: PatternNonValuePlace::Variable(a.clone()),
             value: PatternValuePlace::Variable(v.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         assert!(cc.is_known_empty());
@@ -538,6 +616,7 @@ mod testing {
             attribute: PatternNonValuePlace::Variable(a.clone()),
             value: PatternValuePlace::Variable(v.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // println!("{:#?}", cc);
@@ -566,8 +645,9 @@ mod testing {
             source: None,
             entity: PatternNonValuePlace::Variable(x.clone()),
             attribute: PatternNonValuePlace::Placeholder,
-            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new("hello".to_string()))),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new("hello".to_string()))),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // println!("{:#?}", cc);
@@ -616,8 +696,9 @@ mod testing {
             source: None,
             entity: PatternNonValuePlace::Variable(x.clone()),
             attribute: ident("foo", "roz"),
-            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new("idgoeshere".to_string()))),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new("idgoeshere".to_string()))),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
         cc.apply_pattern(&schema, Pattern {
             source: None,
@@ -625,6 +706,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // Finally, expand column bindings to get the overlaps for ?x.
@@ -692,6 +774,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         let d0_e = QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Entity);
@@ -739,6 +822,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // The type of the provided binding doesn't match the type of the attribute.
@@ -774,6 +858,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // The type of the provided binding doesn't match the type of the attribute.
@@ -808,6 +893,7 @@ mod testing {
             attribute: ident("foo", "roz"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
         cc.apply_pattern(&schema, Pattern {
             source: None,
@@ -815,6 +901,7 @@ mod testing {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // Finally, expand column bindings to get the overlaps for ?x.
@@ -850,6 +937,7 @@ mod testing {
             attribute: PatternNonValuePlace::Variable(y.clone()),
             value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
         cc.apply_pattern(&schema, Pattern {
             source: None,
@@ -857,6 +945,7 @@ mod testing {
             attribute: PatternNonValuePlace::Variable(y.clone()),
             value: PatternValuePlace::Variable(x.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // Finally, expand column bindings to get the overlaps for ?x.
@@ -888,4 +977,70 @@ mod testing {
         assert!(!cc.extracted_types.contains_key(&e));
         assert!(!cc.extracted_types.contains_key(&v));
     }
+
+    #[test]
+    fn test_apply_history_pattern() {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "bar"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let cc = alg(&schema, "[:find ?v ?added :where [$history ?x :foo/bar ?v _ ?added]]");
+        assert!(!cc.is_known_empty());
+
+        let transactions00 = "transactions00".to_string();
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Transactions, transactions00.clone())]);
+
+        let added = Variable::from_valid_name("?added");
+        assert_eq!(cc.column_bindings.get(&added),
+                   Some(&vec![QualifiedAlias::new(transactions00.clone(), TransactionsColumn::Added)]));
+        assert_eq!(cc.known_type(&added), Some(ValueType::Boolean));
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?v")), Some(ValueType::String));
+
+        // `added` must be a boolean.
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_pattern(&schema, Pattern {
+            source: Some(SrcVar::history()),
+            entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+            attribute: ident("foo", "bar"),
+            value: PatternValuePlace::Placeholder,
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::EntidOrInteger(1),
+        });
+        assert!(cc.is_known_empty());
+        assert_eq!(cc.empty_because, Some(EmptyBecause::ValueTypeMismatch(ValueType::Boolean, TypedValue::Long(1))));
+    }
+
+    #[test]
+    fn test_apply_history_pattern_fulltext() {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "fts"), 100);
+        add_attribute(&mut schema, 100, Attribute {
+            value_type: ValueType::String,
+            index: true,
+            fulltext: true,
+            ..Default::default()
+        });
+
+        // We need the text, so the log's rowids must be resolved.
+        let cc = alg(&schema, "[:find ?v ?added :where [$history ?x :foo/fts ?v _ ?added]]");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::AllTransactions, "all_transactions00".to_string())]);
+
+        let added = Variable::from_valid_name("?added");
+        assert_eq!(cc.column_bindings.get(&added),
+                   Some(&vec![QualifiedAlias::new("all_transactions00".to_string(), TransactionsColumn::Added)]));
+
+        // Likewise to match on it.
+        let cc = alg(&schema, r#"[:find ?x :where [$history ?x :foo/fts "hello"]]"#);
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::AllTransactions, "all_transactions00".to_string())]);
+
+        // But not if the value is ignored.
+        let cc = alg(&schema, "[:find ?x :where [$history ?x :foo/fts _]]");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Transactions, "transactions00".to_string())]);
+    }
 }
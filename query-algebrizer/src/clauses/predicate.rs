@@ -9,6 +9,7 @@
 // specific language governing permissions and limitations under the License.
 
 use mentat_core::{
+    HasSchema,
     Schema,
     ValueType,
     ValueTypeSet,
@@ -16,8 +17,15 @@ use mentat_core::{
 
 use mentat_query::{
     FnArg,
+    NotJoin,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
     Predicate,
+    SrcVar,
     TypeAnnotation,
+    UnifyVars,
+    WhereClause,
 };
 
 use clauses::ConjoiningClauses;
@@ -40,19 +48,82 @@ impl ConjoiningClauses {
     /// There are several kinds of predicates in our Datalog:
     /// - A limited set of binary comparison operators: < > <= >= !=.
     ///   These are converted into SQLite binary comparisons and some type constraints.
-    /// - In the future, some predicates that are implemented via function calls in SQLite.
+    /// - `missing?`, which is converted into `NOT EXISTS`.
+    /// - String predicates like `starts-with?` and `re-matches?`, which are implemented via
+    ///   function calls in SQLite.
     ///
-    /// At present we have implemented only the five built-in comparison binary operators.
+    /// At present we have implemented only the five built-in comparison binary operators,
+    /// `missing?`, and the string predicates.
     pub fn apply_predicate<'s>(&mut self, schema: &'s Schema, predicate: Predicate) -> Result<()> {
         // Because we'll be growing the set of built-in predicates, handling each differently,
         // and ultimately allowing user-specified predicates, we match on the predicate name first.
         if let Some(op) = Inequality::from_datalog_operator(predicate.operator.0.as_str()) {
             self.apply_inequality(schema, op, predicate)
         } else {
-            bail!(ErrorKind::UnknownFunction(predicate.operator.clone()))
+            match predicate.operator.0.as_str() {
+                "missing?" => self.apply_missing(schema, predicate),
+                "starts-with?" |
+                "ends-with?" |
+                "includes?" |
+                "equals-ignore-case?" |
+                "re-matches?" => self.apply_string_predicate(schema, predicate),
+                _ => bail!(ErrorKind::UnknownFunction(predicate.operator.clone())),
+            }
         }
     }
 
+    /// Apply `[(missing? $ ?e :attr)]`, which holds when `?e` has no value for `:attr`.
+    ///
+    /// This is exactly `(not [?e :attr _])`, so that's how we apply it: the result is a
+    /// `NOT EXISTS` subquery. As with `not`, `?e` must already be bound.
+    pub fn apply_missing<'s>(&mut self, schema: &'s Schema, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 3 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 3));
+        }
+
+        let mut args = predicate.args.into_iter();
+
+        // TODO: process source variables.
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "source variable", 0)),
+        }
+
+        let entity = args.next().unwrap()
+                         .to_pattern_non_value_place()
+                         .ok_or(ErrorKind::InvalidArgument(predicate.operator.clone(), "entity", 1))?;
+
+        let a = match args.next().unwrap() {
+            FnArg::IdentOrKeyword(i) => schema.get_entid(&i).map(|k| k.into()),
+            FnArg::EntidOrInteger(e) => Some(e),
+            _ => None,
+        };
+        let a = a.and_then(|a| schema.attribute_for_entid(a).map(|_| a))
+                 .ok_or(ErrorKind::InvalidArgument(predicate.operator.clone(), "attribute", 2))?;
+
+        if let PatternNonValuePlace::Variable(ref e) = entity {
+            self.constrain_var_to_type(e.clone(), ValueType::Ref);
+            if self.is_known_empty() {
+                return Ok(());
+            }
+        }
+
+        let not_join = NotJoin {
+            unify_vars: UnifyVars::Implicit,
+            clauses: vec![
+                WhereClause::Pattern(Pattern {
+                    source: None,
+                    entity: entity,
+                    attribute: PatternNonValuePlace::Entid(a),
+                    value: PatternValuePlace::Placeholder,
+                    tx: PatternNonValuePlace::Placeholder,
+                    added: PatternValuePlace::Placeholder,
+                }),
+            ],
+        };
+        self.apply_not_join(schema, not_join)
+    }
+
     fn potential_types(&self, schema: &Schema, fn_arg: &FnArg) -> Result<ValueTypeSet> {
         match fn_arg {
             &FnArg::Variable(ref v) => Ok(self.known_type_set(v)),
@@ -209,6 +280,7 @@ mod testing {
             attribute: PatternNonValuePlace::Placeholder,
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
         assert!(!cc.is_known_empty());
 
@@ -269,6 +341,7 @@ mod testing {
             attribute: PatternNonValuePlace::Placeholder,
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
         assert!(!cc.is_known_empty());
 
@@ -287,6 +360,7 @@ mod testing {
             attribute: ident("foo", "roz"),
             value: PatternValuePlace::Variable(y.clone()),
             tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
         });
 
         // Finally, expand column bindings to get the overlaps for ?x.
@@ -8,15 +8,19 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::rc::Rc;
+
 use mentat_core::{
     TypedValue,
     ValueType,
+    ValueTypeSet,
 };
 
 use mentat_query::{
     FnArg,
     NonIntegerConstant,
     PlainSymbol,
+    Variable,
 };
 
 use clauses::ConjoiningClauses;
@@ -28,6 +32,7 @@ use errors::{
 };
 
 use types::{
+    ColumnConstraint,
     EmptyBecause,
     QueryValue,
 };
@@ -52,6 +57,7 @@ impl ConjoiningClauses {
             // Can't be an entid.
             EntidOrInteger(i) => Ok(QueryValue::TypedValue(TypedValue::Long(i))),
             IdentOrKeyword(_) |
+            Keyword(_) |
             SrcVar(_) |
             Constant(NonIntegerConstant::Boolean(_)) |
             Constant(NonIntegerConstant::Text(_)) |
@@ -84,6 +90,7 @@ impl ConjoiningClauses {
             // TODO: should we allow integers if they seem to be timestamps? It's ambiguous…
             EntidOrInteger(_) |
             IdentOrKeyword(_) |
+            Keyword(_) |
             SrcVar(_) |
             Constant(NonIntegerConstant::Boolean(_)) |
             Constant(NonIntegerConstant::Float(_)) |
@@ -97,10 +104,84 @@ impl ConjoiningClauses {
         }
     }
 
+    /// Just like `resolve_numeric_argument`, but for `ValueType::String`.
+    pub fn resolve_string_argument(&mut self, function: &PlainSymbol, position: usize, arg: FnArg) -> Result<QueryValue> {
+        self.resolve_argument_of_types(function, position, arg, ValueTypeSet::of_one(ValueType::String), "string")
+    }
+
+    /// Resolve an argument that must be one of `types`. Variables are narrowed to `types`;
+    /// constants of any other type mark the CC as known-empty and fail.
+    ///
+    /// Unlike the numeric and instant cases, this produces values of variables that are bound to
+    /// values, and input variables whose values will be supplied when the query is run.
+    pub fn resolve_argument_of_types(&mut self, function: &PlainSymbol, position: usize, arg: FnArg, types: ValueTypeSet, expected: &'static str) -> Result<QueryValue> {
+        use self::FnArg::*;
+        let value = match arg {
+            FnArg::Variable(var) => {
+                self.narrow_types_for_var_checking_tags(var.clone(), types);
+                if let Some(value) = self.bound_value(&var) {
+                    return Ok(QueryValue::TypedValue(value));
+                }
+                if let Some(col) = self.column_bindings.get(&var).and_then(|cols| cols.first()) {
+                    return Ok(QueryValue::Column(col.clone()));
+                }
+                if self.input_variables.contains(&var) {
+                    self.late_bound_inputs.borrow_mut().insert(var.clone());
+                    return Ok(QueryValue::InputVariable(var));
+                }
+                bail!(ErrorKind::UnboundVariable(var.name()));
+            },
+            EntidOrInteger(i) => Some(TypedValue::Long(i)),
+            IdentOrKeyword(kw) => Some(TypedValue::Keyword(Rc::new(kw))),
+            Constant(NonIntegerConstant::Boolean(v)) => Some(TypedValue::Boolean(v)),
+            Constant(NonIntegerConstant::Float(f)) => Some(TypedValue::Double(f)),
+            Constant(NonIntegerConstant::Text(s)) => Some(TypedValue::typed_string(s.as_str())),
+            Constant(NonIntegerConstant::Uuid(u)) => Some(TypedValue::Uuid(u)),
+            Constant(NonIntegerConstant::Instant(v)) => Some(TypedValue::Instant(v)),
+            Constant(NonIntegerConstant::BigInteger(_)) |
+            Keyword(_) |
+            SrcVar(_) |
+            Vector(_) => None,
+        };
+        match value {
+            Some(value) if types.contains(value.value_type()) => Ok(QueryValue::TypedValue(value)),
+            _ => {
+                let reason = if types == ValueTypeSet::of_numeric_types() {
+                    EmptyBecause::NonNumericArgument
+                } else if types == ValueTypeSet::of_one(ValueType::Instant) {
+                    EmptyBecause::NonInstantArgument
+                } else if types == ValueTypeSet::of_one(ValueType::String) {
+                    EmptyBecause::NonStringArgument
+                } else {
+                    EmptyBecause::NonMatchingArgument(types)
+                };
+                self.mark_known_empty(reason);
+                bail!(ErrorKind::InvalidArgument(function.clone(), expected, position));
+            },
+        }
+    }
+
+    /// Narrow the types of `var` to `types`. If `var`'s type is extracted at runtime from a column
+    /// that might hold values of other types, check that column's type tag, too: SQL functions
+    /// would otherwise see values of other types that share a representation -- keywords are text,
+    /// and booleans and refs are integers.
+    fn narrow_types_for_var_checking_tags(&mut self, var: Variable, types: ValueTypeSet) {
+        if !self.known_type_set(&var).is_subset(&types) {
+            if let Some(qa) = self.extracted_types.get(&var).cloned() {
+                self.wheres.add_intersection(ColumnConstraint::HasTypes {
+                    value: qa.0,
+                    value_types: types,
+                    check_value: true,
+                });
+            }
+        }
+        self.narrow_types_for_var(var, types);
+    }
+
     /// Take a function argument and turn it into a `QueryValue` suitable for use in a concrete
     /// constraint.
     #[allow(dead_code)]
-    fn resolve_argument(&self, arg: FnArg) -> Result<QueryValue> {
+    fn resolve_argument(&self, function: &PlainSymbol, position: usize, arg: FnArg) -> Result<QueryValue> {
         use self::FnArg::*;
         match arg {
             FnArg::Variable(var) => {
@@ -111,6 +192,8 @@ impl ConjoiningClauses {
             },
             EntidOrInteger(i) => Ok(QueryValue::PrimitiveLong(i)),
             IdentOrKeyword(_) => unimplemented!(),     // TODO
+            // Option names like `:day` only mean something to the function that takes them.
+            Keyword(_) => bail!(ErrorKind::InvalidArgument(function.clone(), "value", position)),
             Constant(NonIntegerConstant::Boolean(val)) => Ok(QueryValue::TypedValue(TypedValue::Boolean(val))),
             Constant(NonIntegerConstant::Float(f)) => Ok(QueryValue::TypedValue(TypedValue::Double(f))),
             Constant(NonIntegerConstant::Text(s)) => Ok(QueryValue::TypedValue(TypedValue::typed_string(s.as_str()))),
@@ -0,0 +1,599 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use mentat_core::{
+    Schema,
+    SQLValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    ContainsVariables,
+    FnArg,
+    NotJoin,
+    OrJoin,
+    OrWhereClause,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    PlainSymbol,
+    Predicate,
+    Rule,
+    RuleExpr,
+    TypeAnnotation,
+    UnifyVars,
+    Variable,
+    VariableOrPlaceholder,
+    WhereClause,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    PushComputed,
+};
+
+use clauses::convert::ValueConversion;
+
+use clauses::or::union_types;
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    ColumnConstraint,
+    ComputedTable,
+    DatomsTable,
+    EmptyBecause,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+    VariableColumn,
+};
+
+/// Collect the names of the rules invoked by `clause`, including any invoked inside `or` and `not`.
+fn accumulate_invoked_rules(clause: &WhereClause, acc: &mut BTreeSet<PlainSymbol>) {
+    match clause {
+        &WhereClause::RuleExpr(ref r) => {
+            acc.insert(r.name.clone());
+        },
+        &WhereClause::OrJoin(ref o) => {
+            for arm in o.clauses.iter() {
+                match arm {
+                    &OrWhereClause::Clause(ref c) => accumulate_invoked_rules(c, acc),
+                    &OrWhereClause::And(ref cs) => for c in cs.iter() { accumulate_invoked_rules(c, acc) },
+                }
+            }
+        },
+        &WhereClause::NotJoin(ref n) => {
+            for c in n.clauses.iter() {
+                accumulate_invoked_rules(c, acc);
+            }
+        },
+        &WhereClause::Pattern(_) |
+        &WhereClause::Pred(_) |
+        &WhereClause::WhereFn(_) |
+        &WhereClause::TypeAnnotation(_) => (),
+    }
+}
+
+/// Return true if the rule named `caller` can invoke the rule named `callee`, either directly or
+/// through other rules.
+fn can_invoke(rules: &BTreeMap<PlainSymbol, Vec<Rule>>, caller: &PlainSymbol, callee: &PlainSymbol) -> bool {
+    let mut seen: BTreeSet<PlainSymbol> = BTreeSet::new();
+    let mut pending = vec![caller.clone()];
+    while let Some(next) = pending.pop() {
+        let mut invoked = BTreeSet::new();
+        for rule in rules.get(&next).into_iter().flat_map(|definitions| definitions.iter()) {
+            for clause in rule.clauses.iter() {
+                accumulate_invoked_rules(clause, &mut invoked);
+            }
+        }
+        for invoked in invoked.into_iter() {
+            if &invoked == callee {
+                return true;
+            }
+            if seen.insert(invoked.clone()) {
+                pending.push(invoked);
+            }
+        }
+    }
+    false
+}
+
+/// Return true if the rule named `name` can invoke itself, either directly or through other rules.
+pub fn is_recursive(rules: &BTreeMap<PlainSymbol, Vec<Rule>>, name: &PlainSymbol) -> bool {
+    can_invoke(rules, name, name)
+}
+
+/// Split the definitions of the recursive rule `name` into those that don't invoke it and the one
+/// that does.
+///
+/// This is the shape of a SQLite recursive query: any number of initial `SELECT`s, and a single
+/// recursive `SELECT` that refers to the table being built exactly once, and not from a subquery.
+/// The rule must therefore invoke itself directly, outside of any `or` or `not`, from exactly one
+/// of its definitions.
+fn split_recursive_definitions<'r>(rules: &'r BTreeMap<PlainSymbol, Vec<Rule>>,
+                                   name: &PlainSymbol,
+                                   definitions: &'r [Rule]) -> Result<(Vec<&'r Rule>, &'r Rule)> {
+    let mut base = vec![];
+    let mut recursive = vec![];
+    for rule in definitions.iter() {
+        let mut invocations = 0;
+        for clause in rule.clauses.iter() {
+            match clause {
+                &WhereClause::RuleExpr(ref r) if &r.name == name => {
+                    invocations += 1;
+                },
+                clause => {
+                    let mut invoked = BTreeSet::new();
+                    accumulate_invoked_rules(clause, &mut invoked);
+                    if invoked.iter().any(|other| other == name || can_invoke(rules, other, name)) {
+                        bail!(ErrorKind::IndirectlyRecursiveRule(name.clone()));
+                    }
+                },
+            }
+        }
+        match invocations {
+            0 => base.push(rule),
+            1 => recursive.push(rule),
+            _ => bail!(ErrorKind::NonLinearRecursiveRule(name.clone())),
+        }
+    }
+
+    if recursive.len() != 1 {
+        bail!(ErrorKind::NonLinearRecursiveRule(name.clone()));
+    }
+    if base.is_empty() {
+        bail!(ErrorKind::UngroundedRecursiveRule(name.clone()));
+    }
+    Ok((base, recursive.pop().expect("one recursive definition")))
+}
+
+/// A variable that's unique to one expansion of a rule. '#' can't appear in a symbol, so this
+/// can't clash with a variable that the user wrote.
+fn fresh_variable(var: &Variable, expansion: usize) -> Variable {
+    Variable::from_valid_name(format!("{}#{}", var.as_str(), expansion).as_str())
+}
+
+/// The replacements for the variables in one definition of a rule, for one invocation.
+struct Substitution<'a> {
+    rule: &'a PlainSymbol,
+    replacements: BTreeMap<Variable, FnArg>,
+}
+
+impl<'a> Substitution<'a> {
+    fn invalid(&self, var: &Variable) -> Error {
+        ErrorKind::InvalidRuleArgument(self.rule.clone(), var.name()).into()
+    }
+
+    fn arg(&self, var: &Variable) -> FnArg {
+        self.replacements
+            .get(var)
+            .cloned()
+            .unwrap_or_else(|| FnArg::Variable(var.clone()))
+    }
+
+    /// Replace a variable that can't be replaced by a constant: for example, one that's bound by a
+    /// function.
+    fn variable(&self, var: &Variable) -> Result<Variable> {
+        match self.arg(var) {
+            FnArg::Variable(v) => Ok(v),
+            _ => Err(self.invalid(var)),
+        }
+    }
+
+    fn fn_arg(&self, arg: &FnArg) -> FnArg {
+        match arg {
+            &FnArg::Variable(ref v) => self.arg(v),
+            &FnArg::Vector(ref args) => FnArg::Vector(args.iter().map(|a| self.fn_arg(a)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    fn non_value_place(&self, place: &PatternNonValuePlace) -> Result<PatternNonValuePlace> {
+        match place {
+            &PatternNonValuePlace::Variable(ref v) => {
+                self.arg(v)
+                    .to_pattern_non_value_place()
+                    .ok_or_else(|| self.invalid(v))
+            },
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn value_place(&self, place: &PatternValuePlace) -> Result<PatternValuePlace> {
+        match place {
+            &PatternValuePlace::Variable(ref v) => {
+                self.arg(v)
+                    .to_pattern_value_place()
+                    .ok_or_else(|| self.invalid(v))
+            },
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn binding(&self, binding: &Binding) -> Result<Binding> {
+        let places = |places: &Vec<VariableOrPlaceholder>| -> Result<Vec<VariableOrPlaceholder>> {
+            places.iter()
+                  .map(|place| match place {
+                      &VariableOrPlaceholder::Variable(ref v) => self.variable(v).map(VariableOrPlaceholder::Variable),
+                      &VariableOrPlaceholder::Placeholder => Ok(VariableOrPlaceholder::Placeholder),
+                  })
+                  .collect()
+        };
+        Ok(match binding {
+            &Binding::BindScalar(ref v) => Binding::BindScalar(self.variable(v)?),
+            &Binding::BindColl(ref v) => Binding::BindColl(self.variable(v)?),
+            &Binding::BindRel(ref vs) => Binding::BindRel(places(vs)?),
+            &Binding::BindTuple(ref vs) => Binding::BindTuple(places(vs)?),
+        })
+    }
+
+    fn unify_vars(&self, unify_vars: &UnifyVars) -> UnifyVars {
+        match unify_vars {
+            &UnifyVars::Implicit => UnifyVars::Implicit,
+
+            // A variable that's been replaced by a constant has nothing left to unify.
+            &UnifyVars::Explicit(ref vars) => {
+                UnifyVars::Explicit(vars.iter()
+                                        .filter_map(|v| match self.arg(v) {
+                                            FnArg::Variable(v) => Some(v),
+                                            _ => None,
+                                        })
+                                        .collect())
+            },
+        }
+    }
+
+    fn clauses(&self, clauses: &[WhereClause]) -> Result<Vec<WhereClause>> {
+        clauses.iter().map(|c| self.clause(c)).collect()
+    }
+
+    fn clause(&self, clause: &WhereClause) -> Result<WhereClause> {
+        Ok(match clause {
+            &WhereClause::Pattern(ref p) => {
+                WhereClause::Pattern(Pattern {
+                    source: p.source.clone(),
+                    entity: self.non_value_place(&p.entity)?,
+                    attribute: self.non_value_place(&p.attribute)?,
+                    value: self.value_place(&p.value)?,
+                    tx: self.non_value_place(&p.tx)?,
+                    added: self.value_place(&p.added)?,
+                })
+            },
+            &WhereClause::Pred(ref p) => {
+                WhereClause::Pred(Predicate {
+                    operator: p.operator.clone(),
+                    args: p.args.iter().map(|a| self.fn_arg(a)).collect(),
+                })
+            },
+            &WhereClause::WhereFn(ref f) => {
+                WhereClause::WhereFn(WhereFn {
+                    operator: f.operator.clone(),
+                    args: f.args.iter().map(|a| self.fn_arg(a)).collect(),
+                    binding: self.binding(&f.binding)?,
+                })
+            },
+            &WhereClause::TypeAnnotation(ref a) => {
+                WhereClause::TypeAnnotation(TypeAnnotation {
+                    value_type: a.value_type,
+                    variable: self.variable(&a.variable)?,
+                })
+            },
+            &WhereClause::RuleExpr(ref r) => {
+                WhereClause::RuleExpr(RuleExpr {
+                    name: r.name.clone(),
+                    args: r.args.iter().map(|a| self.fn_arg(a)).collect(),
+                })
+            },
+            &WhereClause::OrJoin(ref o) => {
+                let mut arms = Vec::with_capacity(o.clauses.len());
+                for arm in o.clauses.iter() {
+                    arms.push(match arm {
+                        &OrWhereClause::Clause(ref c) => OrWhereClause::Clause(self.clause(c)?),
+                        &OrWhereClause::And(ref cs) => OrWhereClause::And(self.clauses(cs)?),
+                    });
+                }
+                WhereClause::OrJoin(OrJoin::new(self.unify_vars(&o.unify_vars), arms))
+            },
+            &WhereClause::NotJoin(ref n) => {
+                WhereClause::NotJoin(NotJoin {
+                    unify_vars: self.unify_vars(&n.unify_vars),
+                    clauses: self.clauses(&n.clauses)?,
+                })
+            },
+        })
+    }
+}
+
+/// Application of rules.
+impl ConjoiningClauses {
+    /// Produce the clauses of one definition of a rule, as invoked with `args`.
+    ///
+    /// The rule's own variables are replaced by the arguments. Any other variable in the
+    /// definition is renamed to one that's unique to this expansion, so that it can't unify with
+    /// a variable elsewhere in the query or in another expansion of the same rule.
+    fn instantiate_rule(&self, rule: &Rule, args: &[FnArg]) -> Result<Vec<WhereClause>> {
+        if rule.vars.len() != args.len() {
+            bail!(ErrorKind::InvalidNumberOfArguments(rule.name.clone(), args.len(), rule.vars.len()));
+        }
+
+        let mut mentioned = BTreeSet::new();
+        for clause in rule.clauses.iter() {
+            clause.accumulate_mentioned_variables(&mut mentioned);
+        }
+
+        // Every argument must constrain the body; otherwise the rule can't bind it.
+        for var in rule.vars.iter() {
+            if !mentioned.contains(var) {
+                bail!(ErrorKind::UnusedRuleVariable(rule.name.clone(), var.name()));
+            }
+        }
+
+        let expansion = self.alias_counter.next();
+        let mut replacements: BTreeMap<Variable, FnArg> =
+            mentioned.into_iter()
+                     .map(|var| {
+                         let fresh = fresh_variable(&var, expansion);
+                         (var, FnArg::Variable(fresh))
+                     })
+                     .collect();
+        for (var, arg) in rule.vars.iter().zip(args.iter()) {
+            replacements.insert(var.clone(), arg.clone());
+        }
+
+        let substitution = Substitution {
+            rule: &rule.name,
+            replacements: replacements,
+        };
+        substitution.clauses(&rule.clauses)
+    }
+
+    /// Bind each of `args` to the corresponding column of a new alias of `table`, which holds the
+    /// rows of a rule. Variables are bound to their columns, and constants constrain them.
+    fn bind_rule_columns(&mut self,
+                         schema: &Schema,
+                         table: DatomsTable,
+                         columns: &[Variable],
+                         column_types: &BTreeMap<Variable, ValueTypeSet>,
+                         args: Vec<FnArg>) -> Result<()> {
+        let alias = self.next_alias_for_table(table);
+        for (column, arg) in columns.iter().zip(args.into_iter()) {
+            let types = column_types.get(column).cloned().unwrap_or(ValueTypeSet::any());
+            match arg {
+                FnArg::Variable(var) => {
+                    self.narrow_types_for_var(var.clone(), types);
+
+                    // If the column has more than one type, the table projects a type tag, too.
+                    if self.known_type(&var).is_none() &&
+                       !self.input_variables.contains(&var) &&
+                       !self.extracted_types.contains_key(&var) {
+                        let type_tag = QualifiedAlias::new(alias.clone(), VariableColumn::VariableTypeTag(column.clone()));
+                        self.extracted_types.insert(var.clone(), type_tag);
+                    }
+                    self.bind_column_to_var(schema, alias.clone(), VariableColumn::Variable(column.clone()), var);
+                },
+                arg => {
+                    match self.typed_value_from_arg(schema, column, arg, types)? {
+                        ValueConversion::Val(value) => {
+                            if !types.is_unit() {
+                                let type_tag = QualifiedAlias::new(alias.clone(), VariableColumn::VariableTypeTag(column.clone()));
+                                let tag = value.value_type().value_type_tag();
+                                self.wheres.add_intersection(ColumnConstraint::Equals(type_tag, QueryValue::PrimitiveLong(tag as i64)));
+                            }
+                            self.constrain_column_to_constant(alias.clone(), VariableColumn::Variable(column.clone()), value);
+                        },
+                        ValueConversion::Impossible(because) => {
+                            self.mark_known_empty(because);
+                        },
+                    }
+                },
+            }
+        }
+        self.from.push(SourceAlias(table, alias));
+        Ok(())
+    }
+
+    /// Apply an invocation of a recursive rule.
+    ///
+    /// The rule's rows don't depend on the invocation, so we compute all of them in a recursive
+    /// table, and join against that. For the usual transitive closure:
+    ///
+    /// ```edn
+    /// [[(ancestor ?x ?y) [?x :folder/parent ?y]]
+    ///  [(ancestor ?x ?y) [?x :folder/parent ?z] (ancestor ?z ?y)]]
+    /// ```
+    ///
+    /// the definition that doesn't invoke `ancestor` produces the initial rows, and the one that
+    /// does is applied to the rows found so far, joined against a `DatomsTable::RecursiveRule`,
+    /// until no new rows turn up. Rows are deduplicated as they're found, so this terminates even
+    /// if the data contains cycles.
+    fn apply_recursive_rule_expr(&mut self, schema: &Schema, rule_expr: RuleExpr, definitions: &[Rule]) -> Result<()> {
+        let rules = self.rules.clone();
+        let (base_rules, recursive_rule) = split_recursive_definitions(&rules, &rule_expr.name, definitions)?;
+
+        for rule in definitions.iter() {
+            if rule.vars.len() != rule_expr.args.len() {
+                bail!(ErrorKind::InvalidNumberOfArguments(rule.name.clone(), rule_expr.args.len(), rule.vars.len()));
+            }
+        }
+
+        // Every definition is instantiated with the same variables, which name the table's columns.
+        let expansion = self.alias_counter.next();
+        let columns: Vec<Variable> = recursive_rule.vars
+                                                   .iter()
+                                                   .map(|var| fresh_variable(var, expansion))
+                                                   .collect();
+        let column_set: BTreeSet<Variable> = columns.iter().cloned().collect();
+        let column_args: Vec<FnArg> = columns.iter().cloned().map(FnArg::Variable).collect();
+        let column_types_in = |cc: &ConjoiningClauses| -> BTreeMap<Variable, ValueTypeSet> {
+            cc.known_types
+              .iter()
+              .filter(|&(var, _)| column_set.contains(var))
+              .map(|(var, types)| (var.clone(), *types))
+              .collect()
+        };
+
+        // The arms know nothing about the enclosing query.
+        let template = self.use_as_template(&BTreeSet::new());
+
+        let mut base = Vec::with_capacity(base_rules.len());
+        let mut empty_because: Option<EmptyBecause> = None;
+        for rule in base_rules.into_iter() {
+            let mut arm = template.make_receptacle();
+            arm.apply_clauses(schema, self.instantiate_rule(rule, &column_args)?)?;
+            if arm.is_known_empty() {
+                empty_because = arm.empty_because;
+            } else {
+                arm.expand_column_bindings();
+                arm.prune_extracted_types();
+                arm.process_required_types()?;
+                base.push(arm);
+            }
+        }
+
+        // With no initial rows, the recursive definition has nothing to work with.
+        if base.is_empty() {
+            self.mark_known_empty(empty_because.expect("empty for a reason"));
+            return Ok(());
+        }
+
+        let mut column_types = column_types_in(&base[0]);
+        for arm in base.iter().skip(1) {
+            union_types(&mut column_types, &column_types_in(arm));
+        }
+
+        // Pull the invocation of the rule itself out of the recursive definition. We bind its
+        // arguments to the rows found so far.
+        let mut recursive_args: Vec<FnArg> = vec![];
+        let mut recursive_clauses: Vec<WhereClause> = vec![];
+        for clause in self.instantiate_rule(recursive_rule, &column_args)?.into_iter() {
+            let invokes_self = match &clause {
+                &WhereClause::RuleExpr(ref r) => r.name == rule_expr.name,
+                _ => false,
+            };
+            if invokes_self {
+                if let WhereClause::RuleExpr(r) = clause {
+                    recursive_args = r.args;
+                }
+            } else {
+                recursive_clauses.push(clause);
+            }
+        }
+
+        // The recursive definition can produce values of the types it's given, and of any other
+        // types it introduces. Widen the types of the columns until they settle; there are only so
+        // many types, so this terminates.
+        let recursive = loop {
+            let mut arm = template.make_receptacle();
+            arm.bind_rule_columns(schema, DatomsTable::RecursiveRule, &columns, &column_types, recursive_args.clone())?;
+            arm.apply_clauses(schema, recursive_clauses.clone())?;
+            if arm.is_known_empty() {
+                break None;
+            }
+            arm.expand_column_bindings();
+            arm.prune_extracted_types();
+            arm.process_required_types()?;
+
+            let mut widened = column_types.clone();
+            union_types(&mut widened, &column_types_in(&arm));
+            if widened == column_types {
+                break Some(arm);
+            }
+            column_types = widened;
+        };
+
+        let type_extraction: BTreeSet<Variable> =
+            columns.iter()
+                   .filter(|column| !column_types.get(*column).map_or(false, |types| types.is_unit()))
+                   .cloned()
+                   .collect();
+
+        let computed = match recursive {
+            Some(recursive) => ComputedTable::RecursiveRule {
+                projection: columns.clone(),
+                type_extraction: type_extraction,
+                base: base,
+                recursive: recursive,
+            },
+            // The recursive definition can't match anything, so the others are all we have.
+            None => ComputedTable::Union {
+                projection: column_set.clone(),
+                type_extraction: type_extraction,
+                arms: base,
+            },
+        };
+        let table = self.computed_tables.push_computed(computed);
+        self.bind_rule_columns(schema, table, &columns, &column_types, rule_expr.args)
+    }
+
+    /// Apply a rule invocation by expanding it in place.
+    ///
+    /// A rule with a single definition is simply applied to this CC. A rule with several
+    /// definitions matches if any one of them does, so the definitions become the arms of an
+    /// `or-join` on the invocation's variables. A recursive rule is computed separately; see
+    /// `apply_recursive_rule_expr`.
+    ///
+    /// An invocation with only constant arguments, like `(friends 65536 65537)`, has no variables
+    /// to join on: it only asks whether any definition matches. We ask instead whether it isn't
+    /// the case that every definition fails to match: `(not (not …) (not …))`, with one inner
+    /// `not` per definition.
+    pub fn apply_rule_expr(&mut self, schema: &Schema, rule_expr: RuleExpr) -> Result<()> {
+        let rules = self.rules.clone();
+        let definitions = match rules.get(&rule_expr.name) {
+            Some(definitions) => definitions,
+            None => bail!(ErrorKind::UnknownRule(rule_expr.name.clone())),
+        };
+
+        if is_recursive(&rules, &rule_expr.name) {
+            return self.apply_recursive_rule_expr(schema, rule_expr, definitions);
+        }
+
+        let mut alternatives: Vec<Vec<WhereClause>> = Vec::with_capacity(definitions.len());
+        for rule in definitions.iter() {
+            alternatives.push(self.instantiate_rule(rule, &rule_expr.args)?);
+        }
+
+        if alternatives.len() == 1 {
+            let clauses = alternatives.pop().expect("one alternative");
+            return self.apply_clauses(schema, clauses);
+        }
+
+        let unify_vars: BTreeSet<Variable> = rule_expr.args
+                                                      .iter()
+                                                      .filter_map(|arg| arg.as_variable().cloned())
+                                                      .collect();
+        if unify_vars.is_empty() {
+            let none_match = alternatives.into_iter().map(|clauses| {
+                WhereClause::NotJoin(NotJoin {
+                    unify_vars: UnifyVars::Explicit(BTreeSet::new()),
+                    clauses: clauses,
+                })
+            }).collect();
+            return self.apply_not_join(schema, NotJoin {
+                unify_vars: UnifyVars::Explicit(BTreeSet::new()),
+                clauses: none_match,
+            });
+        }
+
+        let arms = alternatives.into_iter().map(OrWhereClause::And).collect();
+        self.apply_or_join(schema, OrJoin::new(UnifyVars::Explicit(unify_vars), arms))
+    }
+}
@@ -0,0 +1,421 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::rc::Rc;
+
+use mentat_core::{
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    Predicate,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    computed,
+    long,
+};
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    ColumnConstraint,
+    Computation,
+    QueryValue,
+};
+
+fn length(value: QueryValue) -> QueryValue {
+    computed(Computation::Function("length", vec![value]))
+}
+
+/// `left + right`, or `left - right`, computed now if both are constants.
+fn add(left: QueryValue, right: QueryValue, negate_right: bool) -> QueryValue {
+    match (left, right) {
+        (QueryValue::TypedValue(TypedValue::Long(l)), QueryValue::TypedValue(TypedValue::Long(r))) => {
+            long(if negate_right { l - r } else { l + r })
+        },
+        (left, right) => computed(Computation::Infix(if negate_right { "-" } else { "+" }, left, right)),
+    }
+}
+
+/// Wrap a regular expression so that it only matches the whole of a string.
+fn anchor(pattern: QueryValue) -> QueryValue {
+    match pattern {
+        QueryValue::TypedValue(TypedValue::String(ref s)) => {
+            QueryValue::TypedValue(TypedValue::String(Rc::new(format!("^(?:{})$", s))))
+        },
+        pattern => {
+            let prefixed = computed(Computation::Infix("||", QueryValue::TypedValue(TypedValue::typed_string("^(?:")), pattern));
+            computed(Computation::Infix("||", prefixed, QueryValue::TypedValue(TypedValue::typed_string(")$"))))
+        },
+    }
+}
+
+/// Application of string predicates and functions.
+///
+/// These are evaluated by SQLite's built-in string functions, and so share their behavior:
+/// lengths and offsets count characters, and `lower`/`upper` only change the case of ASCII
+/// characters.
+impl ConjoiningClauses {
+    /// Apply one of the string predicates:
+    /// - `[(starts-with? ?s "prefix")]`
+    /// - `[(ends-with? ?s "suffix")]`
+    /// - `[(includes? ?s "infix")]`
+    /// - `[(equals-ignore-case? ?s ?t)]`
+    /// - `[(re-matches? "regex" ?s)]`, which holds when all of `?s` matches the regular
+    ///   expression. This calls the `regexp` function that `mentat_db` registers on each
+    ///   connection.
+    ///
+    /// Both arguments must be strings. They can be variables or constants.
+    pub fn apply_string_predicate<'s>(&mut self, _schema: &'s Schema, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 2));
+        }
+
+        let mut args = predicate.args.into_iter();
+        let left = self.resolve_string_argument(&predicate.operator, 0, args.next().unwrap())?;
+        let right = self.resolve_string_argument(&predicate.operator, 1, args.next().unwrap())?;
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let computation = match predicate.operator.0.as_str() {
+            "starts-with?" => {
+                // substr(s, 1, length(p)) = p
+                let prefix = Computation::Function("substr", vec![left, long(1), length(right.clone())]);
+                Computation::Infix("=", computed(prefix), right)
+            },
+            "ends-with?" => {
+                // substr(s, length(s) - length(p) + 1) = p. If `p` is longer than `s`, the start is
+                // zero or negative, and the substring is never as long as `p`.
+                let start = add(add(length(left.clone()), length(right.clone()), true), long(1), false);
+                let suffix = Computation::Function("substr", vec![left, start]);
+                Computation::Infix("=", computed(suffix), right)
+            },
+            "includes?" => {
+                let position = Computation::Function("instr", vec![left, right]);
+                Computation::Infix(">", computed(position), long(0))
+            },
+            "equals-ignore-case?" => {
+                Computation::Infix("=",
+                                   computed(Computation::Function("lower", vec![left])),
+                                   computed(Computation::Function("lower", vec![right])))
+            },
+            "re-matches?" => {
+                Computation::Function("regexp", vec![anchor(left), right])
+            },
+            _ => bail!(ErrorKind::UnknownFunction(predicate.operator.clone())),
+        };
+
+        self.wheres.add_intersection(ColumnConstraint::Computed(computation));
+        Ok(())
+    }
+
+    /// Apply one of the string functions, binding its result to a scalar:
+    /// - `[(str ?x ?y …) ?s]` concatenates strings, numbers, and keywords, like Clojure's `str`.
+    /// - `[(lower-case ?s) ?lower]` and `[(upper-case ?s) ?upper]`.
+    /// - `[(subs ?s start) ?sub]` and `[(subs ?s start end) ?sub]`. `start` and `end` count from
+    ///   zero, and `end` is exclusive. Unlike Clojure, bounds out of range don't fail: they're
+    ///   handled as by SQLite's `substr`.
+    /// - `[(count ?s) ?n]`, the number of characters in `?s`.
+    pub fn apply_string_function<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
+        let var = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::UnexpectedBinding)),
+        };
+
+        let operator = where_fn.operator;
+        let number = where_fn.args.len();
+        let mut args = where_fn.args.into_iter();
+
+        let (computation, value_type) = match operator.0.as_str() {
+            "str" => {
+                if number == 0 {
+                    bail!(ErrorKind::InvalidNumberOfArguments(operator.clone(), number, 1));
+                }
+                let types = ValueTypeSet::of_one(ValueType::String)
+                                .union(&ValueTypeSet::of_numeric_types())
+                                .union(&ValueTypeSet::of_one(ValueType::Keyword));
+                let mut values = Vec::with_capacity(number);
+                for (position, arg) in args.enumerate() {
+                    values.push(self.resolve_argument_of_types(&operator, position, arg, types, "string, number, or keyword")?);
+                }
+
+                // `||` always produces text; a lone argument must be cast.
+                let mut values = values.into_iter();
+                let first = values.next().unwrap();
+                let computation = match values.next() {
+                    None => Computation::CastToText(first),
+                    Some(second) => values.fold(Computation::Infix("||", first, second),
+                                                |acc, value| Computation::Infix("||", computed(acc), value)),
+                };
+                (computation, ValueType::String)
+            },
+            "lower-case" | "upper-case" => {
+                if number != 1 {
+                    bail!(ErrorKind::InvalidNumberOfArguments(operator.clone(), number, 1));
+                }
+                let s = self.resolve_string_argument(&operator, 0, args.next().unwrap())?;
+                let function = if operator.0.as_str() == "lower-case" { "lower" } else { "upper" };
+                (Computation::Function(function, vec![s]), ValueType::String)
+            },
+            "subs" => {
+                if number != 2 && number != 3 {
+                    bail!(ErrorKind::InvalidNumberOfArguments(operator.clone(), number, 3));
+                }
+                let long_type = ValueTypeSet::of_one(ValueType::Long);
+                let s = self.resolve_string_argument(&operator, 0, args.next().unwrap())?;
+                let start = self.resolve_argument_of_types(&operator, 1, args.next().unwrap(), long_type, "integer")?;
+                let end = match args.next() {
+                    Some(arg) => Some(self.resolve_argument_of_types(&operator, 2, arg, long_type, "integer")?),
+                    None => None,
+                };
+
+                // SQLite's `substr` counts from one, and takes a length rather than an end.
+                let from = add(start.clone(), long(1), false);
+                let computation = match end {
+                    None => Computation::Function("substr", vec![s, from]),
+                    Some(end) => Computation::Function("substr", vec![s, from, add(end, start, true)]),
+                };
+                (computation, ValueType::String)
+            },
+            "count" => {
+                if number != 1 {
+                    bail!(ErrorKind::InvalidNumberOfArguments(operator.clone(), number, 1));
+                }
+                let s = self.resolve_string_argument(&operator, 0, args.next().unwrap())?;
+                (Computation::Function("length", vec![s]), ValueType::Long)
+            },
+            _ => bail!(ErrorKind::UnknownFunction(operator.clone())),
+        };
+
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        self.bind_computation_to_var(schema, computation, value_type, var);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use mentat_core::{
+        Attribute,
+    };
+
+    use mentat_query::{
+        FnArg,
+        NamespacedKeyword,
+        NonIntegerConstant,
+        Pattern,
+        PatternNonValuePlace,
+        PatternValuePlace,
+        PlainSymbol,
+        Variable,
+    };
+
+    use clauses::{
+        add_attribute,
+        associate_ident,
+        bind_value_of,
+    };
+
+    use types::{
+        Column,
+        DatomsColumn,
+        EmptyBecause,
+        QualifiedAlias,
+    };
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "name"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "age"), 100);
+        add_attribute(&mut schema, 100, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        schema
+    }
+
+    fn text(s: &str) -> FnArg {
+        FnArg::Constant(NonIntegerConstant::Text(Arc::new(s.to_string())))
+    }
+
+    #[test]
+    fn test_apply_starts_with() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let name = Variable::from_valid_name("?name");
+        bind_value_of(&mut cc, &schema, "name", &name);
+
+        cc.apply_string_predicate(&schema, Predicate {
+            operator: PlainSymbol::new("starts-with?"),
+            args: vec![FnArg::Variable(name.clone()), text("Al")],
+        }).expect("to apply starts-with?");
+        assert!(!cc.is_known_empty());
+
+        let column = QueryValue::Column(QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value));
+        let prefix = QueryValue::TypedValue(TypedValue::typed_string("Al"));
+        let substr = Computation::Function("substr", vec![column, long(1), length(prefix.clone())]);
+        assert_eq!(cc.wheres.0.last(),
+                   Some(&ColumnConstraint::Computed(Computation::Infix("=", computed(substr), prefix)).into()));
+    }
+
+    #[test]
+    fn test_string_predicate_type_mismatch() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let age = Variable::from_valid_name("?age");
+        bind_value_of(&mut cc, &schema, "age", &age);
+
+        cc.apply_string_predicate(&schema, Predicate {
+            operator: PlainSymbol::new("includes?"),
+            args: vec![FnArg::Variable(age.clone()), text("1")],
+        }).expect("to apply includes?");
+        assert!(cc.is_known_empty());
+        assert_eq!(cc.empty_because, Some(EmptyBecause::TypeMismatch {
+            var: age.clone(),
+            existing: ValueTypeSet::of_one(ValueType::Long),
+            desired: ValueTypeSet::of_one(ValueType::String),
+        }));
+
+        // A constant of the wrong type is an error.
+        let mut cc = ConjoiningClauses::default();
+        let name = Variable::from_valid_name("?name");
+        bind_value_of(&mut cc, &schema, "name", &name);
+        assert!(cc.apply_string_predicate(&schema, Predicate {
+            operator: PlainSymbol::new("ends-with?"),
+            args: vec![FnArg::Variable(name.clone()), FnArg::EntidOrInteger(5)],
+        }).is_err());
+        assert_eq!(cc.empty_because, Some(EmptyBecause::NonStringArgument));
+    }
+
+    #[test]
+    fn test_untyped_value_checks_type_tag() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let v = Variable::from_valid_name("?v");
+        cc.apply_pattern(&schema, Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+            attribute: PatternNonValuePlace::Placeholder,
+            value: PatternValuePlace::Variable(v.clone()),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        });
+
+        cc.apply_string_predicate(&schema, Predicate {
+            operator: PlainSymbol::new("includes?"),
+            args: vec![FnArg::Variable(v.clone()), text("x")],
+        }).expect("to apply includes?");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.known_type(&v), Some(ValueType::String));
+        assert!(cc.wheres.0.contains(&ColumnConstraint::HasTypes {
+            value: "all_datoms00".to_string(),
+            value_types: ValueTypeSet::of_one(ValueType::String),
+            check_value: true,
+        }.into()));
+    }
+
+    #[test]
+    fn test_apply_subs() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let name = Variable::from_valid_name("?name");
+        let initials = Variable::from_valid_name("?initials");
+        bind_value_of(&mut cc, &schema, "name", &name);
+
+        cc.apply_string_function(&schema, WhereFn {
+            operator: PlainSymbol::new("subs"),
+            args: vec![FnArg::Variable(name.clone()), FnArg::EntidOrInteger(0), FnArg::EntidOrInteger(2)],
+            binding: Binding::BindScalar(initials.clone()),
+        }).expect("to apply subs");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.known_type(&initials), Some(ValueType::String));
+
+        // Constant bounds are converted to SQLite's convention here, not by SQLite.
+        let column = QueryValue::Column(QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value));
+        let substr = Computation::Function("substr", vec![column, long(1), long(2)]);
+        assert_eq!(cc.column_bindings.get(&initials),
+                   Some(&vec![QualifiedAlias::new("datoms00".to_string(), Column::Computed(Box::new(substr)))]));
+    }
+
+    #[test]
+    fn test_apply_subs_type_mismatch() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let name = Variable::from_valid_name("?name");
+        bind_value_of(&mut cc, &schema, "name", &name);
+
+        // The bounds must be integers.
+        assert!(cc.apply_string_function(&schema, WhereFn {
+            operator: PlainSymbol::new("subs"),
+            args: vec![FnArg::Variable(name.clone()), text("0")],
+            binding: Binding::BindScalar(Variable::from_valid_name("?initials")),
+        }).is_err());
+        assert_eq!(cc.empty_because, Some(EmptyBecause::NonMatchingArgument(ValueTypeSet::of_one(ValueType::Long))));
+    }
+
+    #[test]
+    fn test_apply_count_and_str() {
+        let schema = prepopulated_schema();
+        let mut cc = ConjoiningClauses::default();
+        let name = Variable::from_valid_name("?name");
+        let age = Variable::from_valid_name("?age");
+        let n = Variable::from_valid_name("?n");
+        let label = Variable::from_valid_name("?label");
+        bind_value_of(&mut cc, &schema, "name", &name);
+        bind_value_of(&mut cc, &schema, "age", &age);
+
+        cc.apply_string_function(&schema, WhereFn {
+            operator: PlainSymbol::new("count"),
+            args: vec![FnArg::Variable(name.clone())],
+            binding: Binding::BindScalar(n.clone()),
+        }).expect("to apply count");
+        assert_eq!(cc.known_type(&n), Some(ValueType::Long));
+
+        cc.apply_string_function(&schema, WhereFn {
+            operator: PlainSymbol::new("str"),
+            args: vec![FnArg::Variable(name.clone()), text(": "), FnArg::Variable(age.clone())],
+            binding: Binding::BindScalar(label.clone()),
+        }).expect("to apply str");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.known_type(&label), Some(ValueType::String));
+
+        // No arguments.
+        assert!(cc.apply_string_function(&schema, WhereFn {
+            operator: PlainSymbol::new("str"),
+            args: vec![],
+            binding: Binding::BindScalar(Variable::from_valid_name("?empty")),
+        }).is_err());
+    }
+}
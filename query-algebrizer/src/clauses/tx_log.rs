@@ -0,0 +1,315 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    Schema,
+    TypedValue,
+    ValueType,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    PlainSymbol,
+    SrcVar,
+    VariableOrPlaceholder,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use errors::{
+    BindingError,
+    Error,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    Column,
+    ColumnConstraint,
+    DatomsTable,
+    Inequality,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+    TransactionsColumn,
+};
+
+/// Querying the transaction log.
+impl ConjoiningClauses {
+    /// Take a function argument that names a transaction -- an entid, or a variable bound to one --
+    /// and turn it into a `QueryValue`.
+    fn resolve_tx_argument(&mut self, function: &PlainSymbol, position: usize, arg: FnArg) -> Result<QueryValue> {
+        match arg {
+            FnArg::Variable(var) => {
+                match self.bound_value(&var) {
+                    Some(TypedValue::Ref(tx)) => Ok(QueryValue::Entid(tx)),
+                    Some(TypedValue::Long(tx)) => Ok(QueryValue::Entid(tx)),
+                    Some(_) => bail!(ErrorKind::InvalidArgument(function.clone(), "transaction", position)),
+                    None => {
+                        self.constrain_var_to_type(var.clone(), ValueType::Ref);
+                        self.column_bindings
+                            .get(&var)
+                            .and_then(|cols| cols.first().map(|col| QueryValue::Column(col.clone())))
+                            .ok_or_else(|| Error::from_kind(ErrorKind::UnboundVariable(var.name())))
+                    },
+                }
+            },
+            FnArg::EntidOrInteger(tx) => Ok(QueryValue::Entid(tx)),
+            _ => bail!(ErrorKind::InvalidArgument(function.clone(), "transaction", position)),
+        }
+    }
+
+    /// Apply `[(tx-ids $ ?since ?until) [?tx ...]]`, which binds `?tx` to each transaction in the
+    /// log that is at least `?since` and before `?until`.
+    pub fn apply_tx_ids<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 3 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 3));
+        }
+
+        let tx_var = match where_fn.binding {
+            Binding::BindColl(var) => var,
+            Binding::BindScalar(_) |
+            Binding::BindTuple(_) |
+            Binding::BindRel(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::ExpectedBindColl)),
+        };
+
+        let mut args = where_fn.args.into_iter();
+
+        // TODO: process source variables.
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "source variable", 0)),
+        }
+
+        let since = self.resolve_tx_argument(&where_fn.operator, 1, args.next().unwrap())?;
+        let until = self.resolve_tx_argument(&where_fn.operator, 2, args.next().unwrap())?;
+
+        // Transactions are refs.
+        self.constrain_var_to_type(tx_var.clone(), ValueType::Ref);
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let transactions = self.next_alias_for_table(DatomsTable::Transactions);
+        self.from.push(SourceAlias(DatomsTable::Transactions, transactions.clone()));
+
+        // Each transaction appears once per datom it touched; the query's `DISTINCT` takes care of
+        // that.
+        let tx = QualifiedAlias::new(transactions.clone(), TransactionsColumn::Tx);
+        self.wheres.add_intersection(ColumnConstraint::Inequality {
+            operator: Inequality::LessThanOrEquals,
+            left: since,
+            right: QueryValue::Column(tx.clone()),
+        });
+        self.wheres.add_intersection(ColumnConstraint::Inequality {
+            operator: Inequality::LessThan,
+            left: QueryValue::Column(tx),
+            right: until,
+        });
+
+        self.bind_column_to_var(schema, transactions, TransactionsColumn::Tx, tx_var);
+        Ok(())
+    }
+
+    /// Apply `[(tx-data $ ?tx) [[?e ?a ?v ?tx ?added]]]`, which binds each assertion or retraction
+    /// made by the transaction `?tx`. `?added` is `true` for an assertion.
+    ///
+    /// The log stores the values of fulltext attributes out of line, so if `?v` is bound we read
+    /// the log through `all_transactions`, which puts the text back.
+    pub fn apply_tx_data<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 2));
+        }
+
+        if where_fn.binding.is_empty() {
+            // The binding must introduce at least one bound variable.
+            bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::NoBoundVariable));
+        }
+
+        if !where_fn.binding.is_valid() {
+            // The binding must not duplicate bound variables.
+            bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::RepeatedBoundVariable));
+        }
+
+        let bindings = match where_fn.binding {
+            Binding::BindRel(bindings) => {
+                let bindings_count = bindings.len();
+                if bindings_count < 1 || bindings_count > 5 {
+                    bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(),
+                                                    BindingError::InvalidNumberOfBindings {
+                                                        number: bindings.len(),
+                                                        expected: 5,
+                                                    }));
+                }
+                bindings
+            },
+            Binding::BindScalar(_) |
+            Binding::BindTuple(_) |
+            Binding::BindColl(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::ExpectedBindRel)),
+        };
+        let mut bindings = bindings.into_iter();
+        let b_e = bindings.next().unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_a = bindings.next().unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_v = bindings.next().unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_tx = bindings.next().unwrap_or(VariableOrPlaceholder::Placeholder);
+        let b_added = bindings.next().unwrap_or(VariableOrPlaceholder::Placeholder);
+
+        let mut args = where_fn.args.into_iter();
+
+        // TODO: process source variables.
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "source variable", 0)),
+        }
+
+        let tx = self.resolve_tx_argument(&where_fn.operator, 1, args.next().unwrap())?;
+
+        let table = match b_v {
+            VariableOrPlaceholder::Variable(_) => DatomsTable::AllTransactions,
+            VariableOrPlaceholder::Placeholder => DatomsTable::Transactions,
+        };
+        let transactions = self.next_alias_for_table(table);
+        self.from.push(SourceAlias(table, transactions.clone()));
+
+        self.wheres.add_intersection(ColumnConstraint::Equals(
+            QualifiedAlias::new(transactions.clone(), TransactionsColumn::Tx),
+            tx));
+
+        // Entities, attributes, and transactions are refs, and `added` is a boolean. The value
+        // can be of any type; we'll extract it from the log's type tag.
+        let places = vec![
+            (b_e, TransactionsColumn::Entity, Some(ValueType::Ref)),
+            (b_a, TransactionsColumn::Attribute, Some(ValueType::Ref)),
+            (b_v, TransactionsColumn::Value, None),
+            (b_tx, TransactionsColumn::Tx, Some(ValueType::Ref)),
+            (b_added, TransactionsColumn::Added, Some(ValueType::Boolean)),
+        ];
+
+        for (binding, column, value_type) in places {
+            if let VariableOrPlaceholder::Variable(var) = binding {
+                if let Some(value_type) = value_type {
+                    self.constrain_var_to_type(var.clone(), value_type);
+                    if self.is_known_empty() {
+                        return Ok(());
+                    }
+                }
+
+                self.bind_column_to_var(schema, transactions.clone(), Column::Transactions(column), var);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use mentat_query::{
+        Variable,
+    };
+
+    use types::{
+        ColumnIntersection,
+    };
+
+    #[test]
+    fn test_apply_tx_ids() {
+        let mut cc = ConjoiningClauses::default();
+        let schema = Schema::default();
+
+        let tx = Variable::from_valid_name("?tx");
+        cc.apply_tx_ids(&schema, WhereFn {
+            operator: PlainSymbol::new("tx-ids"),
+            args: vec![
+                FnArg::SrcVar(SrcVar::DefaultSrc),
+                FnArg::EntidOrInteger(1000),
+                FnArg::EntidOrInteger(2000),
+            ],
+            binding: Binding::BindColl(tx.clone()),
+        }).expect("to be able to apply_tx_ids");
+
+        assert!(!cc.is_known_empty());
+
+        let transactions00 = "transactions00".to_string();
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Transactions, transactions00.clone())]);
+
+        let column = QualifiedAlias::new(transactions00.clone(), TransactionsColumn::Tx);
+        assert_eq!(cc.wheres, ColumnIntersection::from(vec![
+            ColumnConstraint::Inequality {
+                operator: Inequality::LessThanOrEquals,
+                left: QueryValue::Entid(1000),
+                right: QueryValue::Column(column.clone()),
+            },
+            ColumnConstraint::Inequality {
+                operator: Inequality::LessThan,
+                left: QueryValue::Column(column.clone()),
+                right: QueryValue::Entid(2000),
+            },
+        ]));
+
+        assert_eq!(cc.column_bindings.get(&tx), Some(&vec![column]));
+        assert_eq!(cc.known_type(&tx), Some(ValueType::Ref));
+    }
+
+    #[test]
+    fn test_apply_tx_data() {
+        let mut cc = ConjoiningClauses::default();
+        let schema = Schema::default();
+
+        let e = Variable::from_valid_name("?e");
+        let v = Variable::from_valid_name("?v");
+        let added = Variable::from_valid_name("?added");
+        cc.apply_tx_data(&schema, WhereFn {
+            operator: PlainSymbol::new("tx-data"),
+            args: vec![
+                FnArg::SrcVar(SrcVar::DefaultSrc),
+                FnArg::EntidOrInteger(1000),
+            ],
+            binding: Binding::BindRel(vec![
+                VariableOrPlaceholder::Variable(e.clone()),
+                VariableOrPlaceholder::Placeholder,
+                VariableOrPlaceholder::Variable(v.clone()),
+                VariableOrPlaceholder::Placeholder,
+                VariableOrPlaceholder::Variable(added.clone()),
+            ]),
+        }).expect("to be able to apply_tx_data");
+
+        assert!(!cc.is_known_empty());
+
+        // The value is bound, so we need the text of any fulltext values.
+        let transactions00 = "all_transactions00".to_string();
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::AllTransactions, transactions00.clone())]);
+        assert_eq!(cc.wheres, ColumnIntersection::from(vec![
+            ColumnConstraint::Equals(QualifiedAlias::new(transactions00.clone(), TransactionsColumn::Tx),
+                                     QueryValue::Entid(1000)),
+        ]));
+
+        assert_eq!(cc.column_bindings.get(&e),
+                   Some(&vec![QualifiedAlias::new(transactions00.clone(), TransactionsColumn::Entity)]));
+        assert_eq!(cc.column_bindings.get(&v),
+                   Some(&vec![QualifiedAlias::new(transactions00.clone(), TransactionsColumn::Value)]));
+        assert_eq!(cc.column_bindings.get(&added),
+                   Some(&vec![QualifiedAlias::new(transactions00.clone(), TransactionsColumn::Added)]));
+
+        assert_eq!(cc.known_type(&e), Some(ValueType::Ref));
+        assert_eq!(cc.known_type(&v), None);
+        assert_eq!(cc.known_type(&added), Some(ValueType::Boolean));
+
+        // The value's type comes from the log.
+        assert_eq!(cc.extracted_types.get(&v),
+                   Some(&QualifiedAlias::new(transactions00.clone(), TransactionsColumn::ValueTypeTag)));
+    }
+}
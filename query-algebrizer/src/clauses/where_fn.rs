@@ -30,7 +30,11 @@ impl ConjoiningClauses {
     /// There are several kinds of functions binding variables in our Datalog:
     /// - A set of functions like `ground`, fulltext` and `get-else` that are translated into SQL
     ///   `VALUES`, `MATCH`, or `JOIN`, yielding bindings.
-    /// - In the future, some functions that are implemented via function calls in SQLite.
+    /// - `tx-ids` and `tx-data`, which query the transaction log rather than the datoms.
+    /// - String functions like `str` and `subs`, which are implemented via function calls in
+    ///   SQLite.
+    /// - Arithmetic over numbers and instants, which becomes a SQL expression.
+    /// - Date functions like `truncate-instant` and `instant-year`, which use SQLite's `strftime`.
     ///
     /// At present we have implemented only a limited selection of functions.
     pub fn apply_where_fn<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
@@ -39,6 +43,26 @@ impl ConjoiningClauses {
         match where_fn.operator.0.as_str() {
             "fulltext" => self.apply_fulltext(schema, where_fn),
             "ground" => self.apply_ground(schema, where_fn),
+            "get-else" => self.apply_get_else(schema, where_fn),
+            "tx-ids" => self.apply_tx_ids(schema, where_fn),
+            "tx-data" => self.apply_tx_data(schema, where_fn),
+            "str" |
+            "lower-case" |
+            "upper-case" |
+            "subs" |
+            "count" => self.apply_string_function(schema, where_fn),
+            "+" |
+            "-" |
+            "*" |
+            "/" => self.apply_arithmetic(schema, where_fn),
+            "truncate-instant" |
+            "instant-year" |
+            "instant-month" |
+            "instant-day" |
+            "instant-hour" |
+            "instant-minute" |
+            "instant->long" |
+            "long->instant" => self.apply_instant_function(schema, where_fn),
             _ => bail!(ErrorKind::UnknownFunction(where_fn.operator.clone())),
         }
     }
@@ -12,12 +12,18 @@ extern crate mentat_query;
 
 use mentat_core::{
     ValueType,
+    ValueTypeSet,
 };
 
 use self::mentat_query::{
+    NamespacedKeyword,
     PlainSymbol,
 };
 
+use types::{
+    SimpleAggregationOp,
+};
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BindingError {
     NoBoundVariable,
@@ -28,6 +34,9 @@ pub enum BindingError {
     /// than Datomic: we won't try to make sense of non-obvious (and potentially erroneous) bindings.
     ExpectedBindRel,
 
+    /// Expected `[?x ...]` but got some other type of binding.
+    ExpectedBindColl,
+
     /// Expected `[?x1 … ?xN]` or `[[?x1 … ?xN]]` but got some other number of bindings.  Mentat is
     /// deliberately more strict than Datomic: we prefer placeholders to omission.
     InvalidNumberOfBindings { number: usize, expected: usize },
@@ -85,11 +94,31 @@ error_chain! {
             display("invalid argument to {}: expected {} in position {}.", function, expected_type, position)
         }
 
+        InvalidDefault(function: PlainSymbol, expected: ValueType) {
+            description("invalid default value")
+            display("invalid default value for {}: expected a value of type {}.", function, expected)
+        }
+
         InvalidLimit(val: String, kind: ValueType) {
             description("invalid limit")
             display("invalid limit {} of type {}: expected natural number.", val, kind)
         }
 
+        InvalidOffset(val: String, kind: ValueType) {
+            description("invalid offset")
+            display("invalid offset {} of type {}: expected non-negative integer.", val, kind)
+        }
+
+        UnorderedCursor {
+            description("cannot seek past a row of a query without :order")
+            display("cannot seek past a row of a query without :order")
+        }
+
+        MissingCursorValue(name: PlainSymbol) {
+            description("cursor has no value for an ordering variable")
+            display("cursor has no value for ordering variable {}", name)
+        }
+
         NonMatchingVariablesInOrClause {
             // TODO: flesh out.
             description("non-matching variables in 'or' clause")
@@ -101,6 +130,61 @@ error_chain! {
             description("non-matching variables in 'not' clause")
             display("non-matching variables in 'not' clause")
         }
+
+        CannotApplyAggregateOperationToTypes(op: SimpleAggregationOp, types: ValueTypeSet) {
+            description("cannot apply aggregate operation to types")
+            display("cannot apply {:?} to types {:?}", op, types)
+        }
+
+        UngroupedOrderVariable(name: PlainSymbol) {
+            description("cannot order an aggregate query by a variable that isn't in :find")
+            display("cannot order by {}: aggregate queries can only be ordered by variables in :find", name)
+        }
+
+        UnknownPullAttribute(attribute: NamespacedKeyword) {
+            description("unknown attribute in pull expression")
+            display("unknown attribute in pull expression: {}", attribute)
+        }
+
+        NonRefPullAttribute(attribute: NamespacedKeyword) {
+            description("only ref attributes can be reversed or nested in a pull expression")
+            display("cannot reverse or nest non-ref attribute {} in pull expression", attribute)
+        }
+
+        CannotPullNonEntity(name: PlainSymbol) {
+            description("pulled variable cannot be an entity")
+            display("cannot pull {}: it is never bound to an entity", name)
+        }
+
+        UnknownRule(name: PlainSymbol) {
+            description("no such rule")
+            display("no rule named {}", name)
+        }
+
+        IndirectlyRecursiveRule(name: PlainSymbol) {
+            description("rule is recursive through another rule, an 'or', or a 'not'")
+            display("rule {} is recursive through another rule, an 'or', or a 'not': a recursive rule must invoke itself directly", name)
+        }
+
+        NonLinearRecursiveRule(name: PlainSymbol) {
+            description("recursive rule invokes itself more than once")
+            display("recursive rule {} must have exactly one definition that invokes it, and that definition must invoke it once", name)
+        }
+
+        UngroundedRecursiveRule(name: PlainSymbol) {
+            description("recursive rule has no definition that doesn't invoke itself")
+            display("recursive rule {} needs a definition that doesn't invoke it", name)
+        }
+
+        UnusedRuleVariable(rule: PlainSymbol, var: PlainSymbol) {
+            description("rule doesn't use one of its variables")
+            display("rule {} doesn't use its variable {}", rule, var)
+        }
+
+        InvalidRuleArgument(rule: PlainSymbol, var: PlainSymbol) {
+            description("invalid argument to rule")
+            display("invalid argument to rule {}: {} can't be bound to that value", rule, var)
+        }
     }
 }
 
@@ -18,9 +18,12 @@ extern crate maplit;
 extern crate mentat_core;
 extern crate mentat_query;
 
-use std::collections::BTreeSet;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 use std::ops::Sub;
-use std::rc::Rc;
+use std::sync::Arc;
 
 mod errors;
 mod types;
@@ -28,6 +31,7 @@ mod validate;
 mod clauses;
 
 use mentat_core::{
+    HasSchema,
     Schema,
     TypedValue,
     ValueType,
@@ -36,10 +40,16 @@ use mentat_core::{
 use mentat_core::counter::RcCounter;
 
 use mentat_query::{
+    Aggregate,
+    Direction,
+    Element,
     FindQuery,
     FindSpec,
+    FnArg,
     Limit,
+    Offset,
     Order,
+    PullAttributeSpec,
     SrcVar,
     Variable,
 };
@@ -59,14 +69,19 @@ pub use types::{
     EmptyBecause,
 };
 
+use types::{
+    Inequality,
+};
+
 #[derive(Debug)]
 pub struct AlgebraicQuery {
     default_source: SrcVar,
-    pub find_spec: Rc<FindSpec>,
-    has_aggregates: bool,
+    pub find_spec: Arc<FindSpec>,
+    pub has_aggregates: bool,
     pub with: BTreeSet<Variable>,
     pub order: Option<Vec<OrderBy>>,
     pub limit: Limit,
+    pub offset: Offset,
     pub cc: clauses::ConjoiningClauses,
 }
 
@@ -81,6 +96,66 @@ impl AlgebraicQuery {
     pub fn unbound_variables(&self) -> BTreeSet<Variable> {
         self.cc.input_variables.sub(&self.cc.value_bound_variable_set())
     }
+
+    /// Restrict the query to the results that its `:order` places after a row in which each
+    /// ordered variable has the value given in `after`. Used for keyset pagination: unlike an
+    /// offset, this gives stable pages even as the store changes, so long as the ordering is total.
+    ///
+    /// For `:order ?a (desc ?b)` this adds `?a > a OR (?a = a AND ?b < b)`.
+    pub fn seek_after(&mut self, after: &BTreeMap<Variable, TypedValue>) -> Result<()> {
+        if self.is_known_empty() {
+            return Ok(());
+        }
+
+        let order = match self.order {
+            Some(ref order) => order,
+            None => bail!(ErrorKind::UnorderedCursor),
+        };
+
+        // Each ordering column, alongside the value it had in the row we're seeking past.
+        let mut keys: Vec<(Inequality, QualifiedAlias, TypedValue)> = Vec::with_capacity(order.len());
+        for &OrderBy(ref direction, ref column) in order.iter() {
+            let inequality = match direction {
+                &Direction::Ascending => Inequality::GreaterThan,
+                &Direction::Descending => Inequality::LessThan,
+            };
+            let (var, key) = match column {
+                &VariableColumn::Variable(ref var) => {
+                    let alias = self.cc.column_bindings.get(var).and_then(|aliases| aliases.first());
+                    (var, alias.cloned())
+                },
+                &VariableColumn::VariableTypeTag(ref var) => {
+                    (var, self.cc.extracted_types.get(var).cloned())
+                },
+            };
+            let value = after.get(var)
+                             .ok_or_else(|| Error::from_kind(ErrorKind::MissingCursorValue(var.name())))?;
+            let value = match column {
+                &VariableColumn::Variable(_) => value.clone(),
+                &VariableColumn::VariableTypeTag(_) => TypedValue::Long(value.value_type().value_type_tag() as i64),
+            };
+            let alias = key.ok_or_else(|| Error::from_kind(ErrorKind::UnboundVariable(var.name())))?;
+            keys.push((inequality, alias, value));
+        }
+
+        // Later orderings only matter when all of the earlier columns are equal.
+        let mut seek = ColumnAlternation::default();
+        for i in 0..keys.len() {
+            let mut intersection = ColumnIntersection::default();
+            for &(_, ref alias, ref value) in keys[0..i].iter() {
+                intersection.add_intersection(ColumnConstraint::Equals(alias.clone(), QueryValue::TypedValue(value.clone())));
+            }
+            let (inequality, ref alias, ref value) = keys[i];
+            intersection.add_intersection(ColumnConstraint::Inequality {
+                operator: inequality,
+                left: QueryValue::Column(alias.clone()),
+                right: QueryValue::TypedValue(value.clone()),
+            });
+            seek.add_alternate(intersection);
+        }
+        self.cc.wheres.add(ColumnConstraintOrAlternation::Alternation(seek));
+        Ok(())
+    }
 }
 
 pub fn algebrize_with_counter(schema: &Schema, parsed: FindQuery, counter: usize) -> Result<AlgebraicQuery> {
@@ -127,6 +202,105 @@ fn validate_and_simplify_order(cc: &ConjoiningClauses, order: Option<Vec<Order>>
     }
 }
 
+/// Decompose an aggregate like `(max ?x)` into the operation and the variable it aggregates.
+/// Fails if the function isn't a supported aggregate, or if it isn't applied to a single variable.
+pub fn simple_aggregate(aggregate: &Aggregate) -> Result<(SimpleAggregationOp, Variable)> {
+    let name = &aggregate.func.0;
+    let op = SimpleAggregationOp::from_datalog_function(name.0.as_str())
+        .ok_or_else(|| Error::from_kind(ErrorKind::UnknownFunction(name.clone())))?;
+
+    if aggregate.args.len() != 1 {
+        bail!(ErrorKind::InvalidNumberOfArguments(name.clone(), aggregate.args.len(), 1));
+    }
+
+    match aggregate.args[0] {
+        FnArg::Variable(ref var) => Ok((op, var.clone())),
+        _ => bail!(ErrorKind::InvalidArgument(name.clone(), "variable", 0)),
+    }
+}
+
+/// Check each aggregate in the find spec: it must be a supported aggregate over a variable bound
+/// by the query, and it must make sense for the types that variable can take.
+/// Returns true if the find spec contains any aggregates at all.
+fn validate_aggregates(cc: &ConjoiningClauses, find_spec: &FindSpec) -> Result<bool> {
+    let mut has_aggregates = false;
+    for elem in find_spec.columns() {
+        if let &Element::Aggregate(ref aggregate) = elem {
+            has_aggregates = true;
+            let (op, var) = simple_aggregate(aggregate)?;
+
+            // If the query is known to be empty we won't run any SQL, and the types of the
+            // variable are meaningless.
+            if cc.is_known_empty() {
+                continue;
+            }
+
+            if cc.bound_value(&var).is_none() && !cc.column_bindings.contains_key(&var) {
+                bail!(ErrorKind::UnboundVariable(var.name()));
+            }
+
+            let types = cc.known_type_set(&var);
+            if op.result_type(types).is_none() {
+                bail!(ErrorKind::CannotApplyAggregateOperationToTypes(op, types));
+            }
+        }
+    }
+    Ok(has_aggregates)
+}
+
+/// Check a pull pattern against the schema: every attribute must exist, and only ref attributes
+/// can be reversed or have a nested pattern.
+fn validate_pull_patterns(schema: &Schema, patterns: &[PullAttributeSpec]) -> Result<()> {
+    for pattern in patterns {
+        let (attribute, nested) = match pattern {
+            &PullAttributeSpec::Wildcard => continue,
+            &PullAttributeSpec::Attribute(ref attribute) => (attribute, None),
+            &PullAttributeSpec::Nested(ref attribute, ref nested) => (attribute, Some(nested)),
+        };
+
+        // `:db/id` isn't an attribute, but it's always available to pull.
+        if attribute.namespace == "db" && attribute.name == "id" && nested.is_none() {
+            continue;
+        }
+
+        let forward = attribute.unreversed().unwrap_or_else(|| attribute.clone());
+        let a = schema.attribute_for_ident(&forward)
+                      .map(|(a, _)| a)
+                      .ok_or_else(|| Error::from_kind(ErrorKind::UnknownPullAttribute(attribute.clone())))?;
+
+        if (attribute.is_backward() || nested.is_some()) && a.value_type != ValueType::Ref {
+            bail!(ErrorKind::NonRefPullAttribute(attribute.clone()));
+        }
+
+        if let Some(nested) = nested {
+            validate_pull_patterns(schema, nested)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check each pull expression in the find spec: it must pull a variable bound by the query to
+/// an entity, using attributes the schema knows about.
+fn validate_pulls(schema: &Schema, cc: &ConjoiningClauses, find_spec: &FindSpec) -> Result<()> {
+    for elem in find_spec.columns() {
+        if let &Element::Pull(ref pull) = elem {
+            validate_pull_patterns(schema, &pull.patterns)?;
+
+            if cc.is_known_empty() {
+                continue;
+            }
+
+            if cc.bound_value(&pull.var).is_none() && !cc.column_bindings.contains_key(&pull.var) {
+                bail!(ErrorKind::UnboundVariable(pull.var.name()));
+            }
+
+            if !cc.known_type_set(&pull.var).contains(ValueType::Ref) {
+                bail!(ErrorKind::CannotPullNonEntity(pull.var.name()));
+            }
+        }
+    }
+    Ok(())
+}
 
 fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
     // Unpack any limit variables in place.
@@ -163,20 +337,58 @@ fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
     if let Some(lim) = refined_limit {
         query.limit = lim;
     }
+
+    // Offsets can be zero, but not negative.
+    let refined_offset =
+        match query.offset {
+            Offset::Variable(ref v) => {
+                match query.cc.bound_value(v) {
+                    Some(TypedValue::Long(n)) => {
+                        if n < 0 {
+                            bail!(ErrorKind::InvalidOffset(n.to_string(), ValueType::Long));
+                        } else {
+                            Some(Offset::Fixed(n as u64))
+                        }
+                    },
+                    Some(val) => {
+                        bail!(ErrorKind::InvalidOffset(format!("{:?}", val), val.value_type()));
+                    },
+                    None => None,
+                }
+            },
+            Offset::None => None,
+            Offset::Fixed(_) => None,
+        };
+
+    if let Some(off) = refined_offset {
+        query.offset = off;
+    }
     Ok(query)
 }
 
 pub fn algebrize_with_inputs(schema: &Schema,
                              parsed: FindQuery,
                              counter: usize,
-                             inputs: QueryInputs) -> Result<AlgebraicQuery> {
+                             mut inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    // Rules are only available to queries that ask for them with `%`.
+    if !parsed.in_rules {
+        inputs.rules.clear();
+    }
+
+    // Lookup refs bound in `:in` need the schema to resolve, so we bind them separately.
+    let lookup_ref_values = ::std::mem::replace(&mut inputs.lookup_ref_values, BTreeMap::new());
+
     let alias_counter = RcCounter::with_initial(counter);
     let mut cc = ConjoiningClauses::with_inputs_and_alias_counter(parsed.in_vars, inputs, alias_counter);
+    cc.bind_lookup_ref_inputs(schema, lookup_ref_values);
 
     // Do we have a variable limit? If so, tell the CC that the var must be numeric.
     if let &Limit::Variable(ref var) = &parsed.limit {
         cc.constrain_var_to_long(var.clone());
     }
+    if let &Offset::Variable(ref var) = &parsed.offset {
+        cc.constrain_var_to_long(var.clone());
+    }
 
     // TODO: integrate default source into pattern processing.
     // TODO: flesh out the rest of find-into-context.
@@ -186,18 +398,34 @@ pub fn algebrize_with_inputs(schema: &Schema,
     cc.prune_extracted_types();
     cc.process_required_types()?;
 
+    let has_aggregates = validate_aggregates(&cc, &parsed.find_spec)?;
+    validate_pulls(schema, &cc, &parsed.find_spec)?;
+
     let (order, extra_vars) = validate_and_simplify_order(&cc, parsed.order)?;
+
+    // Ordering variables are added to `:with`, which would change the set of values being
+    // aggregated. Only allow ordering by the variables we group by.
+    if has_aggregates {
+        for var in extra_vars.iter() {
+            let grouped = parsed.find_spec.columns().any(|e| e == &Element::Variable(var.clone()));
+            if !grouped {
+                bail!(ErrorKind::UngroupedOrderVariable(var.name()));
+            }
+        }
+    }
+
     let with: BTreeSet<Variable> = parsed.with.into_iter().chain(extra_vars.into_iter()).collect();
 
     // This might leave us with an unused `:in` variable.
     let limit = if parsed.find_spec.is_unit_limited() { Limit::Fixed(1) } else { parsed.limit };
     let q = AlgebraicQuery {
         default_source: parsed.default_source,
-        find_spec: Rc::new(parsed.find_spec),
-        has_aggregates: false,           // TODO: we don't parse them yet.
+        find_spec: Arc::new(parsed.find_spec),
+        has_aggregates: has_aggregates,
         with: with,
         order: order,
         limit: limit,
+        offset: parsed.offset,
         cc: cc,
     };
 
@@ -207,6 +435,7 @@ pub fn algebrize_with_inputs(schema: &Schema,
 
 pub use clauses::{
     ConjoiningClauses,
+    lookup_ref_av,
 };
 
 pub use types::{
@@ -216,15 +445,19 @@ pub use types::{
     ColumnConstraintOrAlternation,
     ColumnIntersection,
     ColumnName,
+    Computation,
     ComputedTable,
     DatomsColumn,
     DatomsTable,
     FulltextColumn,
+    LeftJoin,
     OrderBy,
     QualifiedAlias,
     QueryValue,
+    SimpleAggregationOp,
     SourceAlias,
     TableAlias,
+    TransactionsColumn,
     VariableColumn,
 };
 
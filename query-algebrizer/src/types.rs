@@ -24,6 +24,7 @@ use mentat_core::{
 
 use mentat_query::{
     Direction,
+    LookupRef,
     NamespacedKeyword,
     Order,
     Variable,
@@ -37,7 +38,10 @@ pub enum DatomsTable {
     FulltextValues,     // The virtual table mapping IDs to strings.
     FulltextDatoms,     // The fulltext-datoms view.
     AllDatoms,          // Fulltext and non-fulltext datoms.
+    Transactions,       // The transaction log: every assertion and retraction.
+    AllTransactions,    // The transaction log, with fulltext values in place of their rowids.
     Computed(usize),    // A computed table, tracked elsewhere in the query.
+    RecursiveRule,      // The rows found so far by a recursive rule, within its own definition.
 }
 
 /// A source of rows that isn't a named table -- typically a subquery or union.
@@ -53,6 +57,15 @@ pub enum ComputedTable {
         names: Vec<Variable>,
         values: Vec<TypedValue>,
     },
+    /// The rows of a recursive rule. Each of the `base` arms produces rows directly; the
+    /// `recursive` arm joins against `DatomsTable::RecursiveRule` to produce more rows from those
+    /// already found, until there are no new ones.
+    RecursiveRule {
+        projection: Vec<Variable>,
+        type_extraction: BTreeSet<Variable>,
+        base: Vec<::clauses::ConjoiningClauses>,
+        recursive: ::clauses::ConjoiningClauses,
+    },
 }
 
 impl DatomsTable {
@@ -62,7 +75,10 @@ impl DatomsTable {
             DatomsTable::FulltextValues => "fulltext_values",
             DatomsTable::FulltextDatoms => "fulltext_datoms",
             DatomsTable::AllDatoms => "all_datoms",
+            DatomsTable::Transactions => "transactions",
+            DatomsTable::AllTransactions => "all_transactions",
             DatomsTable::Computed(_) => "c",
+            DatomsTable::RecursiveRule => "recursive_rule",
         }
     }
 }
@@ -88,6 +104,17 @@ pub enum FulltextColumn {
     Text,
 }
 
+/// One of the named columns of the transaction log.
+#[derive(PartialEq, Eq, Clone)]
+pub enum TransactionsColumn {
+    Entity,
+    Attribute,
+    Value,
+    Tx,
+    Added,
+    ValueTypeTag,
+}
+
 #[derive(PartialEq, Eq, Clone)]
 pub enum VariableColumn {
     Variable(Variable),
@@ -99,6 +126,15 @@ pub enum Column {
     Fixed(DatomsColumn),
     Fulltext(FulltextColumn),
     Variable(VariableColumn),
+    Transactions(TransactionsColumn),
+
+    /// A column of a `LEFT JOIN`ed table, which takes the given value when the join found no
+    /// row. E.g., `COALESCE(datoms01.v, 0)`.
+    WithDefault(DatomsColumn, TypedValue),
+
+    /// A value computed from other columns, like `lower(datoms01.v)`. The table alias that
+    /// qualifies such a column is that of the first column it reads, if any; it's otherwise unused.
+    Computed(Box<Computation>),
 }
 
 impl From<DatomsColumn> for Column {
@@ -113,6 +149,12 @@ impl From<VariableColumn> for Column {
     }
 }
 
+impl From<TransactionsColumn> for Column {
+    fn from(from: TransactionsColumn) -> Column {
+        Column::Transactions(from)
+    }
+}
+
 impl DatomsColumn {
     pub fn as_str(&self) -> &'static str {
         use self::DatomsColumn::*;
@@ -163,6 +205,9 @@ impl Debug for Column {
             &Column::Fixed(ref c) => c.fmt(f),
             &Column::Fulltext(ref c) => c.fmt(f),
             &Column::Variable(ref v) => v.fmt(f),
+            &Column::Transactions(ref c) => c.fmt(f),
+            &Column::WithDefault(ref c, ref v) => write!(f, "{:?}/default({:?})", c, v),
+            &Column::Computed(ref c) => c.fmt(f),
         }
     }
 }
@@ -189,6 +234,32 @@ impl Debug for FulltextColumn {
     }
 }
 
+impl TransactionsColumn {
+    pub fn as_str(&self) -> &'static str {
+        use self::TransactionsColumn::*;
+        match *self {
+            Entity => "e",
+            Attribute => "a",
+            Value => "v",
+            Tx => "tx",
+            Added => "added",
+            ValueTypeTag => "value_type_tag",
+        }
+    }
+}
+
+impl ColumnName for TransactionsColumn {
+    fn column_name(&self) -> String {
+        self.as_str().to_string()
+    }
+}
+
+impl Debug for TransactionsColumn {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 /// A specific instance of a table within a query. E.g., "datoms123".
 pub type TableAlias = String;
 
@@ -202,6 +273,14 @@ impl Debug for SourceAlias {
     }
 }
 
+/// A table that's joined to the rest of the query with `LEFT JOIN … ON`. Every row of the rest of
+/// the query is kept, whether or not `constraints` find a matching row in `table`.
+#[derive(PartialEq, Eq, Debug)]
+pub struct LeftJoin {
+    pub table: SourceAlias,
+    pub constraints: ColumnIntersection,
+}
+
 /// A particular column of a particular aliased table. E.g., "datoms123", Attribute.
 #[derive(PartialEq, Eq, Clone)]
 pub struct QualifiedAlias(pub TableAlias, pub Column);
@@ -218,8 +297,12 @@ impl QualifiedAlias {
     }
 
     pub fn for_type_tag(&self) -> QualifiedAlias {
-        // TODO: this only makes sense for `DatomsColumn` tables.
-        QualifiedAlias(self.0.clone(), Column::Fixed(DatomsColumn::ValueTypeTag))
+        // TODO: this only makes sense for `DatomsColumn` and `TransactionsColumn` tables.
+        let column = match self.1 {
+            Column::Transactions(_) => Column::Transactions(TransactionsColumn::ValueTypeTag),
+            _ => Column::Fixed(DatomsColumn::ValueTypeTag),
+        };
+        QualifiedAlias(self.0.clone(), column)
     }
 }
 
@@ -234,6 +317,13 @@ pub enum QueryValue {
     // cannot be a boolean, so `datoms00.value_type_tag` must be in the set `#{0, 4, 5}`.
     // Note that `5 = 5.0` in SQLite, and we preserve that here.
     PrimitiveLong(i64),
+
+    // An input variable whose type is known but whose value will only be supplied when the
+    // query is run. It becomes a SQL bind parameter.
+    InputVariable(Variable),
+
+    // An expression evaluated by SQLite, like `length(datoms00.v)`.
+    Computed(Box<Computation>),
 }
 
 impl Debug for QueryValue {
@@ -252,7 +342,71 @@ impl Debug for QueryValue {
             &PrimitiveLong(value) => {
                 write!(f, "primitive({:?})", value)
             },
+            &InputVariable(ref var) => {
+                write!(f, "input({:?})", var)
+            },
+            &Computed(ref computation) => {
+                write!(f, "{:?}", computation)
+            },
+        }
+    }
+}
 
+impl QueryValue {
+    /// Return the first column read by this value, if any.
+    pub fn first_column(&self) -> Option<&QualifiedAlias> {
+        match self {
+            &QueryValue::Column(ref qa) => Some(qa),
+            &QueryValue::Computed(ref computation) => computation.first_column(),
+            &QueryValue::Entid(_) |
+            &QueryValue::TypedValue(_) |
+            &QueryValue::PrimitiveLong(_) |
+            &QueryValue::InputVariable(_) => None,
+        }
+    }
+}
+
+/// An expression that SQLite evaluates for us: this is how we implement functions and predicates
+/// over strings and numbers that have no more direct representation in SQL.
+#[derive(PartialEq, Eq, Clone)]
+pub enum Computation {
+    /// A call to a built-in or registered SQLite function, like `substr(x, 1, 2)`.
+    Function(&'static str, Vec<QueryValue>),
+
+    /// A binary operator, like `x || y` or `x = y`.
+    Infix(&'static str, QueryValue, QueryValue),
+
+    /// `CAST(x AS TEXT)`.
+    CastToText(QueryValue),
+
+    /// `CAST(x AS REAL)`.
+    CastToReal(QueryValue),
+
+    /// `CAST(x AS INTEGER)`.
+    CastToInteger(QueryValue),
+}
+
+impl Computation {
+    /// Return the first column read by this computation, if any.
+    pub fn first_column(&self) -> Option<&QualifiedAlias> {
+        match self {
+            &Computation::Function(_, ref args) => args.iter().filter_map(|arg| arg.first_column()).next(),
+            &Computation::Infix(_, ref left, ref right) => left.first_column().or_else(|| right.first_column()),
+            &Computation::CastToText(ref arg) |
+            &Computation::CastToReal(ref arg) |
+            &Computation::CastToInteger(ref arg) => arg.first_column(),
+        }
+    }
+}
+
+impl Debug for Computation {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        match self {
+            &Computation::Function(name, ref args) => write!(f, "{}{:?}", name, args),
+            &Computation::Infix(op, ref left, ref right) => write!(f, "({:?} {} {:?})", left, op, right),
+            &Computation::CastToText(ref arg) => write!(f, "text({:?})", arg),
+            &Computation::CastToReal(ref arg) => write!(f, "real({:?})", arg),
+            &Computation::CastToInteger(ref arg) => write!(f, "integer({:?})", arg),
         }
     }
 }
@@ -326,6 +480,105 @@ impl Debug for Inequality {
     }
 }
 
+/// The aggregate functions we support in `:find`. Each of these is computed directly by SQLite.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SimpleAggregationOp {
+    Avg,
+    Count,
+    CountDistinct,
+    Max,
+    Min,
+    Sum,
+}
+
+impl SimpleAggregationOp {
+    pub fn from_datalog_function(s: &str) -> Option<SimpleAggregationOp> {
+        match s {
+            "avg"            => Some(SimpleAggregationOp::Avg),
+            "count"          => Some(SimpleAggregationOp::Count),
+            "count-distinct" => Some(SimpleAggregationOp::CountDistinct),
+            "max"            => Some(SimpleAggregationOp::Max),
+            "min"            => Some(SimpleAggregationOp::Min),
+            "sum"            => Some(SimpleAggregationOp::Sum),
+            _                => None,
+        }
+    }
+
+    pub fn to_sql_function(self) -> &'static str {
+        use self::SimpleAggregationOp::*;
+        match self {
+            Avg           => "avg",
+            Count         => "count",
+            CountDistinct => "count",       // With DISTINCT.
+            Max           => "max",
+            Min           => "min",
+            Sum           => "sum",
+        }
+    }
+
+    /// Returns true if this aggregate considers only distinct values.
+    pub fn is_distinct(self) -> bool {
+        self == SimpleAggregationOp::CountDistinct
+    }
+
+    /// Returns true if this aggregate yields `NULL` when it has no input rows.
+    pub fn is_nullable(self) -> bool {
+        use self::SimpleAggregationOp::*;
+        match self {
+            Count | CountDistinct => false,
+            Avg | Max | Min | Sum => true,
+        }
+    }
+
+    /// Return the type of the result of applying this aggregate to a variable that can take the
+    /// given types, or `None` if the aggregate doesn't make sense for those types.
+    ///
+    /// Longs and doubles share a type tag, and SQLite preserves the distinction between integers
+    /// and reals in its results, so a sum over both yields whichever is appropriate.
+    pub fn result_type(self, possibilities: ValueTypeSet) -> Option<ValueType> {
+        use self::SimpleAggregationOp::*;
+        if possibilities.is_empty() {
+            return None;
+        }
+
+        let numeric = possibilities.is_subset(&ValueTypeSet::of_numeric_types());
+        match self {
+            Count | CountDistinct => Some(ValueType::Long),
+            Avg => if numeric { Some(ValueType::Double) } else { None },
+            Sum => if numeric { possibilities.exemplar() } else { None },
+            Max | Min => {
+                if numeric {
+                    possibilities.exemplar()
+                } else if possibilities.is_unit() {
+                    // Instants are stored as integers and strings compare as text, so both have a
+                    // sensible ordering in SQLite. Nothing else does.
+                    match possibilities.exemplar() {
+                        Some(ValueType::Instant) => Some(ValueType::Instant),
+                        Some(ValueType::String) => Some(ValueType::String),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl Debug for SimpleAggregationOp {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        use self::SimpleAggregationOp::*;
+        f.write_str(match self {
+            &Avg => "avg",
+            &Count => "count",
+            &CountDistinct => "count-distinct",
+            &Max => "max",
+            &Min => "min",
+            &Sum => "sum",
+        })
+    }
+}
+
 #[derive(PartialEq, Eq)]
 pub enum ColumnConstraint {
     Equals(QualifiedAlias, QueryValue),
@@ -341,6 +594,9 @@ pub enum ColumnConstraint {
     },
     NotExists(ComputedTable),
     Matches(QualifiedAlias, QueryValue),
+
+    /// Holds when the computation is true, like `instr(datoms00.v, 'foo') > 0`.
+    Computed(Computation),
 }
 
 impl ColumnConstraint {
@@ -483,6 +739,9 @@ impl Debug for ColumnConstraint {
             &NotExists(ref ct) => {
                 write!(f, "NOT EXISTS {:?}", ct)
             },
+            &Computed(ref computation) => {
+                write!(f, "{:?}", computation)
+            },
         }
     }
 }
@@ -500,12 +759,18 @@ pub enum EmptyBecause {
     NonAttributeArgument,
     NonInstantArgument,
     NonNumericArgument,
+    NonStringArgument,
+
+    // An argument that isn't of any of the types a function requires in that position.
+    NonMatchingArgument(ValueTypeSet),
     NonStringFulltextValue,
     UnresolvedIdent(NamespacedKeyword),
     InvalidAttributeIdent(NamespacedKeyword),
     InvalidAttributeEntid(Entid),
     InvalidBinding(Column, TypedValue),
     ValueTypeMismatch(ValueType, TypedValue),
+    NonUniqueLookupRefAttribute(Entid),
+    UnresolvedLookupRef(LookupRef),
     AttributeLookupFailed,         // Catch-all, because the table lookup code is lazy. TODO
 }
 
@@ -537,6 +802,12 @@ impl Debug for EmptyBecause {
             &NonNumericArgument => {
                 write!(f, "Non-numeric argument in numeric place")
             },
+            &NonStringArgument => {
+                write!(f, "Non-string argument in string place")
+            },
+            &NonMatchingArgument(ref types) => {
+                write!(f, "Argument not of types {:?} in place requiring them", types)
+            },
             &NonStringFulltextValue => {
                 write!(f, "Non-string argument for fulltext attribute")
             },
@@ -556,6 +827,12 @@ impl Debug for EmptyBecause {
                 write!(f, "Type mismatch: {:?} doesn't match attribute type {:?}",
                        typed_value, value_type)
             },
+            &NonUniqueLookupRefAttribute(entid) => {
+                write!(f, "Lookup ref attribute {} is not unique", entid)
+            },
+            &UnresolvedLookupRef(ref lookup_ref) => {
+                write!(f, "Couldn't resolve lookup ref {:?}", lookup_ref)
+            },
             &AttributeLookupFailed => {
                 write!(f, "Attribute lookup failed")
             },
@@ -164,6 +164,7 @@ mod tests {
                         attribute: ident("artist", "type"),
                         value: value_ident("artist.type", "group"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     })));
                 assert_eq!(
                     right,
@@ -175,6 +176,7 @@ mod tests {
                                 attribute: ident("artist", "type"),
                                 value: value_ident("artist.type", "person"),
                                 tx: PatternNonValuePlace::Placeholder,
+                                added: PatternValuePlace::Placeholder,
                             }),
                             WhereClause::Pattern(Pattern {
                                 source: None,
@@ -182,6 +184,7 @@ mod tests {
                                 attribute: ident("artist", "gender"),
                                 value: value_ident("artist.gender", "female"),
                                 tx: PatternNonValuePlace::Placeholder,
+                                added: PatternValuePlace::Placeholder,
                             }),
                         ]));
             },
@@ -226,6 +229,7 @@ mod tests {
                         attribute: ident("artist", "type"),
                         value: value_ident("artist.type", "group"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     })));
                 assert_eq!(
                     right,
@@ -237,6 +241,7 @@ mod tests {
                                 attribute: ident("artist", "type"),
                                 value: PatternValuePlace::Variable(Variable::from_valid_name("?type")),
                                 tx: PatternNonValuePlace::Placeholder,
+                                added: PatternValuePlace::Placeholder,
                             }),
                             WhereClause::Pattern(Pattern {
                                 source: None,
@@ -244,6 +249,7 @@ mod tests {
                                 attribute: ident("artist", "role"),
                                 value: value_ident("artist.role", "parody"),
                                 tx: PatternNonValuePlace::Placeholder,
+                                added: PatternValuePlace::Placeholder,
                             }),
                         ]));
             },
@@ -299,6 +305,7 @@ mod tests {
                         attribute: artist_country.clone(),
                         value: value_ident("country", "CA"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     }));
                 assert_eq!(
                     clause2,
@@ -308,6 +315,7 @@ mod tests {
                         attribute: artist_country,
                         value: value_ident("country", "GB"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     }));
             },
             _ => panic!(),
@@ -338,6 +346,7 @@ mod tests {
                         attribute: ident("release", "artists"),
                         value: artist,
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     }));
                 assert_eq!(
                     clause2,
@@ -347,6 +356,7 @@ mod tests {
                         attribute: ident("release", "year"),
                         value: PatternValuePlace::EntidOrInteger(1970),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     }));
             },
             _ => panic!(),
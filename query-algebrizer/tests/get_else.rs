@@ -0,0 +1,181 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate mentat_core;
+extern crate mentat_query;
+extern crate mentat_query_algebrizer;
+extern crate mentat_query_parser;
+
+mod utils;
+
+use mentat_core::{
+    Attribute,
+    Schema,
+    ValueType,
+};
+
+use mentat_query::{
+    NamespacedKeyword,
+    PlainSymbol,
+    Variable,
+};
+
+use mentat_query_algebrizer::{
+    BindingError,
+    Error,
+    ErrorKind,
+};
+
+use utils::{
+    add_attribute,
+    alg,
+    associate_ident,
+    bails,
+};
+
+fn prepopulated_schema() -> Schema {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "name"), 65);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "age"), 66);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "knows"), 67);
+    add_attribute(&mut schema, 65, Attribute {
+        value_type: ValueType::String,
+        multival: false,
+        ..Default::default()
+    });
+    add_attribute(&mut schema, 66, Attribute {
+        value_type: ValueType::Long,
+        multival: false,
+        ..Default::default()
+    });
+    add_attribute(&mut schema, 67, Attribute {
+        value_type: ValueType::Ref,
+        multival: true,
+        ..Default::default()
+    });
+    schema
+}
+
+#[test]
+fn test_get_else() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x ?age
+                    :where [?x :foo/name _]
+                           [(get-else $ ?x :foo/age 0) ?age]]"#;
+    let cc = alg(&schema, query);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.left_joins.len(), 1);
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?age")), Some(ValueType::Long));
+
+    // The defaulted variable can't be something other than the attribute's type.
+    let query = r#"[:find ?x
+                    :where [?x :foo/name ?v]
+                           [(get-else $ ?x :foo/age 0) ?v]]"#;
+    assert!(alg(&schema, query).is_known_empty());
+}
+
+#[test]
+fn test_get_else_default_type() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x ?age
+                    :where [?x :foo/name _]
+                           [(get-else $ ?x :foo/age "unknown") ?age]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::InvalidDefault(op, expected), _) => {
+            assert_eq!(op, PlainSymbol::new("get-else"));
+            assert_eq!(expected, ValueType::Long);
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_get_else_errors() {
+    let schema = prepopulated_schema();
+
+    // Only cardinality-one attributes have a single value to default.
+    let query = r#"[:find ?x ?y
+                    :where [?x :foo/name _]
+                           [(get-else $ ?x :foo/knows 0) ?y]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::InvalidArgument(op, _, 2), _) => {
+            assert_eq!(op, PlainSymbol::new("get-else"));
+        },
+        _ => panic!(),
+    }
+
+    // Unknown attributes.
+    let query = r#"[:find ?x ?y
+                    :where [?x :foo/name _]
+                           [(get-else $ ?x :foo/height 0) ?y]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::InvalidArgument(op, _, 2), _) => {
+            assert_eq!(op, PlainSymbol::new("get-else"));
+        },
+        _ => panic!(),
+    }
+
+    // The entity must already be bound.
+    let query = r#"[:find ?y
+                    :where [(get-else $ ?x :foo/age 0) ?y]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::UnboundVariable(var), _) => {
+            assert_eq!(var, PlainSymbol::new("?x"));
+        },
+        _ => panic!(),
+    }
+
+    // get-else binds exactly one value.
+    let query = r#"[:find ?x ?y
+                    :where [?x :foo/name _]
+                           [(get-else $ ?x :foo/age 0) [?y ...]]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::InvalidBinding(op, BindingError::UnexpectedBinding), _) => {
+            assert_eq!(op, PlainSymbol::new("get-else"));
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_missing() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x
+                    :where [?x :foo/name _]
+                           [(missing? $ ?x :foo/age)]]"#;
+    let cc = alg(&schema, query);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.wheres.len(), 2);
+
+    // Unknown attributes.
+    let query = r#"[:find ?x
+                    :where [?x :foo/name _]
+                           [(missing? $ ?x :foo/height)]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::InvalidArgument(op, _, 2), _) => {
+            assert_eq!(op, PlainSymbol::new("missing?"));
+        },
+        _ => panic!(),
+    }
+
+    // The entity must already be bound.
+    let query = r#"[:find ?x
+                    :where [?x :foo/name _]
+                           [(missing? $ ?y :foo/age)]]"#;
+    match bails(&schema, query) {
+        Error(ErrorKind::UnboundVariable(var), _) => {
+            assert_eq!(var, PlainSymbol::new("?y"));
+        },
+        _ => panic!(),
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate mentat_core;
+extern crate mentat_query;
+extern crate mentat_query_algebrizer;
+extern crate mentat_query_parser;
+
+mod utils;
+
+use mentat_core::{
+    Schema,
+    ValueType,
+};
+
+use mentat_query::{
+    PlainSymbol,
+    Variable,
+};
+
+use mentat_query_algebrizer::{
+    ColumnConstraint,
+    ColumnConstraintOrAlternation,
+    ComputedTable,
+    ConjoiningClauses,
+    ErrorKind,
+    QueryInputs,
+    algebrize_with_inputs,
+};
+
+use mentat_query_parser::{
+    parse_find_string,
+    parse_rules_string,
+};
+
+use utils::{
+    SchemaBuilder,
+    bails_with_inputs,
+};
+
+fn prepopulated_schema() -> Schema {
+    SchemaBuilder::new()
+        .define_simple_attr("foo", "name", ValueType::String, false)
+        .define_simple_attr("foo", "nickname", ValueType::String, false)
+        .define_simple_attr("foo", "parent", ValueType::Ref, false)
+        .define_simple_attr("foo", "born", ValueType::Instant, false)
+        .schema
+}
+
+fn rules(rules: &str) -> QueryInputs {
+    QueryInputs::with_rules(parse_rules_string(rules).expect("rules to have parsed"))
+}
+
+fn alg_with_rules(schema: &Schema, query: &str, rule_set: &str) -> ConjoiningClauses {
+    let parsed = parse_find_string(query).expect("query input to have parsed");
+    algebrize_with_inputs(schema, parsed, 0, rules(rule_set)).expect("algebrizing to have succeeded").cc
+}
+
+#[test]
+fn test_rule_expansion() {
+    let schema = prepopulated_schema();
+
+    // A rule with one definition is applied in place; its types flow out to the query.
+    let rule_set = r#"[[(grandparent ?x ?y) [?x :foo/parent ?z] [?z :foo/parent ?y]]]"#;
+    let query = r#"[:find ?x ?name :in % :where (grandparent ?x ?y) [?y :foo/name ?name]]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Ref));
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Ref));
+
+    // The rule's own variable doesn't leak into the query.
+    assert!(!cc.column_bindings.contains_key(&Variable::from_valid_name("?z")));
+
+    // Several definitions are alternatives.
+    let rule_set = r#"[[(called ?x ?n) [?x :foo/name ?n]]
+                       [(called ?x ?n) [?x :foo/nickname ?n]]]"#;
+    let query = r#"[:find ?x :in % :where (called ?x "Ámbar")]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Ref));
+
+    // With only constants to go on, the alternatives become a check that one of them matches.
+    let query = r#"[:find ?y :in % :where [?y :foo/name _] (called 65540 "Ámbar")]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    assert!(cc.computed_tables.is_empty());
+    match cc.wheres.0.last() {
+        Some(&ColumnConstraintOrAlternation::Constraint(ColumnConstraint::NotExists(ComputedTable::Subquery(ref none_match)))) => {
+            assert_eq!(none_match.wheres.len(), 2);
+        },
+        w => panic!("Expected NOT EXISTS, got {:?}", w),
+    }
+
+    // Rules can invoke other rules.
+    let rule_set = r#"[[(grandparent ?x ?y) (parent ?x ?z) (parent ?z ?y)]
+                       [(parent ?x ?y) [?x :foo/parent ?y]]]"#;
+    let query = r#"[:find ?x :in % :where (grandparent ?x ?y) [?y :foo/born ?b]]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Ref));
+}
+
+#[test]
+fn test_recursive_rule() {
+    let schema = prepopulated_schema();
+    let rule_set = r#"[[(ancestor ?x ?y) [?x :foo/parent ?y]]
+                       [(ancestor ?x ?y) [?x :foo/parent ?z] (ancestor ?z ?y)]]"#;
+
+    // The rule becomes a single recursive table, whatever the depth of the data.
+    let query = r#"[:find ?x ?name :in % :where (ancestor ?x ?y) [?y :foo/name ?name]]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.computed_tables.len(), 1);
+    match cc.computed_tables[0] {
+        ComputedTable::RecursiveRule { ref projection, ref type_extraction, ref base, .. } => {
+            assert_eq!(projection.len(), 2);
+            assert!(type_extraction.is_empty());
+            assert_eq!(base.len(), 1);
+        },
+        ref t => panic!("Expected a recursive rule, got {:?}", t),
+    }
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Ref));
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?y")), Some(ValueType::Ref));
+
+    // Constant arguments constrain the table; they don't change it.
+    let query = r#"[:find ?x :in % :where (ancestor ?x 65540)]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.computed_tables.len(), 1);
+
+    // A string can't be an ancestor.
+    let query = r#"[:find ?x :in % :where (ancestor ?x "Ámbar")]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(cc.is_known_empty());
+
+    // If the recursive definition can't match, the rule is simply its other definitions.
+    let rule_set = r#"[[(named ?x ?n) [?x :foo/name ?n]]
+                       [(named ?x ?n) [?x :foo/parent ?n] (named ?n ?x)]]"#;
+    let query = r#"[:find ?x :in % :where (named ?x ?n)]"#;
+    let cc = alg_with_rules(&schema, query, rule_set);
+    assert!(!cc.is_known_empty());
+    match cc.computed_tables[0] {
+        ComputedTable::Union { ref arms, .. } => assert_eq!(arms.len(), 1),
+        ref t => panic!("Expected a union, got {:?}", t),
+    }
+}
+
+#[test]
+fn test_recursive_rule_errors() {
+    let schema = prepopulated_schema();
+    let rule_set = r#"[[(parent ?x ?y) [?x :foo/parent ?y]]
+                       [(ping ?x ?y) (parent ?x ?y)]
+                       [(ping ?x ?y) (parent ?x ?z) (pong ?z ?y)]
+                       [(pong ?x ?y) (ping ?x ?y)]
+                       [(tree ?x ?y) (parent ?x ?y)]
+                       [(tree ?x ?y) (tree ?x ?z) (tree ?z ?y)]
+                       [(cycle ?x ?y) (cycle ?y ?x)]
+                       [(hidden ?x ?y) (parent ?x ?y)]
+                       [(hidden ?x ?y) (or (hidden ?y ?x) (parent ?y ?x))]]"#;
+
+    let query = r#"[:find ?x :in % :where (ping ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::IndirectlyRecursiveRule(name) => assert_eq!(name, PlainSymbol::new("ping")),
+        e => panic!("Expected IndirectlyRecursiveRule, got {:?}", e),
+    }
+
+    let query = r#"[:find ?x :in % :where (hidden ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::IndirectlyRecursiveRule(name) => assert_eq!(name, PlainSymbol::new("hidden")),
+        e => panic!("Expected IndirectlyRecursiveRule, got {:?}", e),
+    }
+
+    let query = r#"[:find ?x :in % :where (tree ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::NonLinearRecursiveRule(name) => assert_eq!(name, PlainSymbol::new("tree")),
+        e => panic!("Expected NonLinearRecursiveRule, got {:?}", e),
+    }
+
+    let query = r#"[:find ?x :in % :where (cycle ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::UngroundedRecursiveRule(name) => assert_eq!(name, PlainSymbol::new("cycle")),
+        e => panic!("Expected UngroundedRecursiveRule, got {:?}", e),
+    }
+}
+
+#[test]
+fn test_rule_errors() {
+    let schema = prepopulated_schema();
+    let rule_set = r#"[[(parent ?x ?y) [?x :foo/parent ?y]]
+                       [(ancestor ?x ?y) (parent ?x ?y)]
+                       [(ancestor ?x ?y) (parent ?x ?z) (ancestor ?z ?y)]
+                       [(sloppy ?x ?y) [?x :foo/parent _]]]"#;
+
+    // Rules must be requested with `%`.
+    let query = r#"[:find ?x :where (parent ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::UnknownRule(name) => assert_eq!(name, PlainSymbol::new("parent")),
+        e => panic!("Expected UnknownRule, got {:?}", e),
+    }
+
+    let query = r#"[:find ?x :in % :where (sibling ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::UnknownRule(name) => assert_eq!(name, PlainSymbol::new("sibling")),
+        e => panic!("Expected UnknownRule, got {:?}", e),
+    }
+
+    let query = r#"[:find ?x :in % :where (parent ?x)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::InvalidNumberOfArguments(name, given, expected) => {
+            assert_eq!(name, PlainSymbol::new("parent"));
+            assert_eq!(given, 1);
+            assert_eq!(expected, 2);
+        },
+        e => panic!("Expected InvalidNumberOfArguments, got {:?}", e),
+    }
+
+    let query = r#"[:find ?x :in % :where (sloppy ?x ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::UnusedRuleVariable(name, var) => {
+            assert_eq!(name, PlainSymbol::new("sloppy"));
+            assert_eq!(var, PlainSymbol::new("?y"));
+        },
+        e => panic!("Expected UnusedRuleVariable, got {:?}", e),
+    }
+
+    // A string can't be an entity.
+    let query = r#"[:find ?y :in % :where (parent "foo" ?y)]"#;
+    match bails_with_inputs(&schema, query, rules(rule_set)).0 {
+        ErrorKind::InvalidRuleArgument(name, var) => {
+            assert_eq!(name, PlainSymbol::new("parent"));
+            assert_eq!(var, PlainSymbol::new("?x"));
+        },
+        e => panic!("Expected InvalidRuleArgument, got {:?}", e),
+    }
+}
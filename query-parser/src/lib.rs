@@ -32,4 +32,9 @@ pub use parse::{
     Result,
     ResultExt,
     parse_find_string,
+    parse_rules_string,
+    unique_vars,
+    validate_added_place,
+    validate_paging,
+    validate_rule_name,
 };
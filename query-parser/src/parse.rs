@@ -41,6 +41,7 @@ use self::mentat_parser_utils::value_and_span::{
 };
 
 use self::mentat_query::{
+    Aggregate,
     Binding,
     Direction,
     Element,
@@ -49,6 +50,8 @@ use self::mentat_query::{
     FnArg,
     FromValue,
     Limit,
+    NonIntegerConstant,
+    Offset,
     Order,
     OrJoin,
     OrWhereClause,
@@ -57,7 +60,11 @@ use self::mentat_query::{
     PatternNonValuePlace,
     PatternValuePlace,
     Predicate,
+    Pull,
+    PullAttributeSpec,
     QueryFunction,
+    Rule,
+    RuleExpr,
     SrcVar,
     TypeAnnotation,
     UnifyVars,
@@ -97,6 +104,11 @@ error_chain! {
             display(":where parse error")
         }
 
+        RuleParseError(e: ValueParseError) {
+            description("rule parse error")
+            display("rule parse error")
+        }
+
         // Not yet used.
         WithParseError {
             description(":with parse error")
@@ -122,7 +134,94 @@ error_chain! {
             description("limit value not valid")
             display("expected natural number, got {}", val)
         }
+
+        UnknownOffsetVar(var: edn::PlainSymbol) {
+            description("offset var not present in :in")
+            display("offset var {} not present in :in", var)
+        }
+
+        InvalidOffset(val: edn::Value) {
+            description("offset value not valid")
+            display("expected non-negative integer, got {}", val)
+        }
+
+        InvalidRuleName(name: edn::PlainSymbol) {
+            description("invalid rule name")
+            display("invalid rule name: {}", name)
+        }
+
+        AddedWithoutHistory {
+            description("added place outside $history")
+            display("only patterns against $history can have an added place")
+        }
+
+        InvalidAddedPlace {
+            description("added place not valid")
+            display("the added place must be a boolean or a variable")
+        }
+    }
+}
+
+// These checks are shared with code that builds queries directly, such as Mentat's
+// `query_builder`, so that a built query is held to the same rules as a parsed one.
+
+/// Collect `vars` into a set, failing if any variable appears more than once, as in `:in` or
+/// `:with`.
+pub fn unique_vars(vars: Vec<Variable>) -> Result<BTreeSet<Variable>> {
+    let given = vars.len();
+    let set: BTreeSet<Variable> = vars.into_iter().collect();
+    if given != set.len() {
+        // TODO: find out what the variable is!
+        bail!(ErrorKind::DuplicateVariableError);
+    }
+    Ok(set)
+}
+
+/// A `:limit` or `:offset` that's a variable must be one of the `:in` variables. A fixed limit of
+/// zero is never useful.
+pub fn validate_paging(in_vars: &BTreeSet<Variable>, limit: &Limit, offset: &Offset) -> Result<()> {
+    match limit {
+        &Limit::Fixed(0) => bail!(ErrorKind::InvalidLimit(edn::Value::Integer(0))),
+        &Limit::Variable(ref v) if !in_vars.contains(v) => bail!(ErrorKind::UnknownLimitVar(v.name())),
+        _ => {},
+    }
+    if let &Offset::Variable(ref v) = offset {
+        if !in_vars.contains(v) {
+            bail!(ErrorKind::UnknownOffsetVar(v.name()));
+        }
+    }
+    Ok(())
+}
+
+/// A rule name is any plain symbol other than a variable, a source, or a symbol that introduces
+/// one of the other kinds of clause.
+pub fn validate_rule_name(name: &edn::PlainSymbol) -> Result<()> {
+    if name.is_var_symbol() || name.is_src_symbol() {
+        bail!(ErrorKind::InvalidRuleName(name.clone()));
+    }
+    match name.0.as_str() {
+        "and" | "or" | "or-join" | "not" | "not-join" | "_" | "%" => bail!(ErrorKind::InvalidRuleName(name.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Only the history has retractions in it. Elsewhere every datom is an assertion, so a pattern
+/// can only have an `added` place if it's against `$history`, and then only a boolean or a
+/// variable.
+pub fn validate_added_place(source: Option<&SrcVar>, added: &PatternValuePlace) -> Result<()> {
+    if !source.map_or(false, |src| src.is_history()) {
+        bail!(ErrorKind::AddedWithoutHistory);
     }
+    match added {
+        &PatternValuePlace::Placeholder |
+        &PatternValuePlace::Variable(_) |
+        &PatternValuePlace::Constant(NonIntegerConstant::Boolean(_)) => Ok(()),
+        _ => bail!(ErrorKind::InvalidAddedPlace),
+    }
+}
+
+fn to_parse_error<T, R>(e: Error) -> combine::primitives::Error<T, R> {
+    combine::primitives::Error::Other(Box::new(e))
 }
 
 pub struct Query<'a>(std::marker::PhantomData<&'a ()>);
@@ -148,6 +247,16 @@ def_parser!(Query, arguments, Vec<FnArg>, {
     (many::<Vec<FnArg>, _>(Query::fn_arg()))
 });
 
+/// The name of a rule. See `validate_rule_name`.
+def_parser!(Query, rule_name, edn::PlainSymbol, {
+    satisfy_map(|v: &edn::ValueAndSpan| {
+        match v.inner {
+            edn::SpannedValue::PlainSymbol(ref s) => validate_rule_name(s).ok().map(|_| s.clone()),
+            _ => None,
+        }
+    })
+});
+
 def_parser!(Query, direction, Direction, {
     satisfy_map(|v: &edn::ValueAndSpan| {
         match v.inner {
@@ -190,6 +299,20 @@ def_parser!(Query, natural_number, u64, {
     })
 });
 
+def_parser!(Query, non_negative_number, u64, {
+    any().and_then(|v: &edn::ValueAndSpan| {
+        match v.inner {
+            edn::SpannedValue::Integer(x) if (x >= 0) => {
+                Ok(x as u64)
+            },
+            ref spanned => {
+                let e = Box::new(Error::from_kind(ErrorKind::InvalidOffset(spanned.clone().into())));
+                Err(combine::primitives::Error::Other(e))
+            },
+        }
+    })
+});
+
 def_parser!(Where, pattern_non_value_place, PatternNonValuePlace, {
     satisfy_map(PatternNonValuePlace::from_value)
 });
@@ -357,11 +480,22 @@ def_parser!(Where, pattern, WhereClause, {
              Where::pattern_non_value_place(),           // e
              Where::pattern_non_value_place(),           // a
              optional(Where::pattern_value_place()),     // v
-             optional(Where::pattern_non_value_place())) // tx
-                .and_then(|(src, e, a, v, tx)| {
+             optional(Where::pattern_non_value_place()), // tx
+             optional(Where::pattern_value_place()))     // added
+                .and_then(|(src, e, a, v, tx, added)| {
                     let v = v.unwrap_or(PatternValuePlace::Placeholder);
                     let tx = tx.unwrap_or(PatternNonValuePlace::Placeholder);
 
+                    let added = match added {
+                        None => PatternValuePlace::Placeholder,
+                        Some(added) => {
+                            if let Err(e) = validate_added_place(src.as_ref(), &added) {
+                                return Err(to_parse_error(e));
+                            }
+                            added
+                        },
+                    };
+
                     // Pattern::new takes care of reversal of reversed
                     // attributes: [?x :foo/_bar ?y] turns into
                     // [?y :foo/bar ?x].
@@ -381,11 +515,25 @@ def_parser!(Where, pattern, WhereClause, {
                     //
                     // is nonsense. That leaves us with a nested optional, which we unwrap here.
                     Pattern::new(src, e, a, v, tx)
+                        .map(|p| Pattern { added: added, ..p })
                         .map(WhereClause::Pattern)
                         .ok_or(combine::primitives::Error::Expected("pattern".into()))
                 }))
 });
 
+/// A rule invocation: `(rule-name args…)`.
+def_parser!(Where, rule_expr, WhereClause, {
+    list()
+        .of_exactly((Query::rule_name(), Query::arguments())
+            .map(|(name, args)| {
+                WhereClause::RuleExpr(
+                    RuleExpr {
+                        name: name,
+                        args: args,
+                    })
+            }))
+});
+
 def_parser!(Where, clause, WhereClause, {
     choice([try(Where::pattern()),
             // It's either
@@ -401,6 +549,9 @@ def_parser!(Where, clause, WhereClause, {
             try(Where::type_annotation()),
             try(Where::pred()),
             try(Where::where_fn()),
+
+            // Anything else in parentheses is a rule invocation.
+            try(Where::rule_expr()),
     ])
 });
 
@@ -409,6 +560,40 @@ def_parser!(Where, clauses, Vec<WhereClause>, {
     (many1::<Vec<WhereClause>, _>(Where::clause()))
 });
 
+/// The head of a rule definition: `(rule-name ?x ?y)`.
+def_parser!(Where, rule_head, (edn::PlainSymbol, Vec<Variable>), {
+    list()
+        .of_exactly((Query::rule_name(), many1::<Vec<Variable>, _>(Query::variable())))
+        .and_then(|(name, vars)| {
+            let unique: BTreeSet<&Variable> = vars.iter().collect();
+            if unique.len() != vars.len() {
+                let e = Box::new(Error::from_kind(ErrorKind::DuplicateVariableError));
+                Err(combine::primitives::Error::Other(e))
+            } else {
+                Ok((name, vars))
+            }
+        })
+});
+
+/// A single rule definition: `[(rule-name ?x ?y) clauses…]`.
+def_parser!(Where, rule, Rule, {
+    vector()
+        .of_exactly((Where::rule_head(), many1::<Vec<WhereClause>, _>(Where::clause()))
+            .map(|((name, vars), clauses)| {
+                Rule {
+                    name: name,
+                    vars: vars,
+                    clauses: clauses,
+                }
+            }))
+});
+
+/// A rule set, as supplied to a query that names `%` in `:in`: a vector of rule definitions.
+def_parser!(Where, rules, Vec<Rule>, {
+    vector()
+        .of_exactly(many::<Vec<Rule>, _>(Where::rule()))
+});
+
 pub struct Find<'a>(std::marker::PhantomData<&'a ()>);
 
 def_matches_plain_symbol!(Find, period, ".");
@@ -417,8 +602,71 @@ def_matches_plain_symbol!(Find, ellipsis, "...");
 
 def_matches_plain_symbol!(Find, placeholder, "_");
 
+/// An aggregate expression, like `(max ?x)`.
+def_parser!(Find, aggregate, Aggregate, {
+    seq().of_exactly(Query::func())
+        .map(|(func, args)| Aggregate {
+            func: func,
+            args: args,
+        })
+});
+
+def_matches_plain_symbol!(Find, pull, "pull");
+
+def_matches_plain_symbol!(Find, wildcard, "*");
+
+def_parser!(Find, pull_attribute, edn::NamespacedKeyword, {
+    satisfy_map(|v: &edn::ValueAndSpan| {
+        match v.inner {
+            edn::SpannedValue::NamespacedKeyword(ref k) => Some(k.clone()),
+            _ => None,
+        }
+    })
+});
+
+/// A nested pull pattern: `{:foo/child [:bar/x]}`. A single map can nest several attributes.
+def_parser!(Find, pull_nested, Vec<PullAttributeSpec>, {
+    map().of_exactly(many1::<Vec<PullAttributeSpec>, _>(
+        (Find::pull_attribute(), vector().of_exactly(Find::pull_attribute_specs()))
+            .map(|(attribute, patterns)| PullAttributeSpec::Nested(attribute, patterns))))
+});
+
+def_parser!(Find, pull_attribute_spec, PullAttributeSpec, {
+    Find::wildcard().map(|_| PullAttributeSpec::Wildcard)
+        .or(Find::pull_attribute().map(PullAttributeSpec::Attribute))
+});
+
+def_parser!(Find, pull_attribute_specs, Vec<PullAttributeSpec>, {
+    many1::<Vec<Vec<PullAttributeSpec>>, _>(
+        Find::pull_attribute_spec().map(|spec| vec![spec])
+            .or(Find::pull_nested()))
+        .map(|specs| specs.into_iter().flat_map(|x| x).collect())
+});
+
+/// A pull expression, like `(pull ?e [:foo/name {:foo/child [:bar/x]}])`.
+def_parser!(Find, pull_expression, Pull, {
+    seq().of_exactly((Find::pull(),
+                      Query::variable(),
+                      vector().of_exactly(Find::pull_attribute_specs())))
+        .map(|(_, var, patterns)| Pull {
+            var: var,
+            patterns: patterns,
+        })
+});
+
+def_matches_plain_symbol!(Find, ident, "ident");
+
+/// An ident expression, like `(ident ?color)`.
+def_parser!(Find, ident_expression, Variable, {
+    seq().of_exactly((Find::ident(), Query::variable()))
+        .map(|(_, var)| var)
+});
+
 def_parser!(Find, elem, Element, {
     Query::variable().map(Element::Variable)
+        .or(try(Find::pull_expression()).map(Element::Pull))
+        .or(try(Find::ident_expression()).map(Element::Ident))
+        .or(Find::aggregate().map(Element::Aggregate))
 });
 
 def_parser!(Find, find_scalar, FindSpec, {
@@ -447,8 +695,8 @@ def_parser!(Find, find_tuple, FindSpec, {
 /// Parse a stream of values into one of four find specs.
 ///
 /// `:find` must be an array of plain var symbols (?foo), pull expressions, and aggregates.  For now
-/// we only support variables and the annotations necessary to declare which flavor of :find we
-/// want:
+/// we only support variables, aggregates, and the annotations necessary to declare which flavor of
+/// :find we want:
 ///
 ///
 ///     `?x ?y ?z  `     = FindRel
@@ -465,20 +713,27 @@ def_parser!(Find, spec, FindSpec, {
           &mut try(Find::find_rel())])
 });
 
-fn unique_vars<T, E>(vars: Vec<Variable>) -> std::result::Result<BTreeSet<Variable>, combine::primitives::Error<T, E>> {
-    let given = vars.len();
-    let set: BTreeSet<Variable> = vars.into_iter().collect();
-    if given != set.len() {
-        // TODO: find out what the variable is!
-        let e = Box::new(Error::from_kind(ErrorKind::DuplicateVariableError));
-        Err(combine::primitives::Error::Other(e))
-    } else {
-        Ok(set)
-    }
-}
-
 def_parser!(Find, vars, BTreeSet<Variable>, {
-    many(Query::variable()).and_then(unique_vars)
+    many(Query::variable()).and_then(|vars| unique_vars(vars).map_err(to_parse_error))
+});
+
+def_matches_plain_symbol!(Find, rules_var, "%");
+
+/// The contents of `:in`: input variables and, optionally, `%` to accept a rule set.
+def_parser!(Find, in_vars, (BTreeSet<Variable>, bool), {
+    many::<Vec<Option<Variable>>, _>(Query::variable().map(Some)
+                                         .or(Find::rules_var().map(|_| None)))
+        .and_then(|inputs| {
+            let given = inputs.len();
+            let vars: Vec<Variable> = inputs.into_iter().filter_map(|x| x).collect();
+            if given - vars.len() > 1 {
+                // `%` can only appear once.
+                let e = Box::new(Error::from_kind(ErrorKind::DuplicateVariableError));
+                return Err(combine::primitives::Error::Other(e));
+            }
+            let in_rules = given > vars.len();
+            unique_vars(vars).map(|vars| (vars, in_rules)).map_err(to_parse_error)
+        })
 });
 
 /// This is awkward, but will do for now.  We use `keyword_map()` to optionally accept vector find
@@ -487,8 +742,9 @@ def_parser!(Find, vars, BTreeSet<Variable>, {
 def_parser!(Find, query, FindQuery, {
     let find_map = keyword_map_of!(
         ("find", Find::spec()),
-        ("in", Find::vars()),
+        ("in", Find::in_vars()),
         ("limit", Query::variable().map(Limit::Variable).or(Query::natural_number().map(Limit::Fixed))),
+        ("offset", Query::variable().map(Offset::Variable).or(Query::non_negative_number().map(Offset::Fixed))),
         ("order", many1(Query::order())),
         ("where", Where::clauses()),
         ("with", Find::vars()) // Note: no trailing comma allowed!
@@ -496,16 +752,14 @@ def_parser!(Find, query, FindQuery, {
 
     (or(keyword_map(), vector()))
         .of_exactly(find_map)
-        .and_then(|(find_spec, in_vars, limit, order_clauses, where_clauses, with_vars) | -> std::result::Result<FindQuery, combine::primitives::Error<&edn::ValueAndSpan, &edn::ValueAndSpan>>  {
+        .and_then(|(find_spec, in_vars, limit, offset, order_clauses, where_clauses, with_vars) | -> std::result::Result<FindQuery, combine::primitives::Error<&edn::ValueAndSpan, &edn::ValueAndSpan>>  {
             let limit = limit.unwrap_or(Limit::None);
+            let offset = offset.unwrap_or(Offset::None);
 
-            // Make sure that if we have `:limit ?x`, `?x` appears in `:in`.
-            let in_vars = in_vars.unwrap_or(BTreeSet::default());
-            if let Limit::Variable(ref v) = limit {
-                if !in_vars.contains(v) {
-                    let e = Box::new(Error::from_kind(ErrorKind::UnknownLimitVar(v.name())));
-                    return Err(combine::primitives::Error::Other(e));
-                }
+            // Make sure that if we have `:limit ?x`, `?x` appears in `:in`, and likewise `:offset ?x`.
+            let (in_vars, in_rules) = in_vars.unwrap_or((BTreeSet::default(), false));
+            if let Err(e) = validate_paging(&in_vars, &limit, &offset) {
+                return Err(to_parse_error(e));
             }
 
             Ok(FindQuery {
@@ -513,7 +767,9 @@ def_parser!(Find, query, FindQuery, {
                 find_spec: find_spec.ok_or(combine::primitives::Error::Unexpected("expected :find".into()))?,
                 in_sources: BTreeSet::default(),    // TODO
                 in_vars: in_vars,
+                in_rules: in_rules,
                 limit: limit,
+                offset: offset,
                 order: order_clauses,
                 where_clauses: where_clauses.ok_or(combine::primitives::Error::Unexpected("expected :where".into()))?,
                 with: with_vars.unwrap_or(BTreeSet::default()),
@@ -571,6 +827,21 @@ pub fn parse_find_string(string: &str) -> Result<FindQuery> {
         .map_err(|e| Error::from_kind(ErrorKind::FindParseError(e.into())))
 }
 
+/// Parse a rule set, like
+///
+/// ```edn
+/// [[(named-friend ?x ?name) [?x :person/friend ?y] [?y :person/name ?name]]]
+/// ```
+///
+/// for use by queries that name `%` in `:in`.
+pub fn parse_rules_string(string: &str) -> Result<Vec<Rule>> {
+    let expr = edn::parse::value(string)?;
+    Where::rules()
+        .parse(expr.atom_stream())
+        .map(|x| x.0)
+        .map_err(|e| Error::from_kind(ErrorKind::RuleParseError(e.into())))
+}
+
 #[cfg(test)]
 mod test {
     extern crate combine;
@@ -578,6 +849,7 @@ mod test {
     extern crate mentat_query;
 
     use std::rc::Rc;
+    use std::sync::Arc;
 
     use self::combine::Parser;
     use self::edn::OrderedFloat;
@@ -597,7 +869,7 @@ mod test {
     use super::*;
 
     fn variable(x: edn::PlainSymbol) -> Variable {
-        Variable(Rc::new(x))
+        Variable(Arc::new(x))
     }
 
     fn ident_kw(kw: edn::NamespacedKeyword) -> PatternNonValuePlace {
@@ -624,6 +896,7 @@ mod test {
             attribute: ident_kw(a),
             value: PatternValuePlace::Constant(NonIntegerConstant::Float(v)),
             tx: PatternNonValuePlace::Variable(variable(tx)),
+            added: PatternValuePlace::Placeholder,
         }));
     }
 
@@ -645,6 +918,7 @@ mod test {
             attribute: PatternNonValuePlace::Variable(variable(a)),
             value: PatternValuePlace::Variable(variable(v)),
             tx: PatternNonValuePlace::Variable(variable(tx)),
+            added: PatternValuePlace::Placeholder,
         }));
     }
 
@@ -684,6 +958,7 @@ mod test {
             attribute: ident("foo", "bar"),
             value: PatternValuePlace::Placeholder,
             tx: PatternNonValuePlace::Variable(variable(tx)),
+            added: PatternValuePlace::Placeholder,
         }));
     }
 
@@ -744,6 +1019,7 @@ mod test {
                                                   attribute: PatternNonValuePlace::Variable(variable(a)),
                                                   value: PatternValuePlace::Variable(variable(v)),
                                                   tx: PatternNonValuePlace::Placeholder,
+                                                  added: PatternValuePlace::Placeholder,
                                               }))])));
     }
 
@@ -769,6 +1045,7 @@ mod test {
                                                   attribute: PatternNonValuePlace::Variable(variable(a)),
                                                   value: PatternValuePlace::Variable(variable(v)),
                                                   tx: PatternNonValuePlace::Placeholder,
+                                                  added: PatternValuePlace::Placeholder,
                                               }))])));
     }
 
@@ -790,6 +1067,7 @@ mod test {
                                           attribute: PatternNonValuePlace::Variable(variable(a)),
                                           value: PatternValuePlace::Variable(variable(v)),
                                           tx: PatternNonValuePlace::Placeholder,
+                                          added: PatternValuePlace::Placeholder,
                                       })],
                               }));
     }
@@ -811,6 +1089,7 @@ mod test {
                                           attribute: PatternNonValuePlace::Variable(variable(a)),
                                           value: PatternValuePlace::Variable(variable(v)),
                                           tx: PatternNonValuePlace::Placeholder,
+                                          added: PatternValuePlace::Placeholder,
                                       })],
                               }));
     }
@@ -866,6 +1145,23 @@ mod test {
                                                    Element::Variable(variable(vy))]));
     }
 
+    #[test]
+    fn test_find_aggregates() {
+        let vx = edn::PlainSymbol::new("?x");
+        let vy = edn::PlainSymbol::new("?y");
+        let max = edn::PlainSymbol::new("max");
+        let input = edn::Value::Vector(vec![edn::Value::PlainSymbol(vx.clone()),
+                                            edn::Value::List(vec![edn::Value::PlainSymbol(max.clone()),
+                                                                  edn::Value::PlainSymbol(vy.clone())].into_iter().collect())]);
+        assert_parses_to!(|| vector().of_exactly(Find::find_rel()),
+                          input,
+                          FindSpec::FindRel(vec![Element::Variable(variable(vx)),
+                                                 Element::Aggregate(Aggregate {
+                                                     func: QueryFunction(max),
+                                                     args: vec![FnArg::Variable(variable(vy))],
+                                                 })]));
+    }
+
     #[test]
     fn test_natural_numbers() {
         let text = edn::Value::Text("foo".to_string());
@@ -911,6 +1207,16 @@ mod test {
                           ]));
     }
 
+    #[test]
+    fn test_fn_arg_keywords() {
+        assert_edn_parses_to!(|| vector().of_exactly(Query::fn_arg()),
+                              "[:day]",
+                              FnArg::Keyword(edn::Keyword::new("day")));
+        assert_edn_parses_to!(|| vector().of_exactly(Query::fn_arg()),
+                              "[:foo/day]",
+                              FnArg::IdentOrKeyword(edn::NamespacedKeyword::new("foo", "day")));
+    }
+
     #[test]
     fn test_bind_scalar() {
         let vx = edn::PlainSymbol::new("?x");
@@ -17,6 +17,7 @@ extern crate mentat_query;
 extern crate mentat_query_parser;
 
 use std::rc::Rc;
+use std::sync::Arc;
 
 use edn::{
     NamespacedKeyword,
@@ -28,8 +29,11 @@ use mentat_query::{
     Element,
     FindSpec,
     FnArg,
+    IdentOrEntid,
     Limit,
+    LookupRef,
     NonIntegerConstant,
+    Offset,
     Order,
     OrJoin,
     OrWhereClause,
@@ -37,12 +41,22 @@ use mentat_query::{
     PatternNonValuePlace,
     PatternValuePlace,
     Predicate,
+    Pull,
+    PullAttributeSpec,
+    Rule,
+    RuleExpr,
+    SrcVar,
     UnifyVars,
     Variable,
     WhereClause,
 };
 
-use mentat_query_parser::parse_find_string;
+use mentat_core::TypedValue;
+
+use mentat_query_parser::{
+    parse_find_string,
+    parse_rules_string,
+};
 
 ///! N.B., parsing a query can be done without reference to a DB.
 ///! Processing the parsed query into something we can work with
@@ -64,6 +78,7 @@ fn can_parse_predicates() {
                        attribute: PatternNonValuePlace::Placeholder,
                        value: PatternValuePlace::Variable(Variable::from_valid_name("?y")),
                        tx: PatternNonValuePlace::Placeholder,
+                       added: PatternValuePlace::Placeholder,
                    }),
                    WhereClause::Pred(Predicate { operator: PlainSymbol::new("<"), args: vec![
                        FnArg::Variable(Variable::from_valid_name("?y")), FnArg::EntidOrInteger(10),
@@ -90,6 +105,7 @@ fn can_parse_simple_or() {
                                    attribute: PatternNonValuePlace::Placeholder,
                                    value: PatternValuePlace::EntidOrInteger(10),
                                    tx: PatternNonValuePlace::Placeholder,
+                                   added: PatternValuePlace::Placeholder,
                                })),
                            OrWhereClause::Clause(
                                WhereClause::Pattern(Pattern {
@@ -98,6 +114,7 @@ fn can_parse_simple_or() {
                                    attribute: PatternNonValuePlace::Placeholder,
                                    value: PatternValuePlace::EntidOrInteger(15),
                                    tx: PatternNonValuePlace::Placeholder,
+                                   added: PatternValuePlace::Placeholder,
                                })),
                        ],
                    )),
@@ -123,6 +140,7 @@ fn can_parse_unit_or_join() {
                                    attribute: PatternNonValuePlace::Placeholder,
                                    value: PatternValuePlace::EntidOrInteger(15),
                                    tx: PatternNonValuePlace::Placeholder,
+                                   added: PatternValuePlace::Placeholder,
                                })),
                        ],
                    )),
@@ -148,6 +166,7 @@ fn can_parse_simple_or_join() {
                                    attribute: PatternNonValuePlace::Placeholder,
                                    value: PatternValuePlace::EntidOrInteger(10),
                                    tx: PatternNonValuePlace::Placeholder,
+                                   added: PatternValuePlace::Placeholder,
                                })),
                            OrWhereClause::Clause(
                                WhereClause::Pattern(Pattern {
@@ -156,6 +175,7 @@ fn can_parse_simple_or_join() {
                                    attribute: PatternNonValuePlace::Placeholder,
                                    value: PatternValuePlace::EntidOrInteger(-15),
                                    tx: PatternNonValuePlace::Placeholder,
+                                   added: PatternValuePlace::Placeholder,
                                })),
                        ],
                    )),
@@ -186,6 +206,7 @@ fn can_parse_simple_or_and_join() {
                                    attribute: PatternNonValuePlace::Placeholder,
                                    value: PatternValuePlace::EntidOrInteger(10),
                                    tx: PatternNonValuePlace::Placeholder,
+                                   added: PatternValuePlace::Placeholder,
                                })),
                            OrWhereClause::And(
                                vec![
@@ -198,6 +219,7 @@ fn can_parse_simple_or_and_join() {
                                                attribute: ident("foo", "bar"),
                                                value: PatternValuePlace::Variable(Variable::from_valid_name("?y")),
                                                tx: PatternNonValuePlace::Placeholder,
+                                               added: PatternValuePlace::Placeholder,
                                            })),
                                            OrWhereClause::Clause(WhereClause::Pattern(Pattern {
                                                source: None,
@@ -205,6 +227,7 @@ fn can_parse_simple_or_and_join() {
                                                attribute: ident("foo", "baz"),
                                                value: PatternValuePlace::Variable(Variable::from_valid_name("?y")),
                                                tx: PatternNonValuePlace::Placeholder,
+                                               added: PatternValuePlace::Placeholder,
                                            })),
                                        ],
                                    )),
@@ -275,6 +298,70 @@ fn can_parse_limit() {
     assert!(parse_find_string(variable_without_in).is_err());
 }
 
+#[test]
+fn can_parse_offset() {
+    let invalid = "[:find ?x :where [?x :foo/baz ?y] :offset]";
+    assert!(parse_find_string(invalid).is_err());
+
+    let negative_invalid = "[:find ?x :where [?x :foo/baz ?y] :offset -1]";
+    assert!(parse_find_string(negative_invalid).is_err());
+
+    let none = "[:find ?x :where [?x :foo/baz ?y]]";
+    assert_eq!(parse_find_string(none).unwrap().offset,
+               Offset::None);
+
+    let zero = "[:find ?x :where [?x :foo/baz ?y] :offset 0]";
+    assert_eq!(parse_find_string(zero).unwrap().offset,
+               Offset::Fixed(0));
+
+    let both = "[:find ?x :where [?x :foo/baz ?y] :limit 10 :offset 20]";
+    let parsed = parse_find_string(both).unwrap();
+    assert_eq!(parsed.limit, Limit::Fixed(10));
+    assert_eq!(parsed.offset, Offset::Fixed(20));
+
+    let variable_with_in = "[:find ?x :in ?offset :where [?x :foo/baz ?y] :offset ?offset]";
+    assert_eq!(parse_find_string(variable_with_in).unwrap().offset,
+               Offset::Variable(Variable::from_valid_name("?offset")));
+
+    let variable_without_in = "[:find ?x :where [?x :foo/baz ?y] :offset ?offset]";
+    assert!(parse_find_string(variable_without_in).is_err());
+}
+
+#[test]
+fn can_parse_lookup_refs() {
+    let s = r#"[:find ?name :where [[:person/email "a@b.c"] :person/name ?name] [[10 :foo/bar] _ _ _]]"#;
+    let p = parse_find_string(s).unwrap();
+
+    let email = NamespacedKeyword::new("person", "email");
+    let clauses = p.where_clauses;
+    match clauses[0] {
+        WhereClause::Pattern(Pattern { entity: PatternNonValuePlace::LookupRef(ref r), .. }) => {
+            assert_eq!(r.as_ref(), &LookupRef {
+                attribute: IdentOrEntid::Ident(Rc::new(email)),
+                value: TypedValue::typed_string("a@b.c"),
+            });
+        },
+        _ => panic!("Expected a lookup ref"),
+    }
+    match clauses[1] {
+        WhereClause::Pattern(Pattern { entity: PatternNonValuePlace::LookupRef(ref r), .. }) => {
+            assert_eq!(r.as_ref(), &LookupRef {
+                attribute: IdentOrEntid::Entid(10),
+                value: TypedValue::Keyword(Rc::new(NamespacedKeyword::new("foo", "bar"))),
+            });
+        },
+        _ => panic!("Expected a lookup ref"),
+    }
+
+    // A lookup ref has exactly an attribute and a value.
+    assert!(parse_find_string(r#"[:find ?x :where [[:person/email] :person/name ?x]]"#).is_err());
+    assert!(parse_find_string(r#"[:find ?x :where [["a@b.c" :person/email] :person/name ?x]]"#).is_err());
+    assert!(parse_find_string(r#"[:find ?x :where [[:person/email ?y] :person/name ?x]]"#).is_err());
+
+    // Reversing a pattern would put the lookup ref in the value place, where it isn't allowed.
+    assert!(parse_find_string(r#"[:find ?x :where [[:person/email "a@b.c"] :person/_friend ?x]]"#).is_err());
+}
+
 #[test]
 fn can_parse_uuid() {
     let expected = edn::Uuid::parse_str("4cb3f828-752d-497a-90c9-b1fd516d5644").expect("valid uuid");
@@ -288,3 +375,130 @@ fn can_parse_uuid() {
                                 PatternNonValuePlace::Placeholder)
                        .expect("valid pattern")));
 }
+
+#[test]
+fn can_parse_pull() {
+    let s = "[:find (pull ?x [:foo/name {:foo/child [:bar/x {:bar/y [*]}]} :foo/_parent *]) . :where [?x :foo/name _]]";
+    let p = parse_find_string(s).expect("parsed");
+    assert_eq!(p.find_spec,
+               FindSpec::FindScalar(Element::Pull(Pull {
+                   var: Variable::from_valid_name("?x"),
+                   patterns: vec![
+                       PullAttributeSpec::Attribute(NamespacedKeyword::new("foo", "name")),
+                       PullAttributeSpec::Nested(NamespacedKeyword::new("foo", "child"),
+                                                 vec![PullAttributeSpec::Attribute(NamespacedKeyword::new("bar", "x")),
+                                                      PullAttributeSpec::Nested(NamespacedKeyword::new("bar", "y"),
+                                                                                vec![PullAttributeSpec::Wildcard])]),
+                       PullAttributeSpec::Attribute(NamespacedKeyword::new("foo", "_parent")),
+                       PullAttributeSpec::Wildcard,
+                   ],
+               })));
+    assert_eq!(p.find_spec.columns().next().unwrap().to_string(),
+               "(pull ?x [:foo/name {:foo/child [:bar/x {:bar/y [*]}]} :foo/_parent *])");
+
+    // A pull pattern can't be empty.
+    assert!(parse_find_string("[:find (pull ?x []) :where [?x :foo/name _]]").is_err());
+}
+
+#[test]
+fn can_parse_ident() {
+    let s = "[:find ?x (ident ?c) :where [?x :foo/color ?c]]";
+    let p = parse_find_string(s).expect("parsed");
+    assert_eq!(p.find_spec,
+               FindSpec::FindRel(vec![Element::Variable(Variable::from_valid_name("?x")),
+                                      Element::Ident(Variable::from_valid_name("?c"))]));
+    assert_eq!(p.find_spec.columns().nth(1).unwrap().to_string(), "(ident ?c)");
+}
+
+#[test]
+fn can_parse_rules() {
+    let s = "[:find ?x :in ?name % :where (named ?x ?name) (or (named ?x \"Ámbar\") [?x :foo/bar _])]";
+    let p = parse_find_string(s).expect("parsed");
+    assert!(p.in_rules);
+    assert_eq!(p.in_vars, btreeset!{Variable::from_valid_name("?name")});
+    assert_eq!(p.where_clauses[0],
+               WhereClause::RuleExpr(RuleExpr {
+                   name: PlainSymbol::new("named"),
+                   args: vec![FnArg::Variable(Variable::from_valid_name("?x")),
+                              FnArg::Variable(Variable::from_valid_name("?name"))],
+               }));
+    assert_eq!(p.where_clauses[1],
+               WhereClause::OrJoin(OrJoin::new(
+                   UnifyVars::Implicit,
+                   vec![
+                       OrWhereClause::Clause(WhereClause::RuleExpr(RuleExpr {
+                           name: PlainSymbol::new("named"),
+                           args: vec![FnArg::Variable(Variable::from_valid_name("?x")),
+                                      FnArg::Constant(NonIntegerConstant::Text(Arc::new("Ámbar".to_string())))],
+                       })),
+                       OrWhereClause::Clause(WhereClause::Pattern(Pattern {
+                           source: None,
+                           entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+                           attribute: PatternNonValuePlace::Ident(Rc::new(NamespacedKeyword::new("foo", "bar"))),
+                           value: PatternValuePlace::Placeholder,
+                           tx: PatternNonValuePlace::Placeholder,
+                           added: PatternValuePlace::Placeholder,
+                       })),
+                   ])));
+
+    // Without `%`, there are no rules.
+    assert!(!parse_find_string("[:find ?x :in ?y :where [?x :foo/bar ?y]]").expect("parsed").in_rules);
+
+    // `%` can only appear once.
+    assert!(parse_find_string("[:find ?x :in % % :where (named ?x)]").is_err());
+
+    let rules = parse_rules_string("[[(named ?x ?name) [?x :foo/name ?name]]
+                                     [(named ?x ?name) [?x :foo/nickname ?name]]]").expect("parsed");
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules[1],
+               Rule {
+                   name: PlainSymbol::new("named"),
+                   vars: vec![Variable::from_valid_name("?x"), Variable::from_valid_name("?name")],
+                   clauses: vec![
+                       WhereClause::Pattern(Pattern {
+                           source: None,
+                           entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+                           attribute: PatternNonValuePlace::Ident(Rc::new(NamespacedKeyword::new("foo", "nickname"))),
+                           value: PatternValuePlace::Variable(Variable::from_valid_name("?name")),
+                           tx: PatternNonValuePlace::Placeholder,
+                           added: PatternValuePlace::Placeholder,
+                       }),
+                   ],
+               });
+
+    // Rule heads need variables, and can't repeat them.
+    assert!(parse_rules_string("[[(named) [?x :foo/name _]]]").is_err());
+    assert!(parse_rules_string("[[(named ?x ?x) [?x :foo/name ?x]]]").is_err());
+
+    // Rule bodies can't be empty.
+    assert!(parse_rules_string("[[(named ?x)]]").is_err());
+}
+
+#[test]
+fn can_parse_history() {
+    let s = "[:find ?v ?tx ?added :where [$history ?x :foo/bar ?v ?tx ?added]]";
+    let p = parse_find_string(s).expect("parsed");
+    assert_eq!(p.where_clauses[0],
+               WhereClause::Pattern(Pattern {
+                   source: Some(SrcVar::history()),
+                   entity: PatternNonValuePlace::Variable(Variable::from_valid_name("?x")),
+                   attribute: PatternNonValuePlace::Ident(Rc::new(NamespacedKeyword::new("foo", "bar"))),
+                   value: PatternValuePlace::Variable(Variable::from_valid_name("?v")),
+                   tx: PatternNonValuePlace::Variable(Variable::from_valid_name("?tx")),
+                   added: PatternValuePlace::Variable(Variable::from_valid_name("?added")),
+               }));
+
+    // `added` can be a boolean constant.
+    let s = "[:find ?v :where [$history ?x :foo/bar ?v _ false]]";
+    assert!(parse_find_string(s).is_ok());
+
+    // …but nothing else.
+    let s = "[:find ?v :where [$history ?x :foo/bar ?v _ 1]]";
+    assert!(parse_find_string(s).is_err());
+
+    // Only the history has an `added` place.
+    let s = "[:find ?v :where [?x :foo/bar ?v ?tx ?added]]";
+    assert!(parse_find_string(s).is_err());
+    let s = "[:find ?v :where [$ ?x :foo/bar ?v ?tx true]]";
+    assert!(parse_find_string(s).is_err());
+}
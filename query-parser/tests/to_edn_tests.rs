@@ -0,0 +1,366 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#[macro_use]
+extern crate proptest;
+
+extern crate edn;
+extern crate mentat_core;
+extern crate mentat_query;
+extern crate mentat_query_parser;
+
+use std::collections::BTreeSet;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use proptest::prelude::*;
+
+use edn::{
+    DateTime,
+    FromMicros,
+    Keyword,
+    NamespacedKeyword,
+    OrderedFloat,
+    PlainSymbol,
+    Utc,
+    Uuid,
+};
+
+use mentat_core::{
+    TypedValue,
+    ValueType,
+};
+
+use mentat_query::{
+    Aggregate,
+    Binding,
+    Direction,
+    Element,
+    FindQuery,
+    FindSpec,
+    FnArg,
+    IdentOrEntid,
+    Limit,
+    LookupRef,
+    NonIntegerConstant,
+    NotJoin,
+    Offset,
+    Order,
+    OrJoin,
+    OrWhereClause,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    Predicate,
+    Pull,
+    PullAttributeSpec,
+    QueryFunction,
+    RuleExpr,
+    SrcVar,
+    ToEdn,
+    TypeAnnotation,
+    UnifyVars,
+    Variable,
+    VariableOrPlaceholder,
+    WhereClause,
+    WhereFn,
+};
+
+use mentat_query_parser::parse_find_string;
+
+#[test]
+fn test_print_find_query() {
+    let query = parse_find_string(r#"[:find ?x . :where [?x :foo/bar 1.0]]"#).expect("parsed");
+    assert_eq!(query.to_string(), "[ :find ?x . :where [ ?x :foo/bar 1.0 ] ]");
+
+    let query = parse_find_string(r#"[:find [?x ...]
+                                      :in ?a %
+                                      :with ?y
+                                      :limit ?a
+                                      :where [?x :foo/_parent ?y]
+                                             (or [?y :foo/baz "x"]
+                                                 (and [?y :foo/q ?q] [(> ?q 5)]))
+                                             (not-join [?x] [$ ?x :foo/hidden true ?tx])
+                                             [(ground [1 2]) [?z ...]]
+                                      :order (desc ?x)]"#).expect("parsed");
+    let printed = query.to_edn().to_pretty(60).unwrap();
+    assert_eq!(parse_find_string(&printed).expect("parsed"), query);
+    assert_eq!(parse_find_string(&query.to_string()).expect("parsed"), query);
+
+    let query = parse_find_string(r#"[:find ?x
+                                      :where [?x :foo/name "say \"hi\"\n"]
+                                             [?x :foo/id #uuid "4cb3f828-752d-497a-90c9-b1fd516d5644"]
+                                             [$history ?x :foo/bar _ _ false]]"#).expect("parsed");
+    assert_eq!(parse_find_string(&query.to_string()).expect("parsed"), query);
+    assert_eq!(parse_find_string(&query.to_edn().to_pretty(40).unwrap()).expect("parsed"), query);
+}
+
+fn variable() -> BoxedStrategy<Variable> {
+    "[a-z][a-z0-9]{0,4}".prop_map(|name| Variable::from_valid_name(&format!("?{}", name))).boxed()
+}
+
+fn keyword() -> BoxedStrategy<NamespacedKeyword> {
+    ("[a-z]{1,4}", "[a-z][a-z0-9]{0,4}").prop_map(|(ns, name)| NamespacedKeyword::new(ns, name)).boxed()
+}
+
+fn text() -> BoxedStrategy<String> {
+    // Any characters at all, including quotes, backslashes and control characters.
+    prop::collection::vec(any::<char>(), 0..8).prop_map(|chars| chars.into_iter().collect()).boxed()
+}
+
+fn uuid() -> BoxedStrategy<Uuid> {
+    any::<[u8; 16]>().prop_map(|bytes| Uuid::from_bytes(&bytes).expect("16 bytes")).boxed()
+}
+
+fn constant() -> BoxedStrategy<NonIntegerConstant> {
+    prop_oneof![
+        any::<bool>().prop_map(NonIntegerConstant::Boolean),
+        text().prop_map(|s| NonIntegerConstant::Text(Arc::new(s))),
+        uuid().prop_map(NonIntegerConstant::Uuid),
+        (-1.0e6f64..1.0e6f64).prop_map(|f| NonIntegerConstant::Float(OrderedFloat(f))),
+        (0i64..4_000_000_000_000_000i64).prop_map(|micros| NonIntegerConstant::Instant(DateTime::<Utc>::from_micros(micros))),
+    ].boxed()
+}
+
+fn lookup_ref() -> BoxedStrategy<LookupRef> {
+    let value = prop_oneof![
+        any::<i64>().prop_map(TypedValue::Long),
+        text().prop_map(|s| TypedValue::String(Rc::new(s))),
+        keyword().prop_map(|k| TypedValue::Keyword(Rc::new(k))),
+    ];
+    (keyword(), value).prop_map(|(attribute, value)| LookupRef {
+        attribute: IdentOrEntid::Ident(Rc::new(attribute)),
+        value: value,
+    }).boxed()
+}
+
+fn non_value_place() -> BoxedStrategy<PatternNonValuePlace> {
+    prop_oneof![
+        Just(PatternNonValuePlace::Placeholder),
+        variable().prop_map(PatternNonValuePlace::Variable),
+        (0i64..1_000_000).prop_map(PatternNonValuePlace::Entid),
+        keyword().prop_map(|k| PatternNonValuePlace::Ident(Rc::new(k))),
+        lookup_ref().prop_map(|r| PatternNonValuePlace::LookupRef(Rc::new(r))),
+    ].boxed()
+}
+
+fn value_place() -> BoxedStrategy<PatternValuePlace> {
+    prop_oneof![
+        Just(PatternValuePlace::Placeholder),
+        variable().prop_map(PatternValuePlace::Variable),
+        any::<i64>().prop_map(PatternValuePlace::EntidOrInteger),
+        keyword().prop_map(|k| PatternValuePlace::IdentOrKeyword(Rc::new(k))),
+        constant().prop_map(PatternValuePlace::Constant),
+    ].boxed()
+}
+
+fn fn_arg() -> BoxedStrategy<FnArg> {
+    prop_oneof![
+        variable().prop_map(FnArg::Variable),
+        any::<i64>().prop_map(FnArg::EntidOrInteger),
+        keyword().prop_map(FnArg::IdentOrKeyword),
+        "[a-z]{1,5}".prop_map(|k| FnArg::Keyword(Keyword::new(k))),
+        constant().prop_map(FnArg::Constant),
+    ].boxed()
+}
+
+fn variable_or_placeholder() -> BoxedStrategy<VariableOrPlaceholder> {
+    prop_oneof![
+        Just(VariableOrPlaceholder::Placeholder),
+        variable().prop_map(VariableOrPlaceholder::Variable),
+    ].boxed()
+}
+
+fn binding() -> BoxedStrategy<Binding> {
+    prop_oneof![
+        variable().prop_map(Binding::BindScalar),
+        variable().prop_map(Binding::BindColl),
+        prop::collection::vec(variable_or_placeholder(), 1..3).prop_map(Binding::BindTuple),
+        prop::collection::vec(variable_or_placeholder(), 1..3).prop_map(Binding::BindRel),
+    ].boxed()
+}
+
+fn pattern() -> BoxedStrategy<WhereClause> {
+    let source = prop_oneof![Just(None), Just(Some(SrcVar::DefaultSrc)), Just(Some(SrcVar::history()))];
+    let added = prop_oneof![
+        Just(PatternValuePlace::Placeholder),
+        variable().prop_map(PatternValuePlace::Variable),
+        any::<bool>().prop_map(|b| PatternValuePlace::Constant(NonIntegerConstant::Boolean(b))),
+    ];
+    (source, non_value_place(), non_value_place(), value_place(), non_value_place(), added)
+        .prop_map(|(source, e, a, v, tx, added)| {
+            // Only the history can have an `added` place.
+            let added = if source.as_ref().map_or(false, |s| s.is_history()) {
+                added
+            } else {
+                PatternValuePlace::Placeholder
+            };
+            // None of our keywords are reversed, so this always succeeds.
+            let pattern = Pattern::new(source, e, a, v, tx).unwrap();
+            WhereClause::Pattern(Pattern { added: added, ..pattern })
+        })
+        .boxed()
+}
+
+fn leaf_clause() -> BoxedStrategy<WhereClause> {
+    let operator = prop_oneof![Just("<"), Just(">"), Just("<="), Just(">="), Just("!=")];
+    let pred = (operator, prop::collection::vec(fn_arg(), 1..3))
+        .prop_map(|(operator, args)| WhereClause::Pred(Predicate {
+            operator: PlainSymbol::new(operator),
+            args: args,
+        }));
+    let where_fn = ("fn[a-z]{0,3}", prop::collection::vec(fn_arg(), 0..3), binding())
+        .prop_map(|(operator, args, binding)| WhereClause::WhereFn(WhereFn {
+            operator: PlainSymbol::new(operator),
+            args: args,
+            binding: binding,
+        }));
+    let value_type = prop_oneof![
+        Just(ValueType::Ref),
+        Just(ValueType::Boolean),
+        Just(ValueType::Instant),
+        Just(ValueType::Long),
+        Just(ValueType::Double),
+        Just(ValueType::String),
+        Just(ValueType::Keyword),
+        Just(ValueType::Uuid),
+    ];
+    let type_annotation = (value_type, variable())
+        .prop_map(|(value_type, variable)| WhereClause::TypeAnnotation(TypeAnnotation {
+            value_type: value_type,
+            variable: variable,
+        }));
+    let rule_expr = ("rule[a-z]{0,3}", prop::collection::vec(fn_arg(), 1..3))
+        .prop_map(|(name, args)| WhereClause::RuleExpr(RuleExpr {
+            name: PlainSymbol::new(name),
+            args: args,
+        }));
+    prop_oneof![pattern(), pred, where_fn, type_annotation, rule_expr].boxed()
+}
+
+fn unify_vars() -> BoxedStrategy<UnifyVars> {
+    prop_oneof![
+        Just(UnifyVars::Implicit),
+        prop::collection::btree_set(variable(), 1..3).prop_map(UnifyVars::Explicit),
+    ].boxed()
+}
+
+fn clause() -> BoxedStrategy<WhereClause> {
+    leaf_clause().prop_recursive(2, 12, 3, |inner| {
+        let arm = prop_oneof![
+            inner.clone().prop_map(OrWhereClause::Clause),
+            prop::collection::vec(inner.clone(), 1..3).prop_map(OrWhereClause::And),
+        ];
+        prop_oneof![
+            (unify_vars(), prop::collection::vec(arm, 1..3))
+                .prop_map(|(unify_vars, arms)| WhereClause::OrJoin(OrJoin::new(unify_vars, arms))),
+            (unify_vars(), prop::collection::vec(inner.clone(), 1..3))
+                .prop_map(|(unify_vars, clauses)| WhereClause::NotJoin(NotJoin {
+                    unify_vars: unify_vars,
+                    clauses: clauses,
+                })),
+        ]
+    }).boxed()
+}
+
+fn pull_attribute_spec() -> BoxedStrategy<PullAttributeSpec> {
+    let leaf = prop_oneof![
+        Just(PullAttributeSpec::Wildcard),
+        keyword().prop_map(PullAttributeSpec::Attribute),
+    ];
+    leaf.prop_recursive(2, 8, 2, |inner| {
+        (keyword(), prop::collection::vec(inner, 1..3))
+            .prop_map(|(attribute, patterns)| PullAttributeSpec::Nested(attribute, patterns))
+    }).boxed()
+}
+
+fn element() -> BoxedStrategy<Element> {
+    let func = prop_oneof![Just("count"), Just("max"), Just("min"), Just("sum")];
+    prop_oneof![
+        variable().prop_map(Element::Variable),
+        variable().prop_map(Element::Ident),
+        (func, variable()).prop_map(|(func, var)| Element::Aggregate(Aggregate {
+            func: QueryFunction(PlainSymbol::new(func)),
+            args: vec![FnArg::Variable(var)],
+        })),
+        (variable(), prop::collection::vec(pull_attribute_spec(), 1..3))
+            .prop_map(|(var, patterns)| Element::Pull(Pull {
+                var: var,
+                patterns: patterns,
+            })),
+    ].boxed()
+}
+
+fn find_spec() -> BoxedStrategy<FindSpec> {
+    prop_oneof![
+        prop::collection::vec(element(), 1..3).prop_map(FindSpec::FindRel),
+        element().prop_map(FindSpec::FindColl),
+        prop::collection::vec(element(), 1..3).prop_map(FindSpec::FindTuple),
+        element().prop_map(FindSpec::FindScalar),
+    ].boxed()
+}
+
+fn order() -> BoxedStrategy<Order> {
+    (any::<bool>(), variable())
+        .prop_map(|(asc, var)| Order(if asc { Direction::Ascending } else { Direction::Descending }, var))
+        .boxed()
+}
+
+fn find_query() -> BoxedStrategy<FindQuery> {
+    let inputs = (prop::collection::btree_set(variable(), 0..3), any::<bool>(), prop::collection::btree_set(variable(), 0..3));
+    let paging = (0u8..3, 1u64..100, 0u8..3, 0u64..100);
+    let body = (prop::collection::vec(clause(), 1..4), prop::option::of(prop::collection::vec(order(), 1..3)));
+    (find_spec(), inputs, paging, body)
+        .prop_map(|(find_spec, (in_vars, in_rules, with), (limit, limit_n, offset, offset_n), (where_clauses, order))| {
+            // A variable limit or offset has to be one of the inputs.
+            let in_var = in_vars.iter().next().cloned();
+            let limit = match (limit, in_var.clone()) {
+                (0, _) => Limit::None,
+                (2, Some(var)) => Limit::Variable(var),
+                _ => Limit::Fixed(limit_n),
+            };
+            let offset = match (offset, in_var) {
+                (0, _) => Offset::None,
+                (2, Some(var)) => Offset::Variable(var),
+                _ => Offset::Fixed(offset_n),
+            };
+            FindQuery {
+                find_spec: find_spec,
+                default_source: SrcVar::DefaultSrc,
+                with: with,
+                in_vars: in_vars,
+                in_sources: BTreeSet::default(),
+                in_rules: in_rules,
+                limit: limit,
+                offset: offset,
+                where_clauses: where_clauses,
+                order: order,
+            }
+        })
+        .boxed()
+}
+
+proptest! {
+    #[test]
+    fn test_find_query_round_trips(ref query in find_query()) {
+        let printed = query.to_edn().to_string();
+        let parsed = parse_find_string(&printed);
+        prop_assert!(parsed.is_ok(), "couldn't parse {}", printed);
+        prop_assert_eq!(&parsed.unwrap(), query);
+    }
+
+    #[test]
+    fn test_pretty_find_query_round_trips(ref query in find_query()) {
+        let printed = query.to_edn().to_pretty(40).unwrap();
+        let parsed = parse_find_string(&printed);
+        prop_assert!(parsed.is_ok(), "couldn't parse {}", printed);
+        prop_assert_eq!(&parsed.unwrap(), query);
+    }
+}
@@ -19,8 +19,9 @@ extern crate mentat_query_algebrizer;
 extern crate mentat_query_sql;
 extern crate mentat_sql;
 
+use std::collections::BTreeSet;
 use std::iter;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use rusqlite::{
     Row,
@@ -28,6 +29,8 @@ use rusqlite::{
 };
 
 use mentat_core::{
+    Binding,
+    Schema,
     SQLValueType,
     TypedValue,
     ValueType,
@@ -42,6 +45,7 @@ use mentat_query::{
     Element,
     FindSpec,
     Limit,
+    Pull,
     Variable,
 };
 
@@ -50,10 +54,24 @@ use mentat_query_algebrizer::{
     ColumnName,
     ConjoiningClauses,
     VariableColumn,
+    simple_aggregate,
+};
+
+mod pull;
+
+use pull::{
+    PullTemplate,
+};
+
+pub use pull::{
+    ident_for_ref,
 };
 
 use mentat_query_sql::{
     ColumnOrExpression,
+    DatomsView,
+    Expression,
+    GroupBy,
     Name,
     Projection,
     ProjectedColumn,
@@ -70,6 +88,7 @@ error_chain! {
 
     links {
         DbError(mentat_db::Error, mentat_db::ErrorKind);
+        SQLError(mentat_sql::Error, mentat_sql::ErrorKind);
     }
 
     errors {
@@ -82,16 +101,16 @@ error_chain! {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct QueryOutput {
-    pub spec: Rc<FindSpec>,
+    pub spec: Arc<FindSpec>,
     pub results: QueryResults,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum QueryResults {
-    Scalar(Option<TypedValue>),
-    Tuple(Option<Vec<TypedValue>>),
-    Coll(Vec<TypedValue>),
-    Rel(Vec<Vec<TypedValue>>),
+    Scalar(Option<Binding>),
+    Tuple(Option<Vec<Binding>>),
+    Coll(Vec<Binding>),
+    Rel(Vec<Vec<Binding>>),
 }
 
 impl From<QueryOutput> for QueryResults {
@@ -101,7 +120,7 @@ impl From<QueryOutput> for QueryResults {
 }
 
 impl QueryOutput {
-    pub fn empty_factory(spec: &FindSpec) -> Box<Fn() -> QueryResults> {
+    pub fn empty_factory(spec: &FindSpec) -> Box<Fn() -> QueryResults + Send + Sync> {
         use self::FindSpec::*;
         match spec {
             &FindScalar(_) => Box::new(|| QueryResults::Scalar(None)),
@@ -119,7 +138,7 @@ impl QueryOutput {
         self.results.is_empty()
     }
 
-    pub fn empty(spec: &Rc<FindSpec>) -> QueryOutput {
+    pub fn empty(spec: &Arc<FindSpec>) -> QueryOutput {
         use self::FindSpec::*;
         let results =
             match &**spec {
@@ -134,19 +153,19 @@ impl QueryOutput {
         }
     }
 
-    pub fn into_scalar(self) -> Result<Option<TypedValue>> {
+    pub fn into_scalar(self) -> Result<Option<Binding>> {
         self.results.into_scalar()
     }
 
-    pub fn into_coll(self) -> Result<Vec<TypedValue>> {
+    pub fn into_coll(self) -> Result<Vec<Binding>> {
         self.results.into_coll()
     }
 
-    pub fn into_tuple(self) -> Result<Option<Vec<TypedValue>>> {
+    pub fn into_tuple(self) -> Result<Option<Vec<Binding>>> {
         self.results.into_tuple()
     }
 
-    pub fn into_rel(self) -> Result<Vec<Vec<TypedValue>>> {
+    pub fn into_rel(self) -> Result<Vec<Vec<Binding>>> {
         self.results.into_rel()
     }
 }
@@ -172,7 +191,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_scalar(self) -> Result<Option<TypedValue>> {
+    pub fn into_scalar(self) -> Result<Option<Binding>> {
         match self {
             QueryResults::Scalar(o) => Ok(o),
             QueryResults::Coll(_) => bail!(ErrorKind::UnexpectedResultsType("coll", "scalar")),
@@ -181,7 +200,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_coll(self) -> Result<Vec<TypedValue>> {
+    pub fn into_coll(self) -> Result<Vec<Binding>> {
         match self {
             QueryResults::Scalar(_) => bail!(ErrorKind::UnexpectedResultsType("scalar", "coll")),
             QueryResults::Coll(c) => Ok(c),
@@ -190,7 +209,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_tuple(self) -> Result<Option<Vec<TypedValue>>> {
+    pub fn into_tuple(self) -> Result<Option<Vec<Binding>>> {
         match self {
             QueryResults::Scalar(_) => bail!(ErrorKind::UnexpectedResultsType("scalar", "tuple")),
             QueryResults::Coll(_) => bail!(ErrorKind::UnexpectedResultsType("coll", "tuple")),
@@ -199,7 +218,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_rel(self) -> Result<Vec<Vec<TypedValue>>> {
+    pub fn into_rel(self) -> Result<Vec<Vec<Binding>>> {
         match self {
             QueryResults::Scalar(_) => bail!(ErrorKind::UnexpectedResultsType("scalar", "rel")),
             QueryResults::Coll(_) => bail!(ErrorKind::UnexpectedResultsType("coll", "rel")),
@@ -230,18 +249,18 @@ impl TypedIndex {
     ///
     /// This function will return a runtime error if the type code is unknown, or the value is
     /// otherwise not convertible by the DB layer.
-    fn lookup<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<TypedValue> {
+    fn lookup<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Binding> {
         use TypedIndex::*;
 
         match self {
             &Known(value_index, value_type) => {
                 let v: rusqlite::types::Value = row.get(value_index);
-                TypedValue::from_sql_value_pair(v, value_type).map_err(|e| e.into())
+                TypedValue::from_sql_value_pair(v, value_type).map(Binding::Scalar).map_err(|e| e.into())
             },
             &Unknown(value_index, type_index) => {
                 let v: rusqlite::types::Value = row.get(value_index);
                 let value_type_tag: i32 = row.get(type_index);
-                TypedValue::from_sql_value_pair(v, value_type_tag).map_err(|e| e.into())
+                TypedValue::from_sql_value_pair(v, value_type_tag).map(Binding::Scalar).map_err(|e| e.into())
             },
         }
     }
@@ -284,9 +303,51 @@ pub fn projected_column_for_var(var: &Variable, cc: &ConjoiningClauses) -> (Proj
     }
 }
 
+/// The SQL projection and projector templates for the elements of a find spec.
+struct ProjectedElements {
+    sql_projection: Projection,
+    pre_aggregate_projection: Option<Projection>,
+    templates: Vec<TypedIndex>,
+    pulls: Vec<PullTemplate>,
+    group_by: Vec<GroupBy>,
+    nullable_aggregates: Vec<Name>,
+}
+
+impl ProjectedElements {
+    fn simple(sql_projection: Projection, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> ProjectedElements {
+        ProjectedElements {
+            sql_projection: sql_projection,
+            pre_aggregate_projection: None,
+            templates: templates,
+            pulls: pulls,
+            group_by: vec![],
+            nullable_aggregates: vec![],
+        }
+    }
+
+    fn take_templates(&mut self) -> Vec<TypedIndex> {
+        ::std::mem::replace(&mut self.templates, vec![])
+    }
+
+    fn take_pulls(&mut self) -> Vec<PullTemplate> {
+        ::std::mem::replace(&mut self.pulls, vec![])
+    }
+
+    fn combine(self, projector: Box<Projector>, distinct: bool) -> CombinedProjection {
+        CombinedProjection {
+            sql_projection: self.sql_projection,
+            pre_aggregate_projection: self.pre_aggregate_projection,
+            datalog_projector: projector,
+            distinct: distinct,
+            group_by: self.group_by,
+            nullable_aggregates: self.nullable_aggregates,
+        }
+    }
+}
+
 /// Walk an iterator of `Element`s, collecting projector templates and columns.
 ///
-/// Returns a pair: the SQL projection (which should always be a `Projection::Columns`)
+/// Returns the SQL projection (which should always be a `Projection::Columns`)
 /// and a `Vec` of `TypedIndex` 'keys' to use when looking up values.
 ///
 /// Callers must ensure that every `Element` is distinct -- a query like
@@ -299,37 +360,64 @@ pub fn projected_column_for_var(var: &Variable, cc: &ConjoiningClauses) -> (Proj
 fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
     count: usize,
     elements: I,
-    query: &AlgebraicQuery) -> Result<(Projection, Vec<TypedIndex>)> {
+    query: &AlgebraicQuery,
+    view: Option<DatomsView>) -> Result<ProjectedElements> {
+
+    if query.has_aggregates {
+        return project_aggregate_elements(count, elements, query, view);
+    }
 
     let mut cols = Vec::with_capacity(count);
     let mut i: i32 = 0;
     let mut templates = vec![];
+    let mut pulls = vec![];
     let mut with = query.with.clone();
 
-    for e in elements {
-        match e {
-            // Each time we come across a variable, we push a SQL column
-            // into the SQL projection, aliased to the name of the variable,
-            // and we push an annotated index into the projector.
-            &Element::Variable(ref var) => {
-                // If we're projecting this, we don't need it in :with.
-                with.remove(var);
-
-                let (projected_column, maybe_type) = projected_column_for_var(&var, &query.cc);
-                cols.push(projected_column);
-                if let Some(ty) = maybe_type {
-                    let tag = ty.value_type_tag();
-                    templates.push(TypedIndex::Known(i, tag));
-                    i += 1;     // We used one SQL column.
-                } else {
-                    templates.push(TypedIndex::Unknown(i, i + 1));
-                    i += 2;     // We used two SQL columns.
-
-                    // Also project the type from the SQL query.
-                    let (type_column, type_name) = candidate_type_column(&query.cc, &var);
-                    cols.push(ProjectedColumn(type_column, type_name));
-                }
-            }
+    for (index, e) in elements.into_iter().enumerate() {
+        // A pull expression projects its entity just like a variable; the entity is replaced by
+        // the pulled map once all of the rows have been fetched. An ident expression is the same,
+        // but replaces the entity with its ident.
+        let var = match e {
+            &Element::Variable(ref var) => var,
+            &Element::Pull(ref pull) => {
+                pulls.push(PullTemplate::Pull {
+                    index: index,
+                    patterns: pull.patterns.clone(),
+                    view: view,
+                });
+                &pull.var
+            },
+            &Element::Ident(ref var) => {
+                pulls.push(PullTemplate::Ident {
+                    index: index,
+                });
+                var
+            },
+            &Element::Aggregate(_) => {
+                unreachable!("Aggregates are handled by project_aggregate_elements.");
+            },
+        };
+
+        // Each time we come across a variable, we push a SQL column
+        // into the SQL projection, aliased to the name of the variable,
+        // and we push an annotated index into the projector.
+
+        // If we're projecting this, we don't need it in :with.
+        with.remove(var);
+
+        let (projected_column, maybe_type) = projected_column_for_var(&var, &query.cc);
+        cols.push(projected_column);
+        if let Some(ty) = maybe_type {
+            let tag = ty.value_type_tag();
+            templates.push(TypedIndex::Known(i, tag));
+            i += 1;     // We used one SQL column.
+        } else {
+            templates.push(TypedIndex::Unknown(i, i + 1));
+            i += 2;     // We used two SQL columns.
+
+            // Also project the type from the SQL query.
+            let (type_column, type_name) = candidate_type_column(&query.cc, &var);
+            cols.push(ProjectedColumn(type_column, type_name));
         }
     }
 
@@ -345,22 +433,172 @@ fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
         }
     }
 
-    Ok((Projection::Columns(cols), templates))
+    Ok(ProjectedElements::simple(Projection::Columns(cols), templates, pulls))
+}
+
+/// Walk an iterator of `Element`s, at least one of which is an aggregate, collecting projector
+/// templates and two levels of SQL projection.
+///
+/// Aggregates are computed over the distinct bindings of every variable mentioned in the find
+/// spec and in `:with`. The inner projection selects those bindings; the outer projection
+/// refers to the inner columns by name, computing each aggregate and grouping by the
+/// non-aggregated variables:
+///
+/// ```sql
+/// SELECT `?x` AS `?x`, max(`?y`) AS `(max ?y)`
+/// FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` FROM …)
+/// GROUP BY `?x`
+/// ```
+fn project_aggregate_elements<'a, I: IntoIterator<Item = &'a Element>>(
+    count: usize,
+    elements: I,
+    query: &AlgebraicQuery,
+    view: Option<DatomsView>) -> Result<ProjectedElements> {
+
+    let mut inner_cols: Vec<ProjectedColumn> = Vec::with_capacity(count);
+    let mut inner_vars: BTreeSet<Variable> = BTreeSet::new();
+    let mut outer_cols: Vec<ProjectedColumn> = Vec::with_capacity(count);
+    let mut templates = vec![];
+    let mut pulls = vec![];
+    let mut group_by = vec![];
+    let mut nullable_aggregates = vec![];
+    let mut i: i32 = 0;
+
+    {
+        // Each variable is projected once by the inner query, together with its type tag if we
+        // don't know its type -- otherwise DISTINCT would unify values across types.
+        let mut project_inner = |var: &Variable| {
+            if inner_vars.insert(var.clone()) {
+                let (projected_column, maybe_type) = projected_column_for_var(var, &query.cc);
+                inner_cols.push(projected_column);
+                if maybe_type.is_none() {
+                    let (type_column, type_name) = candidate_type_column(&query.cc, var);
+                    inner_cols.push(ProjectedColumn(type_column, type_name));
+                }
+            }
+        };
+
+        for (index, e) in elements.into_iter().enumerate() {
+            // We group by a pulled entity just as we do by a plain variable, and pull from it once
+            // all of the rows have been fetched.
+            match e {
+                &Element::Pull(ref pull) => {
+                    pulls.push(PullTemplate::Pull {
+                        index: index,
+                        patterns: pull.patterns.clone(),
+                        view: view,
+                    });
+                },
+                &Element::Ident(_) => {
+                    pulls.push(PullTemplate::Ident {
+                        index: index,
+                    });
+                },
+                _ => (),
+            }
+
+            match e {
+                &Element::Variable(ref var) |
+                &Element::Ident(ref var) |
+                &Element::Pull(Pull { ref var, .. }) => {
+                    project_inner(var);
+
+                    let name = VariableColumn::Variable(var.clone()).column_name();
+                    outer_cols.push(ProjectedColumn(ColumnOrExpression::ExistingColumn(name.clone()), name.clone()));
+                    group_by.push(GroupBy::ProjectedColumn(name));
+
+                    if let Some(ty) = query.cc.known_type(var) {
+                        templates.push(TypedIndex::Known(i, ty.value_type_tag()));
+                        i += 1;
+                    } else {
+                        templates.push(TypedIndex::Unknown(i, i + 1));
+                        i += 2;
+
+                        let type_name = VariableColumn::VariableTypeTag(var.clone()).column_name();
+                        outer_cols.push(ProjectedColumn(ColumnOrExpression::ExistingColumn(type_name.clone()), type_name.clone()));
+                        group_by.push(GroupBy::ProjectedColumn(type_name));
+                    }
+                },
+                &Element::Aggregate(ref aggregate) => {
+                    // The algebrizer has already checked that each aggregate is well-formed and
+                    // applicable to the types of its variable.
+                    let (op, var) = simple_aggregate(aggregate).expect("aggregate to be validated");
+                    let result_type = op.result_type(query.cc.known_type_set(&var))
+                                        .expect("aggregate to be applicable");
+                    project_inner(&var);
+
+                    let expression = Expression::Aggregate {
+                        sql_op: op.to_sql_function(),
+                        distinct: op.is_distinct(),
+                        arg: ColumnOrExpression::ExistingColumn(VariableColumn::Variable(var).column_name()),
+                    };
+                    let name = aggregate.to_string();
+                    outer_cols.push(ProjectedColumn(ColumnOrExpression::Expression(Box::new(expression)), name.clone()));
+                    templates.push(TypedIndex::Known(i, result_type.value_type_tag()));
+                    i += 1;
+
+                    if op.is_nullable() {
+                        nullable_aggregates.push(name);
+                    }
+                },
+            }
+        }
+
+        for var in query.with.iter() {
+            project_inner(var);
+        }
+    }
+
+    // Without any groups, SQLite produces one row even when there's nothing to aggregate; all but
+    // the counts will be NULL. Those are the rows to filter out. With groups, every group has at
+    // least one row, and so aggregates are never NULL.
+    if !group_by.is_empty() {
+        nullable_aggregates.clear();
+    }
+
+    Ok(ProjectedElements {
+        sql_projection: Projection::Columns(outer_cols),
+        pre_aggregate_projection: Some(Projection::Columns(inner_cols)),
+        templates: templates,
+        pulls: pulls,
+        group_by: group_by,
+        nullable_aggregates: nullable_aggregates,
+    })
 }
 
-pub trait Projector {
-    fn project<'stmt>(&self, rows: Rows<'stmt>) -> Result<QueryOutput>;
+/// Projectors are `Send` and `Sync` so that a translated query can be shared between threads.
+pub trait Projector: Send + Sync {
+    /// Consume `rows` to produce results. Pull expressions fetch more data from `sqlite`, using
+    /// `schema` to interpret it.
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, rows: Rows<'stmt>) -> Result<QueryOutput>;
+
+    /// Produce the bindings for a single row: one for each element of the find spec. This is the
+    /// same decoding that `project` does, but lets callers consume rows as SQLite produces them
+    /// rather than collecting every row first. Pull expressions are applied to each row in turn.
+    fn project_row<'a, 'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>>;
+}
+
+/// Apply each pull template to its column of `rows`.
+fn pull_rows(schema: &Schema,
+             sqlite: &rusqlite::Connection,
+             pulls: &[PullTemplate],
+             rows: &mut Vec<Vec<Binding>>) -> Result<()> {
+    for pull in pulls {
+        let bindings = rows.iter_mut().map(|row| &mut row[pull.index()]).collect();
+        pull.pull(schema, sqlite, bindings)?;
+    }
+    Ok(())
 }
 
 /// A projector that produces a `QueryResult` containing fixed data.
 /// Takes a boxed function that should return an empty result set of the desired type.
 struct ConstantProjector {
-    spec: Rc<FindSpec>,
-    results_factory: Box<Fn() -> QueryResults>,
+    spec: Arc<FindSpec>,
+    results_factory: Box<Fn() -> QueryResults + Send + Sync>,
 }
 
 impl ConstantProjector {
-    fn new(spec: Rc<FindSpec>, results_factory: Box<Fn() -> QueryResults>) -> ConstantProjector {
+    fn new(spec: Arc<FindSpec>, results_factory: Box<Fn() -> QueryResults + Send + Sync>) -> ConstantProjector {
         ConstantProjector {
             spec: spec,
             results_factory: results_factory,
@@ -369,7 +607,7 @@ impl ConstantProjector {
 }
 
 impl Projector for ConstantProjector {
-    fn project<'stmt>(&self, _: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt>(&self, _: &Schema, _: &rusqlite::Connection, _: Rows<'stmt>) -> Result<QueryOutput> {
         let results = (self.results_factory)();
         let spec = self.spec.clone();
         Ok(QueryOutput {
@@ -377,38 +615,41 @@ impl Projector for ConstantProjector {
             results: results,
         })
     }
+
+    fn project_row<'a, 'stmt>(&self, _: &Schema, _: &rusqlite::Connection, _: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
+        // A known-empty query never runs SQL, and so never has any rows.
+        unreachable!("A constant projector has no rows.");
+    }
 }
 
 struct ScalarProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     template: TypedIndex,
+    pulls: Vec<PullTemplate>,
 }
 
 impl ScalarProjector {
-    fn with_template(spec: Rc<FindSpec>, template: TypedIndex) -> ScalarProjector {
+    fn with_template(spec: Arc<FindSpec>, template: TypedIndex, pulls: Vec<PullTemplate>) -> ScalarProjector {
         ScalarProjector {
             spec: spec,
             template: template,
+            pulls: pulls,
         }
     }
 
-    fn combine(spec: Rc<FindSpec>, sql: Projection, mut templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let template = templates.pop().expect("Expected a single template");
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(ScalarProjector::with_template(spec, template)),
-            distinct: false,
-        })
+    fn combine(spec: Arc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let template = elements.templates.pop().expect("Expected a single template");
+        let pulls = elements.take_pulls();
+        Ok(elements.combine(Box::new(ScalarProjector::with_template(spec, template, pulls)), false))
     }
 }
 
 impl Projector for ScalarProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let results =
             if let Some(r) = rows.next() {
                 let row = r?;
-                let binding = self.template.lookup(&row)?;
-                QueryResults::Scalar(Some(binding))
+                QueryResults::Scalar(self.project_row(schema, sqlite, &row)?.pop())
             } else {
                 QueryResults::Scalar(None)
             };
@@ -417,51 +658,56 @@ impl Projector for ScalarProjector {
             results: results,
         })
     }
+
+    fn project_row<'a, 'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
+        let mut binding = self.template.lookup(row)?;
+        for pull in self.pulls.iter() {
+            pull.pull(schema, sqlite, vec![&mut binding])?;
+        }
+        Ok(vec![binding])
+    }
 }
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
 struct TupleProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
+    pulls: Vec<PullTemplate>,
 }
 
 impl TupleProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> TupleProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> TupleProjector {
         TupleProjector {
             spec: spec,
             len: len,
             templates: templates,
+            pulls: pulls,
         }
     }
 
     // This is exactly the same as for rel.
-    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    fn collect_bindings<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
         // There will be at least as many SQL columns as Datalog columns.
         assert!(row.column_count() >= self.len as i32);
         self.templates
             .iter()
-            .map(|ti| ti.lookup(&row))
-            .collect::<Result<Vec<TypedValue>>>()
+            .map(|ti| ti.lookup(row))
+            .collect::<Result<Vec<Binding>>>()
     }
 
-    fn combine(spec: Rc<FindSpec>, column_count: usize, sql: Projection, templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let p = TupleProjector::with_templates(spec, column_count, templates);
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(p),
-            distinct: false,
-        })
+    fn combine(spec: Arc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let p = TupleProjector::with_templates(spec, column_count, elements.take_templates(), elements.take_pulls());
+        Ok(elements.combine(Box::new(p), false))
     }
 }
 
 impl Projector for TupleProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
         let results =
             if let Some(r) = rows.next() {
                 let row = r?;
-                let bindings = self.collect_bindings(row)?;
-                QueryResults::Tuple(Some(bindings))
+                QueryResults::Tuple(Some(self.project_row(schema, sqlite, &row)?))
             } else {
                 QueryResults::Tuple(None)
             };
@@ -470,6 +716,12 @@ impl Projector for TupleProjector {
             results: results,
         })
     }
+
+    fn project_row<'a, 'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
+        let mut bindings = vec![self.collect_bindings(row)?];
+        pull_rows(schema, sqlite, &self.pulls, &mut bindings)?;
+        Ok(bindings.pop().expect("one row"))
+    }
 }
 
 /// A rel projector produces a vector of vectors.
@@ -478,92 +730,107 @@ impl Projector for TupleProjector {
 /// Each column in the inner vector is the result of taking one or two columns from
 /// the `Row`: one for the value and optionally one for the type tag.
 struct RelProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
+    pulls: Vec<PullTemplate>,
 }
 
 impl RelProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> RelProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>, pulls: Vec<PullTemplate>) -> RelProjector {
         RelProjector {
             spec: spec,
             len: len,
             templates: templates,
+            pulls: pulls,
         }
     }
 
-    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    fn collect_bindings<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
         // There will be at least as many SQL columns as Datalog columns.
         assert!(row.column_count() >= self.len as i32);
         self.templates
             .iter()
-            .map(|ti| ti.lookup(&row))
-            .collect::<Result<Vec<TypedValue>>>()
+            .map(|ti| ti.lookup(row))
+            .collect::<Result<Vec<Binding>>>()
     }
 
-    fn combine(spec: Rc<FindSpec>, column_count: usize, sql: Projection, templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let p = RelProjector::with_templates(spec, column_count, templates);
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(p),
-            distinct: true,
-        })
+    fn combine(spec: Arc<FindSpec>, column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let p = RelProjector::with_templates(spec, column_count, elements.take_templates(), elements.take_pulls());
+        Ok(elements.combine(Box::new(p), true))
     }
 }
 
 impl Projector for RelProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
-        let mut out: Vec<Vec<TypedValue>> = vec![];
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+        let mut out: Vec<Vec<Binding>> = vec![];
         while let Some(r) = rows.next() {
             let row = r?;
-            let bindings = self.collect_bindings(row)?;
+            let bindings = self.collect_bindings(&row)?;
             out.push(bindings);
         }
+        pull_rows(schema, sqlite, &self.pulls, &mut out)?;
         Ok(QueryOutput {
             spec: self.spec.clone(),
             results: QueryResults::Rel(out),
         })
     }
+
+    fn project_row<'a, 'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
+        let mut bindings = vec![self.collect_bindings(row)?];
+        pull_rows(schema, sqlite, &self.pulls, &mut bindings)?;
+        Ok(bindings.pop().expect("one row"))
+    }
 }
 
 /// A coll projector produces a vector of values.
 /// Each value is sourced from the same column.
 struct CollProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     template: TypedIndex,
+    pulls: Vec<PullTemplate>,
 }
 
 impl CollProjector {
-    fn with_template(spec: Rc<FindSpec>, template: TypedIndex) -> CollProjector {
+    fn with_template(spec: Arc<FindSpec>, template: TypedIndex, pulls: Vec<PullTemplate>) -> CollProjector {
         CollProjector {
             spec: spec,
             template: template,
+            pulls: pulls,
         }
     }
 
-    fn combine(spec: Rc<FindSpec>, sql: Projection, mut templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let template = templates.pop().expect("Expected a single template");
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(CollProjector::with_template(spec, template)),
-            distinct: true,
-        })
+    fn combine(spec: Arc<FindSpec>, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let template = elements.templates.pop().expect("Expected a single template");
+        let pulls = elements.take_pulls();
+        Ok(elements.combine(Box::new(CollProjector::with_template(spec, template, pulls)), true))
     }
 }
 
 impl Projector for CollProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
-        let mut out: Vec<TypedValue> = vec![];
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryOutput> {
+        let mut out: Vec<Binding> = vec![];
         while let Some(r) = rows.next() {
             let row = r?;
             let binding = self.template.lookup(&row)?;
             out.push(binding);
         }
+        for pull in self.pulls.iter() {
+            pull.pull(schema, sqlite, out.iter_mut().collect())?;
+        }
         Ok(QueryOutput {
             spec: self.spec.clone(),
             results: QueryResults::Coll(out),
         })
     }
+
+    fn project_row<'a, 'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, row: &Row<'a, 'stmt>) -> Result<Vec<Binding>> {
+        let mut binding = self.template.lookup(row)?;
+        for pull in self.pulls.iter() {
+            pull.pull(schema, sqlite, vec![&mut binding])?;
+        }
+        Ok(vec![binding])
+    }
 }
 
 /// Combines the two things you need to turn a query into SQL and turn its results into
@@ -579,6 +846,17 @@ pub struct CombinedProjection {
 
     /// True if this query requires the SQL query to include DISTINCT.
     pub distinct: bool,
+
+    /// If the query aggregates, this is the projection of an inner `SELECT DISTINCT` query that
+    /// collects the bindings to aggregate. `sql_projection` then refers to its columns by name.
+    pub pre_aggregate_projection: Option<Projection>,
+
+    /// The columns of the inner query by which to group when aggregating.
+    pub group_by: Vec<GroupBy>,
+
+    /// Aggregate columns in `sql_projection` that are `NULL` when there is nothing to aggregate.
+    /// Rows in which these are `NULL` should be discarded.
+    pub nullable_aggregates: Vec<Name>,
 }
 
 impl CombinedProjection {
@@ -593,12 +871,12 @@ impl CombinedProjection {
 /// Compute a suitable SQL projection for an algebrized query.
 /// This takes into account a number of things:
 /// - The variable list in the find spec.
-/// - The presence of any aggregate operations in the find spec. TODO: for now we only handle
-///   simple variables
+/// - The presence of any aggregate operations in the find spec.
 /// - The bindings established by the topmost CC.
 /// - The types known at algebrizing time.
 /// - The types extracted from the store for unknown attributes.
-pub fn query_projection(query: &AlgebraicQuery) -> Result<CombinedProjection> {
+/// - The view of the store, if any, that the query reads, so that pull expressions read it too.
+pub fn query_projection(query: &AlgebraicQuery, view: Option<DatomsView>) -> Result<CombinedProjection> {
     use self::FindSpec::*;
 
     let spec = query.find_spec.clone();
@@ -610,29 +888,32 @@ pub fn query_projection(query: &AlgebraicQuery) -> Result<CombinedProjection> {
             sql_projection: Projection::One,
             datalog_projector: Box::new(constant_projector),
             distinct: false,
+            pre_aggregate_projection: None,
+            group_by: vec![],
+            nullable_aggregates: vec![],
         })
     } else {
         match *query.find_spec {
             FindColl(ref element) => {
-                let (cols, templates) = project_elements(1, iter::once(element), query)?;
-                CollProjector::combine(spec, cols, templates).map(|p| p.flip_distinct_for_limit(&query.limit))
+                let elements = project_elements(1, iter::once(element), query, view)?;
+                CollProjector::combine(spec, elements).map(|p| p.flip_distinct_for_limit(&query.limit))
             },
 
             FindScalar(ref element) => {
-                let (cols, templates) = project_elements(1, iter::once(element), query)?;
-                ScalarProjector::combine(spec, cols, templates)
+                let elements = project_elements(1, iter::once(element), query, view)?;
+                ScalarProjector::combine(spec, elements)
             },
 
             FindRel(ref elements) => {
                 let column_count = query.find_spec.expected_column_count();
-                let (cols, templates) = project_elements(column_count, elements, query)?;
-                RelProjector::combine(spec, column_count, cols, templates).map(|p| p.flip_distinct_for_limit(&query.limit))
+                let elements = project_elements(column_count, elements, query, view)?;
+                RelProjector::combine(spec, column_count, elements).map(|p| p.flip_distinct_for_limit(&query.limit))
             },
 
             FindTuple(ref elements) => {
                 let column_count = query.find_spec.expected_column_count();
-                let (cols, templates) = project_elements(column_count, elements, query)?;
-                TupleProjector::combine(spec, column_count, cols, templates)
+                let elements = project_elements(column_count, elements, query, view)?;
+                TupleProjector::combine(spec, column_count, elements)
             },
         }
     }
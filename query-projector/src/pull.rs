@@ -0,0 +1,373 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Pull expressions, like `(pull ?e [:foo/name {:foo/child [:bar/x]} :foo/_parent *])`.
+//!
+//! The main query projects the entity bound to the pulled variable. Once every row has been
+//! collected, we walk the pull pattern one level at a time: each attribute at a level costs a
+//! single SQL query, whichever and however many entities we're pulling. Nested patterns and
+//! component attributes recurse into the next level with the set of entities they refer to.
+//!
+//! A ref with no pattern to pull from it is usually rendered as `{:db/id …}`, but a ref to an
+//! entity that has an ident -- typically an enum value, like `:color/red` -- is rendered as the
+//! ident. `(ident ?v)` in a find spec does the same for the values bound to `?v`.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use std::rc::Rc;
+
+use rusqlite;
+
+use mentat_core::{
+    Binding,
+    Entid,
+    HasSchema,
+    NamespacedKeyword,
+    Schema,
+    SQLValueType,
+    StructuredMap,
+    TypedValue,
+    ValueType,
+};
+
+use mentat_db::{
+    TypedSQLValue,
+};
+
+use mentat_query::{
+    PullAttributeSpec,
+};
+
+use mentat_query_algebrizer::{
+    DatomsTable,
+    SourceAlias,
+};
+
+use mentat_query_sql::{
+    DatomsView,
+    TableOrSubquery,
+};
+
+use mentat_sql::{
+    QueryBuilder,
+    QueryFragment,
+    SQLiteQueryBuilder,
+};
+
+use super::Result;
+
+/// Component attributes are pulled recursively. Well-formed data can't contain a cycle of
+/// components, but we don't want a bad store to send us into a loop: beyond this depth, components
+/// are pulled as `{:db/id …}`, just like any other ref.
+const MAX_COMPONENT_DEPTH: usize = 32;
+
+/// A pull expression or an ident expression in a find spec, to be applied to the entities bound
+/// in its column.
+pub enum PullTemplate {
+    /// `(pull ?e [...])`.
+    Pull {
+        /// The position of the pulled element in each row of results.
+        index: usize,
+        patterns: Vec<PullAttributeSpec>,
+        /// The view of the store that the query reads, if not its current state.
+        view: Option<DatomsView>,
+    },
+
+    /// `(ident ?e)`.
+    Ident {
+        index: usize,
+    },
+}
+
+impl PullTemplate {
+    pub fn index(&self) -> usize {
+        match self {
+            &PullTemplate::Pull { index, .. } |
+            &PullTemplate::Ident { index } => index,
+        }
+    }
+
+    /// Replace each entity in `bindings` with the map pulled from it, or with its ident. Bindings
+    /// that aren't entities, or that don't have an ident, are left alone.
+    pub fn pull(&self,
+                schema: &Schema,
+                sqlite: &rusqlite::Connection,
+                bindings: Vec<&mut Binding>) -> Result<()> {
+        let (patterns, view) = match self {
+            &PullTemplate::Pull { ref patterns, view, .. } => (patterns, view),
+            &PullTemplate::Ident { .. } => {
+                for binding in bindings {
+                    let ident = match *binding {
+                        Binding::Scalar(ref v) => ident_for_ref(schema, v),
+                        _ => None,
+                    };
+                    if let Some(ident) = ident {
+                        *binding = Binding::Scalar(ident);
+                    }
+                }
+                return Ok(());
+            },
+        };
+
+        let entities: BTreeSet<Entid> = bindings.iter()
+                                                .filter_map(|b| as_entity(b))
+                                                .collect();
+        let maps = pull_entities(schema, sqlite, view, patterns, &entities, 0)?;
+        for binding in bindings {
+            let pulled = as_entity(binding).and_then(|e| maps.get(&e).cloned());
+            if let Some(map) = pulled {
+                *binding = Binding::Map(map);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// If `value` is a ref to an entity that has an ident, return the ident as a keyword value.
+pub fn ident_for_ref(schema: &Schema, value: &TypedValue) -> Option<TypedValue> {
+    match value {
+        &TypedValue::Ref(e) => schema.get_ident(e).map(|ident| TypedValue::Keyword(Rc::new(ident.clone()))),
+        _ => None,
+    }
+}
+
+fn as_entity(binding: &Binding) -> Option<Entid> {
+    match binding {
+        &Binding::Scalar(TypedValue::Ref(e)) => Some(e),
+        _ => None,
+    }
+}
+
+fn db_id() -> NamespacedKeyword {
+    NamespacedKeyword::new("db", "id")
+}
+
+/// Render a set of entids as a SQL list. Entids are integers, so it's safe to inline them, and
+/// doing so avoids SQLite's limit on the number of bound parameters.
+fn entid_list(entities: &BTreeSet<Entid>) -> String {
+    entities.iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+}
+
+/// Name `table` for use in a `FROM` clause. In a view of the store, that's a subquery that
+/// reads the table as it appears in the view.
+fn table_sql(table: DatomsTable, view: Option<DatomsView>) -> Result<String> {
+    let alias = SourceAlias(table, table.name().to_string());
+    let source = match view {
+        Some(view) => TableOrSubquery::View(view, alias),
+        None => TableOrSubquery::Table(alias),
+    };
+    let mut builder = SQLiteQueryBuilder::new();
+    source.push_sql(&mut builder)?;
+    Ok(builder.finish().sql)
+}
+
+/// Run `sql`, which must select an entid, an attribute, a value, and a value type tag, in that
+/// order, collecting `(entid, value)` pairs by attribute.
+fn fetch(sqlite: &rusqlite::Connection,
+         sql: &str,
+         into: &mut BTreeMap<Entid, Vec<(Entid, TypedValue)>>) -> Result<()> {
+    let mut statement = sqlite.prepare(sql)?;
+    let mut rows = statement.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let e: Entid = row.get(0);
+        let a: Entid = row.get(1);
+        let v: rusqlite::types::Value = row.get(2);
+        let value_type_tag: i32 = row.get(3);
+        let value = TypedValue::from_sql_value_pair(v, value_type_tag)?;
+        into.entry(a).or_insert_with(Vec::new).push((e, value));
+    }
+    Ok(())
+}
+
+/// Pull `patterns` from each of `entities`, returning a map for each entity.
+fn pull_entities(schema: &Schema,
+                 sqlite: &rusqlite::Connection,
+                 view: Option<DatomsView>,
+                 patterns: &[PullAttributeSpec],
+                 entities: &BTreeSet<Entid>,
+                 depth: usize) -> Result<BTreeMap<Entid, Rc<StructuredMap>>> {
+    if entities.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let mut wildcard = false;
+    let mut include_id = false;
+
+    // Attribute entid -> nested pattern, if any.
+    let mut forward: BTreeMap<Entid, Option<&[PullAttributeSpec]>> = BTreeMap::new();
+    let mut reverse: BTreeMap<Entid, Option<&[PullAttributeSpec]>> = BTreeMap::new();
+
+    for pattern in patterns {
+        let (attribute, nested) = match pattern {
+            &PullAttributeSpec::Wildcard => {
+                wildcard = true;
+                continue;
+            },
+            &PullAttributeSpec::Attribute(ref attribute) => (attribute, None),
+            &PullAttributeSpec::Nested(ref attribute, ref nested) => (attribute, Some(nested.as_slice())),
+        };
+
+        if *attribute == db_id() {
+            include_id = true;
+            continue;
+        }
+
+        // The algebrizer checks pulled attributes against the schema, so an unknown attribute
+        // here can only have no values.
+        if let Some(unreversed) = attribute.unreversed() {
+            if let Some(entid) = schema.get_entid(&unreversed) {
+                reverse.insert(entid.0, nested);
+            }
+        } else if let Some(entid) = schema.get_entid(attribute) {
+            forward.insert(entid.0, nested);
+        }
+    }
+
+    let list = entid_list(entities);
+
+    // Attribute entid -> (entity, value) pairs.
+    let mut forward_values: BTreeMap<Entid, Vec<(Entid, TypedValue)>> = BTreeMap::new();
+    if wildcard {
+        include_id = true;
+        let sql = format!("SELECT e, a, v, value_type_tag FROM {} WHERE e IN ({})",
+                          table_sql(DatomsTable::AllDatoms, view)?, list);
+        fetch(sqlite, sql.as_str(), &mut forward_values)?;
+    } else {
+        for &a in forward.keys() {
+            let table = match schema.attribute_for_entid(a) {
+                Some(attribute) if attribute.fulltext => DatomsTable::FulltextDatoms,
+                _ => DatomsTable::Datoms,
+            };
+            let sql = format!("SELECT e, a, v, value_type_tag FROM {} WHERE a = {} AND e IN ({})",
+                              table_sql(table, view)?, a, list);
+            fetch(sqlite, sql.as_str(), &mut forward_values)?;
+        }
+    }
+
+    // Attribute entid -> (entity, referring entity) pairs. Refs are never fulltext.
+    let mut reverse_values: BTreeMap<Entid, Vec<(Entid, TypedValue)>> = BTreeMap::new();
+    for &a in reverse.keys() {
+        let sql = format!("SELECT v, a, e, {} FROM {} WHERE a = {} AND v IN ({})",
+                          ValueType::Ref.value_type_tag(), table_sql(DatomsTable::Datoms, view)?, a, list);
+        fetch(sqlite, sql.as_str(), &mut reverse_values)?;
+    }
+
+    let mut maps: BTreeMap<Entid, StructuredMap> = entities.iter()
+                                                           .map(|e| (*e, StructuredMap::default()))
+                                                           .collect();
+    if include_id {
+        for (e, map) in maps.iter_mut() {
+            map.insert(db_id(), TypedValue::Ref(*e));
+        }
+    }
+
+    let whole_component = vec![PullAttributeSpec::Wildcard];
+
+    for (a, values) in forward_values.into_iter() {
+        let (ident, attribute) = match (schema.get_ident(a), schema.attribute_for_entid(a)) {
+            (Some(ident), Some(attribute)) => (ident, attribute),
+            _ => continue,
+        };
+
+        let pattern: Option<&[PullAttributeSpec]> = match forward.get(&a) {
+            Some(&Some(nested)) => Some(nested),
+            _ if attribute.component && depth < MAX_COMPONENT_DEPTH => Some(&whole_component),
+            _ => None,
+        };
+
+        let values = resolve_refs(schema, sqlite, view, pattern, values, depth)?;
+        insert_values(&mut maps, ident, attribute.multival, values);
+    }
+
+    for (a, values) in reverse_values.into_iter() {
+        let (ident, attribute) = match (schema.get_ident(a), schema.attribute_for_entid(a)) {
+            (Some(ident), Some(attribute)) => (ident.to_reversed(), attribute),
+            _ => continue,
+        };
+
+        let pattern: Option<&[PullAttributeSpec]> = match reverse.get(&a) {
+            Some(&Some(nested)) => Some(nested),
+            _ => None,
+        };
+
+        // Each entity is a component of at most one other, so the reverse of a component
+        // attribute is single-valued. Any other ref can be referred to by many entities.
+        let values = resolve_refs(schema, sqlite, view, pattern, values, depth)?;
+        insert_values(&mut maps, &ident, !attribute.component, values);
+    }
+
+    Ok(maps.into_iter().map(|(e, map)| (e, Rc::new(map))).collect())
+}
+
+/// Turn `(entity, value)` pairs into bindings, pulling `pattern` from any values that are entities.
+/// Without a pattern, entities with an ident become that ident, and the rest become `{:db/id …}`.
+fn resolve_refs(schema: &Schema,
+                sqlite: &rusqlite::Connection,
+                view: Option<DatomsView>,
+                pattern: Option<&[PullAttributeSpec]>,
+                values: Vec<(Entid, TypedValue)>,
+                depth: usize) -> Result<Vec<(Entid, Binding)>> {
+    let id_only = vec![PullAttributeSpec::Attribute(db_id())];
+    let referenced: BTreeSet<Entid> = values.iter()
+                                            .filter_map(|&(_, ref v)| match v {
+                                                &TypedValue::Ref(r) if pattern.is_some() || schema.get_ident(r).is_none() => Some(r),
+                                                _ => None,
+                                            })
+                                            .collect();
+    let pulled = pull_entities(schema, sqlite, view, pattern.unwrap_or(&id_only), &referenced, depth + 1)?;
+
+    Ok(values.into_iter()
+             .map(|(e, v)| {
+                 let binding = match v {
+                     TypedValue::Ref(r) => pulled.get(&r)
+                                                 .cloned()
+                                                 .map(Binding::Map)
+                                                 .unwrap_or_else(|| {
+                                                     let v = TypedValue::Ref(r);
+                                                     Binding::Scalar(ident_for_ref(schema, &v).unwrap_or(v))
+                                                 }),
+                     v => Binding::Scalar(v),
+                 };
+                 (e, binding)
+             })
+             .collect())
+}
+
+fn insert_values(maps: &mut BTreeMap<Entid, StructuredMap>,
+                 ident: &NamespacedKeyword,
+                 multival: bool,
+                 values: Vec<(Entid, Binding)>) {
+    if multival {
+        let mut collected: BTreeMap<Entid, Vec<Binding>> = BTreeMap::new();
+        for (e, v) in values {
+            collected.entry(e).or_insert_with(Vec::new).push(v);
+        }
+        for (e, mut vs) in collected {
+            // SQLite doesn't promise any particular order; make the results predictable.
+            vs.sort();
+            if let Some(map) = maps.get_mut(&e) {
+                map.insert(ident.clone(), vs);
+            }
+        }
+    } else {
+        for (e, v) in values {
+            if let Some(map) = maps.get_mut(&e) {
+                map.insert(ident.clone(), v);
+            }
+        }
+    }
+}
@@ -18,18 +18,23 @@ use std::boxed::Box;
 
 use mentat_core::{
     Entid,
-    TypedValue,
     SQLTypeAffinity,
+    SQLValueType,
+    TypedValue,
+    ValueType,
 };
 
 use mentat_query::{
     Direction,
     Limit,
+    Offset,
     Variable,
 };
 
 use mentat_query_algebrizer::{
     Column,
+    Computation,
+    DatomsTable,
     OrderBy,
     QualifiedAlias,
     QueryValue,
@@ -57,10 +62,24 @@ use mentat_sql::{
 /// implementation for each storage backend. Passing `TypedValue`s here allows for that.
 pub enum ColumnOrExpression {
     Column(QualifiedAlias),
+    ExistingColumn(Name),   // A column projected by a subquery, referred to by its name.
     Entid(Entid),       // Because it's so common.
     Integer(i32),       // We use these for type codes etc.
     Long(i64),
     Value(TypedValue),
+    Expression(Box<Expression>),
+    InputVariable(Variable),    // Bound as a SQL parameter when the query is run.
+    Computed(Box<Computation>),
+}
+
+/// A SQL expression computed from other columns or expressions.
+pub enum Expression {
+    /// An aggregate function applied to a single argument: `max(x)`, or `count(DISTINCT x)`.
+    Aggregate {
+        sql_op: &'static str,
+        distinct: bool,
+        arg: ColumnOrExpression,
+    },
 }
 
 /// `QueryValue` and `ColumnOrExpression` are almost identical… merge somehow?
@@ -71,6 +90,8 @@ impl From<QueryValue> for ColumnOrExpression {
             QueryValue::Entid(e) => ColumnOrExpression::Entid(e),
             QueryValue::PrimitiveLong(v) => ColumnOrExpression::Long(v),
             QueryValue::TypedValue(v) => ColumnOrExpression::Value(v),
+            QueryValue::InputVariable(v) => ColumnOrExpression::InputVariable(v),
+            QueryValue::Computed(c) => ColumnOrExpression::Computed(c),
         }
     }
 }
@@ -85,6 +106,13 @@ pub enum Projection {
     One,
 }
 
+/// An entry in a `GROUP BY` clause.
+pub enum GroupBy {
+    /// A column projected by this query or a subquery, referred to by name.
+    ProjectedColumn(Name),
+    QueryColumn(QualifiedAlias),
+}
+
 #[derive(Copy, Clone)]
 pub struct Op(pub &'static str);      // TODO: we can do better than this!
 
@@ -110,7 +138,14 @@ pub enum Constraint {
     TypeCheck {
         value: ColumnOrExpression,
         affinity: SQLTypeAffinity
-    }
+    },
+    NotNull {
+        value: ColumnOrExpression,
+    },
+    /// Holds when the expression is true.
+    Holds {
+        value: ColumnOrExpression,
+    },
 }
 
 impl Constraint {
@@ -139,9 +174,9 @@ impl Constraint {
     }
 }
 
-#[allow(dead_code)]
-enum JoinOp {
+pub enum JoinOp {
     Inner,
+    LeftOuter,
 }
 
 // Short-hand for a list of tables all inner-joined.
@@ -153,11 +188,21 @@ impl TableList {
     }
 }
 
+/// A table joined to those before it with an explicit operator and `ON` constraints.
+pub struct JoinedTable {
+    pub op: JoinOp,
+    pub table: TableOrSubquery,
+    pub on: Vec<Constraint>,
+}
+
+/// A list of tables, all inner-joined, followed by tables joined to them one at a time. E.g.,
+///
+/// ```sql
+/// `datoms` AS `datoms00` LEFT JOIN `datoms` AS `datoms01` ON `datoms01`.e = `datoms00`.e …
+/// ```
 pub struct Join {
-    left: TableOrSubquery,
-    op: JoinOp,
-    right: TableOrSubquery,
-    // TODO: constraints (ON, USING).
+    pub left: TableList,
+    pub joins: Vec<JoinedTable>,
 }
 
 #[allow(dead_code)]
@@ -166,6 +211,53 @@ pub enum TableOrSubquery {
     Union(Vec<SelectQuery>, TableAlias),
     Subquery(Box<SelectQuery>),
     Values(Values, TableAlias),
+    Recursive(CommonTableExpression, TableAlias),
+    View(DatomsView, SourceAlias),
+}
+
+/// A view of the store other than its current state. A query run against a view reads a filtered
+/// subquery wherever it would otherwise read one of the datoms tables.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DatomsView {
+    /// The datoms that were true immediately after the given transaction, reconstructed from
+    /// the transaction log.
+    AsOf(Entid),
+
+    /// The current datoms that were asserted after the given transaction.
+    Since(Entid),
+}
+
+impl DatomsView {
+    /// Return true if this view changes the rows of the given table.
+    fn applies_to(&self, table: DatomsTable) -> bool {
+        match table {
+            DatomsTable::Datoms |
+            DatomsTable::FulltextDatoms |
+            DatomsTable::AllDatoms |
+            DatomsTable::Transactions |
+            DatomsTable::AllTransactions => true,
+            DatomsTable::FulltextValues |
+            DatomsTable::Computed(_) |
+            DatomsTable::RecursiveRule => false,
+        }
+    }
+}
+
+/// A recursive common table expression, like
+///
+/// ```sql
+/// WITH RECURSIVE `name` (`?x`, `?y`) AS (SELECT … UNION SELECT … FROM `name` AS `name05` …)
+/// ```
+///
+/// The `initial` queries seed the table. The `recursive` query refers to the table by name, and
+/// is run against the rows found so far until it finds no new ones. The queries are combined with
+/// `UNION`, not `UNION ALL`: duplicate rows are discarded, so cycles in the data can't make the
+/// recursion run forever.
+pub struct CommonTableExpression {
+    pub name: Name,
+    pub columns: Vec<Name>,
+    pub initial: Vec<SelectQuery>,
+    pub recursive: Box<SelectQuery>,
 }
 
 pub enum Values {
@@ -190,8 +282,10 @@ pub struct SelectQuery {
     pub projection: Projection,
     pub from: FromClause,
     pub constraints: Vec<Constraint>,
+    pub group_by: Vec<GroupBy>,
     pub order: Vec<OrderBy>,
     pub limit: Limit,
+    pub offset: Offset,
 }
 
 fn push_variable_column(qb: &mut QueryBuilder, vc: &VariableColumn) -> BuildQueryResult {
@@ -207,7 +301,8 @@ fn push_variable_column(qb: &mut QueryBuilder, vc: &VariableColumn) -> BuildQuer
 
 fn push_column(qb: &mut QueryBuilder, col: &Column) -> BuildQueryResult {
     match col {
-        &Column::Fixed(ref d) => {
+        &Column::Fixed(ref d) |
+        &Column::WithDefault(ref d, _) => {
             qb.push_sql(d.as_str());
             Ok(())
         },
@@ -216,6 +311,34 @@ fn push_column(qb: &mut QueryBuilder, col: &Column) -> BuildQueryResult {
             Ok(())
         },
         &Column::Variable(ref vc) => push_variable_column(qb, vc),
+        &Column::Transactions(ref d) => {
+            qb.push_sql(d.as_str());
+            Ok(())
+        },
+        &Column::Computed(ref c) => push_computation(qb, c),
+    }
+}
+
+/// Push a column qualified by its table alias, like "`datoms01`.v". A column with a default
+/// becomes "COALESCE(`datoms01`.v, default)".
+fn push_qualified_column(qb: &mut QueryBuilder, qa: &QualifiedAlias) -> BuildQueryResult {
+    let &QualifiedAlias(ref table, ref column) = qa;
+    if let &Column::WithDefault(_, ref default) = column {
+        qb.push_sql("COALESCE(");
+        qb.push_identifier(table.as_str())?;
+        qb.push_sql(".");
+        push_column(qb, column)?;
+        qb.push_sql(", ");
+        qb.push_typed_value(default)?;
+        qb.push_sql(")");
+        Ok(())
+    } else if let &Column::Computed(ref computation) = column {
+        // The computation qualifies its own columns.
+        push_computation(qb, computation)
+    } else {
+        qb.push_identifier(table.as_str())?;
+        qb.push_sql(".");
+        push_column(qb, column)
     }
 }
 
@@ -258,14 +381,77 @@ macro_rules! interpose_iter {
     }
 }
 
+// We don't own `QueryValue` or `Computation`, so we can't implement `QueryFragment` for them.
+fn push_query_value(qb: &mut QueryBuilder, value: &QueryValue) -> BuildQueryResult {
+    match value {
+        &QueryValue::Column(ref qa) => push_qualified_column(qb, qa),
+        &QueryValue::Entid(entid) => {
+            qb.push_sql(entid.to_string().as_str());
+            Ok(())
+        },
+        &QueryValue::TypedValue(ref v) => qb.push_typed_value(v),
+        &QueryValue::PrimitiveLong(v) => {
+            qb.push_sql(v.to_string().as_str());
+            Ok(())
+        },
+        &QueryValue::InputVariable(ref var) => qb.push_bind_param(input_variable_param_name(var).as_str()),
+        &QueryValue::Computed(ref c) => push_computation(qb, c),
+    }
+}
+
+/// Push an expression like "substr(`datoms00`.v, 1, 3)". Operands are parenthesized so that
+/// nested computations keep their meaning regardless of operator precedence.
+fn push_computation(qb: &mut QueryBuilder, computation: &Computation) -> BuildQueryResult {
+    match computation {
+        &Computation::Function(name, ref args) => {
+            qb.push_sql(name);
+            qb.push_sql("(");
+            interpose!(arg, args,
+                       { push_query_value(qb, arg)? },
+                       { qb.push_sql(", ") });
+            qb.push_sql(")");
+            Ok(())
+        },
+        &Computation::Infix(op, ref left, ref right) => {
+            qb.push_sql("(");
+            push_query_value(qb, left)?;
+            qb.push_sql(" ");
+            qb.push_sql(op);
+            qb.push_sql(" ");
+            push_query_value(qb, right)?;
+            qb.push_sql(")");
+            Ok(())
+        },
+        &Computation::CastToText(ref arg) => {
+            qb.push_sql("CAST(");
+            push_query_value(qb, arg)?;
+            qb.push_sql(" AS TEXT)");
+            Ok(())
+        },
+        &Computation::CastToReal(ref arg) => {
+            qb.push_sql("CAST(");
+            push_query_value(qb, arg)?;
+            qb.push_sql(" AS REAL)");
+            Ok(())
+        },
+        &Computation::CastToInteger(ref arg) => {
+            qb.push_sql("CAST(");
+            push_query_value(qb, arg)?;
+            qb.push_sql(" AS INTEGER)");
+            Ok(())
+        },
+    }
+}
+
 impl QueryFragment for ColumnOrExpression {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
         use self::ColumnOrExpression::*;
         match self {
-            &Column(QualifiedAlias(ref table, ref column)) => {
-                out.push_identifier(table.as_str())?;
-                out.push_sql(".");
-                push_column(out, column)
+            &Column(ref qa) => {
+                push_qualified_column(out, qa)
+            },
+            &ExistingColumn(ref alias) => {
+                out.push_identifier(alias.as_str())
             },
             &Entid(entid) => {
                 out.push_sql(entid.to_string().as_str());
@@ -282,6 +468,46 @@ impl QueryFragment for ColumnOrExpression {
             &Value(ref v) => {
                 out.push_typed_value(v)
             },
+            &Expression(ref e) => {
+                e.push_sql(out)
+            },
+            &InputVariable(ref var) => {
+                out.push_bind_param(input_variable_param_name(var).as_str())
+            },
+            &Computed(ref c) => {
+                push_computation(out, c)
+            },
+        }
+    }
+}
+
+impl QueryFragment for Expression {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        match self {
+            &Expression::Aggregate { sql_op, distinct, ref arg } => {
+                out.push_sql(sql_op);
+                if distinct {
+                    out.push_sql("(DISTINCT ");
+                } else {
+                    out.push_sql("(");
+                }
+                arg.push_sql(out)?;
+                out.push_sql(")");
+                Ok(())
+            },
+        }
+    }
+}
+
+impl QueryFragment for GroupBy {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        match self {
+            &GroupBy::ProjectedColumn(ref name) => {
+                out.push_identifier(name.as_str())
+            },
+            &GroupBy::QueryColumn(ref qa) => {
+                push_qualified_column(out, qa)
+            },
         }
     }
 }
@@ -368,10 +594,8 @@ impl QueryFragment for Constraint {
                 Ok(())
             },
             &NotExists { ref subquery } => {
-                out.push_sql("NOT EXISTS (");
-                subquery.push_sql(out)?;
-                out.push_sql(")");
-                Ok(())
+                out.push_sql("NOT EXISTS ");
+                subquery.push_sql(out)
             },
             &TypeCheck { ref value, ref affinity } => {
                 out.push_sql("typeof(");
@@ -386,13 +610,24 @@ impl QueryFragment for Constraint {
                 });
                 Ok(())
             },
+            &NotNull { ref value } => {
+                value.push_sql(out)?;
+                out.push_sql(" IS NOT NULL");
+                Ok(())
+            },
+            &Holds { ref value } => {
+                value.push_sql(out)
+            },
         }
     }
 }
 
 impl QueryFragment for JoinOp {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
-        out.push_sql(" JOIN ");
+        match self {
+            &JoinOp::Inner => out.push_sql(" JOIN "),
+            &JoinOp::LeftOuter => out.push_sql(" LEFT JOIN "),
+        }
         Ok(())
     }
 }
@@ -405,6 +640,98 @@ fn source_alias_push_sql(out: &mut QueryBuilder, sa: &SourceAlias) -> BuildQuery
     out.push_identifier(alias.as_str())
 }
 
+/// Push the datoms that were true immediately after `tx`. A datom is true if its most recent
+/// assertion or retraction at or before `tx` was an assertion.
+fn as_of_datoms_push_sql(out: &mut QueryBuilder, tx: Entid) {
+    out.push_sql(&format!("SELECT e, a, v, tx, value_type_tag FROM `transactions` AS `asserted` \
+                           WHERE `asserted`.added = 1 AND `asserted`.tx <= {tx} \
+                           AND NOT EXISTS (SELECT 1 FROM `transactions` AS `later` \
+                           WHERE `later`.e = `asserted`.e AND `later`.a = `asserted`.a \
+                           AND `later`.value_type_tag = `asserted`.value_type_tag AND `later`.v = `asserted`.v \
+                           AND `later`.tx > `asserted`.tx AND `later`.tx <= {tx})", tx=tx));
+}
+
+/// Push the fulltext datoms that were true immediately after `tx`, with their text in place of
+/// their rowid.
+///
+/// The log doesn't record which attributes are fulltext. Fulltext values are the only strings we
+/// store as integers, so we look for those.
+fn as_of_fulltext_datoms_push_sql(out: &mut QueryBuilder, tx: Entid) {
+    out.push_sql("SELECT `as_of`.e AS e, `as_of`.a AS a, `fulltext_values`.text AS v, \
+                  `as_of`.tx AS tx, `as_of`.value_type_tag AS value_type_tag FROM (");
+    as_of_datoms_push_sql(out, tx);
+    out.push_sql(&format!(") AS `as_of`, `fulltext_values` \
+                           WHERE `as_of`.value_type_tag = {} AND typeof(`as_of`.v) = 'integer' \
+                           AND `as_of`.v = `fulltext_values`.rowid", ValueType::String.value_type_tag()));
+}
+
+/// Push the rows of the transaction log that match `filter`, with the text of each fulltext value
+/// in place of its rowid. This is to the log what `all_datoms` is to `datoms`, but the store has
+/// no such view, so we build it here.
+///
+/// As for `as_of_fulltext_datoms_push_sql`, fulltext values are the only strings stored as
+/// integers.
+fn all_transactions_push_sql(out: &mut QueryBuilder, filter: &str) {
+    let fulltext = format!("`transactions`.value_type_tag = {} AND typeof(`transactions`.v) = 'integer'",
+                           ValueType::String.value_type_tag());
+    out.push_sql(&format!("SELECT e, a, v, tx, added, value_type_tag FROM `transactions` \
+                           WHERE NOT ({fulltext}){filter} \
+                           UNION ALL \
+                           SELECT `transactions`.e AS e, `transactions`.a AS a, `fulltext_values`.text AS v, \
+                           `transactions`.tx AS tx, `transactions`.added AS added, \
+                           `transactions`.value_type_tag AS value_type_tag \
+                           FROM `transactions`, `fulltext_values` \
+                           WHERE {fulltext} AND `transactions`.v = `fulltext_values`.rowid{filter}",
+                          fulltext=fulltext, filter=filter));
+}
+
+/// Push the rows of `table` as they appear in `view`, like
+///
+/// ```sql
+/// (SELECT e, a, v, tx, value_type_tag FROM `datoms` WHERE tx > 268435457) AS `datoms00`
+/// ```
+fn view_push_sql(out: &mut QueryBuilder, view: &DatomsView, sa: &SourceAlias) -> BuildQueryResult {
+    let &SourceAlias(ref table, ref alias) = sa;
+    out.push_sql("(");
+    match (*view, *table) {
+        (DatomsView::AsOf(tx), DatomsTable::Datoms) => {
+            as_of_datoms_push_sql(out, tx);
+        },
+        (DatomsView::AsOf(tx), DatomsTable::FulltextDatoms) => {
+            as_of_fulltext_datoms_push_sql(out, tx);
+        },
+        (DatomsView::AsOf(tx), DatomsTable::AllDatoms) => {
+            out.push_sql("SELECT e, a, v, tx, value_type_tag FROM (");
+            as_of_datoms_push_sql(out, tx);
+            out.push_sql(&format!(") WHERE NOT (value_type_tag = {} AND typeof(v) = 'integer') UNION ALL ",
+                                  ValueType::String.value_type_tag()));
+            as_of_fulltext_datoms_push_sql(out, tx);
+        },
+        (DatomsView::AsOf(tx), DatomsTable::Transactions) => {
+            out.push_sql(&format!("SELECT e, a, v, tx, added, value_type_tag FROM `transactions` WHERE tx <= {}", tx));
+        },
+        (DatomsView::AsOf(tx), DatomsTable::AllTransactions) => {
+            all_transactions_push_sql(out, &format!(" AND `transactions`.tx <= {}", tx));
+        },
+        (DatomsView::Since(tx), DatomsTable::Transactions) => {
+            out.push_sql(&format!("SELECT e, a, v, tx, added, value_type_tag FROM `transactions` WHERE tx > {}", tx));
+        },
+        (DatomsView::Since(tx), DatomsTable::AllTransactions) => {
+            all_transactions_push_sql(out, &format!(" AND `transactions`.tx > {}", tx));
+        },
+        (DatomsView::Since(tx), _) => {
+            out.push_sql("SELECT e, a, v, tx, value_type_tag FROM ");
+            out.push_identifier(table.name())?;
+            out.push_sql(&format!(" WHERE tx > {}", tx));
+        },
+        (DatomsView::AsOf(_), _) => {
+            unreachable!("views don't apply to {:?}", table);
+        },
+    }
+    out.push_sql(") AS ");
+    out.push_identifier(alias.as_str())
+}
+
 impl QueryFragment for TableList {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
         if self.0.is_empty() {
@@ -418,11 +745,27 @@ impl QueryFragment for TableList {
     }
 }
 
+impl QueryFragment for JoinedTable {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        self.op.push_sql(out)?;
+        self.table.push_sql(out)?;
+        if !self.on.is_empty() {
+            out.push_sql(" ON ");
+            interpose!(constraint, self.on,
+                       { constraint.push_sql(out)? },
+                       { out.push_sql(" AND ") });
+        }
+        Ok(())
+    }
+}
+
 impl QueryFragment for Join {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
         self.left.push_sql(out)?;
-        self.op.push_sql(out)?;
-        self.right.push_sql(out)
+        for join in self.joins.iter() {
+            join.push_sql(out)?;
+        }
+        Ok(())
     }
 }
 
@@ -430,6 +773,12 @@ impl QueryFragment for TableOrSubquery {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
         use self::TableOrSubquery::*;
         match self {
+            &Table(SourceAlias(DatomsTable::AllTransactions, ref alias)) => {
+                out.push_sql("(");
+                all_transactions_push_sql(out, "");
+                out.push_sql(") AS ");
+                out.push_identifier(alias.as_str())
+            },
             &Table(ref sa) => source_alias_push_sql(out, sa),
             &Union(ref subqueries, ref table_alias) => {
                 out.push_sql("(");
@@ -440,7 +789,10 @@ impl QueryFragment for TableOrSubquery {
                 out.push_identifier(table_alias.as_str())
             },
             &Subquery(ref subquery) => {
-                subquery.push_sql(out)
+                out.push_sql("(");
+                subquery.push_sql(out)?;
+                out.push_sql(")");
+                Ok(())
             },
             &Values(ref values, ref table_alias) => {
                 // XXX: does this work for Values::Unnamed?
@@ -449,7 +801,94 @@ impl QueryFragment for TableOrSubquery {
                 out.push_sql(") AS ");
                 out.push_identifier(table_alias.as_str())
             },
+            &Recursive(ref cte, ref table_alias) => {
+                // Scoping the CTE to its own subquery keeps its name from clashing with any other.
+                out.push_sql("(WITH RECURSIVE ");
+                cte.push_sql(out)?;
+                out.push_sql(" SELECT * FROM ");
+                out.push_identifier(cte.name.as_str())?;
+                out.push_sql(") AS ");
+                out.push_identifier(table_alias.as_str())
+            },
+            &View(ref view, ref sa) => view_push_sql(out, view, sa),
+        }
+    }
+}
+
+impl TableOrSubquery {
+    fn use_view(&mut self, view: DatomsView) {
+        let replacement = match self {
+            &mut TableOrSubquery::Table(ref sa) => {
+                if view.applies_to(sa.0) {
+                    Some(TableOrSubquery::View(view, sa.clone()))
+                } else {
+                    None
+                }
+            },
+            &mut TableOrSubquery::Union(ref mut subqueries, _) => {
+                for subquery in subqueries.iter_mut() {
+                    subquery.use_view(view);
+                }
+                None
+            },
+            &mut TableOrSubquery::Subquery(ref mut subquery) => {
+                subquery.use_view(view);
+                None
+            },
+            &mut TableOrSubquery::Recursive(ref mut cte, _) => {
+                for initial in cte.initial.iter_mut() {
+                    initial.use_view(view);
+                }
+                cte.recursive.use_view(view);
+                None
+            },
+            &mut TableOrSubquery::Values(_, _) |
+            &mut TableOrSubquery::View(_, _) => None,
+        };
+        if let Some(replacement) = replacement {
+            *self = replacement;
+        }
+    }
+}
+
+impl Constraint {
+    fn use_view(&mut self, view: DatomsView) {
+        match self {
+            &mut Constraint::Or { ref mut constraints } |
+            &mut Constraint::And { ref mut constraints } => {
+                for constraint in constraints.iter_mut() {
+                    constraint.use_view(view);
+                }
+            },
+            &mut Constraint::NotExists { ref mut subquery } => {
+                subquery.use_view(view);
+            },
+            &mut Constraint::Infix { .. } |
+            &mut Constraint::In { .. } |
+            &mut Constraint::TypeCheck { .. } |
+            &mut Constraint::NotNull { .. } |
+            &mut Constraint::Holds { .. } => {},
+        }
+    }
+}
+
+impl QueryFragment for CommonTableExpression {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        out.push_identifier(self.name.as_str())?;
+        out.push_sql(" (");
+        interpose!(column, self.columns,
+                   { out.push_identifier(column.as_str())? },
+                   { out.push_sql(", ") });
+        out.push_sql(") AS (");
+
+        // SQLite requires the recursive query to be the last in the compound.
+        for initial in self.initial.iter() {
+            initial.push_sql(out)?;
+            out.push_sql(" UNION ");
         }
+        self.recursive.push_sql(out)?;
+        out.push_sql(")");
+        Ok(())
     }
 }
 
@@ -515,16 +954,20 @@ impl QueryFragment for FromClause {
     }
 }
 
+/// The name of the SQL bind parameter used for the input variable `var`, without the leading `$`.
+pub fn input_variable_param_name(var: &Variable) -> String {
+    // `var` is something like `?foo99-people`.
+    // Trim the `?` and escape the rest. Prepend `i` to distinguish from
+    // the inline value space `v`.
+    let re = regex::Regex::new("[^a-zA-Z_0-9]").unwrap();
+    let without_question = var.as_str().split_at(1).1;
+    let replaced = re.replace_all(without_question, "_");
+    format!("i{}", replaced)                 // We _could_ avoid this copying.
+}
+
 impl SelectQuery {
     fn push_variable_param(&self, var: &Variable, out: &mut QueryBuilder) -> BuildQueryResult {
-        // `var` is something like `?foo99-people`.
-        // Trim the `?` and escape the rest. Prepend `i` to distinguish from
-        // the inline value space `v`.
-        let re = regex::Regex::new("[^a-zA-Z_0-9]").unwrap();
-        let without_question = var.as_str().split_at(1).1;
-        let replaced = re.replace_all(without_question, "_");
-        let bind_param = format!("i{}", replaced);                 // We _could_ avoid this copying.
-        out.push_bind_param(bind_param.as_str())
+        out.push_bind_param(input_variable_param_name(var).as_str())
     }
 }
 
@@ -545,6 +988,13 @@ impl QueryFragment for SelectQuery {
                        { out.push_sql(" AND ") });
         }
 
+        if !self.group_by.is_empty() {
+            out.push_sql(" GROUP BY ");
+            interpose!(group, self.group_by,
+                       { group.push_sql(out)? },
+                       { out.push_sql(", ") });
+        }
+
         if !self.order.is_empty() {
             out.push_sql(" ORDER BY ");
             interpose!(&OrderBy(ref dir, ref var), self.order,
@@ -571,11 +1021,58 @@ impl QueryFragment for SelectQuery {
             },
         }
 
+        // SQLite only accepts `OFFSET` after a `LIMIT`; a negative limit means no limit at all.
+        let has_limit = self.limit != Limit::None;
+        match &self.offset {
+            &Offset::None => (),
+            &Offset::Fixed(offset) => {
+                if !has_limit {
+                    out.push_sql(" LIMIT -1");
+                }
+                out.push_sql(" OFFSET ");
+                out.push_sql(offset.to_string().as_str());
+            },
+            &Offset::Variable(ref var) => {
+                if !has_limit {
+                    out.push_sql(" LIMIT -1");
+                }
+                out.push_sql(" OFFSET ");
+                self.push_variable_param(var, out)?;
+            },
+        }
+
         Ok(())
     }
 }
 
 impl SelectQuery {
+    /// Make this query, and every subquery within it, read from `view` rather than from the
+    /// current state of the store.
+    pub fn use_view(&mut self, view: DatomsView) {
+        match self.from {
+            FromClause::TableList(ref mut tables) => {
+                for table in tables.0.iter_mut() {
+                    table.use_view(view);
+                }
+            },
+            FromClause::Join(ref mut join) => {
+                for table in join.left.0.iter_mut() {
+                    table.use_view(view);
+                }
+                for joined in join.joins.iter_mut() {
+                    joined.table.use_view(view);
+                    for constraint in joined.on.iter_mut() {
+                        constraint.use_view(view);
+                    }
+                }
+            },
+            FromClause::Nothing => {},
+        }
+        for constraint in self.constraints.iter_mut() {
+            constraint.use_view(view);
+        }
+    }
+
     pub fn to_sql_query(&self) -> mentat_sql::Result<SQLQuery> {
         let mut builder = SQLiteQueryBuilder::new();
         self.push_sql(&mut builder).map(|_| builder.finish())
@@ -708,6 +1205,24 @@ mod tests {
         assert_eq!("`fulltext01`.rowid = `datoms02`.v", build(&c));
     }
 
+    #[test]
+    fn test_computed_constraint() {
+        let name = QueryValue::Column(QualifiedAlias("datoms00".to_string(), Column::Fixed(DatomsColumn::Value)));
+        let position = Computation::Function("instr", vec![name.clone(), QueryValue::TypedValue(TypedValue::typed_string("needle"))]);
+        let c = Constraint::Holds {
+            value: ColumnOrExpression::Computed(Box::new(Computation::Infix(">", QueryValue::Computed(Box::new(position)), QueryValue::PrimitiveLong(0)))),
+        };
+        let q = build_query(&c);
+        assert_eq!("(instr(`datoms00`.v, $v0) > 0)", q.sql);
+        assert_eq!(vec![("$v0".to_string(), Rc::new(mentat_sql::Value::Text("needle".to_string())))], q.args);
+
+        // A computed column is rendered in place, whatever its table alias.
+        let lower = QualifiedAlias("datoms00".to_string(), Column::Computed(Box::new(Computation::Function("lower", vec![name]))));
+        let c = Constraint::equal(ColumnOrExpression::Column(lower),
+                                  ColumnOrExpression::Column(QualifiedAlias("datoms01".to_string(), Column::Fixed(DatomsColumn::Value))));
+        assert_eq!("lower(`datoms00`.v) = `datoms01`.v", build(&c));
+    }
+
     #[test]
     fn test_end_to_end() {
         // [:find ?x :where [?x 65537 ?v] [?x 65536 ?v]]
@@ -745,8 +1260,10 @@ mod tests {
                     right: ColumnOrExpression::Entid(65536),
                 },
             ],
+            group_by: vec![],
             order: vec![],
             limit: Limit::None,
+            offset: Offset::None,
         };
 
         let SQLQuery { sql, args } = query.to_sql_query().unwrap();
@@ -762,4 +1279,276 @@ mod tests {
         assert!(args.is_empty());
 
     }
+
+    #[test]
+    fn test_group_by_aggregate() {
+        // [:find ?x (count-distinct ?y) :where [?x 65537 ?y]]
+        let datoms00 = "datoms00".to_string();
+        let inner = SelectQuery {
+            distinct: true,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity)),
+                                    "?x".to_string()),
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Value)),
+                                    "?y".to_string()),
+                            ]),
+            from: FromClause::TableList(TableList(vec![TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms00.clone()))])),
+            constraints: vec![
+                Constraint::equal(ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Attribute)),
+                                  ColumnOrExpression::Entid(65537)),
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let count = Expression::Aggregate {
+            sql_op: "count",
+            distinct: true,
+            arg: ColumnOrExpression::ExistingColumn("?y".to_string()),
+        };
+        let outer = SelectQuery {
+            distinct: false,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(ColumnOrExpression::ExistingColumn("?x".to_string()), "?x".to_string()),
+                                ProjectedColumn(ColumnOrExpression::Expression(Box::new(count)), "(count-distinct ?y)".to_string()),
+                            ]),
+            from: FromClause::TableList(TableList(vec![TableOrSubquery::Subquery(Box::new(inner))])),
+            constraints: vec![],
+            group_by: vec![GroupBy::ProjectedColumn("?x".to_string())],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let SQLQuery { sql, args } = outer.to_sql_query().unwrap();
+        assert_eq!("SELECT `?x` AS `?x`, count(DISTINCT `?y`) AS `(count-distinct ?y)` FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 65537) GROUP BY `?x`", sql);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn test_left_join() {
+        // [:find ?x ?age :where [?x 65537 _] [(get-else $ ?x 65538 0) ?age]]
+        let datoms00 = "datoms00".to_string();
+        let datoms01 = "datoms01".to_string();
+        let eq = Op("=");
+        let age = QualifiedAlias::new(datoms01.clone(), Column::WithDefault(DatomsColumn::Value, TypedValue::Long(0)));
+
+        let query = SelectQuery {
+            distinct: true,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity)),
+                                    "x".to_string()),
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(age),
+                                    "age".to_string()),
+                            ]),
+            from: FromClause::Join(Join {
+                left: TableList(vec![TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms00.clone()))]),
+                joins: vec![
+                    JoinedTable {
+                        op: JoinOp::LeftOuter,
+                        table: TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms01.clone())),
+                        on: vec![
+                            Constraint::Infix {
+                                op: eq.clone(),
+                                left: ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Entity)),
+                                right: ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity)),
+                            },
+                            Constraint::Infix {
+                                op: eq.clone(),
+                                left: ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Attribute)),
+                                right: ColumnOrExpression::Entid(65538),
+                            },
+                        ],
+                    },
+                ],
+            }),
+            constraints: vec![
+                Constraint::Infix {
+                    op: eq.clone(),
+                    left: ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Attribute)),
+                    right: ColumnOrExpression::Entid(65537),
+                },
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let SQLQuery { sql, args } = query.to_sql_query().unwrap();
+        assert_eq!("SELECT DISTINCT `datoms00`.e AS `x`, COALESCE(`datoms01`.v, 0) AS `age` \
+                    FROM `datoms` AS `datoms00` \
+                    LEFT JOIN `datoms` AS `datoms01` ON `datoms01`.e = `datoms00`.e AND `datoms01`.a = 65538 \
+                    WHERE `datoms00`.a = 65537", sql);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn test_recursive_cte() {
+        // [[(ancestor ?x ?y) [?x 65537 ?y]]
+        //  [(ancestor ?x ?y) [?x 65537 ?z] (ancestor ?z ?y)]]
+        let datoms00 = "datoms00".to_string();
+        let datoms01 = "datoms01".to_string();
+        let recursive_rule02 = "recursive_rule02".to_string();
+        let x = Variable::from_valid_name("?x");
+        let y = Variable::from_valid_name("?y");
+
+        let initial = SelectQuery {
+            distinct: false,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity)),
+                                    "?x".to_string()),
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Value)),
+                                    "?y".to_string()),
+                            ]),
+            from: FromClause::TableList(TableList(vec![TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms00.clone()))])),
+            constraints: vec![
+                Constraint::equal(ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Attribute)),
+                                  ColumnOrExpression::Entid(65537)),
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let recursive = SelectQuery {
+            distinct: false,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Entity)),
+                                    "?x".to_string()),
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(recursive_rule02.clone(), VariableColumn::Variable(y.clone()))),
+                                    "?y".to_string()),
+                            ]),
+            from: FromClause::TableList(TableList(vec![
+                TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms01.clone())),
+                TableOrSubquery::Table(SourceAlias(DatomsTable::RecursiveRule, recursive_rule02.clone())),
+            ])),
+            constraints: vec![
+                Constraint::equal(ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Attribute)),
+                                  ColumnOrExpression::Entid(65537)),
+                Constraint::equal(ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Value)),
+                                  ColumnOrExpression::Column(QualifiedAlias::new(recursive_rule02.clone(), VariableColumn::Variable(x.clone())))),
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let cte = CommonTableExpression {
+            name: DatomsTable::RecursiveRule.name().to_string(),
+            columns: vec!["?x".to_string(), "?y".to_string()],
+            initial: vec![initial],
+            recursive: Box::new(recursive),
+        };
+
+        let query = SelectQuery {
+            distinct: true,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new("c03".to_string(), VariableColumn::Variable(x.clone()))),
+                                    "?x".to_string()),
+                            ]),
+            from: FromClause::TableList(TableList(vec![TableOrSubquery::Recursive(cte, "c03".to_string())])),
+            constraints: vec![],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let SQLQuery { sql, args } = query.to_sql_query().unwrap();
+        assert_eq!("SELECT DISTINCT `c03`.`?x` AS `?x` FROM \
+                    (WITH RECURSIVE `recursive_rule` (`?x`, `?y`) AS \
+                    (SELECT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 65537 \
+                    UNION \
+                    SELECT `datoms01`.e AS `?x`, `recursive_rule02`.`?y` AS `?y` FROM `datoms` AS `datoms01`, `recursive_rule` AS `recursive_rule02` \
+                    WHERE `datoms01`.a = 65537 AND `datoms01`.v = `recursive_rule02`.`?x`) \
+                    SELECT * FROM `recursive_rule`) AS `c03`", sql);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn test_use_view() {
+        // [:find ?x :where [?x 65537 _] (not [?x 65538 _])]
+        let datoms00 = "datoms00".to_string();
+        let datoms01 = "datoms01".to_string();
+        let eq = Op("=");
+
+        let not_exists = SelectQuery {
+            distinct: false,
+            projection: Projection::One,
+            from: FromClause::TableList(TableList(vec![TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms01.clone()))])),
+            constraints: vec![
+                Constraint::Infix {
+                    op: eq.clone(),
+                    left: ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Attribute)),
+                    right: ColumnOrExpression::Entid(65538),
+                },
+                Constraint::Infix {
+                    op: eq.clone(),
+                    left: ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity)),
+                    right: ColumnOrExpression::Column(QualifiedAlias::new(datoms01.clone(), DatomsColumn::Entity)),
+                },
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        let mut query = SelectQuery {
+            distinct: true,
+            projection: Projection::Columns(
+                            vec![
+                                ProjectedColumn(
+                                    ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Entity)),
+                                    "x".to_string()),
+                            ]),
+            from: FromClause::TableList(TableList(vec![TableOrSubquery::Table(SourceAlias(DatomsTable::Datoms, datoms00.clone()))])),
+            constraints: vec![
+                Constraint::Infix {
+                    op: eq.clone(),
+                    left: ColumnOrExpression::Column(QualifiedAlias::new(datoms00.clone(), DatomsColumn::Attribute)),
+                    right: ColumnOrExpression::Entid(65537),
+                },
+                Constraint::NotExists {
+                    subquery: TableOrSubquery::Subquery(Box::new(not_exists)),
+                },
+            ],
+            group_by: vec![],
+            order: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+        };
+
+        // Every datoms table, however deeply nested, reads from the view instead.
+        query.use_view(DatomsView::Since(1000));
+
+        let SQLQuery { sql, args } = query.to_sql_query().unwrap();
+        assert_eq!("SELECT DISTINCT `datoms00`.e AS `x` \
+                    FROM (SELECT e, a, v, tx, value_type_tag FROM `datoms` WHERE tx > 1000) AS `datoms00` \
+                    WHERE `datoms00`.a = 65537 \
+                    AND NOT EXISTS (SELECT 1 \
+                    FROM (SELECT e, a, v, tx, value_type_tag FROM `datoms` WHERE tx > 1000) AS `datoms01` \
+                    WHERE `datoms01`.a = 65538 AND `datoms00`.e = `datoms01`.e)", sql);
+        assert!(args.is_empty());
+    }
 }
@@ -20,12 +20,16 @@ extern crate mentat_sql;
 mod translate;
 
 pub use mentat_query_sql::{
+    DatomsView,
     Projection,
+    input_variable_param_name,
 };
 
 pub use translate::{
+    ProjectedSelect,
     cc_to_exists,
     query_to_select,
+    query_to_select_in_view,
 };
 
 error_chain! {
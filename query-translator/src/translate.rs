@@ -17,7 +17,11 @@ use mentat_core::{
     ValueTypeSet,
 };
 
-use mentat_query::Limit;
+use mentat_query::{
+    Limit,
+    Offset,
+    Variable,
+};
 
 use mentat_query_algebrizer::{
     AlgebraicQuery,
@@ -30,6 +34,7 @@ use mentat_query_algebrizer::{
     ConjoiningClauses,
     DatomsColumn,
     DatomsTable,
+    LeftJoin,
     OrderBy,
     QualifiedAlias,
     QueryValue,
@@ -47,8 +52,13 @@ use mentat_query_projector::{
 
 use mentat_query_sql::{
     ColumnOrExpression,
+    CommonTableExpression,
     Constraint,
+    DatomsView,
     FromClause,
+    Join,
+    JoinOp,
+    JoinedTable,
     Op,
     ProjectedColumn,
     Projection,
@@ -58,7 +68,10 @@ use mentat_query_sql::{
     Values,
 };
 
-use std::collections::HashMap;
+use std::collections::{
+    BTreeSet,
+    HashMap,
+};
 
 use super::Result;
 
@@ -160,6 +173,12 @@ impl ToConstraint for ColumnConstraint {
             Equals(left, QueryValue::Column(right)) =>
                 Constraint::equal(left.to_column(), right.to_column()),
 
+            Equals(qa, QueryValue::InputVariable(var)) =>
+                Constraint::equal(qa.to_column(), ColumnOrExpression::InputVariable(var)),
+
+            Equals(qa, QueryValue::Computed(computation)) =>
+                Constraint::equal(qa.to_column(), ColumnOrExpression::Computed(computation)),
+
             Equals(qa, QueryValue::PrimitiveLong(value)) => {
                 let tag_column = qa.for_type_tag().to_column();
                 let value_column = qa.to_column();
@@ -233,6 +252,12 @@ impl ToConstraint for ColumnConstraint {
                     subquery: subquery,
                 }
             },
+
+            Computed(computation) => {
+                Constraint::Holds {
+                    value: ColumnOrExpression::Computed(Box::new(computation)),
+                }
+            },
         }
     }
 }
@@ -259,6 +284,53 @@ impl<T> ConsumableVec<T> {
     }
 }
 
+/// Select `projection` from one arm of a union or of a recursive rule. Every arm must project
+/// the same columns with the same names: each variable, followed by its type tag if it's in
+/// `type_extraction`.
+fn select_for_arm(projection: &[Variable],
+                  type_extraction: &BTreeSet<Variable>,
+                  cc: ConjoiningClauses) -> SelectQuery {
+    // We're going to end up with the variables being projected and also some
+    // type tag columns.
+    let mut columns: Vec<ProjectedColumn> = Vec::with_capacity(projection.len() + type_extraction.len());
+
+    // For each variable, find out which column it maps to within this arm, and
+    // project it as the variable name.
+    // E.g., SELECT datoms03.v AS `?x`.
+    for var in projection.iter() {
+        let (projected_column, maybe_type) = projected_column_for_var(var, &cc);
+        columns.push(projected_column);
+
+        // Similarly, project type tags if they're not known conclusively in the
+        // outer query.
+        // Assumption: we'll never need to project a tag without projecting the value of a variable.
+        if type_extraction.contains(var) {
+            let expression =
+                if let Some(ty) = maybe_type {
+                    // If we know the type for sure, just project the constant.
+                    // SELECT datoms03.v AS `?x`, 10 AS `?x_value_type_tag`
+                    ColumnOrExpression::Integer(ty.value_type_tag())
+                } else {
+                    // Otherwise, we'll have an established type binding! This'll be
+                    // either a datoms table or, recursively, a subquery. Project
+                    // this:
+                    // SELECT datoms03.v AS `?x`,
+                    //        datoms03.value_type_tag AS `?x_value_type_tag`
+                    let extract = cc.extracted_types
+                                    .get(var)
+                                    .expect("Expected variable to have a known type or an extracted type");
+                    ColumnOrExpression::Column(extract.clone())
+                };
+            let type_column = VariableColumn::VariableTypeTag(var.clone());
+            let proj = ProjectedColumn(expression, type_column.column_name());
+            columns.push(proj);
+        }
+    }
+
+    let projection = Projection::Columns(columns);
+    cc_to_select_query(projection, cc, false, None, Limit::None, Offset::None)
+}
+
 fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> TableOrSubquery {
     match computed {
         ComputedTable::Union {
@@ -266,53 +338,38 @@ fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> TableOrSubq
         } => {
             // The projection list for each CC must have the same shape and the same names.
             // The values we project might be fixed or they might be columns.
+            // Each arm simply turns into a subquery.
+            // The SQL translation will stuff "UNION" between each arm.
+            let projection: Vec<Variable> = projection.into_iter().collect();
             TableOrSubquery::Union(
                 arms.into_iter()
-                    .map(|cc| {
-                        // We're going to end up with the variables being projected and also some
-                        // type tag columns.
-                        let mut columns: Vec<ProjectedColumn> = Vec::with_capacity(projection.len() + type_extraction.len());
-
-                        // For each variable, find out which column it maps to within this arm, and
-                        // project it as the variable name.
-                        // E.g., SELECT datoms03.v AS `?x`.
-                        for var in projection.iter() {
-                            let (projected_column, maybe_type) = projected_column_for_var(var, &cc);
-                            columns.push(projected_column);
-
-                            // Similarly, project type tags if they're not known conclusively in the
-                            // outer query.
-                            // Assumption: we'll never need to project a tag without projecting the value of a variable.
-                            if type_extraction.contains(var) {
-                                let expression =
-                                    if let Some(ty) = maybe_type {
-                                        // If we know the type for sure, just project the constant.
-                                        // SELECT datoms03.v AS `?x`, 10 AS `?x_value_type_tag`
-                                        ColumnOrExpression::Integer(ty.value_type_tag())
-                                    } else {
-                                        // Otherwise, we'll have an established type binding! This'll be
-                                        // either a datoms table or, recursively, a subquery. Project
-                                        // this:
-                                        // SELECT datoms03.v AS `?x`,
-                                        //        datoms03.value_type_tag AS `?x_value_type_tag`
-                                        let extract = cc.extracted_types
-                                                        .get(var)
-                                                        .expect("Expected variable to have a known type or an extracted type");
-                                        ColumnOrExpression::Column(extract.clone())
-                                    };
-                                let type_column = VariableColumn::VariableTypeTag(var.clone());
-                                let proj = ProjectedColumn(expression, type_column.column_name());
-                                columns.push(proj);
-                            }
-                        }
-
-                        // Each arm simply turns into a subquery.
-                        // The SQL translation will stuff "UNION" between each arm.
-                        let projection = Projection::Columns(columns);
-                        cc_to_select_query(projection, cc, false, None, Limit::None)
-                  }).collect(),
+                    .map(|cc| select_for_arm(&projection, &type_extraction, cc))
+                    .collect(),
                 alias)
         },
+        ComputedTable::RecursiveRule {
+            projection, type_extraction, base, recursive,
+        } => {
+            // The arms project the columns of the recursive table, in the order we name them here.
+            let mut columns: Vec<String> = Vec::with_capacity(projection.len() + type_extraction.len());
+            for var in projection.iter() {
+                columns.push(VariableColumn::Variable(var.clone()).column_name());
+                if type_extraction.contains(var) {
+                    columns.push(VariableColumn::VariableTypeTag(var.clone()).column_name());
+                }
+            }
+
+            // The recursive arm refers to the table as `DatomsTable::RecursiveRule`.
+            let cte = CommonTableExpression {
+                name: DatomsTable::RecursiveRule.name().to_string(),
+                columns: columns,
+                initial: base.into_iter()
+                             .map(|cc| select_for_arm(&projection, &type_extraction, cc))
+                             .collect(),
+                recursive: Box::new(select_for_arm(&projection, &type_extraction, recursive)),
+            };
+            TableOrSubquery::Recursive(cte, alias)
+        },
         ComputedTable::Subquery(subquery) => {
             TableOrSubquery::Subquery(Box::new(cc_to_exists(subquery)))
         },
@@ -332,18 +389,22 @@ fn cc_to_select_query(projection: Projection,
                       cc: ConjoiningClauses,
                       distinct: bool,
                       order: Option<Vec<OrderBy>>,
-                      limit: Limit) -> SelectQuery {
-    let from = if cc.from.is_empty() {
+                      limit: Limit,
+                      offset: Offset) -> SelectQuery {
+    let from = if cc.from.is_empty() && cc.left_joins.is_empty() {
         FromClause::Nothing
     } else {
         // Move these out of the CC.
         let from = cc.from;
+        let left_joins = cc.left_joins;
         let mut computed: ConsumableVec<_> = cc.computed_tables.into();
 
         // Why do we put computed tables directly into the `FROM` clause? The alternative is to use
         // a CTE (`WITH`). They're typically equivalent, but some SQL systems (notably Postgres)
         // treat CTEs as optimization barriers, so a `WITH` can be significantly slower. Given that
         // this is easy enough to change later, we'll opt for using direct inclusion in `FROM`.
+        // The exception is a recursive rule, which can only be expressed with `WITH RECURSIVE`;
+        // even then, the CTE lives in its own subquery in `FROM`.
         let tables =
             from.into_iter().map(|source_alias| {
                 match source_alias {
@@ -357,7 +418,32 @@ fn cc_to_select_query(projection: Projection,
                 }
             });
 
-        FromClause::TableList(TableList(tables.collect()))
+        let tables: Vec<TableOrSubquery> = tables.collect();
+
+        if left_joins.is_empty() {
+            FromClause::TableList(TableList(tables))
+        } else {
+            // `LEFT JOIN` needs something on its left. If every other table has been
+            // optimized away -- say, because the entity was bound by an input -- use a single row.
+            let left = if tables.is_empty() {
+                vec![TableOrSubquery::Subquery(Box::new(cc_to_exists(ConjoiningClauses::default())))]
+            } else {
+                tables
+            };
+            let joins = left_joins.into_iter().map(|LeftJoin { table, constraints }| {
+                JoinedTable {
+                    op: JoinOp::LeftOuter,
+                    table: TableOrSubquery::Table(table),
+                    on: constraints.into_iter()
+                                   .map(|c| c.to_constraint())
+                                   .collect(),
+                }
+            });
+            FromClause::Join(Join {
+                left: TableList(left),
+                joins: joins.collect(),
+            })
+        }
     };
 
     let order = order.map_or(vec![], |vec| { vec.into_iter().map(|o| o.into()).collect() });
@@ -370,8 +456,10 @@ fn cc_to_select_query(projection: Projection,
                        .into_iter()
                        .map(|c| c.to_constraint())
                        .collect(),
+        group_by: vec![],
         order: order,
         limit: limit,
+        offset: offset,
     }
 }
 
@@ -385,22 +473,83 @@ pub fn cc_to_exists(cc: ConjoiningClauses) -> SelectQuery {
             projection: Projection::One,
             from: FromClause::Nothing,
             constraints: vec![],
+            group_by: vec![],
             order: vec![],
             limit: Limit::None,
+            offset: Offset::None,
         }
     } else {
-        cc_to_select_query(Projection::One, cc, false, None, Limit::None)
+        cc_to_select_query(Projection::One, cc, false, None, Limit::None, Offset::None)
     }
 }
 
 /// Consume a provided `AlgebraicQuery` to yield a new
 /// `ProjectedSelect`.
 pub fn query_to_select(query: AlgebraicQuery) -> Result<ProjectedSelect> {
-    // TODO: we can't pass `query.limit` here if we aggregate during projection.
-    // SQL-based aggregation -- `SELECT SUM(datoms00.e)` -- is fine.
-    let CombinedProjection { sql_projection, datalog_projector, distinct } = query_projection(&query)?;
+    query_to_select_with_view(query, None)
+}
+
+fn query_to_select_with_view(query: AlgebraicQuery, view: Option<DatomsView>) -> Result<ProjectedSelect> {
+    let CombinedProjection {
+        sql_projection,
+        pre_aggregate_projection,
+        datalog_projector,
+        distinct,
+        group_by,
+        nullable_aggregates,
+    } = query_projection(&query, view)?;
+
+    let select = match pre_aggregate_projection {
+        None => cc_to_select_query(sql_projection, query.cc, distinct, query.order, query.limit, query.offset),
+        Some(inner_projection) => {
+            // Aggregate over the distinct bindings produced by the inner query. Ordering and
+            // limits apply to the aggregated results, so they belong to the outer query.
+            let inner = cc_to_select_query(inner_projection, query.cc, true, None, Limit::None, Offset::None);
+            let order = query.order.map_or(vec![], |vec| { vec.into_iter().map(|o| o.into()).collect() });
+            let aggregated = SelectQuery {
+                distinct: false,
+                projection: sql_projection,
+                from: FromClause::TableList(TableList(vec![TableOrSubquery::Subquery(Box::new(inner))])),
+                constraints: vec![],
+                group_by: group_by,
+                order: order,
+                limit: query.limit,
+                offset: query.offset,
+            };
+
+            if nullable_aggregates.is_empty() {
+                aggregated
+            } else {
+                // With nothing to group by, aggregating no rows still yields one row, of NULLs.
+                // Discard it.
+                SelectQuery {
+                    distinct: false,
+                    projection: Projection::Star,
+                    from: FromClause::TableList(TableList(vec![TableOrSubquery::Subquery(Box::new(aggregated))])),
+                    constraints: nullable_aggregates.into_iter()
+                                                    .map(|name| Constraint::NotNull {
+                                                        value: ColumnOrExpression::ExistingColumn(name),
+                                                    })
+                                                    .collect(),
+                    group_by: vec![],
+                    order: vec![],
+                    limit: Limit::None,
+                    offset: Offset::None,
+                }
+            }
+        },
+    };
+
     Ok(ProjectedSelect {
-        query: cc_to_select_query(sql_projection, query.cc, distinct, query.order, query.limit),
+        query: select,
         projector: datalog_projector,
     })
 }
+
+/// Consume a provided `AlgebraicQuery` to yield a new `ProjectedSelect` that reads from the given
+/// view of the store rather than from its current state. Pull expressions read the same view.
+pub fn query_to_select_in_view(query: AlgebraicQuery, view: DatomsView) -> Result<ProjectedSelect> {
+    let mut select = query_to_select_with_view(query, Some(view))?;
+    select.query.use_view(view);
+    Ok(select)
+}
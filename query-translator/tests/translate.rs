@@ -32,14 +32,19 @@ use mentat_core::{
     ValueType,
 };
 
-use mentat_query_parser::parse_find_string;
+use mentat_query_parser::{
+    parse_find_string,
+    parse_rules_string,
+};
 use mentat_query_algebrizer::{
     QueryInputs,
     algebrize,
     algebrize_with_inputs,
 };
 use mentat_query_translator::{
+    DatomsView,
     query_to_select,
+    query_to_select_in_view,
 };
 
 use mentat_sql::SQLQuery;
@@ -166,6 +171,77 @@ fn test_bound_variable_limit() {
     assert_eq!(args, vec![make_arg("$v0", "yyy")]);
 }
 
+#[test]
+fn test_offset() {
+    let schema = prepopulated_schema();
+
+    // SQLite needs a limit before an offset, so we ask for every row.
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"] :offset 10]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT -1 OFFSET 10");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"] :limit 5 :offset 10]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT 5 OFFSET 10");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+}
+
+#[test]
+fn test_variable_offset() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :in ?offset :where [?x :foo/bar "yyy"] :offset ?offset]"#;
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, QueryInputs::default());
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 \
+                     LIMIT -1 OFFSET $ioffset");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?offset"), TypedValue::Long(20))]);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT -1 OFFSET 20");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+}
+
+#[test]
+fn test_seek_after() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x ?y :where [?x :foo/bar ?y] :order ?y (desc ?x) :limit 10]"#;
+    let parsed = parse_find_string(query).expect("parse to succeed");
+    let mut algebrized = algebrize(&schema, parsed).expect("algebrize to succeed");
+    let after = vec![(Variable::from_valid_name("?y"), TypedValue::typed_string("yyy")),
+                     (Variable::from_valid_name("?x"), TypedValue::Ref(5))].into_iter().collect();
+    algebrized.seek_after(&after).expect("seek to succeed");
+    let select = query_to_select(algebrized).expect("translate to succeed");
+    let SQLQuery { sql, args } = select.query.to_sql_query().unwrap();
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                       AND ((`datoms00`.v > $v0) OR (`datoms00`.v = $v0 AND `datoms00`.e < 5)) \
+                     ORDER BY `?y` ASC, `?x` DESC \
+                     LIMIT 10");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+}
+
+#[test]
+fn test_unbound_typed_input() {
+    let schema = Schema::default();
+
+    // We know the type of `?v` but not its value, so it becomes a parameter. Its type tag is
+    // checked in the query, just as if it were a constant.
+    let query = r#"[:find ?x :in ?v-1 :where [?x _ ?v-1]]"#;
+    let inputs = QueryInputs::with_type_sequence(vec![(Variable::from_valid_name("?v-1"), ValueType::Long)]);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.v = $iv_1 \
+                       AND (`datoms00`.value_type_tag = 5)");
+    assert_eq!(args, vec![]);
+}
+
 #[test]
 fn test_bound_variable_limit_affects_distinct() {
     let schema = prepopulated_schema();
@@ -934,6 +1010,7 @@ fn test_fulltext_inputs() {
     let inputs = QueryInputs::new(types, BTreeMap::default()).expect("valid inputs");
 
     // Without binding the value. q_once will err if you try this!
+    // The entity is left as a parameter to be bound when a prepared query is run.
     let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
     assert_eq!(sql, "SELECT DISTINCT `fulltext_values00`.text AS `?val` \
                      FROM \
@@ -941,7 +1018,8 @@ fn test_fulltext_inputs() {
                      `datoms` AS `datoms01` \
                      WHERE `datoms01`.a = 100 \
                        AND `datoms01`.v = `fulltext_values00`.rowid \
-                       AND `fulltext_values00`.text MATCH $v0");
+                       AND `fulltext_values00`.text MATCH $v0 \
+                       AND `datoms01`.e = $ientity");
     assert_eq!(args, vec![make_arg("$v0", "hello"),]);
 
     // With the value bound.
@@ -1013,3 +1091,390 @@ fn test_instant_range() {
                        AND `datoms00`.v > 1497574601257000");
     assert_eq!(args, vec![]);
 }
+
+#[test]
+fn test_aggregate_grouped() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x (max ?y) :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT `?x` AS `?x`, max(`?y`) AS `(max ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) \
+                     GROUP BY `?x`");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_aggregate_with() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // Nothing to group by, and `sum` is NULL over no rows, so we filter the result.
+    let query = r#"[:find (sum ?y) . :with ?x :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT * \
+                     FROM (SELECT sum(`?y`) AS `(sum ?y)` \
+                           FROM (SELECT DISTINCT `datoms00`.v AS `?y`, `datoms00`.e AS `?x` \
+                                 FROM `datoms` AS `datoms00` \
+                                 WHERE `datoms00`.a = 99) \
+                           LIMIT 1) \
+                     WHERE `(sum ?y)` IS NOT NULL");
+    assert_eq!(args, vec![]);
+
+    // Counts are never NULL.
+    let query = r#"[:find (count-distinct ?x) . :where [?x :foo/bar _]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT count(DISTINCT `?x`) AS `(count-distinct ?x)` \
+                     FROM (SELECT DISTINCT `datoms00`.e AS `?x` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) \
+                     LIMIT 1");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_aggregate_type_mismatch() {
+    let schema = prepopulated_schema();
+
+    // :foo/bar is a string attribute; we can't sum strings.
+    let query = r#"[:find (sum ?y) . :where [_ :foo/bar ?y]]"#;
+    let parsed = parse_find_string(query).expect("parse to succeed");
+    assert!(algebrize(&schema, parsed).is_err());
+
+    // Nor can we order an aggregate query by a variable we don't group by.
+    let query = r#"[:find (count ?y) :order ?x :where [?x :foo/bar ?y]]"#;
+    let parsed = parse_find_string(query).expect("parse to succeed");
+    assert!(algebrize(&schema, parsed).is_err());
+}
+
+#[test]
+fn test_recursive_rule() {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, NamespacedKeyword::new("folder", "parent"), 100);
+    add_attribute(&mut schema, 100, Attribute {
+        value_type: ValueType::Ref,
+        ..Default::default()
+    });
+    associate_ident(&mut schema, NamespacedKeyword::new("folder", "name"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+
+    let rules = parse_rules_string(r#"[[(ancestor ?x ?y) [?x :folder/parent ?y]]
+                                       [(ancestor ?x ?y) [?x :folder/parent ?z] (ancestor ?z ?y)]]"#)
+        .expect("rules to parse");
+    let query = r#"[:find ?f :in % :where (ancestor ?f ?root) [?root :folder/name "Root"]]"#;
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, QueryInputs::with_rules(rules));
+    assert_eq!(sql, "SELECT DISTINCT `c00`.`?x#0` AS `?f` \
+                     FROM (WITH RECURSIVE `recursive_rule` (`?x#0`, `?y#0`) AS \
+                           (SELECT `datoms02`.e AS `?x#0`, `datoms02`.v AS `?y#0` \
+                            FROM `datoms` AS `datoms02` \
+                            WHERE `datoms02`.a = 100 \
+                            UNION \
+                            SELECT `datoms05`.e AS `?x#0`, `recursive_rule04`.`?y#0` AS `?y#0` \
+                            FROM `recursive_rule` AS `recursive_rule04`, `datoms` AS `datoms05` \
+                            WHERE `datoms05`.a = 100 \
+                            AND `recursive_rule04`.`?x#0` = `datoms05`.v) \
+                           SELECT * FROM `recursive_rule`) AS `c00`, \
+                          `datoms` AS `datoms06` \
+                     WHERE `datoms06`.a = 101 \
+                     AND `datoms06`.v = $v0 \
+                     AND `c00`.`?y#0` = `datoms06`.e");
+    assert_eq!(args, vec![make_arg("$v0", "Root")]);
+}
+
+fn prepopulated_person_schema() -> Schema {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, NamespacedKeyword::new("person", "name"), 99);
+    add_attribute(&mut schema, 99, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+    associate_ident(&mut schema, NamespacedKeyword::new("person", "age"), 100);
+    add_attribute(&mut schema, 100, Attribute {
+        value_type: ValueType::Long,
+        ..Default::default()
+    });
+    schema
+}
+
+#[test]
+fn test_get_else() {
+    let schema = prepopulated_person_schema();
+
+    let query = r#"[:find ?name ?age
+                    :where [?x :person/name ?name]
+                           [(get-else $ ?x :person/age 0) ?age]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.v AS `?name`, COALESCE(`datoms01`.v, 0) AS `?age` \
+                     FROM `datoms` AS `datoms00` \
+                     LEFT JOIN `datoms` AS `datoms01` ON `datoms01`.e = `datoms00`.e AND `datoms01`.a = 100 \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![]);
+
+    // Constraining the bound variable constrains the defaulted value.
+    let query = r#"[:find ?name
+                    :where [?x :person/name ?name]
+                           [(get-else $ ?x :person/age 0) ?age]
+                           [(< ?age 18)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.v AS `?name` \
+                     FROM `datoms` AS `datoms00` \
+                     LEFT JOIN `datoms` AS `datoms01` ON `datoms01`.e = `datoms00`.e AND `datoms01`.a = 100 \
+                     WHERE `datoms00`.a = 99 \
+                     AND COALESCE(`datoms01`.v, 0) < 18");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_missing() {
+    let schema = prepopulated_person_schema();
+
+    let query = r#"[:find ?name
+                    :where [?x :person/name ?name]
+                           [(missing? $ ?x :person/age)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.v AS `?name` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND NOT EXISTS (SELECT 1 FROM `datoms` AS `datoms01` \
+                                     WHERE `datoms01`.a = 100 \
+                                     AND `datoms00`.e = `datoms01`.e)");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_tx_ids() {
+    let schema = Schema::default();
+
+    let query = r#"[:find ?tx :where [(tx-ids $ 1000 2000) [?tx ...]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `transactions00`.tx AS `?tx` \
+                     FROM `transactions` AS `transactions00` \
+                     WHERE 1000 <= `transactions00`.tx \
+                     AND `transactions00`.tx < 2000");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_tx_data() {
+    let schema = Schema::default();
+
+    let query = r#"[:find ?e ?a ?v ?added :where [(tx-data $ 1000) [[?e ?a ?v _ ?added]]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `all_transactions00`.e AS `?e`, \
+                                     `all_transactions00`.a AS `?a`, \
+                                     `all_transactions00`.v AS `?v`, \
+                                     `all_transactions00`.value_type_tag AS `?v_value_type_tag`, \
+                                     `all_transactions00`.added AS `?added` \
+                     FROM (SELECT e, a, v, tx, added, value_type_tag FROM `transactions` \
+                           WHERE NOT (`transactions`.value_type_tag = 10 AND typeof(`transactions`.v) = 'integer') \
+                           UNION ALL \
+                           SELECT `transactions`.e AS e, `transactions`.a AS a, `fulltext_values`.text AS v, \
+                           `transactions`.tx AS tx, `transactions`.added AS added, \
+                           `transactions`.value_type_tag AS value_type_tag \
+                           FROM `transactions`, `fulltext_values` \
+                           WHERE `transactions`.value_type_tag = 10 AND typeof(`transactions`.v) = 'integer' \
+                           AND `transactions`.v = `fulltext_values`.rowid) AS `all_transactions00` \
+                     WHERE `all_transactions00`.tx = 1000");
+    assert_eq!(args, vec![]);
+
+    // Without the value, we don't need to look up fulltext values.
+    let query = r#"[:find ?e ?added :where [(tx-data $ 1000) [[?e _ _ _ ?added]]]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `transactions00`.e AS `?e`, \
+                                     `transactions00`.added AS `?added` \
+                     FROM `transactions` AS `transactions00` \
+                     WHERE `transactions00`.tx = 1000");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_history() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?v ?tx ?added :where [$history ?x :foo/bar ?v ?tx ?added]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `transactions00`.v AS `?v`, \
+                                     `transactions00`.tx AS `?tx`, \
+                                     `transactions00`.added AS `?added` \
+                     FROM `transactions` AS `transactions00` \
+                     WHERE `transactions00`.a = 99");
+    assert_eq!(args, vec![]);
+
+    // Retractions only.
+    let query = r#"[:find ?x ?v :where [$history ?x :foo/bar ?v _ false]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `transactions00`.e AS `?x`, \
+                                     `transactions00`.v AS `?v` \
+                     FROM `transactions` AS `transactions00` \
+                     WHERE `transactions00`.a = 99 \
+                     AND `transactions00`.added = 0");
+    assert_eq!(args, vec![]);
+
+    // History patterns join against current-state patterns as usual.
+    let query = r#"[:find ?v :where [?x :foo/bar "yyy"] [$history ?x :foo/bar ?v]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `transactions01`.v AS `?v` \
+                     FROM `datoms` AS `datoms00`, `transactions` AS `transactions01` \
+                     WHERE `datoms00`.a = 99 \
+                     AND `datoms00`.v = $v0 \
+                     AND `transactions01`.a = 99 \
+                     AND `datoms00`.e = `transactions01`.e");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+}
+
+#[test]
+fn test_as_of() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x . :where [?x :foo/bar "yyy"]]"#;
+    let parsed = parse_find_string(query).expect("parse to succeed");
+    let algebrized = algebrize(&schema, parsed).expect("algebrize to succeed");
+    let select = query_to_select_in_view(algebrized, DatomsView::AsOf(1000)).expect("translate to succeed");
+    let SQLQuery { sql, args } = select.query.to_sql_query().unwrap();
+    assert_eq!(sql, "SELECT `datoms00`.e AS `?x` \
+                     FROM (SELECT e, a, v, tx, value_type_tag FROM `transactions` AS `asserted` \
+                           WHERE `asserted`.added = 1 AND `asserted`.tx <= 1000 \
+                           AND NOT EXISTS (SELECT 1 FROM `transactions` AS `later` \
+                           WHERE `later`.e = `asserted`.e AND `later`.a = `asserted`.a \
+                           AND `later`.value_type_tag = `asserted`.value_type_tag AND `later`.v = `asserted`.v \
+                           AND `later`.tx > `asserted`.tx AND `later`.tx <= 1000)) AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT 1");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+
+    let parsed = parse_find_string(query).expect("parse to succeed");
+    let algebrized = algebrize(&schema, parsed).expect("algebrize to succeed");
+    let select = query_to_select_in_view(algebrized, DatomsView::Since(1000)).expect("translate to succeed");
+    let SQLQuery { sql, args } = select.query.to_sql_query().unwrap();
+    assert_eq!(sql, "SELECT `datoms00`.e AS `?x` \
+                     FROM (SELECT e, a, v, tx, value_type_tag FROM `datoms` WHERE tx > 1000) AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT 1");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+}
+
+#[test]
+fn test_string_predicates() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :where [?x :foo/bar ?name] [(starts-with? ?name "Al")]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND (substr(`datoms00`.v, 1, length($v0)) = $v0)");
+    assert_eq!(args, vec![make_arg("$v0", "Al")]);
+
+    let query = r#"[:find ?x :where [?x :foo/bar ?name] [(ends-with? ?name "ce")]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND (substr(`datoms00`.v, ((length(`datoms00`.v) - length($v0)) + 1)) = $v0)");
+    assert_eq!(args, vec![make_arg("$v0", "ce")]);
+
+    // Regular expressions only match the whole string.
+    let query = r#"[:find ?x :where [?x :foo/bar ?name] [(re-matches? "A.*e" ?name)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND regexp($v0, `datoms00`.v)");
+    assert_eq!(args, vec![make_arg("$v0", "^(?:A.*e)$")]);
+}
+
+#[test]
+fn test_string_functions() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x ?initial ?n
+                    :where [?x :foo/bar ?name]
+                           [(subs ?name 0 1) ?initial]
+                           [(count ?name) ?n]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, \
+                                     substr(`datoms00`.v, 1, 1) AS `?initial`, \
+                                     length(`datoms00`.v) AS `?n` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![]);
+
+    // A computed value can be used like any other.
+    let query = r#"[:find ?x
+                    :where [?x :foo/bar ?name]
+                           [(lower-case ?name) ?lower]
+                           [?y :foo/bar ?lower]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 99 \
+                     AND `datoms01`.a = 99 \
+                     AND lower(`datoms00`.v) = `datoms01`.v");
+    assert_eq!(args, vec![]);
+
+    let query = r#"[:find ?shout . :where [?x :foo/bar ?name] [(str ?name "!") ?shout]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT (`datoms00`.v || $v0) AS `?shout` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     LIMIT 1");
+    assert_eq!(args, vec![make_arg("$v0", "!")]);
+}
+
+#[test]
+fn test_arithmetic() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x ?older ?ratio
+                    :where [?x :foo/bar ?age]
+                           [(+ ?age 1) ?older]
+                           [(/ 100 ?age) ?ratio]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, \
+                                     (`datoms00`.v + 1) AS `?older`, \
+                                     (100 / `datoms00`.v) AS `?ratio` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND (`datoms00`.v <> 0)");
+    assert_eq!(args, vec![]);
+
+    // Mixing a long with a double produces a double.
+    let query = r#"[:find ?scaled . :where [?x :foo/bar ?age] [(* ?age 1.5) ?scaled]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT (`datoms00`.v * 1.5e0) AS `?scaled` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     LIMIT 1");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_instant_functions() {
+    let schema = prepopulated_typed_schema(ValueType::Instant);
+
+    let query = r#"[:find ?x ?day
+                    :where [?x :foo/bar ?t]
+                           [(truncate-instant ?t :day) ?day]
+                    :order (desc ?day)
+                    :limit 10]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, \
+                                     (`datoms00`.v - (((`datoms00`.v % 86400000000) + 86400000000) % 86400000000)) AS `?day` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     ORDER BY `?day` DESC \
+                     LIMIT 10");
+    assert_eq!(args, vec![]);
+
+    let query = r#"[:find ?year . :where [?x :foo/bar ?t] [(instant-year ?t) ?year]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT CAST(strftime($v0, \
+                                         ((`datoms00`.v - (((`datoms00`.v % 1000000) + 1000000) % 1000000)) / 1000000), \
+                                         $v1) AS INTEGER) AS `?year` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     LIMIT 1");
+    assert_eq!(args, vec![make_arg("$v0", "%Y"), make_arg("$v1", "unixepoch")]);
+}
@@ -40,6 +40,7 @@ use std::collections::{
 
 use std::fmt;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use edn::{
     BigInt,
@@ -50,11 +51,13 @@ use edn::{
 };
 
 pub use edn::{
+    Keyword,
     NamespacedKeyword,
     PlainSymbol,
 };
 
 use mentat_core::{
+    KnownEntid,
     TypedValue,
     ValueType,
 };
@@ -62,7 +65,7 @@ use mentat_core::{
 pub type SrcVarName = String;          // Do not include the required syntactic '$'.
 
 #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct Variable(pub Rc<PlainSymbol>);
+pub struct Variable(pub Arc<PlainSymbol>);
 
 impl Variable {
     pub fn as_str(&self) -> &str {
@@ -81,7 +84,7 @@ impl Variable {
     pub fn from_valid_name(name: &str) -> Variable {
         let s = PlainSymbol::new(name);
         assert!(s.is_var_symbol());
-        Variable(Rc::new(s))
+        Variable(Arc::new(s))
     }
 }
 
@@ -103,7 +106,7 @@ impl FromValue<Variable> for Variable {
 }
 
 impl Variable {
-    pub fn from_rc(sym: Rc<PlainSymbol>) -> Option<Variable> {
+    pub fn from_arc(sym: Arc<PlainSymbol>) -> Option<Variable> {
         if sym.is_var_symbol() {
             Some(Variable(sym.clone()))
         } else {
@@ -111,10 +114,16 @@ impl Variable {
         }
     }
 
+    /// Variables now share their symbols through an `Arc`, so this copies the symbol.
+    #[deprecated(note = "use `Variable::from_arc` or `Variable::from_symbol`")]
+    pub fn from_rc(sym: Rc<PlainSymbol>) -> Option<Variable> {
+        Variable::from_symbol(&sym)
+    }
+
     /// TODO: intern strings. #398.
     pub fn from_symbol(sym: &PlainSymbol) -> Option<Variable> {
         if sym.is_var_symbol() {
-            Some(Variable(Rc::new(sym.clone())))
+            Some(Variable(Arc::new(sym.clone())))
         } else {
             None
         }
@@ -179,7 +188,22 @@ impl FromValue<SrcVar> for SrcVar {
     }
 }
 
+/// The name of the built-in source that contains every assertion and retraction ever made.
+pub const HISTORY_SRC: &'static str = "history";
+
 impl SrcVar {
+    /// The built-in `$history` source.
+    pub fn history() -> SrcVar {
+        SrcVar::NamedSrc(HISTORY_SRC.to_string())
+    }
+
+    pub fn is_history(&self) -> bool {
+        match self {
+            &SrcVar::NamedSrc(ref name) => name == HISTORY_SRC,
+            &SrcVar::DefaultSrc => false,
+        }
+    }
+
     pub fn from_symbol(sym: &PlainSymbol) -> Option<SrcVar> {
         if sym.is_src_symbol() {
             if sym.0 == "$" {
@@ -199,7 +223,7 @@ pub enum NonIntegerConstant {
     Boolean(bool),
     BigInteger(BigInt),
     Float(OrderedFloat<f64>),
-    Text(Rc<String>),
+    Text(Arc<String>),
     Instant(DateTime<Utc>),
     Uuid(Uuid),
 }
@@ -210,7 +234,7 @@ impl NonIntegerConstant {
             NonIntegerConstant::BigInteger(_) => unimplemented!(),     // TODO: #280.
             NonIntegerConstant::Boolean(v) => TypedValue::Boolean(v),
             NonIntegerConstant::Float(v) => TypedValue::Double(v),
-            NonIntegerConstant::Text(v) => TypedValue::String(v),
+            NonIntegerConstant::Text(v) => TypedValue::typed_string(v.as_str()),
             NonIntegerConstant::Instant(v) => TypedValue::Instant(v),
             NonIntegerConstant::Uuid(v) => TypedValue::Uuid(v),
         }
@@ -223,6 +247,9 @@ pub enum FnArg {
     SrcVar(SrcVar),
     EntidOrInteger(i64),
     IdentOrKeyword(NamespacedKeyword),
+    // A keyword without a namespace, like `:day`, which can't be an ident. These name options to
+    // functions.
+    Keyword(Keyword),
     Constant(NonIntegerConstant),
     // The collection values representable in EDN.  There's no advantage to destructuring up front,
     // since consumers will need to handle arbitrarily nested EDN themselves anyway.
@@ -242,6 +269,8 @@ impl FromValue<FnArg> for FnArg {
             PlainSymbol(_) => None,
             NamespacedKeyword(ref x) =>
                 Some(FnArg::IdentOrKeyword(x.clone())),
+            Keyword(ref x) =>
+                Some(FnArg::Keyword(x.clone())),
             Instant(x) =>
                 Some(FnArg::Constant(NonIntegerConstant::Instant(x))),
             Uuid(x) =>
@@ -254,10 +283,9 @@ impl FromValue<FnArg> for FnArg {
                 Some(FnArg::Constant(NonIntegerConstant::BigInteger(x.clone()))),
             Text(ref x) =>
                 // TODO: intern strings. #398.
-                Some(FnArg::Constant(NonIntegerConstant::Text(Rc::new(x.clone())))),
+                Some(FnArg::Constant(NonIntegerConstant::Text(Arc::new(x.clone())))),
             Nil |
             NamespacedSymbol(_) |
-            Keyword(_) |
             Vector(_) |
             List(_) |
             Set(_) |
@@ -273,11 +301,43 @@ impl FnArg {
             _ => None,
         }
     }
+
+    /// Return the pattern place that this argument would occupy in the e, a, or tx position of a
+    /// pattern, or `None` if it can't appear there.
+    pub fn to_pattern_non_value_place(&self) -> Option<PatternNonValuePlace> {
+        match self {
+            &FnArg::Variable(ref v)       => Some(PatternNonValuePlace::Variable(v.clone())),
+            &FnArg::EntidOrInteger(x)     => if x >= 0 {
+                Some(PatternNonValuePlace::Entid(x))
+            } else {
+                None
+            },
+            &FnArg::IdentOrKeyword(ref k) => Some(PatternNonValuePlace::Ident(Rc::new(k.clone()))),
+            &FnArg::SrcVar(_) |
+            &FnArg::Keyword(_) |
+            &FnArg::Constant(_) |
+            &FnArg::Vector(_)             => None,
+        }
+    }
+
+    /// Return the pattern place that this argument would occupy in the v position of a pattern,
+    /// or `None` if it can't appear there.
+    pub fn to_pattern_value_place(&self) -> Option<PatternValuePlace> {
+        match self {
+            &FnArg::Variable(ref v)       => Some(PatternValuePlace::Variable(v.clone())),
+            &FnArg::EntidOrInteger(x)     => Some(PatternValuePlace::EntidOrInteger(x)),
+            &FnArg::IdentOrKeyword(ref k) => Some(PatternValuePlace::IdentOrKeyword(Rc::new(k.clone()))),
+            &FnArg::Constant(ref c)       => Some(PatternValuePlace::Constant(c.clone())),
+            &FnArg::SrcVar(_) |
+            &FnArg::Keyword(_) |
+            &FnArg::Vector(_)             => None,
+        }
+    }
 }
 
 /// e, a, tx can't be values -- no strings, no floats -- and so
-/// they can only be variables, entity IDs, ident keywords, or
-/// placeholders.
+/// they can only be variables, entity IDs, ident keywords, lookup
+/// refs, or placeholders.
 /// This encoding allows us to represent integers that aren't
 /// entity IDs. That'll get filtered out in the context of the
 /// database.
@@ -287,26 +347,29 @@ pub enum PatternNonValuePlace {
     Variable(Variable),
     Entid(i64),                       // Will always be +ve. See #190.
     Ident(Rc<NamespacedKeyword>),
+    LookupRef(Rc<LookupRef>),
 }
 
 impl PatternNonValuePlace {
     // I think we'll want move variants, so let's leave these here for now.
     #[allow(dead_code)]
-    fn into_pattern_value_place(self) -> PatternValuePlace {
+    fn into_pattern_value_place(self) -> Option<PatternValuePlace> {
         match self {
-            PatternNonValuePlace::Placeholder => PatternValuePlace::Placeholder,
-            PatternNonValuePlace::Variable(x) => PatternValuePlace::Variable(x),
-            PatternNonValuePlace::Entid(x)    => PatternValuePlace::EntidOrInteger(x),
-            PatternNonValuePlace::Ident(x)    => PatternValuePlace::IdentOrKeyword(x),
+            PatternNonValuePlace::Placeholder  => Some(PatternValuePlace::Placeholder),
+            PatternNonValuePlace::Variable(x)  => Some(PatternValuePlace::Variable(x)),
+            PatternNonValuePlace::Entid(x)     => Some(PatternValuePlace::EntidOrInteger(x)),
+            PatternNonValuePlace::Ident(x)     => Some(PatternValuePlace::IdentOrKeyword(x)),
+            PatternNonValuePlace::LookupRef(_) => None,
         }
     }
 
-    fn to_pattern_value_place(&self) -> PatternValuePlace {
+    fn to_pattern_value_place(&self) -> Option<PatternValuePlace> {
         match *self {
-            PatternNonValuePlace::Placeholder     => PatternValuePlace::Placeholder,
-            PatternNonValuePlace::Variable(ref x) => PatternValuePlace::Variable(x.clone()),
-            PatternNonValuePlace::Entid(x)        => PatternValuePlace::EntidOrInteger(x),
-            PatternNonValuePlace::Ident(ref x)    => PatternValuePlace::IdentOrKeyword(x.clone()),
+            PatternNonValuePlace::Placeholder     => Some(PatternValuePlace::Placeholder),
+            PatternNonValuePlace::Variable(ref x) => Some(PatternValuePlace::Variable(x.clone())),
+            PatternNonValuePlace::Entid(x)        => Some(PatternValuePlace::EntidOrInteger(x)),
+            PatternNonValuePlace::Ident(ref x)    => Some(PatternValuePlace::IdentOrKeyword(x.clone())),
+            PatternNonValuePlace::LookupRef(_)    => None,
         }
     }
 }
@@ -330,17 +393,56 @@ impl FromValue<PatternNonValuePlace> for PatternNonValuePlace {
             },
             edn::SpannedValue::NamespacedKeyword(ref x) =>
                 Some(PatternNonValuePlace::Ident(Rc::new(x.clone()))),
+            edn::SpannedValue::Vector(_) =>
+                LookupRef::from_value(v).map(|r| PatternNonValuePlace::LookupRef(Rc::new(r))),
             _ => None,
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
 pub enum IdentOrEntid {
     Ident(Rc<NamespacedKeyword>),
     Entid(i64),
 }
 
+/// A lookup ref, like `[:person/email "a@b.c"]`, names the entity that has the given value for a
+/// unique attribute.
+///
+/// The parser can't know the type of the attribute, so an integer value is read as a `Long` and a
+/// keyword as a `Keyword`. The algebrizer coerces these to a `Ref` when the attribute calls for
+/// one.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct LookupRef {
+    pub attribute: IdentOrEntid,
+    pub value: TypedValue,
+}
+
+impl FromValue<LookupRef> for LookupRef {
+    fn from_value(v: &edn::ValueAndSpan) -> Option<LookupRef> {
+        let items = match v.inner {
+            edn::SpannedValue::Vector(ref items) if items.len() == 2 => items,
+            _ => return None,
+        };
+        let attribute = match items[0].inner {
+            edn::SpannedValue::Integer(x) if x >= 0 => IdentOrEntid::Entid(x),
+            edn::SpannedValue::NamespacedKeyword(ref x) => IdentOrEntid::Ident(Rc::new(x.clone())),
+            _ => return None,
+        };
+        let value = match PatternValuePlace::from_value(&items[1]) {
+            Some(PatternValuePlace::EntidOrInteger(x)) => TypedValue::Long(x),
+            Some(PatternValuePlace::IdentOrKeyword(x)) => TypedValue::Keyword(x),
+            Some(PatternValuePlace::Constant(NonIntegerConstant::BigInteger(_))) => return None,
+            Some(PatternValuePlace::Constant(c)) => c.into_typed_value(),
+            _ => return None,
+        };
+        Some(LookupRef {
+            attribute: attribute,
+            value: value,
+        })
+    }
+}
+
 /// The `v` part of a pattern can be much broader: it can represent
 /// integers that aren't entity IDs (particularly negative integers),
 /// strings, and all the rest. We group those under `Constant`.
@@ -374,7 +476,7 @@ impl FromValue<PatternValuePlace> for PatternValuePlace {
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Instant(x))),
             edn::SpannedValue::Text(ref x) =>
                 // TODO: intern strings. #398.
-                Some(PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new(x.clone())))),
+                Some(PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new(x.clone())))),
             edn::SpannedValue::Uuid(ref u) =>
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Uuid(u.clone()))),
 
@@ -422,31 +524,194 @@ impl PatternValuePlace {
     }
 }
 
-/*
-pub enum PullPattern {
-    Constant(Constant),
-    Variable(Variable),
+impl From<Variable> for PatternNonValuePlace {
+    fn from(v: Variable) -> PatternNonValuePlace {
+        PatternNonValuePlace::Variable(v)
+    }
+}
+
+impl From<KnownEntid> for PatternNonValuePlace {
+    fn from(e: KnownEntid) -> PatternNonValuePlace {
+        PatternNonValuePlace::Entid(e.0)
+    }
 }
 
+impl From<NamespacedKeyword> for PatternNonValuePlace {
+    fn from(k: NamespacedKeyword) -> PatternNonValuePlace {
+        PatternNonValuePlace::Ident(Rc::new(k))
+    }
+}
+
+impl From<LookupRef> for PatternNonValuePlace {
+    fn from(r: LookupRef) -> PatternNonValuePlace {
+        PatternNonValuePlace::LookupRef(Rc::new(r))
+    }
+}
+
+impl From<Variable> for PatternValuePlace {
+    fn from(v: Variable) -> PatternValuePlace {
+        PatternValuePlace::Variable(v)
+    }
+}
+
+impl From<NamespacedKeyword> for PatternValuePlace {
+    fn from(k: NamespacedKeyword) -> PatternValuePlace {
+        PatternValuePlace::IdentOrKeyword(Rc::new(k))
+    }
+}
+
+impl From<i64> for PatternValuePlace {
+    fn from(x: i64) -> PatternValuePlace {
+        PatternValuePlace::EntidOrInteger(x)
+    }
+}
+
+impl From<bool> for PatternValuePlace {
+    fn from(x: bool) -> PatternValuePlace {
+        PatternValuePlace::Constant(NonIntegerConstant::Boolean(x))
+    }
+}
+
+impl<'a> From<&'a str> for PatternValuePlace {
+    fn from(x: &'a str) -> PatternValuePlace {
+        PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new(x.to_string())))
+    }
+}
+
+/// A value in the place it would have if it had been written in a query.
+impl From<TypedValue> for PatternValuePlace {
+    fn from(v: TypedValue) -> PatternValuePlace {
+        match v {
+            TypedValue::Ref(x)     => PatternValuePlace::EntidOrInteger(x),
+            TypedValue::Long(x)    => PatternValuePlace::EntidOrInteger(x),
+            TypedValue::Keyword(x) => PatternValuePlace::IdentOrKeyword(x),
+            TypedValue::Boolean(x) => PatternValuePlace::Constant(NonIntegerConstant::Boolean(x)),
+            TypedValue::Double(x)  => PatternValuePlace::Constant(NonIntegerConstant::Float(x)),
+            TypedValue::Instant(x) => PatternValuePlace::Constant(NonIntegerConstant::Instant(x)),
+            TypedValue::String(x)  => PatternValuePlace::Constant(NonIntegerConstant::Text(Arc::new((*x).clone()))),
+            TypedValue::Uuid(x)    => PatternValuePlace::Constant(NonIntegerConstant::Uuid(x)),
+        }
+    }
+}
+
+/// One entry in the pattern of a pull expression.
+///
+/// ```edn
+/// (pull ?e [:foo/name {:foo/child [:bar/x]} :foo/_parent *])
+/// ```
+///
+/// yields, in order, an `Attribute`, a `Nested`, another (reversed) `Attribute`, and a `Wildcard`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PullAttributeSpec {
+    /// `*`: every attribute of the entity.
+    Wildcard,
+    /// A single attribute, which might be reversed: `:foo/_parent`.
+    Attribute(NamespacedKeyword),
+    /// A ref attribute and the pattern to pull from each entity it refers to.
+    Nested(NamespacedKeyword, Vec<PullAttributeSpec>),
+}
+
+impl std::fmt::Display for PullAttributeSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            &PullAttributeSpec::Wildcard => write!(f, "*"),
+            &PullAttributeSpec::Attribute(ref attr) => write!(f, "{}", attr),
+            &PullAttributeSpec::Nested(ref attr, ref patterns) => {
+                write!(f, "{{{} [", attr)?;
+                for (i, p) in patterns.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", p)?;
+                }
+                write!(f, "]}}")
+            },
+        }
+    }
+}
+
+/// A pull expression in a `:find` list: `(pull ?e [:foo/name])`.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Pull {
-    pub src: SrcVar,
     pub var: Variable,
-    pub pattern: PullPattern,      // Constant, variable, or plain variable.
+    pub patterns: Vec<PullAttributeSpec>,
+}
+
+impl std::fmt::Display for Pull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "(pull {} [", self.var)?;
+        for (i, p) in self.patterns.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", p)?;
+        }
+        write!(f, "])")
+    }
 }
-*/
 
-/*
+/// An aggregate expression in a `:find` list, like `(max ?x)` or `(count-distinct ?y)`.
+/// The parser accepts any function name and argument list; the algebrizer checks that the
+/// function is a supported aggregate and that the arguments make sense.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Aggregate {
-    pub fn_name: String,
+    pub func: QueryFunction,
     pub args: Vec<FnArg>,
 }
-*/
+
+impl std::fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({}", self.func.0)?;
+        for arg in self.args.iter() {
+            match arg {
+                &FnArg::Variable(ref var) => write!(f, " {}", var)?,
+                arg => write!(f, " {:?}", arg)?,
+            }
+        }
+        write!(f, ")")
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Element {
     Variable(Variable),
-    // Aggregate(Aggregate),   // TODO
-    // Pull(Pull),             // TODO
+    Aggregate(Aggregate),
+    Pull(Pull),
+
+    /// `(ident ?v)`: like `?v`, but a ref to an entity that has a `:db/ident` -- typically an
+    /// enum value, like `:color/red` -- is projected as that ident.
+    Ident(Variable),
+}
+
+impl Element {
+    /// Returns true if this element aggregates over the rows of the query, rather than naming a
+    /// single binding in each row.
+    pub fn is_aggregate(&self) -> bool {
+        match self {
+            &Element::Variable(_) => false,
+            &Element::Pull(_) => false,
+            &Element::Ident(_) => false,
+            &Element::Aggregate(_) => true,
+        }
+    }
+}
+
+impl From<Variable> for Element {
+    fn from(v: Variable) -> Element {
+        Element::Variable(v)
+    }
+}
+
+impl From<Pull> for Element {
+    fn from(p: Pull) -> Element {
+        Element::Pull(p)
+    }
+}
+
+impl From<Aggregate> for Element {
+    fn from(a: Aggregate) -> Element {
+        Element::Aggregate(a)
+    }
 }
 
 impl std::fmt::Display for Element {
@@ -455,6 +720,15 @@ impl std::fmt::Display for Element {
             &Element::Variable(ref var) => {
                 write!(f, "{}", var)
             },
+            &Element::Aggregate(ref agg) => {
+                write!(f, "{}", agg)
+            },
+            &Element::Pull(ref pull) => {
+                write!(f, "{}", pull)
+            },
+            &Element::Ident(ref var) => {
+                write!(f, "(ident {})", var)
+            },
         }
     }
 }
@@ -466,6 +740,15 @@ pub enum Limit {
     Variable(Variable),
 }
 
+/// The number of results to skip before the first one returned. Unlike a limit, an offset can be
+/// zero.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Offset {
+    None,
+    Fixed(u64),
+    Variable(Variable),
+}
+
 /// A definition of the first part of a find query: the
 /// `[:find ?foo ?bar…]` bit.
 ///
@@ -569,6 +852,16 @@ impl FindSpec {
             &FindRel(ref v)    => Box::new(v.iter()),
         }
     }
+
+    /// Returns true if any of the elements in this find spec is an aggregate.
+    pub fn has_aggregates(&self) -> bool {
+        self.columns().any(|e| e.is_aggregate())
+    }
+
+    /// Returns true if any of the elements in this find spec is a pull expression.
+    pub fn has_pulls(&self) -> bool {
+        self.columns().any(|e| match e { &Element::Pull(_) => true, _ => false })
+    }
 }
 
 // Datomic accepts variable or placeholder.  DataScript accepts recursive bindings.  Mentat sticks
@@ -663,6 +956,8 @@ impl Binding {
 // A pattern with a reversed attribute — :foo/_bar — is reversed
 // at the point of parsing. These `Pattern` instances only represent
 // one direction.
+// The fifth place, `added`, is only meaningful against the `$history` source: it's `true` for
+// an assertion and `false` for a retraction.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Pattern {
     pub source: Option<SrcVar>,
@@ -670,6 +965,7 @@ pub struct Pattern {
     pub attribute: PatternNonValuePlace,
     pub value: PatternValuePlace,
     pub tx: PatternNonValuePlace,
+    pub added: PatternValuePlace,
 }
 
 impl Pattern {
@@ -690,14 +986,14 @@ impl Pattern {
                 // e and v have different types; we must convert them.
                 // Not every parseable value is suitable for the entity field!
                 // As such, this is a failable constructor.
-                let e_v = e.to_pattern_value_place();
-                if let Some(v_e) = v.to_pattern_non_value_place() {
+                if let (Some(e_v), Some(v_e)) = (e.to_pattern_value_place(), v.to_pattern_non_value_place()) {
                     return Some(Pattern {
                         source: src,
                         entity: v_e,
                         attribute: PatternNonValuePlace::Ident(Rc::new(k.to_reversed())),
                         value: e_v,
                         tx: tx,
+                        added: PatternValuePlace::Placeholder,
                     });
                 } else {
                     return None;
@@ -710,6 +1006,7 @@ impl Pattern {
             attribute: a,
             value: v,
             tx: tx,
+            added: PatternValuePlace::Placeholder,
         })
     }
 }
@@ -796,6 +1093,29 @@ pub struct NotJoin {
     pub clauses: Vec<WhereClause>,
 }
 
+/// An invocation of a rule, like `(ancestor ?x ?y)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuleExpr {
+    pub name: PlainSymbol,
+    pub args: Vec<FnArg>,
+}
+
+/// One definition of a rule, like
+///
+/// ```edn
+/// [(named-friend ?x ?name) [?x :person/friend ?y] [?y :person/name ?name]]
+/// ```
+///
+/// A rule can have several definitions with the same name and arity. An invocation of the rule
+/// matches if any one of its definitions does, just as if the definitions were the arms of an
+/// `or-join` on the rule's variables.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    pub name: PlainSymbol,
+    pub vars: Vec<Variable>,
+    pub clauses: Vec<WhereClause>,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TypeAnnotation {
     pub value_type: ValueType,
@@ -809,7 +1129,7 @@ pub enum WhereClause {
     OrJoin(OrJoin),
     Pred(Predicate),
     WhereFn(WhereFn),
-    RuleExpr,
+    RuleExpr(RuleExpr),
     Pattern(Pattern),
     TypeAnnotation(TypeAnnotation),
 }
@@ -822,10 +1142,13 @@ pub struct FindQuery {
     pub with: BTreeSet<Variable>,
     pub in_vars: BTreeSet<Variable>,
     pub in_sources: BTreeSet<SrcVar>,
+
+    /// True if `%` appears in `:in`: the query uses the rules supplied alongside its inputs.
+    pub in_rules: bool,
     pub limit: Limit,
+    pub offset: Offset,
     pub where_clauses: Vec<WhereClause>,
     pub order: Option<Vec<Order>>,
-    // TODO: in_rules;
 }
 
 impl FindQuery {
@@ -836,7 +1159,9 @@ impl FindQuery {
             with: BTreeSet::default(),
             in_vars: BTreeSet::default(),
             in_sources: BTreeSet::default(),
+            in_rules: false,
             limit: Limit::None,
+            offset: Offset::None,
             where_clauses: where_clauses,
             order: None,
         }
@@ -892,7 +1217,7 @@ impl ContainsVariables for WhereClause {
             &NotJoin(ref n)        => n.accumulate_mentioned_variables(acc),
             &WhereFn(ref f)        => f.accumulate_mentioned_variables(acc),
             &TypeAnnotation(ref a) => a.accumulate_mentioned_variables(acc),
-            &RuleExpr              => (),
+            &RuleExpr(ref r)       => r.accumulate_mentioned_variables(acc),
         }
     }
 }
@@ -955,6 +1280,26 @@ impl ContainsVariables for Predicate {
     }
 }
 
+impl ContainsVariables for RuleExpr {
+    fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
+        for arg in &self.args {
+            if let &FnArg::Variable(ref v) = arg {
+                acc_ref(acc, v)
+            }
+        }
+    }
+}
+
+impl ContainsVariables for Rule {
+    fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
+        for v in &self.vars {
+            acc_ref(acc, v);
+        }
+        for clause in &self.clauses {
+            clause.accumulate_mentioned_variables(acc);
+        }
+    }
+}
 
 impl ContainsVariables for TypeAnnotation {
     fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
@@ -1011,5 +1356,403 @@ impl ContainsVariables for Pattern {
         if let PatternNonValuePlace::Variable(ref v) = self.tx {
             acc_ref(acc, v)
         }
+        if let PatternValuePlace::Variable(ref v) = self.added {
+            acc_ref(acc, v)
+        }
+    }
+}
+
+/// Walk clauses to find the lookup refs they mention, so that a caller with access to the store
+/// can resolve them before algebrizing.
+pub trait ContainsLookupRefs {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>);
+    fn collect_lookup_refs(&self) -> BTreeSet<LookupRef> {
+        let mut out = BTreeSet::new();
+        self.accumulate_lookup_refs(&mut out);
+        out
+    }
+}
+
+impl ContainsLookupRefs for WhereClause {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        use WhereClause::*;
+        match self {
+            &OrJoin(ref o)         => o.accumulate_lookup_refs(acc),
+            &Pattern(ref p)        => p.accumulate_lookup_refs(acc),
+            &NotJoin(ref n)        => n.accumulate_lookup_refs(acc),
+            &Pred(_) |
+            &WhereFn(_) |
+            &TypeAnnotation(_) |
+            &RuleExpr(_)           => (),
+        }
+    }
+}
+
+impl ContainsLookupRefs for FindQuery {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        for clause in &self.where_clauses {
+            clause.accumulate_lookup_refs(acc);
+        }
+    }
+}
+
+impl ContainsLookupRefs for OrWhereClause {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        use OrWhereClause::*;
+        match self {
+            &And(ref clauses) => for clause in clauses { clause.accumulate_lookup_refs(acc) },
+            &Clause(ref clause) => clause.accumulate_lookup_refs(acc),
+        }
+    }
+}
+
+impl ContainsLookupRefs for OrJoin {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        for clause in &self.clauses {
+            clause.accumulate_lookup_refs(acc);
+        }
+    }
+}
+
+impl ContainsLookupRefs for NotJoin {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        for clause in &self.clauses {
+            clause.accumulate_lookup_refs(acc);
+        }
+    }
+}
+
+impl ContainsLookupRefs for Rule {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        for clause in &self.clauses {
+            clause.accumulate_lookup_refs(acc);
+        }
+    }
+}
+
+impl ContainsLookupRefs for Pattern {
+    fn accumulate_lookup_refs(&self, acc: &mut BTreeSet<LookupRef>) {
+        for place in &[&self.entity, &self.attribute, &self.tx] {
+            if let &&PatternNonValuePlace::LookupRef(ref r) = place {
+                acc_ref(acc, r.as_ref())
+            }
+        }
+    }
+}
+
+/// Turn a query, or part of one, back into the EDN it would be parsed from. Parsing the output of
+/// `FindQuery::to_edn` gives back an equal `FindQuery`; `edn::Value::to_pretty` lays it out.
+pub trait ToEdn {
+    fn to_edn(&self) -> edn::Value;
+}
+
+fn edn_symbol(name: &str) -> edn::Value {
+    edn::Value::PlainSymbol(PlainSymbol::new(name))
+}
+
+fn edn_list<I>(values: I) -> edn::Value where I: IntoIterator<Item=edn::Value> {
+    edn::Value::List(values.into_iter().collect())
+}
+
+fn edn_vector<I>(values: I) -> edn::Value where I: IntoIterator<Item=edn::Value> {
+    edn::Value::Vector(values.into_iter().collect())
+}
+
+fn edn_all<'a, T, I>(items: I) -> Vec<edn::Value> where T: ToEdn + 'a, I: IntoIterator<Item=&'a T> {
+    items.into_iter().map(|x| x.to_edn()).collect()
+}
+
+/// `(head args…)`.
+fn edn_call<'a, T, I>(head: edn::Value, args: I) -> edn::Value where T: ToEdn + 'a, I: IntoIterator<Item=&'a T> {
+    edn_list(::std::iter::once(head).chain(args.into_iter().map(|x| x.to_edn())))
+}
+
+impl ToEdn for Variable {
+    fn to_edn(&self) -> edn::Value {
+        edn::Value::PlainSymbol(self.name())
+    }
+}
+
+impl ToEdn for SrcVar {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &SrcVar::DefaultSrc => edn_symbol("$"),
+            &SrcVar::NamedSrc(ref name) => edn_symbol(&format!("${}", name)),
+        }
+    }
+}
+
+impl ToEdn for NonIntegerConstant {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &NonIntegerConstant::Boolean(x) => edn::Value::Boolean(x),
+            &NonIntegerConstant::BigInteger(ref x) => edn::Value::BigInteger(x.clone()),
+            &NonIntegerConstant::Float(x) => edn::Value::Float(x),
+            &NonIntegerConstant::Text(ref x) => edn::Value::Text(x.as_ref().clone()),
+            &NonIntegerConstant::Instant(x) => edn::Value::Instant(x),
+            &NonIntegerConstant::Uuid(x) => edn::Value::Uuid(x),
+        }
+    }
+}
+
+/// A value as it would appear in a lookup ref.
+impl ToEdn for TypedValue {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &TypedValue::Ref(x) => edn::Value::Integer(x),
+            &TypedValue::Boolean(x) => edn::Value::Boolean(x),
+            &TypedValue::Long(x) => edn::Value::Integer(x),
+            &TypedValue::Double(x) => edn::Value::Float(x),
+            &TypedValue::Instant(x) => edn::Value::Instant(x),
+            &TypedValue::String(ref x) => edn::Value::Text(x.as_ref().clone()),
+            &TypedValue::Keyword(ref x) => edn::Value::NamespacedKeyword(x.as_ref().clone()),
+            &TypedValue::Uuid(x) => edn::Value::Uuid(x),
+        }
+    }
+}
+
+impl ToEdn for FnArg {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &FnArg::Variable(ref v) => v.to_edn(),
+            &FnArg::SrcVar(ref s) => s.to_edn(),
+            &FnArg::EntidOrInteger(x) => edn::Value::Integer(x),
+            &FnArg::IdentOrKeyword(ref k) => edn::Value::NamespacedKeyword(k.clone()),
+            &FnArg::Keyword(ref k) => edn::Value::Keyword(k.clone()),
+            &FnArg::Constant(ref c) => c.to_edn(),
+            &FnArg::Vector(ref args) => edn_vector(edn_all(args)),
+        }
+    }
+}
+
+impl ToEdn for IdentOrEntid {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &IdentOrEntid::Ident(ref k) => edn::Value::NamespacedKeyword(k.as_ref().clone()),
+            &IdentOrEntid::Entid(x) => edn::Value::Integer(x),
+        }
+    }
+}
+
+impl ToEdn for LookupRef {
+    fn to_edn(&self) -> edn::Value {
+        edn_vector(vec![self.attribute.to_edn(), self.value.to_edn()])
+    }
+}
+
+impl ToEdn for PatternNonValuePlace {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &PatternNonValuePlace::Placeholder => edn_symbol("_"),
+            &PatternNonValuePlace::Variable(ref v) => v.to_edn(),
+            &PatternNonValuePlace::Entid(x) => edn::Value::Integer(x),
+            &PatternNonValuePlace::Ident(ref k) => edn::Value::NamespacedKeyword(k.as_ref().clone()),
+            &PatternNonValuePlace::LookupRef(ref r) => r.to_edn(),
+        }
+    }
+}
+
+impl ToEdn for PatternValuePlace {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &PatternValuePlace::Placeholder => edn_symbol("_"),
+            &PatternValuePlace::Variable(ref v) => v.to_edn(),
+            &PatternValuePlace::EntidOrInteger(x) => edn::Value::Integer(x),
+            &PatternValuePlace::IdentOrKeyword(ref k) => edn::Value::NamespacedKeyword(k.as_ref().clone()),
+            &PatternValuePlace::Constant(ref c) => c.to_edn(),
+        }
+    }
+}
+
+impl ToEdn for PullAttributeSpec {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &PullAttributeSpec::Wildcard => edn_symbol("*"),
+            &PullAttributeSpec::Attribute(ref k) => edn::Value::NamespacedKeyword(k.clone()),
+            &PullAttributeSpec::Nested(ref k, ref patterns) => {
+                let mut m = std::collections::BTreeMap::new();
+                m.insert(edn::Value::NamespacedKeyword(k.clone()), edn_vector(edn_all(patterns)));
+                edn::Value::Map(m)
+            },
+        }
+    }
+}
+
+impl ToEdn for Element {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &Element::Variable(ref v) => v.to_edn(),
+            &Element::Aggregate(ref a) => edn_call(edn::Value::PlainSymbol(a.func.0.clone()), &a.args),
+            &Element::Pull(ref p) => edn_list(vec![edn_symbol("pull"), p.var.to_edn(), edn_vector(edn_all(&p.patterns))]),
+            &Element::Ident(ref v) => edn_list(vec![edn_symbol("ident"), v.to_edn()]),
+        }
+    }
+}
+
+impl FindSpec {
+    /// The values that follow `:find`.
+    fn to_edn_values(&self) -> Vec<edn::Value> {
+        match self {
+            &FindSpec::FindRel(ref elements) => edn_all(elements),
+            &FindSpec::FindColl(ref element) => vec![edn_vector(vec![element.to_edn(), edn_symbol("...")])],
+            &FindSpec::FindTuple(ref elements) => vec![edn_vector(edn_all(elements))],
+            &FindSpec::FindScalar(ref element) => vec![element.to_edn(), edn_symbol(".")],
+        }
+    }
+}
+
+impl ToEdn for Order {
+    fn to_edn(&self) -> edn::Value {
+        let direction = match self.0 {
+            Direction::Ascending => "asc",
+            Direction::Descending => "desc",
+        };
+        edn_list(vec![edn_symbol(direction), self.1.to_edn()])
+    }
+}
+
+impl ToEdn for VariableOrPlaceholder {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &VariableOrPlaceholder::Placeholder => edn_symbol("_"),
+            &VariableOrPlaceholder::Variable(ref v) => v.to_edn(),
+        }
+    }
+}
+
+impl ToEdn for Binding {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &Binding::BindScalar(ref v) => v.to_edn(),
+            &Binding::BindColl(ref v) => edn_vector(vec![v.to_edn(), edn_symbol("...")]),
+            &Binding::BindRel(ref vs) => edn_vector(vec![edn_vector(edn_all(vs))]),
+            &Binding::BindTuple(ref vs) => edn_vector(edn_all(vs)),
+        }
+    }
+}
+
+impl ToEdn for Pattern {
+    fn to_edn(&self) -> edn::Value {
+        let mut values = vec![];
+        if let Some(ref source) = self.source {
+            values.push(source.to_edn());
+        }
+        values.push(self.entity.to_edn());
+        values.push(self.attribute.to_edn());
+
+        // Trailing placeholders can be left out.
+        let mut rest = vec![self.value.to_edn(), self.tx.to_edn(), self.added.to_edn()];
+        let placeholder = edn_symbol("_");
+        while rest.last() == Some(&placeholder) {
+            rest.pop();
+        }
+        values.extend(rest);
+        edn_vector(values)
+    }
+}
+
+impl ToEdn for OrWhereClause {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &OrWhereClause::Clause(ref clause) => clause.to_edn(),
+            &OrWhereClause::And(ref clauses) => edn_call(edn_symbol("and"), clauses),
+        }
+    }
+}
+
+/// `(name vars… clauses…)`, where `vars` is `[vars…]` for an explicit join and absent otherwise.
+fn join_to_edn<'a, T, I>(name: &str, join_name: &str, unify_vars: &UnifyVars, clauses: I) -> edn::Value
+where T: ToEdn + 'a, I: IntoIterator<Item=&'a T> {
+    let head = match unify_vars {
+        &UnifyVars::Implicit => vec![edn_symbol(name)],
+        &UnifyVars::Explicit(ref vars) => vec![edn_symbol(join_name), edn_vector(edn_all(vars))],
+    };
+    edn_list(head.into_iter().chain(clauses.into_iter().map(|c| c.to_edn())))
+}
+
+fn value_type_name(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Ref => "ref",
+        ValueType::Boolean => "boolean",
+        ValueType::Instant => "instant",
+        ValueType::Long => "long",
+        ValueType::Double => "double",
+        ValueType::String => "string",
+        ValueType::Keyword => "keyword",
+        ValueType::Uuid => "uuid",
+    }
+}
+
+impl ToEdn for WhereClause {
+    fn to_edn(&self) -> edn::Value {
+        match self {
+            &WhereClause::Pattern(ref p) => p.to_edn(),
+            &WhereClause::OrJoin(ref o) => join_to_edn("or", "or-join", &o.unify_vars, &o.clauses),
+            &WhereClause::NotJoin(ref n) => join_to_edn("not", "not-join", &n.unify_vars, &n.clauses),
+            &WhereClause::Pred(ref p) =>
+                edn_vector(vec![edn_call(edn::Value::PlainSymbol(p.operator.clone()), &p.args)]),
+            &WhereClause::WhereFn(ref f) =>
+                edn_vector(vec![edn_call(edn::Value::PlainSymbol(f.operator.clone()), &f.args), f.binding.to_edn()]),
+            &WhereClause::RuleExpr(ref r) => edn_call(edn::Value::PlainSymbol(r.name.clone()), &r.args),
+            &WhereClause::TypeAnnotation(ref t) =>
+                edn_vector(vec![edn_list(vec![edn_symbol(value_type_name(t.value_type)), t.variable.to_edn()])]),
+        }
+    }
+}
+
+impl ToEdn for Rule {
+    fn to_edn(&self) -> edn::Value {
+        let head = edn_call(edn::Value::PlainSymbol(self.name.clone()), &self.vars);
+        edn_vector(::std::iter::once(head).chain(edn_all(&self.clauses)))
+    }
+}
+
+impl ToEdn for FindQuery {
+    fn to_edn(&self) -> edn::Value {
+        let keyword = |name| edn::Value::from_keyword(None, name);
+
+        let mut values = vec![keyword("find")];
+        values.extend(self.find_spec.to_edn_values());
+
+        if !self.in_vars.is_empty() || self.in_rules {
+            values.push(keyword("in"));
+            values.extend(edn_all(&self.in_vars));
+            if self.in_rules {
+                values.push(edn_symbol("%"));
+            }
+        }
+
+        if !self.with.is_empty() {
+            values.push(keyword("with"));
+            values.extend(edn_all(&self.with));
+        }
+
+        match self.limit {
+            Limit::None => {},
+            Limit::Fixed(n) => values.extend(vec![keyword("limit"), edn::Value::Integer(n as i64)]),
+            Limit::Variable(ref v) => values.extend(vec![keyword("limit"), v.to_edn()]),
+        }
+
+        match self.offset {
+            Offset::None => {},
+            Offset::Fixed(n) => values.extend(vec![keyword("offset"), edn::Value::Integer(n as i64)]),
+            Offset::Variable(ref v) => values.extend(vec![keyword("offset"), v.to_edn()]),
+        }
+
+        values.push(keyword("where"));
+        values.extend(edn_all(&self.where_clauses));
+
+        if let Some(ref order) = self.order {
+            values.push(keyword("order"));
+            values.extend(edn_all(order));
+        }
+
+        edn_vector(values)
+    }
+}
+
+impl std::fmt::Display for FindQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.to_edn())
     }
 }
@@ -76,6 +76,7 @@ mod tests {
     use super::*;
     use std::rc::Rc;
     use mentat_core::{
+        Binding,
         HasSchema,
         KnownEntid,
     };
@@ -182,7 +183,7 @@ mod tests {
 
         let entities = conn.q_once(&sqlite, r#"[:find ?e . :where [?e :foo/bar 100]]"#, None).expect("Expected query to work").into_scalar().expect("expected scalar results");
         let entid = match entities {
-            Some(TypedValue::Ref(entid)) => entid,
+            Some(Binding::Scalar(TypedValue::Ref(entid))) => entid,
             x => panic!("expected Some(Ref), got {:?}", x),
         };
 
@@ -203,7 +204,7 @@ mod tests {
 
         let entities = conn.q_once(&sqlite, r#"[:find ?e . :where [?e :foo/bar 100]]"#, None).expect("Expected query to work").into_scalar().expect("expected scalar results");
         let entid = match entities {
-            Some(TypedValue::Ref(entid)) => entid,
+            Some(Binding::Scalar(TypedValue::Ref(entid))) => entid,
             x => panic!("expected Some(Ref), got {:?}", x),
         };
 
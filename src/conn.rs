@@ -10,6 +10,8 @@
 
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
+
 use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use rusqlite;
@@ -34,8 +36,8 @@ use mentat_core::intern_set::InternSet;
 
 use mentat_db::db;
 use mentat_db::{
-    transact,
     transact_terms,
+    transact_with_functions,
     PartitionMap,
     TxReport,
 };
@@ -46,6 +48,10 @@ use mentat_tx;
 
 use mentat_tx::entities::TempId;
 
+use mentat_query_translator::{
+    DatomsView,
+};
+
 use mentat_tx_parser;
 
 use cache::{
@@ -62,16 +68,50 @@ use entity_builder::{
 
 use errors::*;
 
+use interrupt::{
+    QueryOptions,
+};
+
+use plan_cache::{
+    DEFAULT_QUERY_PLAN_CACHE_CAPACITY,
+    QueryPlanCache,
+    QueryPlanCacheStats,
+};
+
 use query::{
     lookup_value_for_attribute,
+    lookup_value_for_attribute_in_view,
     lookup_values_for_attribute,
+    lookup_values_for_attribute_in_view,
     PreparedResult,
     q_once,
+    q_once_after,
+    q_once_in_view,
+    q_once_query,
+    q_once_query_in_view,
+    q_once_with_options,
     q_prepare,
+    q_prepare_in_view,
+    q_prepare_query,
+    q_prepare_query_in_view,
     q_explain,
+    q_explain_in_view,
+    q_plan,
+    q_plan_once,
+    q_plan_query,
+    q_plan_query_once,
+    FindQuery,
+    QueryCursor,
     QueryExplanation,
     QueryInputs,
     QueryOutput,
+    Variable,
+};
+
+use tx_functions::{
+    Entity,
+    TxFunctionRegistry,
+    TxFunctionView,
 };
 
 /// Connection metadata required to query from, or apply transactions to, a Mentat store.
@@ -111,10 +151,14 @@ pub struct Conn {
 
     // TODO: maintain set of change listeners or handles to transaction report queues. #298.
 
-    // TODO: maintain cache of query plans that could be shared across threads and invalidated when
-    // the schema changes. #315.
+    /// Plans for queries run through this `Conn`, made against the current schema. The cache is
+    /// cleared whenever a transaction changes the schema.
+    query_plan_cache: Mutex<QueryPlanCache>,
 
     attribute_cache: RwLock<AttributeCacher>,
+
+    /// Transaction functions that transactions through this `Conn` can invoke.
+    tx_functions: TxFunctionRegistry,
 }
 
 /// A convenience wrapper around a single SQLite connection and a Conn. This is suitable
@@ -142,6 +186,10 @@ pub trait Queryable {
         where T: Into<Option<QueryInputs>>;
     fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult
         where T: Into<Option<QueryInputs>>;
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>>;
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>>;
     fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Vec<TypedValue>>
         where E: Into<Entid>;
     fn lookup_value_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
@@ -159,6 +207,8 @@ pub struct InProgress<'a, 'c> {
     partition_map: PartitionMap,
     schema: Schema,
     cache: RwLockWriteGuard<'a, AttributeCacher>,
+    query_plan_cache: &'a Mutex<QueryPlanCache>,
+    tx_functions: &'a TxFunctionRegistry,
 }
 
 /// Represents an in-progress set of reads to the store. Just like `InProgress`,
@@ -176,6 +226,16 @@ impl<'a, 'c> Queryable for InProgressRead<'a, 'c> {
         self.0.q_prepare(query, inputs)
     }
 
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        self.0.q_once_query(query, inputs)
+    }
+
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        self.0.q_prepare_query(query, inputs)
+    }
+
     fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>> {
         self.0.q_explain(query, inputs)
@@ -211,6 +271,22 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
                   inputs)
     }
 
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once_query(&*(self.transaction),
+                     &self.schema,
+                     query,
+                     inputs)
+    }
+
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare_query(&*(self.transaction),
+                        &self.schema,
+                        query,
+                        inputs)
+    }
+
     fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>> {
         q_explain(&*(self.transaction),
@@ -232,6 +308,122 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     }
 }
 
+/// A read-only view of the store as it was immediately after a transaction, or of what has been
+/// asserted since then. See `Conn::as_of` and `Conn::since`.
+///
+/// Queries against a view use the current schema.
+pub struct StoreView<'a, 'c> {
+    conn: &'a Conn,
+    sqlite: &'c rusqlite::Connection,
+    view: DatomsView,
+}
+
+impl<'a, 'c> Queryable for StoreView<'a, 'c> {
+    fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once_in_view(self.sqlite, &*self.conn.current_schema(), self.view, query, inputs)
+    }
+
+    fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare_in_view(self.sqlite, &*self.conn.current_schema(), self.view, query, inputs)
+    }
+
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once_query_in_view(self.sqlite, &*self.conn.current_schema(), self.view, query, inputs)
+    }
+
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare_query_in_view(self.sqlite, &*self.conn.current_schema(), self.view, query, inputs)
+    }
+
+    fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>> {
+        q_explain_in_view(self.sqlite, &*self.conn.current_schema(), self.view, query, inputs)
+    }
+
+    fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Vec<TypedValue>>
+        where E: Into<Entid> {
+        lookup_values_for_attribute_in_view(self.sqlite, &*self.conn.current_schema(), self.view, entity, attribute)
+    }
+
+    fn lookup_value_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        lookup_value_for_attribute_in_view(self.sqlite, &*self.conn.current_schema(), self.view, entity, attribute)
+    }
+}
+
+/// What a transaction would have done, had it been committed.  See
+/// `InProgress::transact_speculatively`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpeculativeReport {
+    pub report: TxReport,
+
+    /// The [e a v added] datoms the transaction asserted and retracted, ordered by (e, a, v, added).
+    /// This includes the transaction's `:db/txInstant` datom.
+    pub datoms: Vec<(Entid, Entid, TypedValue, bool)>,
+
+    /// The schema the transaction would have produced, if it altered the schema.
+    pub schema: Option<Schema>,
+}
+
+/// A read-only view of the store as it is midway through a speculative transaction, before the
+/// transaction is rolled back.  See `InProgress::speculate`.
+///
+/// Queries against a speculation use the schema the transaction would have produced, and don't
+/// use the attribute cache, which doesn't know about speculative datoms.
+pub struct Speculation<'s> {
+    sqlite: &'s rusqlite::Connection,
+    report: &'s SpeculativeReport,
+    schema: &'s Schema,
+    cache: AttributeCacher,
+}
+
+impl<'s> Speculation<'s> {
+    pub fn report(&self) -> &SpeculativeReport {
+        self.report
+    }
+}
+
+impl<'s> Queryable for Speculation<'s> {
+    fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once_query(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare_query(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>> {
+        q_explain(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Vec<TypedValue>>
+        where E: Into<Entid> {
+        lookup_values_for_attribute(self.sqlite, self.schema, &self.cache, entity, attribute)
+    }
+
+    fn lookup_value_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        lookup_value_for_attribute(self.sqlite, self.schema, &self.cache, entity, attribute)
+    }
+}
+
 impl<'a, 'c> HasSchema for InProgressRead<'a, 'c> {
     fn entid_for_type(&self, t: ValueType) -> Option<KnownEntid> {
         self.0.entid_for_type(t)
@@ -325,7 +517,8 @@ impl<'a, 'c> InProgress<'a, 'c> {
         //    `Metadata` on return. If we used `Cell` or other mechanisms, we'd be using
         //    `Default::default` in those situations to extract the partition map, and so there
         //    would still be some cost.
-        let (report, next_partition_map, next_schema) = transact(&self.transaction, self.partition_map.clone(), &self.schema, &self.schema, entities)?;
+        let tx_functions = self.tx_functions.expander(&*self.cache);
+        let (report, next_partition_map, next_schema) = transact_with_functions(&self.transaction, self.partition_map.clone(), &self.schema, &self.schema, &tx_functions, entities)?;
         self.partition_map = next_partition_map;
         if let Some(schema) = next_schema {
             self.schema = schema;
@@ -339,6 +532,54 @@ impl<'a, 'c> InProgress<'a, 'c> {
         self.transact_entities(entities)
     }
 
+    /// Transact `transaction` in a savepoint, report what it did, and then roll it back.  This
+    /// `InProgress` is left as it was.
+    pub fn transact_speculatively(&mut self, transaction: &str) -> Result<SpeculativeReport> {
+        self.speculate(transaction, |_| Ok(())).map(|(report, ())| report)
+    }
+
+    /// Like `transact_speculatively`, but also give `f` the chance to query the store as the
+    /// transaction left it, before it is rolled back.
+    pub fn speculate<F, T>(&mut self, transaction: &str, f: F) -> Result<(SpeculativeReport, T)>
+        where F: FnOnce(&Speculation) -> Result<T> {
+        let assertion_vector = edn::parse::value(transaction)?;
+        let entities = mentat_tx_parser::Tx::parse(&assertion_vector)?;
+        self.speculate_entities(entities, f)
+    }
+
+    pub fn speculate_entities<I, F, T>(&mut self, entities: I, f: F) -> Result<(SpeculativeReport, T)>
+        where I: IntoIterator<Item=mentat_tx::entities::Entity>,
+              F: FnOnce(&Speculation) -> Result<T> {
+        // The savepoint is rolled back if anything fails.  Our partition map and schema are never
+        // touched, so there's nothing else to undo.
+        let mut savepoint = self.transaction.savepoint()?;
+
+        let result = {
+            let tx_functions = self.tx_functions.expander(&*self.cache);
+            let (report, _, next_schema) = transact_with_functions(&savepoint, self.partition_map.clone(), &self.schema, &self.schema, &tx_functions, entities)?;
+            let datoms = db::read_transaction(&savepoint, next_schema.as_ref().unwrap_or(&self.schema), report.tx_id)?;
+            let report = SpeculativeReport {
+                report: report,
+                datoms: datoms,
+                schema: next_schema,
+            };
+
+            let value = {
+                let speculation = Speculation {
+                    sqlite: &savepoint,
+                    report: &report,
+                    schema: report.schema.as_ref().unwrap_or(&self.schema),
+                    cache: AttributeCacher::new(),
+                };
+                f(&speculation)?
+            };
+            (report, value)
+        };
+
+        savepoint.rollback()?;
+        Ok(result)
+    }
+
     pub fn rollback(self) -> Result<()> {
         self.transaction.rollback().map_err(|e| e.into())
     }
@@ -364,6 +605,9 @@ impl<'a, 'c> InProgress<'a, 'c> {
         if self.schema != *(metadata.schema) {
             metadata.schema = Arc::new(self.schema);
 
+            // Plans made against the old schema might be wrong.
+            self.query_plan_cache.lock().unwrap().clear();
+
             // TODO: rebuild vocabularies and notify consumers that they've changed -- it's possible
             // that a change has arrived over the wire and invalidated some local module.
             // TODO: consider making vocabulary lookup lazy -- we won't need it much of the time.
@@ -389,6 +633,38 @@ impl Store {
     pub fn begin_transaction<'m>(&'m mut self) -> Result<InProgress<'m, 'm>> {
         self.conn.begin_transaction(&mut self.sqlite)
     }
+
+    pub fn as_of<'m>(&'m self, tx: Entid) -> StoreView<'m, 'm> {
+        self.conn.as_of(&self.sqlite, tx)
+    }
+
+    pub fn since<'m>(&'m self, tx: Entid) -> StoreView<'m, 'm> {
+        self.conn.since(&self.sqlite, tx)
+    }
+
+    /// Report what `transaction` would do, without committing it.  See
+    /// `InProgress::transact_speculatively`.
+    pub fn with(&mut self, transaction: &str) -> Result<SpeculativeReport> {
+        let mut in_progress = self.begin_transaction()?;
+        let report = in_progress.transact_speculatively(transaction)?;
+        in_progress.rollback()?;
+        Ok(report)
+    }
+
+    /// Report what `transaction` would do, and give `f` the chance to query the result, without
+    /// committing it.  See `InProgress::speculate`.
+    pub fn speculate<F, T>(&mut self, transaction: &str, f: F) -> Result<(SpeculativeReport, T)>
+        where F: FnOnce(&Speculation) -> Result<T> {
+        let mut in_progress = self.begin_transaction()?;
+        let result = in_progress.speculate(transaction, f)?;
+        in_progress.rollback()?;
+        Ok(result)
+    }
+
+    pub fn register_tx_function<F>(&mut self, name: NamespacedKeyword, function: F) -> Result<()>
+        where F: Fn(&TxFunctionView, &[edn::ValueAndSpan]) -> Result<Vec<Entity>> + Send + Sync + 'static {
+        self.conn.register_tx_function(name, function)
+    }
 }
 
 impl Queryable for Store {
@@ -402,6 +678,16 @@ impl Queryable for Store {
         self.conn.q_prepare(&self.sqlite, query, inputs)
     }
 
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        self.conn.q_once_query(&self.sqlite, query, inputs)
+    }
+
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        self.conn.q_prepare_query(&self.sqlite, query, inputs)
+    }
+
     fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>> {
         self.conn.q_explain(&self.sqlite, query, inputs)
@@ -418,12 +704,43 @@ impl Queryable for Store {
     }
 }
 
+/// A query run once can share a plan if its input values can be bound each time the plan is run,
+/// just as for a prepared query. Returns the types of the inputs if so: the plan is made with
+/// those, and the values are bound afterwards.
+///
+/// Rules and lookup refs are incorporated into the plan itself, so they can't be shared. Nor can
+/// keywords: the algebrizer resolves a keyword in an entity or attribute place to an entid, which
+/// it can only do if it knows the keyword up front.
+fn late_bound_input_types(inputs: &Option<QueryInputs>) -> Option<BTreeMap<Variable, ValueType>> {
+    match inputs {
+        &None => Some(BTreeMap::new()),
+        &Some(ref inputs) if inputs.rules.is_empty() && inputs.lookup_ref_values.is_empty() &&
+                             !inputs.values.values().any(|v| v.value_type() == ValueType::Keyword) => {
+            Some(inputs.types.clone())
+        },
+        &Some(_) => None,
+    }
+}
+
+/// A prepared query can share a plan if its inputs only declare types: values and rules are
+/// incorporated into the plan itself. Returns the declared types if so.
+fn shareable_input_types(inputs: &Option<QueryInputs>) -> Option<BTreeMap<Variable, ValueType>> {
+    match inputs {
+        &None => Some(BTreeMap::new()),
+        &Some(ref inputs) if inputs.values.is_empty() && inputs.rules.is_empty() &&
+                             inputs.lookup_ref_values.is_empty() => Some(inputs.types.clone()),
+        &Some(_) => None,
+    }
+}
+
 impl Conn {
     // Intentionally not public.
     fn new(partition_map: PartitionMap, schema: Schema) -> Conn {
         Conn {
             metadata: Mutex::new(Metadata::new(0, partition_map, Arc::new(schema))),
-            attribute_cache: RwLock::new(AttributeCacher::new())
+            query_plan_cache: Mutex::new(QueryPlanCache::new(DEFAULT_QUERY_PLAN_CACHE_CAPACITY)),
+            attribute_cache: RwLock::new(AttributeCacher::new()),
+            tx_functions: TxFunctionRegistry::default(),
         }
     }
 
@@ -460,12 +777,49 @@ impl Conn {
                      query: &str,
                      inputs: T) -> Result<QueryOutput>
         where T: Into<Option<QueryInputs>> {
+        self.q_once_with_options(sqlite, query, inputs, &QueryOptions::default())
+    }
+
+    /// Like `q_once`, but stopping the query with `ErrorKind::QueryAborted` if it runs for longer
+    /// than allowed by `options`, or if its cancellation token is cancelled. The token can be
+    /// cancelled from another thread while this one is busy running the query.
+    pub fn q_once_with_options<T>(&self,
+                                  sqlite: &rusqlite::Connection,
+                                  query: &str,
+                                  inputs: T,
+                                  options: &QueryOptions) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
 
+        let inputs = inputs.into();
         let metadata = self.metadata.lock().unwrap();
-        q_once(sqlite,
-               &*metadata.schema,        // Doesn't clone, unlike `current_schema`.
-               query,
-               inputs)
+        let schema = &*metadata.schema;        // Doesn't clone, unlike `current_schema`.
+
+        let input_types = match late_bound_input_types(&inputs) {
+            Some(input_types) => input_types,
+            None => return q_once_with_options(sqlite, schema, query, inputs, options),
+        };
+
+        if input_types.is_empty() {
+            let plan = self.query_plan_cache
+                           .lock()
+                           .unwrap()
+                           .get_or_plan(query, input_types, || q_plan_once(sqlite, schema, query))?;
+            return plan.prepare(sqlite, metadata.schema.clone())?.with_options(options.clone()).run(None);
+        }
+
+        let planned = self.query_plan_cache
+                          .lock()
+                          .unwrap()
+                          .get_or_plan(query, input_types.clone(), || {
+                              q_plan(sqlite, schema, query, QueryInputs::with_types(input_types))
+                          });
+        match planned {
+            Ok(plan) => plan.prepare(sqlite, metadata.schema.clone())?.with_options(options.clone()).run(inputs),
+
+            // Not every use of an input can be bound late -- `ground`, for one, needs its value
+            // up front. Plan with the values instead, just this once.
+            Err(_) => q_once_with_options(sqlite, schema, query, inputs, options),
+        }
     }
 
     pub fn q_prepare<'sqlite, 'query, T>(&self,
@@ -473,12 +827,115 @@ impl Conn {
                         query: &'query str,
                         inputs: T) -> PreparedResult<'sqlite>
         where T: Into<Option<QueryInputs>> {
+        self.q_prepare_with_options(sqlite, query, inputs, QueryOptions::default())
+    }
+
+    /// Like `q_prepare`, but applying `options` to each run of the prepared query.
+    pub fn q_prepare_with_options<'sqlite, 'query, T>(&self,
+                        sqlite: &'sqlite rusqlite::Connection,
+                        query: &'query str,
+                        inputs: T,
+                        options: QueryOptions) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>> {
 
+        let inputs = inputs.into();
         let metadata = self.metadata.lock().unwrap();
-        q_prepare(sqlite,
-                  &*metadata.schema,
-                  query,
-                  inputs)
+        let schema = &*metadata.schema;
+
+        match shareable_input_types(&inputs) {
+            Some(input_types) => {
+                let plan = self.query_plan_cache
+                               .lock()
+                               .unwrap()
+                               .get_or_plan(query, input_types, || q_plan(sqlite, schema, query, inputs))?;
+                Ok(plan.prepare(sqlite, metadata.schema.clone())?.with_options(options))
+            },
+            None => {
+                let plan = q_plan(sqlite, schema, query, inputs)?;
+                Ok(plan.prepare(sqlite, metadata.schema.clone())?.with_options(options))
+            },
+        }
+    }
+
+    /// Query the Mentat store with a query built in code, such as by `query_builder::Find`.
+    ///
+    /// Plans are cached by query text. A built query is printed to get its text, so it shares a
+    /// plan with any equal query, whether built or parsed.
+    pub fn q_once_query<T>(&self,
+                           sqlite: &rusqlite::Connection,
+                           query: FindQuery,
+                           inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+
+        let inputs = inputs.into();
+        let metadata = self.metadata.lock().unwrap();
+        let schema = &*metadata.schema;
+
+        let input_types = match late_bound_input_types(&inputs) {
+            Some(input_types) => input_types,
+            None => return q_once_query(sqlite, schema, query, inputs),
+        };
+
+        let text = query.to_string();
+        if input_types.is_empty() {
+            let plan = self.query_plan_cache
+                           .lock()
+                           .unwrap()
+                           .get_or_plan(&text, input_types, || q_plan_query_once(sqlite, schema, query))?;
+            return plan.prepare(sqlite, metadata.schema.clone())?.run(None);
+        }
+
+        // Plan from the text, so that we still have the query if it can't be planned this way.
+        let planned = self.query_plan_cache
+                          .lock()
+                          .unwrap()
+                          .get_or_plan(&text, input_types.clone(), || {
+                              q_plan(sqlite, schema, &text, QueryInputs::with_types(input_types))
+                          });
+        match planned {
+            Ok(plan) => plan.prepare(sqlite, metadata.schema.clone())?.run(inputs),
+            Err(_) => q_once_query(sqlite, schema, query, inputs),
+        }
+    }
+
+    /// Like `q_prepare`, but for a query built in code. See `q_once_query`.
+    pub fn q_prepare_query<'sqlite, T>(&self,
+                                       sqlite: &'sqlite rusqlite::Connection,
+                                       query: FindQuery,
+                                       inputs: T) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>> {
+
+        let inputs = inputs.into();
+        let metadata = self.metadata.lock().unwrap();
+        let schema = &*metadata.schema;
+
+        let plan = match shareable_input_types(&inputs) {
+            Some(input_types) => {
+                let text = query.to_string();
+                self.query_plan_cache
+                    .lock()
+                    .unwrap()
+                    .get_or_plan(&text, input_types, || q_plan_query(sqlite, schema, query, inputs))?
+            },
+            None => Arc::new(q_plan_query(sqlite, schema, query, inputs)?),
+        };
+        plan.prepare(sqlite, metadata.schema.clone())
+    }
+
+    /// Query the Mentat store for the results that the query's `:order` places after `cursor`.
+    /// See `QueryCursor`.
+    pub fn q_once_after<T>(&self,
+                           sqlite: &rusqlite::Connection,
+                           query: &str,
+                           inputs: T,
+                           cursor: &QueryCursor) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once_after(sqlite, &*self.current_schema(), query, inputs, cursor)
+    }
+
+    /// How often queries run or prepared through this `Conn` have been able to reuse a plan.
+    pub fn query_plan_cache_stats(&self) -> QueryPlanCacheStats {
+        self.query_plan_cache.lock().unwrap().stats()
     }
 
     pub fn q_explain<T>(&self,
@@ -506,6 +963,26 @@ impl Conn {
         lookup_value_for_attribute(sqlite, &*self.current_schema(), cc, entity, attribute)
     }
 
+    /// A read-only view of the store as it was immediately after the transaction `tx`. The view
+    /// is reconstructed from the transaction log, so it includes datoms that have since been
+    /// retracted and excludes those asserted later.
+    pub fn as_of<'m, 'conn>(&'m self, sqlite: &'conn rusqlite::Connection, tx: Entid) -> StoreView<'m, 'conn> {
+        StoreView {
+            conn: self,
+            sqlite: sqlite,
+            view: DatomsView::AsOf(tx),
+        }
+    }
+
+    /// A read-only view of the current datoms that were asserted after the transaction `tx`.
+    pub fn since<'m, 'conn>(&'m self, sqlite: &'conn rusqlite::Connection, tx: Entid) -> StoreView<'m, 'conn> {
+        StoreView {
+            conn: self,
+            sqlite: sqlite,
+            view: DatomsView::Since(tx),
+        }
+    }
+
     /// Take a SQLite transaction.
     fn begin_transaction_with_behavior<'m, 'conn>(&'m mut self, sqlite: &'conn mut rusqlite::Connection, behavior: TransactionBehavior) -> Result<InProgress<'m, 'conn>> {
         let tx = sqlite.transaction_with_behavior(behavior)?;
@@ -527,6 +1004,8 @@ impl Conn {
             partition_map: current_partition_map,
             schema: (*current_schema).clone(),
             cache: self.attribute_cache.write().unwrap(),
+            query_plan_cache: &self.query_plan_cache,
+            tx_functions: &self.tx_functions,
         })
     }
 
@@ -564,6 +1043,21 @@ impl Conn {
         Ok(report)
     }
 
+    /// Register a transaction function as `name`, replacing any function already registered as
+    /// `name`. Transactions through this `Conn` can then invoke it like `[:app/increment e a 1]`,
+    /// and the transactor will transact the entities it returns in place of the invocation.
+    ///
+    /// The `:db` namespace is reserved for built-in operations.
+    pub fn register_tx_function<F>(&mut self, name: NamespacedKeyword, function: F) -> Result<()>
+        where F: Fn(&TxFunctionView, &[edn::ValueAndSpan]) -> Result<Vec<Entity>> + Send + Sync + 'static {
+        self.tx_functions.register(name, function)
+    }
+
+    /// Unregister the transaction function registered as `name`, returning true if there was one.
+    pub fn unregister_tx_function(&mut self, name: &NamespacedKeyword) -> bool {
+        self.tx_functions.unregister(name)
+    }
+
     // TODO: Figure out how to set max cache size and max result size and implement those on cache
     // Question: Should those be only for lazy cache? The eager cache could perhaps grow infinitely
     // and it becomes up to the client to manage memory usage by excising from cache when no longer
@@ -602,9 +1096,11 @@ mod tests {
     use std::time::Instant;
 
     use mentat_core::{
+        Binding,
         TypedValue,
     };
     use query::{
+        QueryInputs,
         Variable,
     };
 
@@ -697,7 +1193,7 @@ mod tests {
 
             let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
                                     .expect("query succeeded");
-            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one).into())));
 
             let report = in_progress.transact(t2).expect("t2 succeeded");
             in_progress.commit().expect("commit succeeded");
@@ -742,10 +1238,87 @@ mod tests {
                                           values).expect("prepare succeeded");
 
         let yeses = prepared.run(None).expect("result");
-        assert_eq!(yeses.results, QueryResults::Coll(vec![TypedValue::Ref(yes)]));
+        assert_eq!(yeses.results, QueryResults::Coll(vec![TypedValue::Ref(yes).into()]));
 
         let yeses_again = prepared.run(None).expect("result");
-        assert_eq!(yeses_again.results, QueryResults::Coll(vec![TypedValue::Ref(yes)]));
+        assert_eq!(yeses_again.results, QueryResults::Coll(vec![TypedValue::Ref(yes).into()]));
+    }
+
+    #[test]
+    fn test_query_plan_cache() {
+        let mut c = db::new_connection("").expect("Couldn't open conn.");
+        let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+        conn.transact(&mut c, r#"[
+            [:db/add "s" :db/ident :foo/boolean]
+            [:db/add "s" :db/valueType :db.type/boolean]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+        ]"#).expect("successful transaction");
+
+        let report = conn.transact(&mut c, r#"[
+            [:db/add "u" :foo/boolean true]
+            [:db/add "p" :foo/boolean false]
+        ]"#).expect("successful transaction");
+        let yes = report.tempids.get("u").expect("found it").clone();
+        let no = report.tempids.get("p").expect("found it").clone();
+
+        let query = r#"[:find [?x ...] :where [?x :foo/boolean true]]"#;
+        let expected = QueryResults::Coll(vec![TypedValue::Ref(yes).into()]);
+
+        let before = conn.query_plan_cache_stats();
+        assert_eq!(conn.q_once(&c, query, None).expect("results").results, expected);
+        assert_eq!(conn.q_once(&c, query, None).expect("results").results, expected);
+        let after = conn.query_plan_cache_stats();
+        assert_eq!(after.misses - before.misses, 1);
+        assert_eq!(after.hits - before.hits, 1);
+
+        // Prepared queries with typed inputs share plans, too.
+        let typed = r#"[:find [?x ...] :in ?v :where [?x :foo/boolean ?v]]"#;
+        let vv = Variable::from_valid_name("?v");
+        for _ in 0..2 {
+            let inputs = QueryInputs::with_type_sequence(vec![(vv.clone(), ValueType::Boolean)]);
+            let mut prepared = conn.q_prepare(&c, typed, inputs).expect("prepared");
+            let results = prepared.run(QueryInputs::with_value_sequence(vec![(vv.clone(), true.into())])).expect("results");
+            assert_eq!(results.results, expected);
+        }
+        let after_prepare = conn.query_plan_cache_stats();
+        assert_eq!(after_prepare.misses - after.misses, 1);
+        assert_eq!(after_prepare.hits - after.hits, 1);
+
+        // So do queries run once with input values, which are bound to the shared plan.
+        let with_value = |v: bool| QueryInputs::with_value_sequence(vec![(vv.clone(), v.into())]);
+        assert_eq!(conn.q_once(&c, typed, with_value(true)).expect("results").results, expected);
+        assert_eq!(conn.q_once(&c, typed, with_value(false)).expect("results").results,
+                   QueryResults::Coll(vec![TypedValue::Ref(no).into()]));
+        let after_values = conn.query_plan_cache_stats();
+        assert_eq!(after_values.misses, after_prepare.misses);
+        assert_eq!(after_values.hits - after_prepare.hits, 2);
+
+        // Data changes don't invalidate plans…
+        conn.transact(&mut c, r#"[[:db/add "q" :foo/boolean false]]"#).expect("successful transaction");
+        conn.q_once(&c, query, None).expect("results");
+        let after_data = conn.query_plan_cache_stats();
+        assert_eq!(after_data.hits - after_values.hits, 1);
+
+        // … but schema changes do.
+        conn.transact(&mut c, r#"[{:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#)
+            .expect("successful transaction");
+        assert_eq!(conn.q_once(&c, query, None).expect("results").results, expected);
+        let after_schema = conn.query_plan_cache_stats();
+        assert_eq!(after_schema.misses - after_data.misses, 1);
+        assert_eq!(after_schema.hits, after_data.hits);
+
+        // Queries built in code share plans by their printed form.
+        let built = || ::mentat_query_parser::parse_find_string(query).expect("parsed");
+        assert_eq!(conn.q_once_query(&c, built(), None).expect("results").results, expected);
+        assert_eq!(conn.q_once_query(&c, built(), None).expect("results").results, expected);
+        let inputs = QueryInputs::with_type_sequence(vec![(vv.clone(), ValueType::Boolean)]);
+        let typed_built = ::mentat_query_parser::parse_find_string(typed).expect("parsed");
+        let mut prepared = conn.q_prepare_query(&c, typed_built, inputs).expect("prepared");
+        let results = prepared.run(QueryInputs::with_value_sequence(vec![(vv.clone(), true.into())])).expect("results");
+        assert_eq!(results.results, expected);
+        let after_built = conn.query_plan_cache_stats();
+        assert_eq!(after_built.misses - after_schema.misses, 2);
+        assert_eq!(after_built.hits - after_schema.hits, 1);
     }
 
     #[test]
@@ -778,7 +1351,7 @@ mod tests {
             let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
                                     .expect("query succeeded");
 
-            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one).into())));
 
             // And we can do direct lookup, too.
             let kw = in_progress.lookup_value_for_attribute(one, &edn::NamespacedKeyword::new("db", "ident"))
@@ -833,11 +1406,175 @@ mod tests {
         let report = conn.transact(&mut sqlite, "[[:db/add \"u\" :db/ident :a/keyword]
                                                   [:db/add \"u\" :db/ident :b/keyword]]");
         match report.unwrap_err() {
-            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::NotYetImplemented(_)), _) => { },
-            x => panic!("expected EDN parse error, got {:?}", x),
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::ConflictingUpserts(conflicts)), _) => {
+                assert_eq!(conflicts.len(), 1);
+                assert_eq!(conflicts[0].tempid, ::mentat_tx::entities::TempId::External("u".to_string()));
+                assert_eq!(conflicts[0].upserts.len(), 2);
+                assert_eq!(conflicts[0].entids().len(), 2);
+            },
+            x => panic!("expected conflicting upserts, got {:?}", x),
         }
     }
 
+    #[test]
+    fn test_tx_functions() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "c" :db/ident :foo/count]
+            [:db/add "c" :db/valueType :db.type/long]
+            [:db/add "c" :db/cardinality :db.cardinality/one]
+        ]"#).expect("successful transaction");
+
+        // Like [:foo/increment e a delta].
+        conn.register_tx_function(kw!(:foo/increment), |view, args| {
+            if args.len() != 3 {
+                bail!("expected [:foo/increment e a delta]");
+            }
+            let e = args[0].inner.as_integer().ok_or("expected an entid")?;
+            let a = args[1].inner.as_namespaced_keyword().ok_or("expected an attribute")?;
+            let delta = args[2].inner.as_integer().ok_or("expected a delta")?;
+
+            let current = match view.lookup_value_for_attribute(e, a)? {
+                Some(TypedValue::Long(current)) => current,
+                _ => 0,
+            };
+            ::tx_functions::parse_entities(format!("[[:db/add {} {} {}]]", e, a, current + delta).as_str())
+        }).expect("registered");
+
+        conn.register_tx_function(kw!(:foo/fail), |_view, _args| {
+            bail!("failed on purpose")
+        }).expect("registered");
+
+        // The :db namespace is reserved.
+        match conn.register_tx_function(kw!(:db/increment), |_view, _args| Ok(vec![])).unwrap_err() {
+            Error(ErrorKind::InvalidTxFunctionName(name), _) => assert_eq!(name, ":db/increment"),
+            x => panic!("expected invalid name error, got {:?}", x),
+        }
+
+        let report = conn.transact(&mut sqlite, "[[:db/add \"e\" :foo/count 1]]").expect("transacted");
+        let e = report.tempids["e"];
+
+        // Invocations are expanded in place, alongside ordinary assertions.
+        let t = format!("[[:foo/increment {} :foo/count 2] [:db/add \"f\" :foo/count 10]]", e);
+        let report = conn.transact(&mut sqlite, t.as_str()).expect("incremented");
+        let f = report.tempids["f"];
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, e, &kw!(:foo/count)).expect("looked up"),
+                   Some(TypedValue::Long(3)));
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, f, &kw!(:foo/count)).expect("looked up"),
+                   Some(TypedValue::Long(10)));
+
+        // Functions read the store inside the transaction.
+        {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+            in_progress.transact(format!("[[:db/add {} :foo/count 5]]", e).as_str()).expect("transacted");
+            in_progress.transact(format!("[[:foo/increment {} :foo/count 1]]", e).as_str()).expect("incremented");
+            in_progress.commit().expect("committed");
+        }
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, e, &kw!(:foo/count)).expect("looked up"),
+                   Some(TypedValue::Long(6)));
+
+        match conn.transact(&mut sqlite, "[[:foo/nope 1]]").unwrap_err() {
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::UnknownTxFunction(name)), _) => {
+                assert_eq!(name, ":foo/nope");
+            },
+            x => panic!("expected unknown function error, got {:?}", x),
+        }
+
+        match conn.transact(&mut sqlite, "[[:foo/fail]]").unwrap_err() {
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::TxFunctionFailed(name)), _) => {
+                assert_eq!(name, ":foo/fail");
+            },
+            x => panic!("expected failed function error, got {:?}", x),
+        }
+
+        // Functions that invoke themselves, directly or through each other, are cut off.
+        conn.register_tx_function(kw!(:foo/forever), |_view, _args| {
+            ::tx_functions::parse_entities("[[:foo/forever]]")
+        }).expect("registered");
+        conn.register_tx_function(kw!(:foo/ping), |_view, _args| {
+            ::tx_functions::parse_entities("[[:foo/pong]]")
+        }).expect("registered");
+        conn.register_tx_function(kw!(:foo/pong), |_view, _args| {
+            ::tx_functions::parse_entities("[[:foo/ping]]")
+        }).expect("registered");
+
+        match conn.transact(&mut sqlite, "[[:foo/forever]]").unwrap_err() {
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::TxFunctionDepthExceeded(name, limit)), _) => {
+                assert_eq!(name, ":foo/forever");
+                assert_eq!(limit, ::mentat_db::MAX_TX_FUNCTION_DEPTH);
+            },
+            x => panic!("expected depth exceeded error, got {:?}", x),
+        }
+
+        match conn.transact(&mut sqlite, "[[:foo/ping]]").unwrap_err() {
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::TxFunctionDepthExceeded(_, _)), _) => { },
+            x => panic!("expected depth exceeded error, got {:?}", x),
+        }
+
+        // Failed transactions changed nothing.
+        assert_eq!(conn.lookup_value_for_attribute(&sqlite, e, &kw!(:foo/count)).expect("looked up"),
+                   Some(TypedValue::Long(6)));
+    }
+
+    #[test]
+    fn test_transact_speculatively() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "c" :db/ident :foo/count]
+            [:db/add "c" :db/valueType :db.type/long]
+            [:db/add "c" :db/cardinality :db.cardinality/one]
+        ]"#).expect("successful transaction");
+        let report = conn.transact(&mut sqlite, "[[:db/add \"e\" :foo/count 1]]").expect("transacted");
+        let e = report.tempids["e"];
+        let count = conn.current_schema().get_entid(&kw!(:foo/count)).expect("entid").0;
+
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun successfully");
+        let t = format!(r#"[[:db/add {} :foo/count 2]
+                            [:db/add "n" :db/ident :foo/name]
+                            [:db/add "n" :db/valueType :db.type/string]
+                            [:db/add "n" :db/cardinality :db.cardinality/one]
+                            [:db/add "m" :foo/count 10]]"#, e);
+        let (speculative, (during, name)) = in_progress.speculate(t.as_str(), |speculation| {
+            // The speculative state is visible, including the new attribute.
+            let during = speculation.lookup_value_for_attribute(e, &kw!(:foo/count))?;
+            let name = speculation.q_once("[:find ?a . :where [?a :db/ident :foo/name]]", None)?.into_scalar()?;
+            Ok((during, name))
+        }).expect("speculated");
+
+        let n = speculative.report.tempids["n"];
+        let m = speculative.report.tempids["m"];
+        assert_eq!(during, Some(TypedValue::Long(2)));
+        assert_eq!(name, Some(Binding::Scalar(TypedValue::Ref(n))));
+
+        let tx = speculative.report.tx_id;
+        assert!(speculative.datoms.contains(&(e, count, TypedValue::Long(1), false)));
+        assert!(speculative.datoms.contains(&(e, count, TypedValue::Long(2), true)));
+        assert!(speculative.datoms.contains(&(m, count, TypedValue::Long(10), true)));
+        assert!(speculative.datoms.iter().any(|&(datom_e, _, _, added)| datom_e == tx && added));
+        assert_eq!(speculative.schema.expect("schema changed").get_entid(&kw!(:foo/name)),
+                   Some(KnownEntid(n)));
+
+        // Afterwards, nothing has changed.
+        assert_eq!(in_progress.lookup_value_for_attribute(e, &kw!(:foo/count)).expect("looked up"),
+                   Some(TypedValue::Long(1)));
+        assert_eq!(in_progress.get_entid(&kw!(:foo/name)), None);
+        assert_eq!(in_progress.lookup_value_for_attribute(m, &kw!(:foo/count)).expect("looked up"),
+                   None);
+
+        // A failed speculation changes nothing either.
+        assert!(in_progress.transact_speculatively("[[:db/add \"x\" :foo/nope 1]]").is_err());
+
+        // The speculative transaction didn't use up any entids.
+        let report = in_progress.transact(t.as_str()).expect("transacted");
+        assert_eq!(report.tx_id, speculative.report.tx_id);
+        assert_eq!(report.tempids, speculative.report.tempids);
+        in_progress.commit().expect("committed");
+    }
+
     #[test]
     fn test_add_to_cache_failure_no_attribute() {
         let mut sqlite = db::new_connection("").unwrap();
@@ -891,7 +1628,7 @@ mod tests {
         let entities = conn.q_once(&sqlite, r#"[:find ?e . :where [?e :foo/bar 400]]"#, None).expect("Expected query to work").into_scalar().expect("expected rel results");
         let first = entities.expect("expected a result");
         let entid = match first {
-            TypedValue::Ref(entid) => entid,
+            Binding::Scalar(TypedValue::Ref(entid)) => entid,
             x => panic!("expected Some(Ref), got {:?}", x),
         };
 
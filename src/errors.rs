@@ -17,6 +17,7 @@ use std::collections::BTreeSet;
 use edn;
 use mentat_core::{
     Attribute,
+    ValueType,
 };
 use mentat_db;
 use mentat_query;
@@ -53,6 +54,21 @@ error_chain! {
             display("variables {:?} unbound at query execution time", names)
         }
 
+        UntypedInput(name: String) {
+            description("input of unknown type in prepared query")
+            display("input {} has no known type: declare one when preparing the query", name)
+        }
+
+        InputTypeMismatch(name: String, expected: ValueType, provided: ValueType) {
+            description("input of the wrong type")
+            display("input {} should be {:?}, but was {:?}", name, expected, provided)
+        }
+
+        InvalidQuery(reason: String) {
+            description("invalid query")
+            display("invalid query: {}", reason)
+        }
+
         InvalidArgumentName(name: String) {
             description("invalid argument name")
             display("invalid argument name: '{}'", name)
@@ -92,5 +108,15 @@ error_chain! {
             description("schema changed since query was prepared")
             display("schema changed since query was prepared")
         }
+
+        QueryAborted(reason: ::interrupt::QueryAbortReason) {
+            description("query aborted")
+            display("query {}", reason)
+        }
+
+        InvalidTxFunctionName(name: String) {
+            description("invalid transaction function name")
+            display("invalid transaction function name: '{}': the :db namespace is reserved", name)
+        }
     }
 }
@@ -0,0 +1,211 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Stopping queries that run for too long.
+//!
+//! SQLite calls a progress handler every so often while it runs a statement; if the handler
+//! returns non-zero, the statement fails with `SQLITE_INTERRUPT`. We install a handler for the
+//! duration of each query that has a timeout or a cancellation token, and turn that failure into
+//! `ErrorKind::QueryAborted`.
+
+use std::cell::Cell;
+use std::fmt;
+use std::os::raw::{
+    c_int,
+    c_void,
+};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use rusqlite;
+use rusqlite::ffi;
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+
+/// The number of SQLite virtual machine instructions between checks. Checking the clock is cheap
+/// next to this much work, and a thousand instructions take well under a millisecond.
+const PROGRESS_INTERVAL: c_int = 1000;
+
+/// Why a query was stopped before it finished.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueryAbortReason {
+    Cancelled,
+    TimedOut,
+}
+
+impl fmt::Display for QueryAbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &QueryAbortReason::Cancelled => write!(f, "cancelled"),
+            &QueryAbortReason::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// A handle with which to cancel queries from another thread. Clones share their state: cancelling
+/// one cancels every query run with any of them, including queries that haven't yet started. Make
+/// a new token for each piece of work that can be cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Limits on how a query runs. The default places no limits.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use mentat::{CancellationToken, QueryOptions};
+/// let token = CancellationToken::new();
+/// let options = QueryOptions::default()
+///                   .with_timeout(Duration::from_millis(500))
+///                   .with_cancellation(token.clone());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct QueryOptions {
+    /// How long each run of the query may take, measured from when it starts.
+    pub timeout: Option<Duration>,
+
+    /// A token that stops the query when cancelled.
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl QueryOptions {
+    pub fn with_timeout(mut self, timeout: Duration) -> QueryOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_cancellation(mut self, token: CancellationToken) -> QueryOptions {
+        self.cancellation = Some(token);
+        self
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.timeout.is_none() && self.cancellation.is_none()
+    }
+}
+
+/// The state that the progress handler consults. It's boxed so that its address is stable while
+/// SQLite holds a pointer to it.
+struct InterruptCheck {
+    deadline: Option<Instant>,
+    cancellation: Option<CancellationToken>,
+    interrupted: Cell<bool>,
+}
+
+impl InterruptCheck {
+    fn reason(&self) -> Option<QueryAbortReason> {
+        if self.cancellation.as_ref().map_or(false, |token| token.is_cancelled()) {
+            return Some(QueryAbortReason::Cancelled);
+        }
+        if self.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Some(QueryAbortReason::TimedOut);
+        }
+        None
+    }
+}
+
+unsafe extern "C" fn progress_handler(context: *mut c_void) -> c_int {
+    let check = &*(context as *const InterruptCheck);
+    if check.reason().is_some() {
+        check.interrupted.set(true);
+        1
+    } else {
+        0
+    }
+}
+
+/// Applies `QueryOptions` to a connection for as long as it lives. A connection has only one
+/// progress handler, so only one query with options can run on a connection at a time.
+pub struct Interrupter<'sqlite> {
+    sqlite: &'sqlite rusqlite::Connection,
+    check: Option<Box<InterruptCheck>>,
+}
+
+impl<'sqlite> Interrupter<'sqlite> {
+    /// Start enforcing `options` on `sqlite`. Fails if the query has already been cancelled.
+    pub fn install(sqlite: &'sqlite rusqlite::Connection, options: &QueryOptions) -> Result<Interrupter<'sqlite>> {
+        if options.is_unlimited() {
+            return Ok(Interrupter {
+                sqlite: sqlite,
+                check: None,
+            });
+        }
+
+        let check = Box::new(InterruptCheck {
+            deadline: options.timeout.map(|timeout| Instant::now() + timeout),
+            cancellation: options.cancellation.clone(),
+            interrupted: Cell::new(false),
+        });
+        if let Some(reason) = check.reason() {
+            bail!(ErrorKind::QueryAborted(reason));
+        }
+
+        unsafe {
+            let context = &*check as *const InterruptCheck as *mut c_void;
+            ffi::sqlite3_progress_handler(sqlite.handle(), PROGRESS_INTERVAL, Some(progress_handler), context);
+        }
+        Ok(Interrupter {
+            sqlite: sqlite,
+            check: Some(check),
+        })
+    }
+
+    /// Replace the error from a query that we stopped with `ErrorKind::QueryAborted`. SQLite
+    /// reports an interruption wherever it happens to be, so that error could have come from any
+    /// layer.
+    pub fn check<T>(&self, result: Result<T>) -> Result<T> {
+        match (result, self.check.as_ref()) {
+            (Err(e), Some(check)) => {
+                if check.interrupted.get() {
+                    let reason = check.reason().unwrap_or(QueryAbortReason::TimedOut);
+                    Err(Error::from_kind(ErrorKind::QueryAborted(reason)))
+                } else {
+                    Err(e)
+                }
+            },
+            (result, _) => result,
+        }
+    }
+}
+
+impl<'sqlite> Drop for Interrupter<'sqlite> {
+    fn drop(&mut self) {
+        if self.check.is_some() {
+            unsafe {
+                ffi::sqlite3_progress_handler(self.sqlite.handle(), 0, None, ptr::null_mut());
+            }
+        }
+    }
+}
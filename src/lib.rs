@@ -32,9 +32,11 @@ extern crate mentat_tx_parser;
 
 pub use mentat_core::{
     Attribute,
+    Binding,
     Entid,
     HasSchema,
     NamespacedKeyword,
+    StructuredMap,
     TypedValue,
     Uuid,
     ValueType,
@@ -76,26 +78,47 @@ macro_rules! kw {
 pub mod cache;
 pub mod errors;
 pub mod ident;
+pub mod interrupt;
 pub mod vocabulary;
 pub mod conn;
+pub mod plan_cache;
 pub mod query;
 pub mod entity_builder;
+pub mod query_builder;
+pub mod tx_functions;
 
 pub fn get_name() -> String {
     return String::from("mentat");
 }
 
+pub use interrupt::{
+    CancellationToken,
+    QueryAbortReason,
+    QueryOptions,
+};
+
 pub use query::{
+    IdentOrEntid,
     IntoResult,
+    LookupRef,
     PlainSymbol,
+    QueryCursor,
     QueryExecutionResult,
     QueryExplanation,
     QueryInputs,
     QueryOutput,
+    QueryPlan,
     QueryPlanStep,
     QueryResults,
+    QueryStream,
+    Rule,
     Variable,
+    ident_for_ref,
+    parse_rules_string,
     q_once,
+    q_once_after,
+    q_once_with_options,
+    q_prepare_with_options,
 };
 
 pub use conn::{
@@ -103,7 +126,18 @@ pub use conn::{
     InProgress,
     Metadata,
     Queryable,
+    SpeculativeReport,
+    Speculation,
     Store,
+    StoreView,
+};
+
+pub use plan_cache::{
+    QueryPlanCacheStats,
+};
+
+pub use tx_functions::{
+    TxFunctionView,
 };
 
 #[cfg(test)]
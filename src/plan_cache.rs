@@ -0,0 +1,210 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::BTreeMap;
+
+use std::sync::Arc;
+
+use mentat_core::{
+    ValueType,
+};
+
+use mentat_query::{
+    Variable,
+};
+
+use errors::{
+    Result,
+};
+
+use query::{
+    QueryPlan,
+};
+
+/// The number of plans a `Conn` keeps by default.
+pub const DEFAULT_QUERY_PLAN_CACHE_CAPACITY: usize = 128;
+
+/// A plan depends on the text of the query and on the types of the inputs that will be bound
+/// when it's run.
+#[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
+struct QueryPlanKey {
+    query: String,
+    input_types: BTreeMap<Variable, ValueType>,
+}
+
+/// How often a `QueryPlanCache` has been able to avoid planning a query.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueryPlanCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A least-recently-used cache of query plans. Plans are only valid for the schema with which they
+/// were made, so the cache must be cleared whenever the schema changes.
+///
+/// The cache and its plans are `Send`, so a `Conn` can share one cache between all of the threads
+/// that query through it.
+pub struct QueryPlanCache {
+    capacity: usize,
+
+    /// Incremented on every lookup, so that each entry records when it was last used.
+    clock: u64,
+    plans: BTreeMap<QueryPlanKey, (u64, Arc<QueryPlan>)>,
+
+    /// From the time of last use to the key. The first entry is the least recently used.
+    recency: BTreeMap<u64, QueryPlanKey>,
+
+    stats: QueryPlanCacheStats,
+}
+
+impl QueryPlanCache {
+    pub fn new(capacity: usize) -> QueryPlanCache {
+        QueryPlanCache {
+            capacity: capacity,
+            clock: 0,
+            plans: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            stats: QueryPlanCacheStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.plans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plans.is_empty()
+    }
+
+    pub fn stats(&self) -> QueryPlanCacheStats {
+        self.stats
+    }
+
+    /// Drop every plan. The hit and miss counts are kept.
+    pub fn clear(&mut self) {
+        self.plans.clear();
+        self.recency.clear();
+    }
+
+    /// Return the plan for `query` with inputs of `input_types`, calling `plan` to make one if we
+    /// don't already have it. A plan that fails to be made is not cached, and nor is one that
+    /// depends on the contents of the store.
+    pub fn get_or_plan<F>(&mut self, query: &str, input_types: BTreeMap<Variable, ValueType>, plan: F) -> Result<Arc<QueryPlan>>
+        where F: FnOnce() -> Result<QueryPlan> {
+        let key = QueryPlanKey {
+            query: query.to_string(),
+            input_types: input_types,
+        };
+
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some(entry) = self.plans.get_mut(&key) {
+            self.recency.remove(&entry.0);
+            self.recency.insert(now, key.clone());
+            entry.0 = now;
+            self.stats.hits += 1;
+            return Ok(entry.1.clone());
+        }
+
+        self.stats.misses += 1;
+        let plan = Arc::new(plan()?);
+
+        if self.capacity > 0 && !plan.depends_on_data() {
+            if self.plans.len() >= self.capacity {
+                self.evict_least_recently_used();
+            }
+            self.recency.insert(now, key.clone());
+            self.plans.insert(key, (now, plan.clone()));
+        }
+
+        Ok(plan)
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.recency.keys().next().cloned();
+        if let Some(oldest) = oldest {
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.plans.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_query::{
+        Element,
+        FindSpec,
+    };
+
+    fn empty_plan() -> Result<QueryPlan> {
+        let spec = FindSpec::FindScalar(Element::Variable(Variable::from_valid_name("?x")));
+        Ok(QueryPlan::Empty {
+            find_spec: Arc::new(spec),
+            depends_on_data: false,
+        })
+    }
+
+    fn assert_send_and_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_plans_can_be_shared_between_threads() {
+        assert_send_and_sync::<QueryPlan>();
+        assert_send_and_sync::<Arc<QueryPlan>>();
+        assert_send_and_sync::<::std::sync::Mutex<QueryPlanCache>>();
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let mut cache = QueryPlanCache::new(2);
+        let mut types = BTreeMap::new();
+
+        cache.get_or_plan("a", types.clone(), empty_plan).expect("plan");
+        cache.get_or_plan("a", types.clone(), || panic!("should be cached")).expect("plan");
+        assert_eq!(cache.stats(), QueryPlanCacheStats { hits: 1, misses: 1 });
+
+        // Different input types need a different plan.
+        types.insert(Variable::from_valid_name("?x"), ValueType::Long);
+        cache.get_or_plan("a", types.clone(), empty_plan).expect("plan");
+        assert_eq!(cache.stats(), QueryPlanCacheStats { hits: 1, misses: 2 });
+        assert_eq!(cache.len(), 2);
+
+        // Failures aren't cached.
+        assert!(cache.get_or_plan("b", types.clone(), || bail!("no plan")).is_err());
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats(), QueryPlanCacheStats { hits: 1, misses: 3 });
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = QueryPlanCache::new(2);
+        let types = BTreeMap::new();
+
+        cache.get_or_plan("a", types.clone(), empty_plan).expect("plan");
+        cache.get_or_plan("b", types.clone(), empty_plan).expect("plan");
+
+        // Use `a` again, so that `b` is the oldest.
+        cache.get_or_plan("a", types.clone(), || panic!("should be cached")).expect("plan");
+
+        cache.get_or_plan("c", types.clone(), empty_plan).expect("plan");
+        assert_eq!(cache.len(), 2);
+
+        cache.get_or_plan("a", types.clone(), || panic!("should be cached")).expect("plan");
+        cache.get_or_plan("c", types.clone(), || panic!("should be cached")).expect("plan");
+        cache.get_or_plan("b", types.clone(), empty_plan).expect("plan");
+        assert_eq!(cache.stats(), QueryPlanCacheStats { hits: 3, misses: 4 });
+    }
+}
@@ -9,22 +9,38 @@
 // specific language governing permissions and limitations under the License.
 
 use rusqlite;
-use rusqlite::types::ToSql;
+use rusqlite::types::{
+    ToSql,
+    ToSqlOutput,
+};
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 
 use std::rc::Rc;
+use std::sync::Arc;
 
 use mentat_core::{
+    Binding,
     Entid,
     HasSchema,
     KnownEntid,
     Schema,
     TypedValue,
+    ValueType,
 };
 
+use mentat_db::TypedSQLValue;
+use mentat_db::db::MentatStoring;
+use mentat_db::types::AVPair;
+
 use mentat_query_algebrizer::{
     AlgebraicQuery,
     algebrize_with_inputs,
     EmptyBecause,
+    lookup_ref_av,
 };
 
 pub use mentat_query_algebrizer::{
@@ -32,15 +48,21 @@ pub use mentat_query_algebrizer::{
 };
 
 pub use mentat_query::{
+    FindQuery,
+    IdentOrEntid,
+    LookupRef,
     NamespacedKeyword,
     PlainSymbol,
+    Rule,
     Variable,
 };
 
 use mentat_query::{
+    ContainsLookupRefs,
     Element,
-    FindQuery,
     FindSpec,
+    Limit,
+    Offset,
     Pattern,
     PatternNonValuePlace,
     PatternValuePlace,
@@ -51,6 +73,10 @@ use mentat_query_parser::{
     parse_find_string,
 };
 
+pub use mentat_query_parser::{
+    parse_rules_string,
+};
+
 use mentat_query_projector::{
     Projector,
 };
@@ -60,12 +86,17 @@ use mentat_sql::{
 };
 
 use mentat_query_translator::{
+    DatomsView,
+    ProjectedSelect,
+    input_variable_param_name,
     query_to_select,
+    query_to_select_in_view,
 };
 
 pub use mentat_query_projector::{
     QueryOutput,        // Includes the columns/find spec.
     QueryResults,       // The results themselves.
+    ident_for_ref,
 };
 
 use errors::{
@@ -77,57 +108,194 @@ use cache::{
     AttributeCacher,
 };
 
+use interrupt::{
+    Interrupter,
+    QueryOptions,
+};
+
 pub type QueryExecutionResult = Result<QueryOutput>;
 pub type PreparedResult<'sqlite> = Result<PreparedQuery<'sqlite>>;
 
 pub enum PreparedQuery<'sqlite> {
     Empty {
-        find_spec: Rc<FindSpec>,
+        find_spec: Arc<FindSpec>,
     },
     Bound {
+        sqlite: &'sqlite rusqlite::Connection,
+        schema: Arc<Schema>,
         statement: rusqlite::Statement<'sqlite>,
-        args: Vec<(String, Rc<rusqlite::types::Value>)>,
-        projector: Box<Projector>,
+        args: Vec<(String, Arc<rusqlite::types::Value>)>,
+        /// The inputs that must be supplied each time the query is run, and their types.
+        input_types: BTreeMap<Variable, ValueType>,
+        projector: Arc<Projector>,
+        /// Limits that apply to each run of the query.
+        options: QueryOptions,
+    },
+}
+
+/// A query that has been parsed, algebrized, and translated to SQL, but not yet prepared against a
+/// SQLite connection. A plan is only valid for the schema with which it was made.
+///
+/// A query that mentions lookup refs is planned with the entities they named at the time, so its
+/// plan is also only valid for the contents of the store at that time; see `depends_on_data`.
+///
+/// Plans are `Send` and `Sync`, so that one cached plan can be shared by queries on any thread.
+pub enum QueryPlan {
+    Empty {
+        find_spec: Arc<FindSpec>,
+        depends_on_data: bool,
+    },
+    Bound {
+        sql: String,
+        args: Vec<(String, Arc<rusqlite::types::Value>)>,
+        input_types: BTreeMap<Variable, ValueType>,
+        projector: Arc<Projector>,
+        depends_on_data: bool,
     },
 }
 
+impl QueryPlan {
+    /// True if this plan incorporates resolved lookup refs, and so can't be reused once the store
+    /// changes.
+    pub fn depends_on_data(&self) -> bool {
+        match self {
+            &QueryPlan::Empty { depends_on_data, .. } |
+            &QueryPlan::Bound { depends_on_data, .. } => depends_on_data,
+        }
+    }
+
+    /// Prepare this plan's SQL against `sqlite`. `schema` must be the schema the plan was made with;
+    /// the prepared query shares it rather than copying it.
+    pub fn prepare<'sqlite>(&self, sqlite: &'sqlite rusqlite::Connection, schema: Arc<Schema>) -> PreparedResult<'sqlite> {
+        match self {
+            &QueryPlan::Empty { ref find_spec, .. } => {
+                Ok(PreparedQuery::Empty {
+                    find_spec: find_spec.clone(),
+                })
+            },
+            &QueryPlan::Bound { ref sql, ref args, ref input_types, ref projector, .. } => {
+                let statement = sqlite.prepare(sql.as_str())?;
+                Ok(PreparedQuery::Bound {
+                    sqlite,
+                    schema,
+                    statement,
+                    args: args.clone(),
+                    input_types: input_types.clone(),
+                    projector: projector.clone(),
+                    options: QueryOptions::default(),
+                })
+            },
+        }
+    }
+}
+
 impl<'sqlite> PreparedQuery<'sqlite> {
-    pub fn run<T>(&mut self, _inputs: T) -> QueryExecutionResult where T: Into<Option<QueryInputs>> {
+    /// Apply `options` to each subsequent run of this query.
+    pub fn with_options(mut self, options: QueryOptions) -> PreparedQuery<'sqlite> {
+        if let PreparedQuery::Bound { options: ref mut existing, .. } = self {
+            *existing = options;
+        }
+        self
+    }
+
+    /// Run the query, binding `inputs` to the variables that had no value when the query was
+    /// prepared. Each such input must be supplied, and must be of the type given at preparation.
+    pub fn run<T>(&mut self, inputs: T) -> QueryExecutionResult where T: Into<Option<QueryInputs>> {
         match self {
             &mut PreparedQuery::Empty { ref find_spec } => {
                 Ok(QueryOutput::empty(find_spec))
             },
-            &mut PreparedQuery::Bound { ref mut statement, ref args, ref projector } => {
-                let rows = run_statement(statement, args)?;
-                projector
-                      .project(rows)
-                      .map_err(|e| e.into())
+            &mut PreparedQuery::Bound { sqlite, ref schema, ref mut statement, ref args, ref input_types, ref projector, ref options } => {
+                let bindings = bind_inputs(args, input_types, inputs.into())?;
+                let interrupter = Interrupter::install(sqlite, options)?;
+                let results = run_statement(statement, &bindings).and_then(|rows| {
+                    projector
+                          .project(schema, sqlite, rows)
+                          .map_err(|e| e.into())
+                });
+                interrupter.check(results)
             }
         }
     }
+
+    /// Run the query just as `run` does, but rather than collecting every result, return an
+    /// iterator that reads and decodes one row at a time from the prepared statement. Each row has
+    /// one binding per element of the find spec. The statement can't be run again until the
+    /// iterator is dropped.
+    pub fn stream<'stmt, T>(&'stmt mut self, inputs: T) -> Result<QueryStream<'stmt>> where T: Into<Option<QueryInputs>> {
+        match *self {
+            PreparedQuery::Empty { .. } => {
+                Ok(QueryStream::Empty)
+            },
+            PreparedQuery::Bound { sqlite, ref schema, ref mut statement, ref args, ref input_types, ref projector, ref options } => {
+                let bindings = bind_inputs(args, input_types, inputs.into())?;
+                // The options apply until the stream is dropped.
+                let interrupter = Interrupter::install(sqlite, options)?;
+                let rows = interrupter.check(run_statement(statement, &bindings))?;
+                Ok(QueryStream::Rows {
+                    sqlite,
+                    schema: &**schema,
+                    rows,
+                    projector: projector.clone(),
+                    interrupter,
+                })
+            }
+        }
+    }
+}
+
+/// The results of a prepared query, read from SQLite as they're needed. See
+/// `PreparedQuery::stream`.
+pub enum QueryStream<'stmt> {
+    Empty,
+    Rows {
+        sqlite: &'stmt rusqlite::Connection,
+        schema: &'stmt Schema,
+        rows: rusqlite::Rows<'stmt>,
+        projector: Arc<Projector>,
+        interrupter: Interrupter<'stmt>,
+    },
+}
+
+impl<'stmt> Iterator for QueryStream<'stmt> {
+    type Item = Result<Vec<Binding>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            &mut QueryStream::Empty => None,
+            &mut QueryStream::Rows { sqlite, schema, ref mut rows, ref projector, ref interrupter } => {
+                let result = match rows.next() {
+                    None => return None,
+                    Some(Err(e)) => Err(e.into()),
+                    Some(Ok(row)) => projector.project_row(schema, sqlite, &row).map_err(|e| e.into()),
+                };
+                Some(interrupter.check(result))
+            },
+        }
+    }
 }
 
 pub trait IntoResult {
-    fn into_scalar_result(self) -> Result<Option<TypedValue>>;
-    fn into_coll_result(self) -> Result<Vec<TypedValue>>;
-    fn into_tuple_result(self) -> Result<Option<Vec<TypedValue>>>;
-    fn into_rel_result(self) -> Result<Vec<Vec<TypedValue>>>;
+    fn into_scalar_result(self) -> Result<Option<Binding>>;
+    fn into_coll_result(self) -> Result<Vec<Binding>>;
+    fn into_tuple_result(self) -> Result<Option<Vec<Binding>>>;
+    fn into_rel_result(self) -> Result<Vec<Vec<Binding>>>;
 }
 
 impl IntoResult for QueryExecutionResult {
-    fn into_scalar_result(self) -> Result<Option<TypedValue>> {
+    fn into_scalar_result(self) -> Result<Option<Binding>> {
         self?.into_scalar().map_err(|e| e.into())
     }
 
-    fn into_coll_result(self) -> Result<Vec<TypedValue>> {
+    fn into_coll_result(self) -> Result<Vec<Binding>> {
         self?.into_coll().map_err(|e| e.into())
     }
 
-    fn into_tuple_result(self) -> Result<Option<Vec<TypedValue>>> {
+    fn into_tuple_result(self) -> Result<Option<Vec<Binding>>> {
         self?.into_tuple().map_err(|e| e.into())
     }
 
-    fn into_rel_result(self) -> Result<Vec<Vec<TypedValue>>> {
+    fn into_rel_result(self) -> Result<Vec<Vec<Binding>>> {
         self?.into_rel().map_err(|e| e.into())
     }
 }
@@ -154,13 +322,44 @@ pub struct QueryPlanStep {
     pub detail: String,
 }
 
-fn algebrize_query<'schema, T>
-(schema: &'schema Schema,
+/// Find the entities named by the lookup refs in `query`, in any rules it uses, and in `inputs`,
+/// and record them in `inputs` for the algebrizer. Returns `true` if there were any lookup refs.
+fn resolve_lookup_refs(sqlite: &rusqlite::Connection,
+                       schema: &Schema,
+                       query: &FindQuery,
+                       inputs: &mut QueryInputs) -> Result<bool> {
+    let mut lookup_refs = query.collect_lookup_refs();
+    if query.in_rules {
+        for rule in inputs.rules.iter() {
+            rule.accumulate_lookup_refs(&mut lookup_refs);
+        }
+    }
+    lookup_refs.extend(inputs.lookup_ref_values.values().cloned());
+    if lookup_refs.is_empty() {
+        return Ok(false);
+    }
+
+    // A lookup ref that can't name anything -- say, because its attribute isn't unique -- makes
+    // the query known-empty when it's algebrized, so there's no need to look it up.
+    let avs: BTreeSet<AVPair> = lookup_refs.iter()
+                                           .filter_map(|lookup_ref| lookup_ref_av(schema, lookup_ref).ok())
+                                           .collect();
+    let avs: Vec<&AVPair> = avs.iter().collect();
+    let resolved = sqlite.resolve_avs(&avs[..])?;
+    inputs.lookup_refs.extend(resolved.into_iter().map(|(av, e)| (av.clone(), e)));
+    Ok(true)
+}
+
+fn algebrize_query<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
  query: FindQuery,
  inputs: T) -> Result<AlgebraicQuery>
     where T: Into<Option<QueryInputs>>
 {
-    let algebrized = algebrize_with_inputs(schema, query, 0, inputs.into().unwrap_or(QueryInputs::default()))?;
+    let mut inputs = inputs.into().unwrap_or(QueryInputs::default());
+    resolve_lookup_refs(sqlite, schema, &query, &mut inputs)?;
+    let algebrized = algebrize_with_inputs(schema, query, 0, inputs)?;
     let unbound = algebrized.unbound_variables();
     // Because we are running once, we can check that all of our `:in` variables are bound at this point.
     // If they aren't, the user has made an error -- perhaps writing the wrong variable in `:in`, or
@@ -174,6 +373,7 @@ fn algebrize_query<'schema, T>
 fn fetch_values<'sqlite, 'schema>
 (sqlite: &'sqlite rusqlite::Connection,
  schema: &'schema Schema,
+ view: Option<DatomsView>,
  entity: Entid,
  attribute: Entid,
  only_one: bool) -> QueryExecutionResult {
@@ -192,9 +392,9 @@ fn fetch_values<'sqlite, 'schema>
     let query = FindQuery::simple(spec,
                                   vec![WhereClause::Pattern(pattern)]);
 
-    let algebrized = algebrize_query(schema, query, None)?;
+    let algebrized = algebrize_query(sqlite, schema, query, None)?;
 
-    run_algebrized_query(sqlite, algebrized)
+    run_algebrized_query(sqlite, schema, view, algebrized, &QueryOptions::default())
 }
 
 fn lookup_attribute(schema: &Schema, attribute: &NamespacedKeyword) -> Result<KnownEntid> {
@@ -219,7 +419,8 @@ pub fn lookup_value<'sqlite, 'schema, 'cache, E, A>
     if cached.is_some() {
         return Ok(cached);
     }
-    fetch_values(sqlite, schema, entid, attrid, true).into_scalar_result()
+    // These queries only ever project plain values.
+    Ok(fetch_values(sqlite, schema, None, entid, attrid, true).into_scalar_result()?.and_then(Binding::into_scalar))
 }
 
 pub fn lookup_values<'sqlite, 'schema, 'cache, E, A>
@@ -234,7 +435,8 @@ pub fn lookup_values<'sqlite, 'schema, 'cache, E, A>
     if let Some(cached) = cache.get_values_for_entid(&attrid, &entid).cloned() {
         return Ok(cached);
     }
-    fetch_values(sqlite, schema, entid, attrid, false).into_coll_result()
+    let values = fetch_values(sqlite, schema, None, entid, attrid, false).into_coll_result()?;
+    Ok(values.into_iter().filter_map(Binding::into_scalar).collect())
 }
 
 /// Return a single value for the provided entity and attribute.
@@ -261,9 +463,35 @@ pub fn lookup_values_for_attribute<'sqlite, 'schema, 'cache, 'attribute, E>
     lookup_values(sqlite, schema, cache, entity.into(), lookup_attribute(schema, attribute)?)
 }
 
-fn run_statement<'sqlite, 'stmt, 'bound>
+/// Return a single value for the provided entity and attribute, as it appears in the given view of
+/// the store. The attribute cache only knows about the current state, so it isn't consulted.
+pub fn lookup_value_for_attribute_in_view<'sqlite, 'schema, 'attribute, E>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ entity: E,
+ attribute: &'attribute NamespacedKeyword) -> Result<Option<TypedValue>>
+ where E: Into<Entid> {
+    let attrid = lookup_attribute(schema, attribute)?.into();
+    Ok(fetch_values(sqlite, schema, Some(view), entity.into(), attrid, true).into_scalar_result()?.and_then(Binding::into_scalar))
+}
+
+pub fn lookup_values_for_attribute_in_view<'sqlite, 'schema, 'attribute, E>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ entity: E,
+ attribute: &'attribute NamespacedKeyword) -> Result<Vec<TypedValue>>
+ where E: Into<Entid> {
+    let attrid = lookup_attribute(schema, attribute)?.into();
+    let values = fetch_values(sqlite, schema, Some(view), entity.into(), attrid, false).into_coll_result()?;
+    Ok(values.into_iter().filter_map(Binding::into_scalar).collect())
+}
+
+fn run_statement<'sqlite, 'stmt, 'bound, V>
 (statement: &'stmt mut rusqlite::Statement<'sqlite>,
- bindings: &'bound [(String, Rc<rusqlite::types::Value>)]) -> Result<rusqlite::Rows<'stmt>> {
+ bindings: &'bound [(String, V)]) -> Result<rusqlite::Rows<'stmt>>
+    where V: AsRef<rusqlite::types::Value> {
 
     let rows = if bindings.is_empty() {
         statement.query(&[])?
@@ -277,6 +505,42 @@ fn run_statement<'sqlite, 'stmt, 'bound>
     Ok(rows)
 }
 
+/// Turn a value into its SQLite representation, owned so that it can outlive `value`. The type
+/// tag isn't needed: the prepared statement already constrains it.
+fn input_to_sql_value(value: &TypedValue) -> rusqlite::types::Value {
+    match value.to_sql_value_pair().0 {
+        ToSqlOutput::Borrowed(v) => v.into(),
+        ToSqlOutput::Owned(v) => v,
+    }
+}
+
+/// Extend the arguments of a prepared statement with a value for each of its late-bound inputs,
+/// checking that every input is present and of the type declared when the query was prepared.
+fn bind_inputs(args: &[(String, Arc<rusqlite::types::Value>)],
+               input_types: &BTreeMap<Variable, ValueType>,
+               inputs: Option<QueryInputs>) -> Result<Vec<(String, Arc<rusqlite::types::Value>)>> {
+    let mut values = inputs.map(|inputs| inputs.values).unwrap_or_default();
+
+    let missing: BTreeSet<String> = input_types.keys()
+                                               .filter(|var| !values.contains_key(*var))
+                                               .map(|var| var.to_string())
+                                               .collect();
+    if !missing.is_empty() {
+        bail!(ErrorKind::UnboundVariables(missing));
+    }
+
+    let mut bindings = args.to_vec();
+    for (var, &expected) in input_types.iter() {
+        let value = values.remove(var).unwrap();
+        let provided = value.value_type();
+        if provided != expected {
+            bail!(ErrorKind::InputTypeMismatch(var.to_string(), expected, provided));
+        }
+        bindings.push((format!("${}", input_variable_param_name(var)), Arc::new(input_to_sql_value(&value))));
+    }
+    Ok(bindings)
+}
+
 fn run_sql_query<'sqlite, 'sql, 'bound, T, F>
 (sqlite: &'sqlite rusqlite::Connection,
  sql: &'sql str,
@@ -293,17 +557,32 @@ fn run_sql_query<'sqlite, 'sql, 'bound, T, F>
     Ok(result)
 }
 
-fn algebrize_query_str<'schema, 'query, T>
-(schema: &'schema Schema,
+fn algebrize_query_str<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
  query: &'query str,
  inputs: T) -> Result<AlgebraicQuery>
     where T: Into<Option<QueryInputs>>
 {
     let parsed = parse_find_string(query)?;
-    algebrize_query(schema, parsed, inputs)
+    algebrize_query(sqlite, schema, parsed, inputs)
+}
+
+/// Translate the query to SQL that reads from `view`, or from the current state of the store if
+/// there is no view.
+fn translate_in_view(algebrized: AlgebraicQuery, view: Option<DatomsView>) -> Result<ProjectedSelect> {
+    let select = match view {
+        None => query_to_select(algebrized)?,
+        Some(view) => query_to_select_in_view(algebrized, view)?,
+    };
+    Ok(select)
 }
 
-fn run_algebrized_query<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebrized: AlgebraicQuery) -> QueryExecutionResult {
+fn run_algebrized_query<'sqlite, 'schema>(sqlite: &'sqlite rusqlite::Connection,
+                                         schema: &'schema Schema,
+                                         view: Option<DatomsView>,
+                                         algebrized: AlgebraicQuery,
+                                         options: &QueryOptions) -> QueryExecutionResult {
     assert!(algebrized.unbound_variables().is_empty(),
             "Unbound variables should be checked by now");
     if algebrized.is_known_empty() {
@@ -311,21 +590,23 @@ fn run_algebrized_query<'sqlite>(sqlite: &'sqlite rusqlite::Connection, algebriz
         return Ok(QueryOutput::empty(&algebrized.find_spec));
     }
 
-    let select = query_to_select(algebrized)?;
+    let select = translate_in_view(algebrized, view)?;
     let SQLQuery { sql, args } = select.query.to_sql_query()?;
 
     let mut statement = sqlite.prepare(sql.as_str())?;
-    let rows = run_statement(&mut statement, &args)?;
-
-    select.projector
-          .project(rows)
-          .map_err(|e| e.into())
+    let interrupter = Interrupter::install(sqlite, options)?;
+    let results = run_statement(&mut statement, &args).and_then(|rows| {
+        select.projector
+              .project(schema, sqlite, rows)
+              .map_err(|e| e.into())
+    });
+    interrupter.check(results)
 }
 
 /// Take an EDN query string, a reference to an open SQLite connection, a Mentat schema, and an
 /// optional collection of input bindings (which should be keyed by `"?varname"`), and execute the
 /// query immediately, blocking the current thread.
-/// Returns a structure that corresponds to the kind of input query, populated with `TypedValue`
+/// Returns a structure that corresponds to the kind of input query, populated with `Binding`
 /// instances.
 /// The caller is responsible for ensuring that the SQLite connection has an open transaction if
 /// isolation is required.
@@ -336,9 +617,121 @@ pub fn q_once<'sqlite, 'schema, 'query, T>
  inputs: T) -> QueryExecutionResult
         where T: Into<Option<QueryInputs>>
 {
-    let algebrized = algebrize_query_str(schema, query, inputs)?;
+    q_once_with_options(sqlite, schema, query, inputs, &QueryOptions::default())
+}
 
-    run_algebrized_query(sqlite, algebrized)
+/// Like `q_once`, but stopping the query with `ErrorKind::QueryAborted` if it runs for longer than
+/// allowed by `options`, or if it is cancelled.
+pub fn q_once_with_options<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ options: &QueryOptions) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let algebrized = algebrize_query_str(sqlite, schema, query, inputs)?;
+
+    run_algebrized_query(sqlite, schema, None, algebrized, options)
+}
+
+/// Like `q_once`, but running a query that was built in code -- see `query_builder::Find` --
+/// rather than parsed from a string.
+pub fn q_once_query<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: FindQuery,
+ inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let algebrized = algebrize_query(sqlite, schema, query, inputs)?;
+
+    run_algebrized_query(sqlite, schema, None, algebrized, &QueryOptions::default())
+}
+
+/// Like `q_once_query`, but reading from the given view of the store.
+pub fn q_once_query_in_view<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ query: FindQuery,
+ inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let algebrized = algebrize_query(sqlite, schema, query, inputs)?;
+
+    run_algebrized_query(sqlite, schema, Some(view), algebrized, &QueryOptions::default())
+}
+
+/// The position just after a row of a query's results, from which to fetch the next page with
+/// `q_once_after`. A cursor holds the value of each variable in the row; the query's `:order`
+/// decides which of them matter.
+///
+/// Keyset pagination only gives stable pages if the ordering is total, so order by something
+/// unique -- typically the entity -- last.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryCursor(BTreeMap<Variable, TypedValue>);
+
+impl QueryCursor {
+    /// A cursor positioned after the row in which each variable has the given value.
+    pub fn after<I>(values: I) -> QueryCursor where I: IntoIterator<Item=(Variable, TypedValue)> {
+        QueryCursor(values.into_iter().collect())
+    }
+
+    /// A cursor positioned after the last row of `output`, or `None` if there are no rows.
+    ///
+    /// Only the variables in `:find` are available, so each variable in the query's `:order` must
+    /// also appear there.
+    pub fn after_last(output: &QueryOutput) -> Option<QueryCursor> {
+        let row: Vec<Binding> = match output.results {
+            QueryResults::Scalar(ref binding) => binding.iter().cloned().collect(),
+            QueryResults::Tuple(ref row) => row.iter().flat_map(|row| row.iter().cloned()).collect(),
+            QueryResults::Coll(ref bindings) => bindings.last().into_iter().cloned().collect(),
+            QueryResults::Rel(ref rows) => rows.last().into_iter().flat_map(|row| row.iter().cloned()).collect(),
+        };
+        if row.is_empty() {
+            return None;
+        }
+
+        let values = output.spec.columns().zip(row.into_iter()).filter_map(|(element, binding)| {
+            match (element, binding) {
+                (&Element::Variable(ref var), Binding::Scalar(value)) => Some((var.clone(), value)),
+                _ => None,
+            }
+        });
+        Some(QueryCursor::after(values))
+    }
+}
+
+/// Like `q_once`, but returning only the results that the query's `:order` places after `cursor`.
+/// Combine with `:limit` to fetch a page at a time.
+pub fn q_once_after<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ cursor: &QueryCursor) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let mut algebrized = algebrize_query_str(sqlite, schema, query, inputs)?;
+    algebrized.seek_after(&cursor.0)?;
+
+    run_algebrized_query(sqlite, schema, None, algebrized, &QueryOptions::default())
+}
+
+/// Like `q_once`, but reading from the given view of the store rather than its current state.
+/// Pull expressions read from the view too.
+pub fn q_once_in_view<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ query: &'query str,
+ inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let algebrized = algebrize_query_str(sqlite, schema, query, inputs)?;
+
+    run_algebrized_query(sqlite, schema, Some(view), algebrized, &QueryOptions::default())
 }
 
 pub fn q_prepare<'sqlite, 'schema, 'query, T>
@@ -348,30 +741,186 @@ pub fn q_prepare<'sqlite, 'schema, 'query, T>
  inputs: T) -> PreparedResult<'sqlite>
         where T: Into<Option<QueryInputs>>
 {
-    let algebrized = algebrize_query_str(schema, query, inputs)?;
+    prepare_in_view(sqlite, schema, None, query, inputs)
+}
+
+/// Like `q_prepare`, but applying `options` to each run of the prepared query.
+pub fn q_prepare_with_options<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ options: QueryOptions) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>>
+{
+    Ok(prepare_in_view(sqlite, schema, None, query, inputs)?.with_options(options))
+}
 
-    let unbound = algebrized.unbound_variables();
-    if !unbound.is_empty() {
-        // TODO: Allow binding variables at execution time, not just
-        // preparation time.
-        bail!(ErrorKind::UnboundVariables(unbound.into_iter().map(|v| v.to_string()).collect()));
-    }
+pub fn q_prepare_in_view<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ query: &'query str,
+ inputs: T) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>>
+{
+    prepare_in_view(sqlite, schema, Some(view), query, inputs)
+}
+
+/// Like `q_prepare`, but for a query that was built in code rather than parsed from a string.
+pub fn q_prepare_query<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: FindQuery,
+ inputs: T) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>>
+{
+    plan_query_in_view(sqlite, schema, None, query, inputs)?.prepare(sqlite, Arc::new(schema.clone()))
+}
+
+pub fn q_prepare_query_in_view<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ query: FindQuery,
+ inputs: T) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>>
+{
+    plan_query_in_view(sqlite, schema, Some(view), query, inputs)?.prepare(sqlite, Arc::new(schema.clone()))
+}
+
+fn prepare_in_view<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: Option<DatomsView>,
+ query: &'query str,
+ inputs: T) -> PreparedResult<'sqlite>
+        where T: Into<Option<QueryInputs>>
+{
+    // We only have a reference to the schema, so the prepared query needs its own copy. `Conn`
+    // prepares its plans against the `Arc` it already holds instead.
+    plan_in_view(sqlite, schema, view, query, inputs)?.prepare(sqlite, Arc::new(schema.clone()))
+}
+
+/// Parse, algebrize, and translate `query`, without preparing it against a connection. Inputs
+/// that aren't given values must have known types; their values are supplied when the prepared
+/// query is run. Lookup refs are resolved against `sqlite` now, not each time the query is run.
+pub fn q_plan<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T) -> Result<QueryPlan>
+        where T: Into<Option<QueryInputs>>
+{
+    plan_in_view(sqlite, schema, None, query, inputs)
+}
+
+/// Like `q_plan`, but for a query with no inputs that will be run immediately, as by `q_once`.
+pub fn q_plan_once<'sqlite, 'schema, 'query>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str) -> Result<QueryPlan>
+{
+    let parsed = parse_find_string(query)?;
+    q_plan_query_once(sqlite, schema, parsed)
+}
+
+/// Like `q_plan`, but for a query that was built in code rather than parsed from a string.
+pub fn q_plan_query<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: FindQuery,
+ inputs: T) -> Result<QueryPlan>
+        where T: Into<Option<QueryInputs>>
+{
+    plan_query_in_view(sqlite, schema, None, query, inputs)
+}
+
+/// Like `q_plan_once`, but for a query that was built in code rather than parsed from a string.
+pub fn q_plan_query_once<'sqlite, 'schema>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: FindQuery) -> Result<QueryPlan>
+{
+    let depends_on_data = !query.collect_lookup_refs().is_empty();
+    let algebrized = algebrize_query(sqlite, schema, query, None)?;
+    plan_algebrized(algebrized, None, depends_on_data)
+}
 
+fn plan_in_view<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: Option<DatomsView>,
+ query: &'query str,
+ inputs: T) -> Result<QueryPlan>
+        where T: Into<Option<QueryInputs>>
+{
+    let parsed = parse_find_string(query)?;
+    plan_query_in_view(sqlite, schema, view, parsed, inputs)
+}
+
+fn plan_query_in_view<'sqlite, 'schema, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: Option<DatomsView>,
+ query: FindQuery,
+ inputs: T) -> Result<QueryPlan>
+        where T: Into<Option<QueryInputs>>
+{
+    // Unlike `q_once`, we allow `:in` variables to be unbound: they'll be bound each time the
+    // query is run.
+    let mut inputs = inputs.into().unwrap_or(QueryInputs::default());
+    let depends_on_data = resolve_lookup_refs(sqlite, schema, &query, &mut inputs)?;
+    let algebrized = algebrize_with_inputs(schema, query, 0, inputs)?;
+    plan_algebrized(algebrized, view, depends_on_data)
+}
+
+fn plan_algebrized(algebrized: AlgebraicQuery, view: Option<DatomsView>, depends_on_data: bool) -> Result<QueryPlan> {
     if algebrized.is_known_empty() {
         // We don't need to do any SQL work at all.
-        return Ok(PreparedQuery::Empty {
+        return Ok(QueryPlan::Empty {
             find_spec: algebrized.find_spec,
+            depends_on_data: depends_on_data,
         });
     }
 
-    let select = query_to_select(algebrized)?;
+    // We can only bind a value to a parameter if we know its type up front: either it was
+    // declared in the inputs, or the query implies it.
+    let mut input_types = BTreeMap::new();
+    let late_bound_inputs = algebrized.cc.late_bound_inputs();
+    for var in algebrized.unbound_variables() {
+        let is_limit = match algebrized.limit {
+            Limit::Variable(ref limit) => limit == &var,
+            _ => false,
+        };
+        let is_offset = match algebrized.offset {
+            Offset::Variable(ref offset) => offset == &var,
+            _ => false,
+        };
+        let is_used = algebrized.cc.column_bindings.contains_key(&var) ||
+                      late_bound_inputs.contains(&var);
+        if !is_limit && !is_offset && !is_used {
+            // Mentioned in `:in`, but not used, so there's nothing to bind.
+            continue;
+        }
+        match algebrized.cc.known_type(&var) {
+            Some(value_type) => {
+                input_types.insert(var, value_type);
+            },
+            None => bail!(ErrorKind::UntypedInput(var.to_string())),
+        }
+    }
+
+    let select = translate_in_view(algebrized, view)?;
     let SQLQuery { sql, args } = select.query.to_sql_query()?;
-    let statement = sqlite.prepare(sql.as_str())?;
+    let args = args.into_iter().map(|(name, value)| (name, Arc::new((*value).clone()))).collect();
 
-    Ok(PreparedQuery::Bound {
-        statement,
+    Ok(QueryPlan::Bound {
+        sql,
         args,
-        projector: select.projector
+        input_types,
+        projector: Arc::from(select.projector),
+        depends_on_data,
     })
 }
 
@@ -382,11 +931,33 @@ pub fn q_explain<'sqlite, 'schema, 'query, T>
  inputs: T) -> Result<QueryExplanation>
         where T: Into<Option<QueryInputs>>
 {
-    let algebrized = algebrize_query_str(schema, query, inputs)?;
+    explain_in_view(sqlite, schema, None, query, inputs)
+}
+
+pub fn q_explain_in_view<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: DatomsView,
+ query: &'query str,
+ inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>>
+{
+    explain_in_view(sqlite, schema, Some(view), query, inputs)
+}
+
+fn explain_in_view<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ view: Option<DatomsView>,
+ query: &'query str,
+ inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>>
+{
+    let algebrized = algebrize_query_str(sqlite, schema, query, inputs)?;
     if algebrized.is_known_empty() {
         return Ok(QueryExplanation::KnownEmpty(algebrized.cc.empty_because.unwrap()));
     }
-    let query = query_to_select(algebrized)?.query.to_sql_query()?;
+    let query = translate_in_view(algebrized, view)?.query.to_sql_query()?;
 
     let plan_sql = format!("EXPLAIN QUERY PLAN {}", query.sql);
 
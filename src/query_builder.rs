@@ -0,0 +1,476 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+// Queries, like transactions, are fundamentally strings of EDN. A programmatic consumer that
+// assembles a query at runtime has to build that string by concatenation, and only finds out
+// that it got something wrong when the parser rejects it.
+//
+// This file provides a builder that produces a `FindQuery` -- the parser's output -- directly:
+//
+//     let query = Find::rel(vec![v("?e"), v("?name")])
+//                     .where_pattern(v("?e"), kw!(:person/name), v("?name"))
+//                     .order_asc("?name")
+//                     .build()?;
+//     let results = store.q_once_query(query, None)?;
+//
+// The parser does some validation that isn't expressed in the types of the query AST: variable
+// lists can't contain duplicates, `:limit ?x` needs `?x` in `:in`, and so on. `build` repeats
+// those checks and fails with the same errors, so a built query reaches the algebrizer in the
+// same shape as a parsed one.
+
+use std::collections::BTreeSet;
+
+use mentat_query::{
+    Direction,
+    Element,
+    FindQuery,
+    FindSpec,
+    FnArg,
+    Limit,
+    Offset,
+    Order,
+    OrWhereClause,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    PlainSymbol,
+    Predicate,
+    SrcVar,
+    UnifyVars,
+    Variable,
+    WhereClause,
+};
+
+use mentat_query_parser::{
+    unique_vars,
+    validate_added_place,
+    validate_paging,
+    validate_rule_name,
+};
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+
+/// Name a query variable, like `?x`.
+///
+/// Panics if `name` doesn't start with `?`. Builder methods that take a variable also accept a
+/// string, and report a bad name when the query is built.
+pub fn v(name: &str) -> Variable {
+    Variable::from_valid_name(name)
+}
+
+/// Something that names a query variable: a `Variable`, or a string like `"?x"`.
+pub trait IntoVariable {
+    fn into_variable(self) -> Result<Variable>;
+}
+
+impl IntoVariable for Variable {
+    fn into_variable(self) -> Result<Variable> {
+        Ok(self)
+    }
+}
+
+impl<'a> IntoVariable for &'a Variable {
+    fn into_variable(self) -> Result<Variable> {
+        Ok(self.clone())
+    }
+}
+
+impl<'a> IntoVariable for &'a str {
+    fn into_variable(self) -> Result<Variable> {
+        if self.starts_with('?') {
+            Ok(Variable::from_valid_name(self))
+        } else {
+            bail!(ErrorKind::InvalidQuery(format!("'{}' is not a variable", self)))
+        }
+    }
+}
+
+fn invalid<T>(reason: &str) -> Result<T> {
+    bail!(ErrorKind::InvalidQuery(reason.to_string()))
+}
+
+/// A `FindQuery` under construction. Start with one of the four kinds of find spec -- `rel`,
+/// `coll`, `tuple`, or `scalar` -- add clauses, and then call `build`.
+pub struct Find {
+    find_spec: FindSpec,
+    in_vars: Vec<Variable>,
+    in_rules: bool,
+    with: Vec<Variable>,
+    limit: Limit,
+    offset: Offset,
+    where_clauses: Vec<WhereClause>,
+    order: Vec<Order>,
+
+    /// The first mistake made while building, reported by `build`.
+    error: Option<Error>,
+}
+
+impl Find {
+    fn new(find_spec: FindSpec) -> Find {
+        Find {
+            find_spec: find_spec,
+            in_vars: vec![],
+            in_rules: false,
+            with: vec![],
+            limit: Limit::None,
+            offset: Offset::None,
+            where_clauses: vec![],
+            order: vec![],
+            error: None,
+        }
+    }
+
+    /// `:find ?x ?y`.
+    pub fn rel<I, E>(elements: I) -> Find where I: IntoIterator<Item=E>, E: Into<Element> {
+        Find::new(FindSpec::FindRel(elements.into_iter().map(|e| e.into()).collect()))
+    }
+
+    /// `:find [?x ?y]`.
+    pub fn tuple<I, E>(elements: I) -> Find where I: IntoIterator<Item=E>, E: Into<Element> {
+        Find::new(FindSpec::FindTuple(elements.into_iter().map(|e| e.into()).collect()))
+    }
+
+    /// `:find [?x ...]`.
+    pub fn coll<E>(element: E) -> Find where E: Into<Element> {
+        Find::new(FindSpec::FindColl(element.into()))
+    }
+
+    /// `:find ?x .`.
+    pub fn scalar<E>(element: E) -> Find where E: Into<Element> {
+        Find::new(FindSpec::FindScalar(element.into()))
+    }
+
+    fn record<T>(&mut self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(x) => Some(x),
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+                None
+            },
+        }
+    }
+
+    /// Add a variable to `:in`.
+    pub fn in_var<V>(mut self, var: V) -> Find where V: IntoVariable {
+        if let Some(var) = self.record(var.into_variable()) {
+            self.in_vars.push(var);
+        }
+        self
+    }
+
+    /// Add `%` to `:in`, so that the query can use the rules supplied with its inputs.
+    pub fn in_rules(mut self) -> Find {
+        self.in_rules = true;
+        self
+    }
+
+    /// Add a variable to `:with`.
+    pub fn with<V>(mut self, var: V) -> Find where V: IntoVariable {
+        if let Some(var) = self.record(var.into_variable()) {
+            self.with.push(var);
+        }
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Find {
+        self.limit = Limit::Fixed(limit);
+        self
+    }
+
+    /// `:limit ?x`. The variable must also be added to `:in`.
+    pub fn limit_var<V>(mut self, var: V) -> Find where V: IntoVariable {
+        if let Some(var) = self.record(var.into_variable()) {
+            self.limit = Limit::Variable(var);
+        }
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Find {
+        self.offset = Offset::Fixed(offset);
+        self
+    }
+
+    /// `:offset ?x`. The variable must also be added to `:in`.
+    pub fn offset_var<V>(mut self, var: V) -> Find where V: IntoVariable {
+        if let Some(var) = self.record(var.into_variable()) {
+            self.offset = Offset::Variable(var);
+        }
+        self
+    }
+
+    /// Add the pattern `[e a v]` to `:where`. As in a parsed query, a reversed attribute like
+    /// `:foo/_bar` swaps the entity and the value.
+    pub fn where_pattern<E, A, V>(mut self, e: E, a: A, v: V) -> Find
+    where E: Into<PatternNonValuePlace>,
+          A: Into<PatternNonValuePlace>,
+          V: Into<PatternValuePlace> {
+        let pattern = Pattern::simple(e.into(), a.into(), v.into())
+                              .ok_or_else(|| ErrorKind::InvalidQuery("the value of a reversed pattern must be an entity".to_string()).into());
+        if let Some(pattern) = self.record(pattern) {
+            self.where_clauses.push(WhereClause::Pattern(pattern));
+        }
+        self
+    }
+
+    /// Add the predicate `[(operator args…)]` to `:where`.
+    pub fn where_pred<I>(mut self, operator: &str, args: I) -> Find where I: IntoIterator<Item=FnArg> {
+        self.where_clauses.push(WhereClause::Pred(Predicate {
+            operator: PlainSymbol::new(operator),
+            args: args.into_iter().collect(),
+        }));
+        self
+    }
+
+    /// Add any clause to `:where`: `or`, `not`, a function call, a rule invocation, and so on.
+    pub fn where_clause(mut self, clause: WhereClause) -> Find {
+        self.where_clauses.push(clause);
+        self
+    }
+
+    pub fn order_asc<V>(self, var: V) -> Find where V: IntoVariable {
+        self.order(Direction::Ascending, var)
+    }
+
+    pub fn order_desc<V>(self, var: V) -> Find where V: IntoVariable {
+        self.order(Direction::Descending, var)
+    }
+
+    fn order<V>(mut self, direction: Direction, var: V) -> Find where V: IntoVariable {
+        if let Some(var) = self.record(var.into_variable()) {
+            self.order.push(Order(direction, var));
+        }
+        self
+    }
+
+    /// Check the query as the parser would, and produce it.
+    pub fn build(self) -> Result<FindQuery> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        match self.find_spec {
+            FindSpec::FindRel(ref elements) |
+            FindSpec::FindTuple(ref elements) if elements.is_empty() => {
+                return invalid(":find needs at least one element");
+            },
+            _ => {},
+        }
+        if self.where_clauses.is_empty() {
+            return invalid(":where needs at least one clause");
+        }
+        for clause in self.where_clauses.iter() {
+            validate_clause(clause)?;
+        }
+
+        let in_vars = unique_vars(self.in_vars)?;
+        let with = unique_vars(self.with)?;
+        validate_paging(&in_vars, &self.limit, &self.offset)?;
+
+        Ok(FindQuery {
+            find_spec: self.find_spec,
+            default_source: SrcVar::DefaultSrc,
+            with: with,
+            in_vars: in_vars,
+            in_sources: BTreeSet::default(),
+            in_rules: self.in_rules,
+            limit: self.limit,
+            offset: self.offset,
+            where_clauses: self.where_clauses,
+            order: if self.order.is_empty() { None } else { Some(self.order) },
+        })
+    }
+}
+
+fn validate_unify_vars(unify_vars: &UnifyVars) -> Result<()> {
+    match *unify_vars {
+        UnifyVars::Explicit(ref vars) if vars.is_empty() => invalid("a join needs at least one variable"),
+        _ => Ok(()),
+    }
+}
+
+fn validate_clauses(clauses: &[WhereClause]) -> Result<()> {
+    if clauses.is_empty() {
+        return invalid("`and`, `or`, and `not` need at least one clause");
+    }
+    for clause in clauses.iter() {
+        validate_clause(clause)?;
+    }
+    Ok(())
+}
+
+/// Clauses added with `where_clause` are built by hand, so check the things the parser would
+/// have refused to produce.
+fn validate_clause(clause: &WhereClause) -> Result<()> {
+    match *clause {
+        WhereClause::Pattern(ref pattern) => validate_pattern(pattern),
+        WhereClause::OrJoin(ref or_join) => {
+            validate_unify_vars(&or_join.unify_vars)?;
+            if or_join.clauses.is_empty() {
+                return invalid("`or` needs at least one clause");
+            }
+            for arm in or_join.clauses.iter() {
+                match *arm {
+                    OrWhereClause::Clause(ref clause) => validate_clause(clause)?,
+                    OrWhereClause::And(ref clauses) => validate_clauses(clauses)?,
+                }
+            }
+            Ok(())
+        },
+        WhereClause::NotJoin(ref not_join) => {
+            validate_unify_vars(&not_join.unify_vars)?;
+            validate_clauses(&not_join.clauses)
+        },
+        WhereClause::RuleExpr(ref rule_expr) => {
+            validate_rule_name(&rule_expr.name)?;
+            Ok(())
+        },
+        WhereClause::Pred(_) |
+        WhereClause::WhereFn(_) |
+        WhereClause::TypeAnnotation(_) => Ok(()),
+    }
+}
+
+fn validate_pattern(pattern: &Pattern) -> Result<()> {
+    // `Pattern::new` turns `[?x :foo/_bar ?y]` into `[?y :foo/bar ?x]`; nothing downstream
+    // expects to see a reversed attribute.
+    if let PatternNonValuePlace::Ident(ref a) = pattern.attribute {
+        if a.is_backward() {
+            return invalid("patterns with reversed attributes must be made with `Pattern::new`");
+        }
+    }
+
+    // A placeholder is how a pattern without an `added` place is represented.
+    if pattern.added != PatternValuePlace::Placeholder {
+        validate_added_place(pattern.source.as_ref(), &pattern.added)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn;
+
+    use mentat_query::{
+        NotJoin,
+        OrJoin,
+        RuleExpr,
+    };
+
+    use mentat_query_parser;
+    use mentat_query_parser::parse_find_string;
+
+    #[test]
+    fn test_build_matches_parse() {
+        let built = Find::rel(vec![v("?e"), v("?name")])
+                        .in_var("?limit")
+                        .limit_var("?limit")
+                        .where_pattern(v("?e"), kw!(:foo/name), v("?name"))
+                        .where_pattern(v("?e"), kw!(:foo/_parent), v("?p"))
+                        .where_pattern(v("?p"), kw!(:foo/age), 42i64)
+                        .order_desc("?name")
+                        .build()
+                        .expect("built");
+        let parsed = parse_find_string(r#"[:find ?e ?name
+                                           :in ?limit
+                                           :limit ?limit
+                                           :where [?e :foo/name ?name]
+                                                  [?e :foo/_parent ?p]
+                                                  [?p :foo/age 42]
+                                           :order (desc ?name)]"#).expect("parsed");
+        assert_eq!(built, parsed);
+
+        let built = Find::coll(v("?x"))
+                        .where_pattern(v("?x"), kw!(:foo/name), "Alice")
+                        .with("?y")
+                        .offset(2)
+                        .build()
+                        .expect("built");
+        let parsed = parse_find_string(r#"[:find [?x ...] :with ?y :offset 2 :where [?x :foo/name "Alice"]]"#).expect("parsed");
+        assert_eq!(built, parsed);
+    }
+
+    fn assert_invalid(find: Find) {
+        match find.build() {
+            Err(Error(ErrorKind::InvalidQuery(_), _)) => {},
+            x => panic!("expected an invalid query, got {:?}", x),
+        }
+    }
+
+    fn assert_parse_error(find: Find, expected: mentat_query_parser::ErrorKind) {
+        match find.build() {
+            Err(Error(ErrorKind::QueryParseError(kind), _)) => assert_eq!(kind.to_string(), expected.to_string()),
+            x => panic!("expected {:?}, got {:?}", expected, x),
+        }
+    }
+
+    #[test]
+    fn test_build_validates() {
+        let pattern = |find: Find| find.where_pattern(v("?x"), kw!(:foo/bar), v("?y"));
+
+        // No :where.
+        assert_invalid(Find::scalar(v("?x")));
+
+        // No :find.
+        assert_invalid(pattern(Find::rel(Vec::<Variable>::new())));
+
+        // Not a variable.
+        assert_invalid(pattern(Find::scalar(v("?x"))).order_asc("x"));
+
+        // A reversed pattern whose value can't be an entity.
+        assert_invalid(Find::scalar(v("?x")).where_pattern(v("?x"), kw!(:foo/_bar), "nope"));
+
+        // Empty `or` and `not`.
+        assert_invalid(pattern(Find::scalar(v("?x")))
+                           .where_clause(WhereClause::OrJoin(OrJoin::new(UnifyVars::Implicit, vec![]))));
+        assert_invalid(pattern(Find::scalar(v("?x")))
+                           .where_clause(WhereClause::NotJoin(NotJoin {
+                               unify_vars: UnifyVars::Explicit(BTreeSet::new()),
+                               clauses: vec![WhereClause::Pattern(Pattern::simple(v("?x").into(), kw!(:foo/baz).into(), v("?z").into()).unwrap())],
+                           })));
+
+        assert_parse_error(pattern(Find::scalar(v("?x"))).in_var("?a").in_var("?a"),
+                           mentat_query_parser::ErrorKind::DuplicateVariableError);
+        assert_parse_error(pattern(Find::scalar(v("?x"))).limit(0),
+                           mentat_query_parser::ErrorKind::InvalidLimit(edn::Value::Integer(0)));
+        assert_parse_error(pattern(Find::scalar(v("?x"))).limit_var("?n"),
+                           mentat_query_parser::ErrorKind::UnknownLimitVar(PlainSymbol::new("?n")));
+        assert_parse_error(pattern(Find::scalar(v("?x"))).offset_var("?n"),
+                           mentat_query_parser::ErrorKind::UnknownOffsetVar(PlainSymbol::new("?n")));
+
+        // Rules can't be named like other clauses.
+        assert_parse_error(pattern(Find::scalar(v("?x")))
+                               .where_clause(WhereClause::RuleExpr(RuleExpr {
+                                   name: PlainSymbol::new("or"),
+                                   args: vec![FnArg::Variable(v("?x"))],
+                               })),
+                           mentat_query_parser::ErrorKind::InvalidRuleName(PlainSymbol::new("or")));
+
+        // An added place needs $history, and must be a boolean or a variable.
+        let added = |source: Option<SrcVar>, added: PatternValuePlace| {
+            let mut p = Pattern::simple(v("?x").into(), kw!(:foo/bar).into(), v("?y").into()).unwrap();
+            p.source = source;
+            p.added = added;
+            Find::scalar(v("?x")).where_clause(WhereClause::Pattern(p))
+        };
+        added(Some(SrcVar::history()), false.into()).build().expect("built");
+        assert_parse_error(added(None, v("?added").into()),
+                           mentat_query_parser::ErrorKind::AddedWithoutHistory);
+        assert_parse_error(added(Some(SrcVar::history()), "nope".into()),
+                           mentat_query_parser::ErrorKind::InvalidAddedPlace);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+// Transaction functions are named Rust closures, registered on a `Conn`, that a transaction can
+// invoke like `[:app/increment e a delta]`. Each invocation is expanded by the transactor, inside
+// the SQLite transaction, into the entities the closure returns. That gives atomic
+// read-modify-write without round-tripping through application code.
+//
+// Closures are handed a `TxFunctionView`, which can query the store as it stands before the
+// transaction is applied, and the invocation's arguments as EDN.
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use edn;
+
+use mentat_core::{
+    Entid,
+    NamespacedKeyword,
+    Schema,
+    TypedValue,
+};
+
+use mentat_db;
+
+pub use mentat_tx::entities::Entity;
+
+use mentat_tx_parser;
+
+use cache::{
+    AttributeCacher,
+};
+
+use conn::{
+    Queryable,
+};
+
+use errors::*;
+
+use query::{
+    FindQuery,
+    lookup_value_for_attribute,
+    lookup_values_for_attribute,
+    PreparedResult,
+    q_explain,
+    q_once,
+    q_once_query,
+    q_prepare,
+    q_prepare_query,
+    QueryExplanation,
+    QueryInputs,
+    QueryOutput,
+};
+
+/// A transaction function: given a view of the store and the arguments it was invoked with,
+/// return the entities to transact in place of the invocation.
+pub type TxFunction = Fn(&TxFunctionView, &[edn::ValueAndSpan]) -> Result<Vec<Entity>> + Send + Sync;
+
+/// A read-only view of the store from inside a transaction, as seen by transaction functions.
+///
+/// The view reflects everything committed before the transaction, together with earlier
+/// transactions in the same `InProgress`, but none of the transaction being expanded.
+pub struct TxFunctionView<'a, 'c> {
+    sqlite: &'c rusqlite::Connection,
+    schema: &'a Schema,
+    cache: &'a AttributeCacher,
+}
+
+impl<'a, 'c> TxFunctionView<'a, 'c> {
+    pub fn schema(&self) -> &Schema {
+        self.schema
+    }
+}
+
+impl<'a, 'c> Queryable for TxFunctionView<'a, 'c> {
+    fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_once_query<T>(&self, query: FindQuery, inputs: T) -> Result<QueryOutput>
+        where T: Into<Option<QueryInputs>> {
+        q_once_query(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_prepare_query<T>(&self, query: FindQuery, inputs: T) -> PreparedResult
+        where T: Into<Option<QueryInputs>> {
+        q_prepare_query(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>> {
+        q_explain(self.sqlite, self.schema, query, inputs)
+    }
+
+    fn lookup_values_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Vec<TypedValue>>
+        where E: Into<Entid> {
+        lookup_values_for_attribute(self.sqlite, self.schema, self.cache, entity, attribute)
+    }
+
+    fn lookup_value_for_attribute<E>(&self, entity: E, attribute: &edn::NamespacedKeyword) -> Result<Option<TypedValue>>
+        where E: Into<Entid> {
+        lookup_value_for_attribute(self.sqlite, self.schema, self.cache, entity, attribute)
+    }
+}
+
+/// Parse EDN like `[[:db/add 100 :foo/bar 1]]` into entities, for returning from a transaction
+/// function.
+pub fn parse_entities(transaction: &str) -> Result<Vec<Entity>> {
+    let assertion_vector = edn::parse::value(transaction)?;
+    Ok(mentat_tx_parser::Tx::parse(&assertion_vector)?)
+}
+
+/// The transaction functions registered on a `Conn`, by name.
+#[derive(Default)]
+pub struct TxFunctionRegistry {
+    functions: BTreeMap<NamespacedKeyword, Box<TxFunction>>,
+}
+
+impl TxFunctionRegistry {
+    /// Register `function` as `name`, replacing any function already registered as `name`. The
+    /// `:db` namespace is reserved for built-in operations.
+    pub fn register<F>(&mut self, name: NamespacedKeyword, function: F) -> Result<()>
+        where F: Fn(&TxFunctionView, &[edn::ValueAndSpan]) -> Result<Vec<Entity>> + Send + Sync + 'static {
+        if name.namespace == "db" {
+            bail!(ErrorKind::InvalidTxFunctionName(name.to_string()));
+        }
+        self.functions.insert(name, Box::new(function));
+        Ok(())
+    }
+
+    /// Remove the function registered as `name`, returning true if there was one.
+    pub fn unregister(&mut self, name: &NamespacedKeyword) -> bool {
+        self.functions.remove(name).is_some()
+    }
+
+    pub fn expander<'a>(&'a self, cache: &'a AttributeCacher) -> TxFunctionExpander<'a> {
+        TxFunctionExpander {
+            registry: self,
+            cache: cache,
+        }
+    }
+}
+
+/// Expands invocations for the transactor, giving each function a view of the store.
+pub struct TxFunctionExpander<'a> {
+    registry: &'a TxFunctionRegistry,
+    cache: &'a AttributeCacher,
+}
+
+impl<'a> mentat_db::TxFunctions for TxFunctionExpander<'a> {
+    fn expand(&self, sqlite: &rusqlite::Connection, schema: &Schema, f: &NamespacedKeyword, args: &[edn::ValueAndSpan]) -> mentat_db::Result<Vec<Entity>> {
+        let function = self.registry.functions.get(f)
+                           .ok_or_else(|| mentat_db::ErrorKind::UnknownTxFunction(f.to_string()))?;
+        let view = TxFunctionView {
+            sqlite: sqlite,
+            schema: schema,
+            cache: self.cache,
+        };
+        function(&view, args)
+            .map_err(|e| mentat_db::Error::with_chain(e, mentat_db::ErrorKind::TxFunctionFailed(f.to_string())))
+    }
+}
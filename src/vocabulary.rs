@@ -524,7 +524,7 @@ impl<T> HasVocabularies for T where T: HasSchema + Queryable {
                 .into_iter()
                 .filter_map(|v|
                     match (&v[0], &v[1]) {
-                        (&TypedValue::Ref(vocab), &TypedValue::Long(version))
+                        (&Binding::Scalar(TypedValue::Ref(vocab)), &Binding::Scalar(TypedValue::Long(version)))
                         if version > 0 && (version < u32::max_value() as i64) => Some((vocab, version as u32)),
                         (_, _) => None,
                     })
@@ -537,7 +537,7 @@ impl<T> HasVocabularies for T where T: HasSchema + Queryable {
                 .into_iter()
                 .filter_map(|v| {
                     match (&v[0], &v[1]) {
-                        (&TypedValue::Ref(vocab), &TypedValue::Ref(attr)) => Some((vocab, attr)),
+                        (&Binding::Scalar(TypedValue::Ref(vocab)), &Binding::Scalar(TypedValue::Ref(attr))) => Some((vocab, attr)),
                         (_, _) => None,
                     }
                     });
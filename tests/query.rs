@@ -15,13 +15,20 @@ extern crate time;
 extern crate mentat;
 extern crate mentat_core;
 extern crate mentat_db;
+extern crate mentat_query;
 extern crate mentat_query_algebrizer;       // For errors.
+extern crate mentat_query_parser;
+extern crate rusqlite;
 
+use std::rc::Rc;
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 use chrono::FixedOffset;
 
 use mentat_core::{
+    Binding,
     DateTime,
     HasSchema,
     KnownEntid,
@@ -32,17 +39,41 @@ use mentat_core::{
 };
 
 use mentat::{
+    CancellationToken,
+    IdentOrEntid,
+    LookupRef,
     NamespacedKeyword,
     PlainSymbol,
+    QueryAbortReason,
+    QueryCursor,
     QueryInputs,
+    QueryOptions,
+    QueryOutput,
+    Queryable,
     QueryResults,
+    StructuredMap,
     Variable,
     new_connection,
+    parse_rules_string,
     q_once,
 };
 
 use mentat::conn::Conn;
 
+use mentat_query::FnArg;
+
+use mentat::query_builder::{
+    Find,
+    v,
+};
+
+use mentat::query::{
+    QueryExplanation,
+    q_explain,
+};
+
+use mentat_query_algebrizer::EmptyBecause;
+
 use mentat::errors::{
     Error,
     ErrorKind,
@@ -117,7 +148,7 @@ fn test_scalar() {
 
     assert_eq!(1, results.len());
 
-    if let QueryResults::Scalar(Some(TypedValue::Keyword(ref rc))) = results {
+    if let QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Keyword(ref rc)))) = results {
         // Should be '24'.
         assert_eq!(&NamespacedKeyword::new("db.type", "keyword"), rc.as_ref());
         assert_eq!(KnownEntid(24),
@@ -151,8 +182,8 @@ fn test_tuple() {
     if let QueryResults::Tuple(Some(ref tuple)) = results {
         let cardinality_one = NamespacedKeyword::new("db.cardinality", "one");
         assert_eq!(tuple.len(), 2);
-        assert_eq!(tuple[0], TypedValue::Boolean(true));
-        assert_eq!(tuple[1], db.schema.get_entid(&cardinality_one).expect("c1").into());
+        assert_eq!(tuple[0], Binding::Scalar(TypedValue::Boolean(true)));
+        assert_eq!(tuple[1], Binding::Scalar(db.schema.get_entid(&cardinality_one).expect("c1").into()));
     } else {
         panic!("Expected tuple.");
     }
@@ -199,7 +230,7 @@ fn test_inputs() {
                         .expect("query to succeed")
                         .results;
 
-    if let QueryResults::Scalar(Some(TypedValue::Keyword(value))) = results {
+    if let QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Keyword(value)))) = results {
         assert_eq!(value.as_ref(), &NamespacedKeyword::new("db.install", "valueType"));
     } else {
         panic!("Expected scalar.");
@@ -226,6 +257,298 @@ fn test_unbound_inputs() {
     }
 }
 
+#[test]
+fn test_prepared_inputs() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let report = conn.transact(&mut c, r#"[
+        {:db/id "a" :foo/name "Alice" :foo/age 30}
+        {:db/id "b" :foo/name "Bob"   :foo/age 40}
+    ]"#).unwrap();
+    let alice = report.tempids.get("a").cloned().expect("Alice");
+    let bob = report.tempids.get("b").cloned().expect("Bob");
+
+    let name = Variable::from_valid_name("?name");
+    let age = Variable::from_valid_name("?age");
+
+    // The type of `?name` is declared; the type of `?age` is implied by `:foo/age`.
+    let mut prepared = conn.q_prepare(&c,
+                                      r#"[:find ?e . :in ?name ?age :where [?e :foo/name ?name] [?e :foo/age ?age]]"#,
+                                      QueryInputs::with_type_sequence(vec![(name.clone(), ValueType::String)]))
+                           .expect("prepared");
+
+    let run = |prepared: &mut mentat::query::PreparedQuery, n: &str, a: i64| {
+        let inputs = QueryInputs::with_value_sequence(vec![(name.clone(), TypedValue::typed_string(n)),
+                                                          (age.clone(), TypedValue::Long(a))]);
+        prepared.run(inputs).expect("results").into_scalar().expect("scalar")
+    };
+    assert_eq!(run(&mut prepared, "Alice", 30), Some(Binding::Scalar(TypedValue::Ref(alice))));
+    assert_eq!(run(&mut prepared, "Bob", 40), Some(Binding::Scalar(TypedValue::Ref(bob))));
+    assert_eq!(run(&mut prepared, "Bob", 30), None);
+
+    // Every input must be supplied…
+    match prepared.run(QueryInputs::with_value_sequence(vec![(name.clone(), TypedValue::typed_string("Alice"))])) {
+        Result::Err(Error(ErrorKind::UnboundVariables(vars), _)) => {
+            assert_eq!(vars, vec!["?age".to_string()].into_iter().collect());
+        },
+        _ => panic!("Expected unbound variables."),
+    }
+
+    // … and be of the right type.
+    match prepared.run(QueryInputs::with_value_sequence(vec![(name.clone(), TypedValue::Long(30)),
+                                                             (age.clone(), TypedValue::Long(30))])) {
+        Result::Err(Error(ErrorKind::InputTypeMismatch(var, expected, provided), _)) => {
+            assert_eq!(var, "?name");
+            assert_eq!(expected, ValueType::String);
+            assert_eq!(provided, ValueType::Long);
+        },
+        _ => panic!("Expected a type mismatch."),
+    }
+
+    // We can't prepare a query if we don't know the type of an input.
+    match conn.q_prepare(&c, r#"[:find ?e :in ?v :where [?e _ ?v]]"#, None) {
+        Result::Err(Error(ErrorKind::UntypedInput(var), _)) => {
+            assert_eq!(var, "?v");
+        },
+        _ => panic!("Expected an untyped input."),
+    }
+}
+
+#[test]
+fn test_stream() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob"   :foo/age 40}
+        {:foo/name "Carol" :foo/age 50}
+    ]"#).unwrap();
+
+    // Rows are decoded just as they are when collected, including values of unknown type.
+    let mut prepared = conn.q_prepare(&c,
+                                      r#"[:find ?name ?v :where [?e :foo/name ?name] [?e _ ?v] :order ?name ?v]"#,
+                                      None)
+                           .expect("prepared");
+    let collected = prepared.run(None).expect("results").into_rel().expect("rel");
+    let streamed = prepared.stream(None).expect("stream").collect::<Result<Vec<_>, _>>().expect("rows");
+    assert_eq!(collected.len(), 6);
+    assert_eq!(streamed, collected);
+
+    // We only read as many rows as we need.
+    let first = prepared.stream(None).expect("stream").next().expect("a row").expect("no error");
+    assert_eq!(first, vec![Binding::Scalar(TypedValue::typed_string("Alice")), Binding::Scalar(TypedValue::Long(30))]);
+
+    // Pull expressions are applied to each row.
+    let mut prepared = conn.q_prepare(&c,
+                                      r#"[:find [(pull ?e [:foo/name]) ...] :where [?e :foo/age ?age] [(> ?age 35)]]"#,
+                                      None)
+                           .expect("prepared");
+    let collected = prepared.run(None).expect("results").into_coll().expect("coll");
+    let streamed = prepared.stream(None)
+                           .expect("stream")
+                           .map(|row| row.expect("row").pop().expect("binding"))
+                           .collect::<Vec<_>>();
+    assert_eq!(collected.len(), 2);
+    assert_eq!(streamed, collected);
+
+    // A query that can't produce results produces no rows.
+    let mut prepared = conn.q_prepare(&c, r#"[:find ?e :where [?e :foo/age "old"]]"#, None).expect("prepared");
+    assert_eq!(prepared.stream(None).expect("stream").count(), 0);
+}
+
+#[test]
+fn test_query_options() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    // Quick to plan, but far too slow to finish: the bootstrap datoms joined with themselves four
+    // times over.
+    let slow = r#"[:find (count ?v) . :where [?a _ ?v] [?b _ _] [?c _ _] [?d _ _]]"#;
+    let fast = r#"[:find ?e . :where [?e :db/ident :db/ident]]"#;
+
+    // No options, no limits.
+    let r = conn.q_once_with_options(&c, fast, None, &QueryOptions::default()).expect("results");
+    assert_eq!(r.results, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Ref(1)))));
+
+    match conn.q_once_with_options(&c, slow, None, &QueryOptions::default().with_timeout(Duration::from_millis(50))) {
+        Err(Error(ErrorKind::QueryAborted(QueryAbortReason::TimedOut), _)) => {},
+        x => panic!("Got unexpected result {:?}", x),
+    }
+
+    // A query whose token is already cancelled doesn't start.
+    let token = CancellationToken::new();
+    token.cancel();
+    match conn.q_once_with_options(&c, fast, None, &QueryOptions::default().with_cancellation(token)) {
+        Err(Error(ErrorKind::QueryAborted(QueryAbortReason::Cancelled), _)) => {},
+        x => panic!("Got unexpected result {:?}", x),
+    }
+
+    // Queries can be cancelled from another thread while they run.
+    let token = CancellationToken::new();
+    let canceller = {
+        let token = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        })
+    };
+    let mut prepared = conn.q_prepare_with_options(&c, slow, None, QueryOptions::default().with_cancellation(token))
+                           .expect("prepared");
+    match prepared.run(None) {
+        Err(Error(ErrorKind::QueryAborted(QueryAbortReason::Cancelled), _)) => {},
+        x => panic!("Got unexpected result {:?}", x),
+    }
+    canceller.join().expect("cancelled");
+    drop(prepared);
+
+    // Once the query has finished, the connection is back to normal.
+    let r = conn.q_once(&c, fast, None).expect("results");
+    assert_eq!(r.results, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Ref(1)))));
+}
+
+#[test]
+fn test_offset_and_cursor() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob"   :foo/age 40}
+        {:foo/name "Carol" :foo/age 40}
+        {:foo/name "Dave"  :foo/age 50}
+        {:foo/name "Eve"   :foo/age 60}
+    ]"#).unwrap();
+
+    let names = |rows: Vec<Vec<Binding>>| -> Vec<Binding> {
+        rows.into_iter().map(|mut row| row.remove(1)).collect()
+    };
+    let name = |n: &str| Binding::Scalar(TypedValue::typed_string(n));
+
+    // Fixed offsets.
+    let query = r#"[:find ?age ?name :where [?e :foo/age ?age] [?e :foo/name ?name] :order ?age ?name :limit 2 :offset 2]"#;
+    let rows = conn.q_once(&c, query, None).expect("results").into_rel().expect("rel");
+    assert_eq!(names(rows), vec![name("Carol"), name("Dave")]);
+
+    // Variable offsets, supplied each time a prepared query is run.
+    let query = r#"[:find ?age ?name :in ?skip :where [?e :foo/age ?age] [?e :foo/name ?name] :order ?age ?name :offset ?skip]"#;
+    let inputs = QueryInputs::with_type_sequence(vec![(Variable::from_valid_name("?skip"), ValueType::Long)]);
+    let mut prepared = conn.q_prepare(&c, query, inputs).expect("prepared");
+    let skip = |n| QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?skip"), TypedValue::Long(n))]);
+    let rows = prepared.run(skip(4)).expect("results").into_rel().expect("rel");
+    assert_eq!(names(rows), vec![name("Eve")]);
+    let rows = prepared.run(skip(0)).expect("results").into_rel().expect("rel");
+    assert_eq!(rows.len(), 5);
+    drop(prepared);
+
+    // Keyset pagination picks up after the last row, even when it ties on the first ordering.
+    let query = r#"[:find ?age ?name :where [?e :foo/age ?age] [?e :foo/name ?name] :order (desc ?age) ?name :limit 2]"#;
+    let page = conn.q_once(&c, query, None).expect("results");
+    let cursor = QueryCursor::after_last(&page).expect("a cursor");
+    assert_eq!(names(page.into_rel().expect("rel")), vec![name("Eve"), name("Dave")]);
+
+    let page = conn.q_once_after(&c, query, None, &cursor).expect("results");
+    let cursor = QueryCursor::after_last(&page).expect("a cursor");
+    assert_eq!(names(page.into_rel().expect("rel")), vec![name("Bob"), name("Carol")]);
+
+    let page = conn.q_once_after(&c, query, None, &cursor).expect("results");
+    let cursor = QueryCursor::after_last(&page).expect("a cursor");
+    assert_eq!(names(page.into_rel().expect("rel")), vec![name("Alice")]);
+
+    let page = conn.q_once_after(&c, query, None, &cursor).expect("results");
+    assert_eq!(QueryCursor::after_last(&page), None);
+
+    // The cursor must have a value for each ordering variable.
+    let query = r#"[:find ?name :where [?e :foo/age ?age] [?e :foo/name ?name] :order ?age]"#;
+    let cursor = QueryCursor::after(vec![(Variable::from_valid_name("?name"), TypedValue::typed_string("Bob"))]);
+    match conn.q_once_after(&c, query, None, &cursor) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::MissingCursorValue(_)), _)) => {},
+        x => panic!("Got unexpected result {:?}", x),
+    }
+}
+
+#[test]
+fn test_lookup_refs() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/email :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+        {:db/ident :foo/name  :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/email "alice@example.com" :foo/name "Alice"}
+        {:foo/email "bob@example.com" :foo/name "Bob"}
+    ]"#).unwrap();
+
+    let name = |n: &str| Binding::Scalar(TypedValue::typed_string(n));
+
+    // In the entity place of a pattern.
+    let query = r#"[:find ?name . :where [[:foo/email "alice@example.com"] :foo/name ?name]]"#;
+    let result = conn.q_once(&c, query, None).expect("results").into_scalar().expect("scalar");
+    assert_eq!(result, Some(name("Alice")));
+
+    // Inside an `or`, an arm whose lookup ref names no entity simply doesn't match.
+    let query = r#"[:find [?name ...]
+                    :where (or [[:foo/email "bob@example.com"] :foo/name ?name]
+                               [[:foo/email "nobody@example.com"] :foo/name ?name])]"#;
+    let result = conn.q_once(&c, query, None).expect("results").into_coll().expect("coll");
+    assert_eq!(result, vec![name("Bob")]);
+
+    // A lookup ref that names no entity makes the query known-empty.
+    let query = r#"[:find ?name . :where [[:foo/email "nobody@example.com"] :foo/name ?name]]"#;
+    match q_explain(&c, &*conn.current_schema(), query, None).expect("explained") {
+        QueryExplanation::KnownEmpty(EmptyBecause::UnresolvedLookupRef(_)) => {},
+        _ => panic!("Expected a known-empty query"),
+    }
+
+    // Lookup refs need a unique attribute.
+    let query = r#"[:find ?e . :where [[:foo/name "Alice"] :foo/email ?e]]"#;
+    match q_explain(&c, &*conn.current_schema(), query, None).expect("explained") {
+        QueryExplanation::KnownEmpty(EmptyBecause::NonUniqueLookupRefAttribute(_)) => {},
+        _ => panic!("Expected a known-empty query"),
+    }
+
+    // Bound to an input.
+    let email = |e: &str| LookupRef {
+        attribute: IdentOrEntid::Ident(Rc::new(NamespacedKeyword::new("foo", "email"))),
+        value: TypedValue::typed_string(e),
+    };
+    let query = r#"[:find ?name . :in ?e :where [?e :foo/name ?name]]"#;
+    let inputs = QueryInputs::default().with_lookup_ref(Variable::from_valid_name("?e"), email("bob@example.com"));
+    let result = conn.q_once(&c, query, inputs).expect("results").into_scalar().expect("scalar");
+    assert_eq!(result, Some(name("Bob")));
+
+    let inputs = QueryInputs::default().with_lookup_ref(Variable::from_valid_name("?e"), email("nobody@example.com"));
+    let result = conn.q_once(&c, query, inputs).expect("results").into_scalar().expect("scalar");
+    assert_eq!(result, None);
+
+    // Plans that resolve lookup refs aren't reused, so they see later changes to the store.
+    let query = r#"[:find ?name . :where [[:foo/email "carol@example.com"] :foo/name ?name]]"#;
+    let result = conn.q_once(&c, query, None).expect("results").into_scalar().expect("scalar");
+    assert_eq!(result, None);
+    conn.transact(&mut c, r#"[{:foo/email "carol@example.com" :foo/name "Carol"}]"#).unwrap();
+    let result = conn.q_once(&c, query, None).expect("results").into_scalar().expect("scalar");
+    assert_eq!(result, Some(name("Carol")));
+}
+
 #[test]
 fn test_instants_and_uuids() {
     // We assume, perhaps foolishly, that the clocks on test machines won't lose more than an
@@ -252,9 +575,9 @@ fn test_instants_and_uuids() {
         QueryResults::Tuple(Some(vals)) => {
             let mut vals = vals.into_iter();
             match (vals.next(), vals.next(), vals.next(), vals.next()) {
-                (Some(TypedValue::Ref(e)),
-                 Some(TypedValue::Uuid(u)),
-                 Some(TypedValue::Instant(t)),
+                (Some(Binding::Scalar(TypedValue::Ref(e))),
+                 Some(Binding::Scalar(TypedValue::Uuid(u))),
+                 Some(Binding::Scalar(TypedValue::Instant(t))),
                  None) => {
                      assert!(e > 40);       // There are at least this many entities in the store.
                      assert_eq!(Ok(u), Uuid::from_str("cf62d552-6569-4d1b-b667-04703041dfc4"));
@@ -293,7 +616,7 @@ fn test_tx() {
     match r {
         QueryResults::Rel(ref v) => {
             assert_eq!(*v, vec![
-                vec![TypedValue::Ref(t.tx_id),]
+                vec![Binding::Scalar(TypedValue::Ref(t.tx_id)),]
             ]);
         },
         _ => panic!("Expected query to work."),
@@ -330,7 +653,7 @@ fn test_tx_as_input() {
     match r {
         QueryResults::Rel(ref v) => {
             assert_eq!(*v, vec![
-                vec![TypedValue::Uuid(Uuid::from_str("cf62d552-6569-4d1b-b667-04703041dfc4").expect("Valid UUID")),]
+                vec![Binding::Scalar(TypedValue::Uuid(Uuid::from_str("cf62d552-6569-4d1b-b667-04703041dfc4").expect("Valid UUID"))),]
             ]);
         },
         _ => panic!("Expected query to work."),
@@ -369,9 +692,9 @@ fn test_fulltext() {
         QueryResults::Tuple(Some(vals)) => {
             let mut vals = vals.into_iter();
             match (vals.next(), vals.next(), vals.next(), vals.next()) {
-                (Some(TypedValue::Ref(x)),
-                 Some(TypedValue::String(text)),
-                 Some(TypedValue::Double(score)),
+                (Some(Binding::Scalar(TypedValue::Ref(x))),
+                 Some(Binding::Scalar(TypedValue::String(text))),
+                 Some(Binding::Scalar(TypedValue::Double(score))),
                  None) => {
                      assert_eq!(x, v);
                      assert_eq!(text.as_str(), "hello darkness my old friend");
@@ -433,8 +756,8 @@ fn test_fulltext() {
     match r {
         QueryResults::Rel(rels) => {
             assert_eq!(rels, vec![
-                vec![TypedValue::Ref(v),
-                     TypedValue::String("I've come to talk with you again".to_string().into()),
+                vec![Binding::Scalar(TypedValue::Ref(v)),
+                     Binding::Scalar(TypedValue::String("I've come to talk with you again".to_string().into())),
                 ]
             ]);
         },
@@ -471,8 +794,8 @@ fn test_instant_range_query() {
     match r {
         QueryResults::Coll(vals) => {
             assert_eq!(vals,
-                       vec![TypedValue::Ref(*ids.get("b").unwrap()),
-                            TypedValue::Ref(*ids.get("c").unwrap())]);
+                       vec![Binding::Scalar(TypedValue::Ref(*ids.get("b").unwrap())),
+                            Binding::Scalar(TypedValue::Ref(*ids.get("c").unwrap()))]);
         },
         _ => panic!("Expected query to work."),
     }
@@ -557,7 +880,7 @@ fn test_type_reqs() {
 
     let entid = match res {
         QueryResults::Rel(ref vs) if vs.len() == 1 && vs[0].len() == 1 && vs[0][0].matches_type(ValueType::Ref) =>
-            if let TypedValue::Ref(eid) = vs[0][0] {
+            if let Binding::Scalar(TypedValue::Ref(eid)) = vs[0][0] {
                 eid
             } else {
                 // Already checked this.
@@ -613,10 +936,971 @@ fn test_type_reqs() {
                   .into();
     match res {
         QueryResults::Coll(vals) => {
-            assert_eq!(vals, vec![TypedValue::Long(5), TypedValue::Long(33)])
+            assert_eq!(vals, vec![Binding::Scalar(TypedValue::Long(5)), Binding::Scalar(TypedValue::Long(33))])
         },
         v => {
             panic!("Query returned unexpected type: {:?}", v);
         }
     };
 }
+
+#[test]
+fn test_aggregates() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob"   :foo/age 30}
+        {:foo/name "Carol" :foo/age 40}
+    ]"#).unwrap();
+
+    // Without :with, we aggregate over the set of ages.
+    let r = conn.q_once(&mut c, "[:find (sum ?age) . :where [_ :foo/age ?age]]", None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Long(70)))));
+
+    // With :with, we aggregate over each person's age.
+    let r = conn.q_once(&mut c, "[:find (sum ?age) . :with ?p :where [?p :foo/age ?age]]", None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Long(100)))));
+
+    let r = conn.q_once(&mut c, "[:find [(avg ?age) (min ?name) (count ?p)] :with ?p :where [?p :foo/age ?age] [?p :foo/name ?name]]", None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Tuple(Some(vec![Binding::Scalar(TypedValue::Double((100.0 / 3.0).into())),
+                                                Binding::Scalar(TypedValue::typed_string("Alice")),
+                                                Binding::Scalar(TypedValue::Long(3))])));
+
+    // Grouping.
+    let r = conn.q_once(&mut c, "[:find ?age (count ?p) :order ?age :where [?p :foo/age ?age]]", None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![vec![Binding::Scalar(TypedValue::Long(30)), Binding::Scalar(TypedValue::Long(2))],
+                                         vec![Binding::Scalar(TypedValue::Long(40)), Binding::Scalar(TypedValue::Long(1))]]));
+
+    // Aggregating nothing yields nothing.
+    let r = conn.q_once(&mut c, r#"[:find (max ?age) . :where [?p :foo/age ?age] [?p :foo/name "Dave"]]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Scalar(None));
+}
+
+fn pulled(pairs: Vec<(NamespacedKeyword, Binding)>) -> Binding {
+    Binding::Map(Rc::new(StructuredMap(pairs.into_iter().collect())))
+}
+
+#[test]
+fn test_pull() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name    :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/child   :db/valueType :db.type/ref    :db/cardinality :db.cardinality/many}
+        {:db/ident :foo/address :db/valueType :db.type/ref    :db/cardinality :db.cardinality/one
+         :db/isComponent true}
+        {:db/ident :bar/x       :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/child "b"]
+        [:db/add "a" :foo/child "c"]
+        [:db/add "a" :foo/address "p"]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "b" :bar/x 1]
+        [:db/add "c" :foo/name "Carol"]
+        [:db/add "c" :bar/x 2]
+        [:db/add "p" :bar/x 99]
+    ]"#).unwrap().tempids;
+    let a = *ids.get("a").unwrap();
+    let b = *ids.get("b").unwrap();
+    let c_ = *ids.get("c").unwrap();
+    let p = *ids.get("p").unwrap();
+
+    let id = |e| (kw!(:db/id), Binding::Scalar(TypedValue::Ref(e)));
+    let name = |s: &str| (kw!(:foo/name), Binding::Scalar(TypedValue::typed_string(s)));
+    let x = |n: i64| (kw!(:bar/x), Binding::Scalar(TypedValue::Long(n)));
+
+    // Nested patterns follow refs.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [(pull ?e [:foo/name {:foo/child [:bar/x]}]) ...]
+                            :where [?e :foo/name "Alice"]]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![
+        pulled(vec![name("Alice"),
+                    (kw!(:foo/child), vec![pulled(vec![x(1)]), pulled(vec![x(2)])].into())]),
+    ]));
+
+    // Reverse attributes find the entities that refer to this one.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name (pull ?e [:db/id :foo/_child])
+                            :order ?name
+                            :where [?e :bar/x _] [?e :foo/name ?name]]"#, None)
+                .expect("results")
+                .into();
+    let parents: Binding = vec![pulled(vec![id(a)])].into();
+    assert_eq!(r, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::typed_string("Bob")),
+             pulled(vec![id(b), (kw!(:foo/_child), parents.clone())])],
+        vec![Binding::Scalar(TypedValue::typed_string("Carol")),
+             pulled(vec![id(c_), (kw!(:foo/_child), parents.clone())])],
+    ]));
+
+    // The wildcard pulls every attribute, and components are pulled whole.
+    let r = conn.q_once(&mut c,
+                        r#"[:find (pull ?e [*]) . :where [?e :foo/name "Alice"]]"#, None)
+                .expect("results")
+                .into();
+    let mut children = vec![pulled(vec![id(b)]), pulled(vec![id(c_)])];
+    children.sort();
+    assert_eq!(r, QueryResults::Scalar(Some(
+        pulled(vec![id(a),
+                    name("Alice"),
+                    (kw!(:foo/child), children.into()),
+                    (kw!(:foo/address), pulled(vec![id(p), x(99)]))]))));
+
+    // Pulling an unknown attribute is an error.
+    match conn.q_once(&mut c, r#"[:find (pull ?e [:foo/nope]) . :where [?e :foo/name "Alice"]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::UnknownPullAttribute(_)), _)) => {},
+        x => panic!("Expected query to fail, got {:?}.", x),
+    }
+}
+
+#[test]
+fn test_project_idents() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name  :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/color :db/valueType :db.type/ref    :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/likes :db/valueType :db.type/ref    :db/cardinality :db.cardinality/one}
+        {:db/ident :color/red}
+        {:db/ident :color/blue}
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        {:db/id "a" :foo/name "Alice" :foo/color :color/red}
+        {:db/id "b" :foo/name "Bob" :foo/color :color/blue :foo/likes "a"}
+    ]"#).unwrap().tempids;
+    let a = *ids.get("a").unwrap();
+
+    let keyword = |ns: &str, name: &str| Binding::Scalar(TypedValue::Keyword(Rc::new(NamespacedKeyword::new(ns, name))));
+    let name = |n: &str| Binding::Scalar(TypedValue::typed_string(n));
+
+    // Without `ident`, we get the entity.
+    let r = conn.q_once(&mut c, r#"[:find ?c . :where [?e :foo/name "Alice"] [?e :foo/color ?c]]"#, None)
+                .expect("results")
+                .into_scalar()
+                .expect("scalar");
+    match r {
+        Some(Binding::Scalar(TypedValue::Ref(_))) => {},
+        x => panic!("Expected an entity, got {:?}", x),
+    }
+
+    let r = conn.q_once(&mut c, r#"[:find ?name (ident ?c) :where [?e :foo/name ?name] [?e :foo/color ?c] :order ?name]"#, None)
+                .expect("results")
+                .into_rel()
+                .expect("rel");
+    assert_eq!(r, vec![vec![name("Alice"), keyword("color", "red")],
+                       vec![name("Bob"), keyword("color", "blue")]]);
+
+    // Entities without an ident are left alone.
+    let r = conn.q_once(&mut c, r#"[:find (ident ?x) . :where [_ :foo/likes ?x]]"#, None)
+                .expect("results")
+                .into_scalar()
+                .expect("scalar");
+    assert_eq!(r, Some(Binding::Scalar(TypedValue::Ref(a))));
+
+    // Pull renders refs to entities with idents as the ident.
+    let r = conn.q_once(&mut c, r#"[:find (pull ?e [:foo/name :foo/color :foo/likes]) . :where [?e :foo/name "Bob"]]"#, None)
+                .expect("results")
+                .into_scalar()
+                .expect("scalar");
+    assert_eq!(r, Some(pulled(vec![(kw!(:foo/name), name("Bob")),
+                                   (kw!(:foo/color), keyword("color", "blue")),
+                                   (kw!(:foo/likes), pulled(vec![(kw!(:db/id), Binding::Scalar(TypedValue::Ref(a)))]))])));
+}
+
+#[test]
+fn test_query_builder() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob" :foo/age 25}
+        {:foo/name "Carol" :foo/age 40}
+    ]"#).unwrap();
+
+    let query = Find::coll(v("?name"))
+                    .where_pattern(v("?e"), kw!(:foo/name), v("?name"))
+                    .where_pattern(v("?e"), kw!(:foo/age), v("?age"))
+                    .in_var("?min")
+                    .where_pred(">", vec![FnArg::Variable(v("?age")), FnArg::Variable(v("?min"))])
+                    .order_desc("?name")
+                    .build()
+                    .expect("built");
+    let inputs = QueryInputs::with_value_sequence(vec![(v("?min"), TypedValue::Long(26))]);
+    let r = conn.q_once_query(&c, query, inputs)
+                .expect("results")
+                .into_coll()
+                .expect("coll");
+    assert_eq!(r, vec![Binding::Scalar(TypedValue::typed_string("Carol")),
+                       Binding::Scalar(TypedValue::typed_string("Alice"))]);
+
+    // The same checks as the parser.
+    match Find::scalar(v("?e")).where_pattern(v("?e"), kw!(:foo/age), 30i64).limit_var("?n").build() {
+        Err(Error(ErrorKind::QueryParseError(mentat_query_parser::ErrorKind::UnknownLimitVar(_)), _)) => {},
+        x => panic!("Expected an unknown limit var, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_rules() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name     :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/nickname :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/parent   :db/valueType :db.type/ref    :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/nickname "Ali"]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "b" :foo/parent "a"]
+        [:db/add "c" :foo/name "Carol"]
+        [:db/add "c" :foo/parent "b"]
+    ]"#).unwrap().tempids;
+    let a = *ids.get("a").unwrap();
+
+    let rules = parse_rules_string(r#"[
+        [(grandparent ?x ?y) [?x :foo/parent ?z] [?z :foo/parent ?y]]
+        [(called ?x ?n) [?x :foo/name ?n]]
+        [(called ?x ?n) [?x :foo/nickname ?n]]
+        [(related ?x ?y) [?x :foo/parent ?y]]
+        [(related ?x ?y) [?y :foo/parent ?x]]
+        [(related ?x ?y) (grandparent ?x ?y)]
+    ]"#).expect("parsed rules");
+    let inputs = || QueryInputs::with_rules(rules.clone());
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name .
+                            :in %
+                            :where [?c :foo/name "Carol"] (grandparent ?c ?g) [?g :foo/name ?name]]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::typed_string("Alice")))));
+
+    // Either definition of a rule can match, and arguments can be constants.
+    let r = conn.q_once(&mut c, r#"[:find ?x . :in % :where (called ?x "Ali")]"#, inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Ref(a)))));
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?n :in % :where [?x :foo/name "Alice"] (called ?x ?n) :order ?n]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::typed_string("Ali"))],
+        vec![Binding::Scalar(TypedValue::typed_string("Alice"))],
+    ]));
+
+    // A rule can be invoked with only constants, to check whether any of its definitions match.
+    {
+        let names_if_called = |name: &str| {
+            let query = format!(r#"[:find [?name ...] :in % :where [_ :foo/name ?name] (called {} "{}")]"#, a, name);
+            let results: QueryResults = conn.q_once(&c, query.as_str(), inputs()).expect("results").into();
+            match results {
+                QueryResults::Coll(names) => names.len(),
+                r => panic!("Expected a collection, got {:?}.", r),
+            }
+        };
+        assert_eq!(names_if_called("Alice"), 3);
+        assert_eq!(names_if_called("Ali"), 3);
+        assert_eq!(names_if_called("Bob"), 0);
+    }
+
+    // Variables that appear only inside a rule don't unify with anything outside it.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name
+                            :in %
+                            :where [?z :foo/name "Bob"] (related ?z ?y) [?y :foo/name ?name]
+                            :order ?name]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::typed_string("Alice"))],
+        vec![Binding::Scalar(TypedValue::typed_string("Carol"))],
+    ]));
+
+    // Rules are only available to queries that ask for them.
+    match conn.q_once(&mut c, r#"[:find ?x . :where (called ?x "Ali")]"#, inputs()) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::UnknownRule(_)), _)) => {},
+        x => panic!("Expected query to fail, got {:?}.", x),
+    }
+}
+
+#[test]
+fn test_recursive_rules() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :folder/name   :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :folder/parent :db/valueType :db.type/ref    :db/cardinality :db.cardinality/one}
+        {:db/ident :page/url      :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :page/link     :db/valueType :db.type/ref    :db/cardinality :db.cardinality/many}
+    ]"#).unwrap();
+
+    // A folder hierarchy, and a link graph with a cycle: a -> b -> c -> a, c -> d.
+    conn.transact(&mut c, r#"[
+        [:db/add "root" :folder/name "root"]
+        [:db/add "docs" :folder/name "docs"]
+        [:db/add "docs" :folder/parent "root"]
+        [:db/add "work" :folder/name "work"]
+        [:db/add "work" :folder/parent "docs"]
+        [:db/add "taxes" :folder/name "taxes"]
+        [:db/add "taxes" :folder/parent "work"]
+        [:db/add "music" :folder/name "music"]
+        [:db/add "music" :folder/parent "root"]
+        [:db/add "a" :page/url "a"]
+        [:db/add "b" :page/url "b"]
+        [:db/add "c" :page/url "c"]
+        [:db/add "d" :page/url "d"]
+        [:db/add "e" :page/url "e"]
+        [:db/add "a" :page/link "b"]
+        [:db/add "b" :page/link "c"]
+        [:db/add "c" :page/link "a"]
+        [:db/add "c" :page/link "d"]
+    ]"#).unwrap();
+
+    let rules = parse_rules_string(r#"[
+        [(ancestor ?f ?a) [?f :folder/parent ?a]]
+        [(ancestor ?f ?a) [?f :folder/parent ?p] (ancestor ?p ?a)]
+        [(reachable ?x ?y) [?x :page/link ?y]]
+        [(reachable ?x ?y) (reachable ?x ?z) [?z :page/link ?y]]
+    ]"#).expect("parsed rules");
+    let inputs = || QueryInputs::with_rules(rules.clone());
+    let strings = |names: Vec<&str>| -> QueryResults {
+        QueryResults::Coll(names.into_iter().map(|n| Binding::Scalar(TypedValue::typed_string(n))).collect())
+    };
+
+    // Every ancestor of a folder, however deep.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?name ...]
+                            :in %
+                            :where [?f :folder/name "taxes"] (ancestor ?f ?a) [?a :folder/name ?name]
+                            :order ?name]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, strings(vec!["docs", "root", "work"]));
+
+    // And every descendant.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?name ...]
+                            :in %
+                            :where [?a :folder/name "docs"] (ancestor ?f ?a) [?f :folder/name ?name]
+                            :order ?name]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, strings(vec!["taxes", "work"]));
+
+    // Cycles terminate, and a page in a cycle can reach itself.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?url ...]
+                            :in %
+                            :where [?a :page/url "a"] (reachable ?a ?p) [?p :page/url ?url]
+                            :order ?url]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, strings(vec!["a", "b", "c", "d"]));
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?url ...]
+                            :in %
+                            :where [?e :page/url "e"] (reachable ?p ?e) [?p :page/url ?url]]"#,
+                        inputs())
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![]));
+
+    // Only direct, linear recursion can be compiled.
+    let rules = parse_rules_string(r#"[
+        [(connected ?x ?y) [?x :page/link ?y]]
+        [(connected ?x ?y) (connected ?x ?z) (connected ?z ?y)]
+    ]"#).expect("parsed rules");
+    match conn.q_once(&mut c, r#"[:find ?x :in % :where (connected ?x ?y)]"#, QueryInputs::with_rules(rules)) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::NonLinearRecursiveRule(_)), _)) => {},
+        x => panic!("Expected query to fail, got {:?}.", x),
+    }
+}
+
+#[test]
+fn test_get_else_and_missing() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "Bob"}
+    ]"#).unwrap();
+
+    // Bob has no age, so he gets the default.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name ?age
+                            :where [?p :foo/name ?name] [(get-else $ ?p :foo/age -1) ?age]
+                            :order ?name]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![vec![Binding::Scalar(TypedValue::typed_string("Alice")), Binding::Scalar(TypedValue::Long(30))],
+                                         vec![Binding::Scalar(TypedValue::typed_string("Bob")), Binding::Scalar(TypedValue::Long(-1))]]));
+
+    // The default can be constrained like any other value.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?name ...]
+                            :where [?p :foo/name ?name] [(get-else $ ?p :foo/age 0) ?age] [(< ?age 18)]]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::typed_string("Bob"))]));
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?name ...]
+                            :where [?p :foo/name ?name] [(missing? $ ?p :foo/age)]]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::typed_string("Bob"))]));
+
+    // The default must agree with the attribute.
+    match conn.q_once(&mut c, r#"[:find ?age :where [?p :foo/name _] [(get-else $ ?p :foo/age "none") ?age]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidDefault(_, ValueType::Long)), _)) => {},
+        x => panic!("Expected query to fail, got {:?}.", x),
+    }
+}
+
+#[test]
+fn test_string_predicates_and_functions() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30}
+        {:foo/name "alan"  :foo/age 25}
+        {:foo/name "Bob"   :foo/age 40}
+    ]"#).unwrap();
+
+    {
+        let names = |predicate: &str| {
+            let query = format!("[:find [?name ...] :where [_ :foo/name ?name] {} :order ?name]", predicate);
+            let results: QueryResults = conn.q_once(&c, query.as_str(), None).expect("results").into();
+            match results {
+                QueryResults::Coll(names) => names.into_iter().map(|name| match name {
+                    Binding::Scalar(TypedValue::String(s)) => (*s).clone(),
+                    _ => panic!("Expected a string."),
+                }).collect::<Vec<String>>(),
+                _ => panic!("Expected a collection."),
+            }
+        };
+
+        assert_eq!(names(r#"[(starts-with? ?name "Al")]"#), vec!["Alice"]);
+        assert_eq!(names(r#"[(ends-with? ?name "b")]"#), vec!["Bob"]);
+        assert_eq!(names(r#"[(includes? ?name "l")]"#), vec!["Alice", "alan"]);
+        assert_eq!(names(r#"[(equals-ignore-case? ?name "BOB")]"#), vec!["Bob"]);
+        assert_eq!(names(r#"[(re-matches? "[Aa]l.n" ?name)]"#), vec!["alan"]);
+        assert_eq!(names(r#"[(re-matches? "[Aa]l" ?name)]"#), Vec::<String>::new());
+        // Backslashes that aren't string escapes reach the regex unchanged.
+        assert_eq!(names(r#"[(re-matches? "\w{3,4}" ?name)]"#), vec!["Bob", "alan"]);
+    }
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?upper ?initial ?length ?label
+                            :where [?p :foo/name ?name]
+                                   [?p :foo/age ?age]
+                                   [(upper-case ?name) ?upper]
+                                   [(subs ?name 0 1) ?initial]
+                                   [(count ?name) ?length]
+                                   [(str ?name " is " ?age) ?label]
+                                   [(< ?length 4)]]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![vec![Binding::Scalar(TypedValue::typed_string("BOB")),
+                                              Binding::Scalar(TypedValue::typed_string("B")),
+                                              Binding::Scalar(TypedValue::Long(3)),
+                                              Binding::Scalar(TypedValue::typed_string("Bob is 40"))]]));
+
+    // Strings only.
+    match conn.q_once(&mut c, r#"[:find ?p :where [?p :foo/age ?age] [(includes? ?age 4)]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidArgument(_, "string", 1)), _)) => {},
+        x => panic!("Expected query to fail, got {:?}.", x),
+    }
+}
+
+#[test]
+fn test_regex_on_caller_opened_connection() {
+    // Not opened with `new_connection`, so nothing has registered our SQL functions yet.
+    let mut c = rusqlite::Connection::open_in_memory().expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+    conn.transact(&mut c, r#"[{:foo/name "Alice"} {:foo/name "Bob"}]"#).unwrap();
+
+    let r = conn.q_once(&c, r#"[:find [?name ...] :where [_ :foo/name ?name] [(re-matches? "^A" ?name)]]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::typed_string("Alice"))]));
+}
+
+#[test]
+fn test_prepared_function_arguments() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice"}
+        {:foo/name "alan"}
+        {:foo/name "Bob"}
+    ]"#).unwrap();
+
+    let strings = |output: QueryOutput| -> Vec<String> {
+        let results: QueryResults = output.into();
+        match results {
+            QueryResults::Coll(values) => values.into_iter().map(|value| match value {
+                Binding::Scalar(TypedValue::String(s)) => (*s).clone(),
+                _ => panic!("Expected a string."),
+            }).collect(),
+            _ => panic!("Expected a collection."),
+        }
+    };
+
+    // Inputs that are only used as function arguments are bound when the query is run.
+    let prefix = Variable::from_valid_name("?prefix");
+    let mut prepared = conn.q_prepare(&c,
+                                      r#"[:find [?name ...] :in ?prefix :where [_ :foo/name ?name] [(starts-with? ?name ?prefix)] :order ?name]"#,
+                                      QueryInputs::with_type_sequence(vec![(prefix.clone(), ValueType::String)]))
+                           .expect("prepared");
+    let mut run = |p: &str| strings(prepared.run(QueryInputs::with_value_sequence(vec![(prefix.clone(), TypedValue::typed_string(p))])).expect("results"));
+    assert_eq!(run("Al"), vec!["Alice"]);
+    assert_eq!(run("B"), vec!["Bob"]);
+    assert_eq!(run("Z"), Vec::<String>::new());
+
+    let length = Variable::from_valid_name("?length");
+    let mut prepared = conn.q_prepare(&c,
+                                      r#"[:find [?initial ...] :in ?length :where [_ :foo/name ?name] [(subs ?name 0 ?length) ?initial] :order ?initial]"#,
+                                      QueryInputs::with_type_sequence(vec![(length.clone(), ValueType::Long)]))
+                           .expect("prepared");
+    let mut run = |n: i64| strings(prepared.run(QueryInputs::with_value_sequence(vec![(length.clone(), TypedValue::Long(n))])).expect("results"));
+    assert_eq!(run(1), vec!["A", "B", "a"]);
+    assert_eq!(run(2), vec!["Al", "Bo", "al"]);
+}
+
+#[test]
+fn test_arithmetic() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string  :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long    :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/born :db/valueType :db.type/instant :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/name "Alice" :foo/age 30 :foo/born #inst "2017-01-01T00:00:00.000Z"}
+        {:foo/name "Bob"   :foo/age 0  :foo/born #inst "2017-01-02T00:00:00.000Z"}
+    ]"#).unwrap();
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name ?older ?half ?scaled
+                            :where [?p :foo/name ?name]
+                                   [?p :foo/age ?age]
+                                   [(+ ?age 1) ?older]
+                                   [(/ ?age 2) ?half]
+                                   [(* ?age 0.5) ?scaled]
+                            :order ?name]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![vec![Binding::Scalar(TypedValue::typed_string("Alice")),
+                                              Binding::Scalar(TypedValue::Long(31)),
+                                              Binding::Scalar(TypedValue::Long(15)),
+                                              Binding::Scalar(TypedValue::Double(15.0.into()))],
+                                         vec![Binding::Scalar(TypedValue::typed_string("Bob")),
+                                              Binding::Scalar(TypedValue::Long(1)),
+                                              Binding::Scalar(TypedValue::Long(0)),
+                                              Binding::Scalar(TypedValue::Double(0.0.into()))]]));
+
+    // Dividing by zero produces no result rather than NULL.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?name ...]
+                            :where [?p :foo/name ?name]
+                                   [?p :foo/age ?age]
+                                   [(/ 60 ?age) ?ratio]]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::typed_string("Alice"))]));
+
+    // Subtracting instants produces a duration in microseconds.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?delta .
+                            :where [?a :foo/name "Alice"]
+                                   [?b :foo/name "Bob"]
+                                   [?a :foo/born ?x]
+                                   [?b :foo/born ?y]
+                                   [(- ?y ?x) ?delta]]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Long(24 * 60 * 60 * 1_000_000)))));
+
+    // Numbers only.
+    match conn.q_once(&mut c, r#"[:find ?x :where [?p :foo/name ?name] [(+ ?name 1) ?x]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidArgument(_, "numeric", 0)), _)) => {},
+        x => panic!("Expected query to fail, got {:?}.", x),
+    }
+}
+
+#[test]
+fn test_instant_functions() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :visit/at :db/valueType :db.type/instant :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:visit/at #inst "2018-01-01T09:00:00.000Z"}
+        {:visit/at #inst "2018-01-01T17:30:00.000Z"}
+        {:visit/at #inst "2018-01-03T12:00:00.000Z"}
+        {:visit/at #inst "2018-02-14T12:00:00.000Z"}
+        {:visit/at #inst "1969-12-31T23:00:00.000Z"}
+    ]"#).unwrap();
+
+    let instant = |s: &str| TypedValue::Instant(DateTime::<Utc>::from_str(s).unwrap());
+
+    // Visits per day, oldest first. Instants before the epoch round down, too.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?day (count ?v)
+                            :where [?v :visit/at ?t]
+                                   [(truncate-instant ?t :day) ?day]
+                            :order ?day
+                            :limit 3]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![vec![Binding::Scalar(instant("1969-12-31T00:00:00.000Z")),
+                                              Binding::Scalar(TypedValue::Long(1))],
+                                         vec![Binding::Scalar(instant("2018-01-01T00:00:00.000Z")),
+                                              Binding::Scalar(TypedValue::Long(2))],
+                                         vec![Binding::Scalar(instant("2018-01-03T00:00:00.000Z")),
+                                              Binding::Scalar(TypedValue::Long(1))]]));
+
+    // Weeks start on Monday; 2018-01-01 was a Monday.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?week ...]
+                            :where [_ :visit/at ?t]
+                                   [(truncate-instant ?t :week) ?week]
+                                   [(instant-year ?t) ?year]
+                                   [(>= ?year 2018)]
+                            :order (desc ?week)]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(instant("2018-02-12T00:00:00.000Z")),
+                                          Binding::Scalar(instant("2018-01-01T00:00:00.000Z"))]));
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?month ...]
+                            :where [_ :visit/at ?t]
+                                   [(instant-year ?t) ?year]
+                                   [(>= ?year 2018)]
+                                   [(truncate-instant ?t :month) ?month]
+                            :order ?month]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(instant("2018-01-01T00:00:00.000Z")),
+                                          Binding::Scalar(instant("2018-02-01T00:00:00.000Z"))]));
+
+    // Converting to and from microseconds round-trips.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?micros ?later]
+                            :where [_ :visit/at ?t]
+                                   [(instant-year ?t) ?year]
+                                   [(instant-month ?t) ?month]
+                                   [(>= ?year 2018)]
+                                   [(> ?month 1)]
+                                   [(instant->long ?t) ?micros]
+                                   [(+ ?micros 3600000000) ?next]
+                                   [(long->instant ?next) ?later]]"#,
+                        None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Tuple(Some(vec![Binding::Scalar(TypedValue::Long(1518609600000000)),
+                                                Binding::Scalar(instant("2018-02-14T13:00:00.000Z"))])));
+}
+
+#[test]
+fn test_tx_log() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let t1 = conn.transact(&mut c, r#"[{:db/id "a" :foo/name "Alice" :foo/age 30}]"#).unwrap();
+    let alice = t1.tempids.get("a").cloned().expect("Alice");
+    let t2 = conn.transact(&mut c, format!("[[:db/add {} :foo/age 31]]", alice).as_str()).unwrap();
+
+    // Every transaction since the schema was defined.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?tx ...]
+                            :in ?since ?until
+                            :where [(tx-ids $ ?since ?until) [?tx ...]]
+                            :order ?tx]"#,
+                        QueryInputs::with_value_sequence(vec![
+                            (Variable::from_valid_name("?since"), TypedValue::Ref(t1.tx_id)),
+                            (Variable::from_valid_name("?until"), TypedValue::Ref(t2.tx_id + 1)),
+                        ]))
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::Ref(t1.tx_id)),
+                                          Binding::Scalar(TypedValue::Ref(t2.tx_id))]));
+
+    // The second transaction retracted one age and asserted another.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?e ?v ?added
+                            :in ?tx
+                            :where [(tx-data $ ?tx) [[?e ?a ?v _ ?added]]]
+                                   [?a :db/ident :foo/age]
+                            :order ?v]"#,
+                        QueryInputs::with_value_sequence(vec![
+                            (Variable::from_valid_name("?tx"), TypedValue::Ref(t2.tx_id)),
+                        ]))
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::Ref(alice)), Binding::Scalar(TypedValue::Long(30)), Binding::Scalar(TypedValue::Boolean(false))],
+        vec![Binding::Scalar(TypedValue::Ref(alice)), Binding::Scalar(TypedValue::Long(31)), Binding::Scalar(TypedValue::Boolean(true))],
+    ]));
+}
+
+#[test]
+fn test_tx_log_fulltext() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/bio  :db/valueType :db.type/string :db/cardinality :db.cardinality/one
+         :db/fulltext true :db/index true}
+    ]"#).unwrap();
+
+    let t1 = conn.transact(&mut c, r#"[{:foo/name "Alice" :foo/bio "hello darkness my old friend"}]"#).unwrap();
+
+    // The log stores the bio by rowid, but we get its text back.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?v ...]
+                            :in ?tx
+                            :where [(tx-data $ ?tx) [[?e _ ?v]]]
+                                   [?e :foo/name _]
+                            :order ?v]"#,
+                        QueryInputs::with_value_sequence(vec![
+                            (Variable::from_valid_name("?tx"), TypedValue::Ref(t1.tx_id)),
+                        ]))
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::typed_string("Alice")),
+                                          Binding::Scalar(TypedValue::typed_string("hello darkness my old friend"))]));
+}
+
+#[test]
+fn test_history() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let t1 = conn.transact(&mut c, r#"[{:db/id "a" :foo/age 30}]"#).unwrap();
+    let alice = t1.tempids.get("a").cloned().expect("Alice");
+    let t2 = conn.transact(&mut c, format!("[[:db/add {} :foo/age 31]]", alice).as_str()).unwrap();
+
+    // The current state only knows about the new age…
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?v ...] :where [?e :foo/age ?v]]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::Long(31))]));
+
+    // …but the history has both, and the retraction of the old one.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?v ?tx ?added
+                            :where [$history ?e :foo/age ?v ?tx ?added]
+                            :order ?tx ?v]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::Long(30)), Binding::Scalar(TypedValue::Ref(t1.tx_id)), Binding::Scalar(TypedValue::Boolean(true))],
+        vec![Binding::Scalar(TypedValue::Long(30)), Binding::Scalar(TypedValue::Ref(t2.tx_id)), Binding::Scalar(TypedValue::Boolean(false))],
+        vec![Binding::Scalar(TypedValue::Long(31)), Binding::Scalar(TypedValue::Ref(t2.tx_id)), Binding::Scalar(TypedValue::Boolean(true))],
+    ]));
+
+    // Only retractions.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?v ...] :where [$history ?e :foo/age ?v _ false]]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::Long(30))]));
+}
+
+#[test]
+fn test_history_fulltext() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/bio :db/valueType :db.type/string :db/cardinality :db.cardinality/one
+         :db/fulltext true :db/index true}
+    ]"#).unwrap();
+
+    let t1 = conn.transact(&mut c, r#"[{:db/id "a" :foo/bio "hello darkness my old friend"}]"#).unwrap();
+    let alice = t1.tempids.get("a").cloned().expect("Alice");
+    let t2 = conn.transact(&mut c, format!("[[:db/add {} :foo/bio \"I've come to talk with you again\"]]", alice).as_str()).unwrap();
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?v ?tx ?added
+                            :where [$history ?e :foo/bio ?v ?tx ?added]
+                            :order ?tx ?v]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::typed_string("hello darkness my old friend")), Binding::Scalar(TypedValue::Ref(t1.tx_id)), Binding::Scalar(TypedValue::Boolean(true))],
+        vec![Binding::Scalar(TypedValue::typed_string("I've come to talk with you again")), Binding::Scalar(TypedValue::Ref(t2.tx_id)), Binding::Scalar(TypedValue::Boolean(true))],
+        vec![Binding::Scalar(TypedValue::typed_string("hello darkness my old friend")), Binding::Scalar(TypedValue::Ref(t2.tx_id)), Binding::Scalar(TypedValue::Boolean(false))],
+    ]));
+
+    // We can match on the text, too.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?tx ...]
+                            :where [$history ?e :foo/bio "hello darkness my old friend" ?tx]
+                            :order ?tx]"#, None)
+                .expect("results")
+                .into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::Ref(t1.tx_id)),
+                                          Binding::Scalar(TypedValue::Ref(t2.tx_id))]));
+}
+
+#[test]
+fn test_as_of_and_since() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :foo/age  :db/valueType :db.type/long   :db/cardinality :db.cardinality/one}
+    ]"#).unwrap();
+
+    let t1 = conn.transact(&mut c, r#"[{:db/id "a" :foo/name "Alice" :foo/age 30}]"#).unwrap();
+    let alice = t1.tempids.get("a").cloned().expect("Alice");
+    let t2 = conn.transact(&mut c, format!("[[:db/add {} :foo/age 31]]", alice).as_str()).unwrap();
+    let t3 = conn.transact(&mut c, format!("[[:db/retract {} :foo/name \"Alice\"]]", alice).as_str()).unwrap();
+
+    let name = NamespacedKeyword::new("foo", "name");
+    let age = NamespacedKeyword::new("foo", "age");
+    let ages = r#"[:find [?v ...] :where [?e :foo/age ?v]]"#;
+
+    // Now.
+    assert_eq!(conn.lookup_value_for_attribute(&c, alice, &name).expect("lookup"), None);
+    assert_eq!(conn.lookup_value_for_attribute(&c, alice, &age).expect("lookup"), Some(TypedValue::Long(31)));
+
+    // Before the age changed.
+    let view = conn.as_of(&c, t1.tx_id);
+    assert_eq!(view.lookup_value_for_attribute(alice, &name).expect("lookup"), Some(TypedValue::typed_string("Alice")));
+    assert_eq!(view.lookup_value_for_attribute(alice, &age).expect("lookup"), Some(TypedValue::Long(30)));
+    let r = view.q_once(ages, None).expect("results").into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::Long(30))]));
+
+    // Before the name was retracted.
+    let view = conn.as_of(&c, t2.tx_id);
+    assert_eq!(view.lookup_value_for_attribute(alice, &name).expect("lookup"), Some(TypedValue::typed_string("Alice")));
+    assert_eq!(view.lookup_value_for_attribute(alice, &age).expect("lookup"), Some(TypedValue::Long(31)));
+
+    // After.
+    let view = conn.as_of(&c, t3.tx_id);
+    assert_eq!(view.lookup_value_for_attribute(alice, &name).expect("lookup"), None);
+
+    // Before Alice existed.
+    let view = conn.as_of(&c, t1.tx_id - 1);
+    let r = view.q_once(ages, None).expect("results").into();
+    assert_eq!(r, QueryResults::Coll(vec![]));
+
+    // Only the new age was asserted after the first transaction, and the name is gone.
+    let view = conn.since(&c, t1.tx_id);
+    let r = view.q_once(ages, None).expect("results").into();
+    assert_eq!(r, QueryResults::Coll(vec![Binding::Scalar(TypedValue::Long(31))]));
+    assert_eq!(view.lookup_value_for_attribute(alice, &name).expect("lookup"), None);
+
+    // Pull expressions read from the view, too.
+    let pull = r#"[:find (pull ?e [:foo/name :foo/age]) . :where [?e :foo/age _]]"#;
+    let pull_all = r#"[:find (pull ?e [*]) . :where [?e :foo/age _]]"#;
+    let r = view.q_once(pull, None).expect("results").into();
+    assert_eq!(r, QueryResults::Scalar(Some(pulled(vec![(kw!(:foo/age), Binding::Scalar(TypedValue::Long(31)))]))));
+
+    let view = conn.as_of(&c, t1.tx_id);
+    let r = view.q_once(pull, None).expect("results").into();
+    assert_eq!(r, QueryResults::Scalar(Some(pulled(vec![(kw!(:foo/name), Binding::Scalar(TypedValue::typed_string("Alice"))),
+                                                        (kw!(:foo/age), Binding::Scalar(TypedValue::Long(30)))]))));
+    let r = view.q_once(pull_all, None).expect("results").into();
+    assert_eq!(r, QueryResults::Scalar(Some(pulled(vec![(kw!(:db/id), Binding::Scalar(TypedValue::Ref(alice))),
+                                                        (kw!(:foo/name), Binding::Scalar(TypedValue::typed_string("Alice"))),
+                                                        (kw!(:foo/age), Binding::Scalar(TypedValue::Long(30)))]))));
+}
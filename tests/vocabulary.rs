@@ -35,6 +35,7 @@ use mentat_core::{
 use mentat_db::AttributeValidation;
 
 use mentat::{
+    Binding,
     Conn,
     NamespacedKeyword,
     Queryable,
@@ -116,8 +117,9 @@ fn test_real_world() {
                               None)
                       .into_rel_result()
                       .expect("query succeeded");
-    assert_eq!(results,
-               vec![vec![alice, now.clone()], vec![barbara, now.clone()]]);
+    let expected: Vec<Vec<Binding>> = vec![vec![alice.into(), now.clone().into()],
+                                           vec![barbara.into(), now.clone().into()]];
+    assert_eq!(results, expected);
 }
 
 #[test]
@@ -205,8 +207,8 @@ fn test_add_vocab() {
                        .into_tuple_result()
                        .expect("query returns")
                        .expect("a result");
-        assert_eq!(ver_attr[0], TypedValue::Long(1));
-        assert_eq!(ver_attr[1], TypedValue::typed_ns_keyword("foo", "bar"));
+        assert_eq!(ver_attr[0], Binding::Scalar(TypedValue::Long(1)));
+        assert_eq!(ver_attr[1], Binding::Scalar(TypedValue::typed_ns_keyword("foo", "bar")));
 
         // If we commit, it'll stick around.
         in_progress.commit().expect("commit succeeded");
@@ -220,8 +222,8 @@ fn test_add_vocab() {
             .into_tuple_result()
             .expect("query returns")
             .expect("a result");
-    assert_eq!(ver_attr[0], TypedValue::Long(1));
-    assert_eq!(ver_attr[1], TypedValue::typed_ns_keyword("foo", "bar"));
+    assert_eq!(ver_attr[0], Binding::Scalar(TypedValue::Long(1)));
+    assert_eq!(ver_attr[1], Binding::Scalar(TypedValue::typed_ns_keyword("foo", "bar")));
 
     // Scoped borrow of `conn`.
     {
@@ -255,11 +257,11 @@ fn test_add_vocab() {
                     None)
             .into_coll_result()
             .expect("query returns");
-    assert_eq!(actual_attributes,
-               vec![
-                   TypedValue::typed_ns_keyword("foo", "bar"),
-                   TypedValue::typed_ns_keyword("foo", "baz"),
-               ]);
+    let expected: Vec<Binding> = vec![
+        TypedValue::typed_ns_keyword("foo", "bar").into(),
+        TypedValue::typed_ns_keyword("foo", "baz").into(),
+    ];
+    assert_eq!(actual_attributes, expected);
 
     // Now let's modify our vocabulary without bumping the version. This is invalid and will result
     // in an error.
@@ -15,6 +15,7 @@ use std::process;
 use tabwriter::TabWriter;
 
 use mentat::{
+    Binding,
     Queryable,
     QueryExplanation,
     QueryOutput,
@@ -22,6 +23,7 @@ use mentat::{
     Store,
     TxReport,
     TypedValue,
+    ident_for_ref,
 };
 
 use command_parser::{
@@ -219,14 +221,14 @@ impl Repl {
         match query_output.results {
             QueryResults::Scalar(v) => {
                 if let Some(val) = v {
-                    writeln!(output, "| {}\t |", &self.typed_value_as_string(val))?;
+                    writeln!(output, "| {}\t |", &self.binding_as_string(val))?;
                 }
             },
 
             QueryResults::Tuple(vv) => {
                 if let Some(vals) = vv {
                     for val in vals {
-                        write!(output, "| {}\t", self.typed_value_as_string(val))?;
+                        write!(output, "| {}\t", self.binding_as_string(val))?;
                     }
                     writeln!(output, "|")?;
                 }
@@ -234,14 +236,14 @@ impl Repl {
 
             QueryResults::Coll(vv) => {
                 for val in vv {
-                    writeln!(output, "| {}\t|", self.typed_value_as_string(val))?;
+                    writeln!(output, "| {}\t|", self.binding_as_string(val))?;
                 }
             },
 
             QueryResults::Rel(vvv) => {
                 for vv in vvv {
                     for v in vv {
-                        write!(output, "| {}\t", self.typed_value_as_string(v))?;
+                        write!(output, "| {}\t", self.binding_as_string(v))?;
                     }
                     writeln!(output, "|")?;
                 }
@@ -306,6 +308,22 @@ impl Repl {
         Ok(report)
     }
 
+    fn binding_as_string(&self, value: Binding) -> String {
+        match value {
+            Binding::Scalar(v) => self.typed_value_as_string(v),
+            Binding::Vec(vs) => {
+                let vs: Vec<String> = vs.iter().cloned().map(|v| self.binding_as_string(v)).collect();
+                format!("[{}]", vs.join(" "))
+            },
+            Binding::Map(m) => {
+                let pairs: Vec<String> = m.0.iter()
+                                            .map(|(k, v)| format!("{} {}", k, self.binding_as_string(v.clone())))
+                                            .collect();
+                format!("{{{}}}", pairs.join(", "))
+            },
+        }
+    }
+
     fn typed_value_as_string(&self, value: TypedValue) -> String {
         match value {
             TypedValue::Boolean(b) => if b { "true".to_string() } else { "false".to_string() },
@@ -313,7 +331,14 @@ impl Repl {
             TypedValue::Instant(i) => format!("{}", i),
             TypedValue::Keyword(k) => format!("{}", k),
             TypedValue::Long(l) => format!("{}", l),
-            TypedValue::Ref(r) => format!("{}", r),
+            TypedValue::Ref(r) => {
+                // Refs to enum-like entities are more useful shown as their idents.
+                let v = TypedValue::Ref(r);
+                match ident_for_ref(&*self.store.conn().current_schema(), &v) {
+                    Some(TypedValue::Keyword(k)) => format!("{}", k),
+                    _ => format!("{}", r),
+                }
+            },
             TypedValue::String(s) => format!("{:?}", s.to_string()),
             TypedValue::Uuid(u) => format!("{}", u),
         }
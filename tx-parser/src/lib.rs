@@ -147,6 +147,59 @@ def_parser!(Tx, add_or_retract, Entity, {
             }))
 });
 
+def_matches_namespaced_keyword!(Tx, literal_db_retract_entity, "db", "retractEntity");
+
+def_matches_namespaced_keyword!(Tx, literal_db_retract_attribute, "db", "retractAttribute");
+
+def_parser!(Tx, retract_entity, Entity, {
+    vector().of_exactly(
+        Tx::literal_db_retract_entity()
+            .with(Tx::entid_or_lookup_ref_or_temp_id())
+            .map(|e| Entity::RetractEntity { e: e }))
+});
+
+def_parser!(Tx, retract_attribute, Entity, {
+    vector().of_exactly(
+        Tx::literal_db_retract_attribute()
+            .with((Tx::entid_or_lookup_ref_or_temp_id(),
+                   Tx::forward_entid()))
+            .map(|(e, a)| Entity::RetractAttribute { e: e, a: a }))
+});
+
+def_matches_namespaced_keyword!(Tx, literal_db_cas, "db", "cas");
+
+def_parser!(Tx, cas, Entity, {
+    vector().of_exactly(
+        Tx::literal_db_cas()
+            .with((Tx::entid_or_lookup_ref_or_temp_id(),
+                   Tx::forward_entid(),
+                   Tx::atom(),
+                   Tx::atom()))
+            .map(|(e, a, v_old, v_new)| {
+                Entity::Cas {
+                    e: e,
+                    a: a,
+                    v_old: if v_old.inner.is_nil() { None } else { Some(v_old.clone()) },
+                    v_new: v_new.clone(),
+                }
+            }))
+});
+
+// Accepts any namespaced keyword outside the :db namespace, which is reserved for built-in
+// operations.
+def_parser!(Tx, function_name, edn::NamespacedKeyword, {
+    satisfy_map(|x: &'a edn::ValueAndSpan| {
+        x.inner.as_namespaced_keyword().and_then(|k| if k.namespace == "db" { None } else { Some(k.clone()) })
+    })
+});
+
+def_parser!(Tx, call, Entity, {
+    vector().of_exactly(
+        (Tx::function_name(),
+         many(satisfy_map(|x: &'a edn::ValueAndSpan| Some(x.clone()))))
+            .map(|(f, args)| Entity::Call { f: f, args: args }))
+});
+
 def_parser!(Tx, map_notation, MapNotation, {
     map()
         .of_exactly(many((Tx::entid(), Tx::atom_or_lookup_ref_or_vector())))
@@ -156,8 +209,14 @@ def_parser!(Tx, map_notation, MapNotation, {
 });
 
 def_parser!(Tx, entity, Entity, {
-    Tx::add_or_retract()
-        .or(Tx::map_notation().map(Entity::MapNotation))
+    choice::<[&mut Parser<Input = _, Output = Entity>; 6], _>
+        ([&mut try(Tx::retract_entity()),
+          &mut try(Tx::retract_attribute()),
+          &mut try(Tx::cas()),
+          &mut try(Tx::call()),
+          &mut Tx::add_or_retract(),
+          &mut Tx::map_notation().map(Entity::MapNotation),
+        ])
 });
 
 def_parser!(Tx, entities, Vec<Entity>, {
@@ -347,4 +406,118 @@ mod tests {
         assert_eq!(result,
                    Ok(Entity::MapNotation(expected)));
     }
+
+    #[test]
+    fn test_retract_entity() {
+        let input = Value::Vector(vec![kw("db", "retractEntity"),
+                                       Value::Integer(101)]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::RetractEntity {
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
+                   }));
+    }
+
+    #[test]
+    fn test_retract_attribute() {
+        let input = Value::Vector(vec![kw("db", "retractAttribute"),
+                                       Value::List(vec![Value::PlainSymbol(PlainSymbol::new("lookup-ref")),
+                                                        kw("test", "a1"),
+                                                        Value::Text("v1".into())].into_iter().collect()),
+                                       kw("test", "a")]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::RetractAttribute {
+                       e: EntidOrLookupRefOrTempId::LookupRef(LookupRef {
+                           a: Entid::Ident(NamespacedKeyword::new("test", "a1")),
+                           v: Value::Text("v1".into()),
+                       }),
+                       a: Entid::Ident(NamespacedKeyword::new("test", "a")),
+                   }));
+
+        // Backward attributes name no datoms of the entity.
+        let input = Value::Vector(vec![kw("db", "retractAttribute"),
+                                       Value::Integer(101),
+                                       kw("test", "_a")]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        assert!(Tx::entity().parse(stream).is_err());
+    }
+
+    #[test]
+    fn test_cas() {
+        let input = Value::Vector(vec![kw("db", "cas"),
+                                       Value::Integer(101),
+                                       kw("test", "a"),
+                                       Value::Integer(1),
+                                       Value::Integer(2)]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::Cas {
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
+                       a: Entid::Ident(NamespacedKeyword::new("test", "a")),
+                       v_old: Some(ValueAndSpan::new(SpannedValue::Integer(1), Span(21, 22))),
+                       v_new: ValueAndSpan::new(SpannedValue::Integer(2), Span(23, 24)),
+                   }));
+
+        // nil expects no current value.
+        let input = Value::Vector(vec![kw("db", "cas"),
+                                       Value::Integer(101),
+                                       kw("test", "a"),
+                                       Value::Nil,
+                                       Value::Integer(2)]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::Cas {
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
+                       a: Entid::Ident(NamespacedKeyword::new("test", "a")),
+                       v_old: None,
+                       v_new: ValueAndSpan::new(SpannedValue::Integer(2), Span(25, 26)),
+                   }));
+    }
+
+    #[test]
+    fn test_call() {
+        let input = Value::Vector(vec![kw("app", "increment"),
+                                       Value::Integer(101),
+                                       kw("test", "a"),
+                                       Value::Integer(2)]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::Call {
+                       f: NamespacedKeyword::new("app", "increment"),
+                       args: vec![ValueAndSpan::new(SpannedValue::Integer(101), Span(16, 19)),
+                                  ValueAndSpan::new(SpannedValue::NamespacedKeyword(NamespacedKeyword::new("test", "a")), Span(20, 27)),
+                                  ValueAndSpan::new(SpannedValue::Integer(2), Span(28, 29))],
+                   }));
+
+        // Unknown :db operations aren't function calls.
+        let input = Value::Vector(vec![kw("db", "increment"),
+                                       Value::Integer(101)]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        assert!(Tx::entity().parse(stream).is_err());
+    }
 }
@@ -125,4 +125,25 @@ pub enum Entity {
     },
     // Like {:db/id "tempid" a1 v1 a2 v2}.
     MapNotation(MapNotation),
+    // Like [:db/retractEntity e].
+    RetractEntity {
+        e: EntidOrLookupRefOrTempId,
+    },
+    // Like [:db/retractAttribute e a].
+    RetractAttribute {
+        e: EntidOrLookupRefOrTempId,
+        a: Entid,
+    },
+    // Like [:db/cas e a old-v new-v].  An old value of `nil` expects no current value.
+    Cas {
+        e: EntidOrLookupRefOrTempId,
+        a: Entid,
+        v_old: Option<edn::ValueAndSpan>,
+        v_new: edn::ValueAndSpan,
+    },
+    // Like [:app/function arg1 arg2], invoking a transaction function.
+    Call {
+        f: NamespacedKeyword,
+        args: Vec<edn::ValueAndSpan>,
+    },
 }